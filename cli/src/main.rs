@@ -4,8 +4,6 @@ use std::net::TcpStream;
 extern crate clap;
 use clap::{App, Arg};
 
-use resp;
-
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 fn main() -> Result<()> {
@@ -28,6 +26,12 @@ fn main() -> Result<()> {
                 .help("Port of kyev server")
                 .default_value("8080"),
         )
+        .arg(
+            Arg::with_name("rdb")
+                .long("rdb")
+                .value_name("FILE")
+                .help("Transfer an RDB dump from the server to FILE, then exit"),
+        )
         .get_matches();
 
     let host = format!(
@@ -40,16 +44,20 @@ fn main() -> Result<()> {
     let (reader, mut writer) = (&stream, &stream);
     let mut buf_reader = BufReader::new(reader);
 
+    if let Some(path) = matches.value_of("rdb") {
+        return export_rdb(&mut buf_reader, writer, path);
+    }
+
     loop {
         let mut input = String::new();
-        let mut output = String::new();
+        let mut output = Vec::new();
 
         write_prompt(&host);
         stdin().read_line(&mut input)?;
         let resp = encode_resp(&input);
 
-        writer.write(resp.as_bytes())?;
-        while let Ok(bytes_read) = buf_reader.read_line(&mut output) {
+        writer.write_all(&resp)?;
+        while let Ok(bytes_read) = buf_reader.read_until(b'\n', &mut output) {
             if bytes_read == 0 {
                 break;
             }
@@ -70,44 +78,85 @@ fn main() -> Result<()> {
     }
 }
 
+/// Asks the server for a copy of its data with SYNC and saves the RDB file it sends back.
+fn export_rdb(reader: &mut impl BufRead, mut writer: impl Write, path: &str) -> Result<()> {
+    writer.write_all(&encode_resp("SYNC"))?;
+    println!("SYNC sent to master, writing bulk transfer to '{}'", path);
+
+    // The master sends newlines to keep the link alive while it prepares the transfer.
+    let mut header = String::new();
+    while header.trim_end().is_empty() {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 {
+            return Err("Connection closed before the transfer began".into());
+        }
+    }
+    let header = header.trim_end();
+    if let Some(error) = header.strip_prefix('-') {
+        return Err(error.into());
+    }
+    let len = header
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<usize>().ok())
+        .ok_or_else(|| format!("Unexpected reply to SYNC: {}", header))?;
+    println!("Transfer size: {} bytes", len);
+
+    // The payload is not followed by a CRLF, so read exactly its length.
+    let mut rdb = vec![0; len];
+    reader.read_exact(&mut rdb)?;
+    std::fs::write(path, &rdb)?;
+    println!("Transfer finished with success.");
+    Ok(())
+}
+
 fn translate_resp(value: &resp::Value) -> String {
     use resp::Value;
     match value {
-        Value::SimpleString(s) | Value::BulkString(s) => format!("\"{}\"", s),
-        Value::Error(e) => format!("{}", e),
-        Value::Null => format!("(nil)"),
+        Value::SimpleString(s) => format!("\"{}\"", s),
+        Value::BulkString(b) => format!("\"{}\"", escape(b)),
+        Value::Error(e) => e.to_string(),
+        Value::Null => "(nil)".to_string(),
         Value::Integer(i) => format!("(integer) {}", i),
         Value::Array(arr) => {
             let lines: Vec<String> = arr
                 .iter()
                 .enumerate()
-                .map(|(i, val)| format!("{}) {}", i + 1, translate_resp(&val)))
+                .map(|(i, val)| format!("{}) {}", i + 1, translate_resp(val)))
                 .collect();
             lines.join("\n")
         }
     }
 }
 
+/// Escapes non-printable bytes as `\xNN` so binary values can be displayed.
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect()
+}
+
 fn write_prompt(host: &str) {
     print!("{}> ", host);
     stdout().flush().unwrap();
 }
 
-fn encode_resp(input: &str) -> String {
+fn encode_resp(input: &str) -> Vec<u8> {
     let array = tokenize(input.trim_end())
         .iter()
-        .map(|s| resp::bulk_string(s.as_str()))
+        .map(resp::bulk_string)
         .collect();
     resp::encode(&resp::array(array))
 }
 
 fn tokenize(s: &str) -> Vec<String> {
     let mut tokens = Vec::new();
-    let mut chars = s.chars();
+    let chars = s.chars();
 
     let mut in_quote = false;
     let mut token = String::new();
-    while let Some(c) = chars.next() {
+    for c in chars {
         if c == '"' {
             in_quote = !in_quote;
             continue;
@@ -149,4 +198,19 @@ mod tests {
             tokenize("ECHO \"foo bar\"")
         );
     }
+
+    #[test]
+    fn test_export_rdb() {
+        let path = std::env::temp_dir().join(format!("kyev-cli-{}.rdb", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut reader = &b"\n\n$9\r\nREDIS0009"[..];
+        let mut sent = vec![];
+        export_rdb(&mut reader, &mut sent, path).unwrap();
+        assert_eq!(encode_resp("SYNC"), sent);
+        assert_eq!(b"REDIS0009".to_vec(), std::fs::read(path).unwrap());
+        std::fs::remove_file(path).unwrap();
+
+        let mut reader = &b"-ERR not allowed\r\n"[..];
+        assert!(export_rdb(&mut reader, vec![], path).is_err());
+    }
 }
@@ -1,7 +1,7 @@
 use std::convert::From;
 use std::io::{BufRead, BufReader, Read};
 
-static DELIMITER: &str = "\r\n";
+static DELIMITER: &[u8] = b"\r\n";
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -28,20 +28,30 @@ impl From<std::str::Utf8Error> for Error {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Array(Vec<Value>),
     SimpleString(String),
-    BulkString(String),
+    BulkString(Vec<u8>),
     Error(String),
     Integer(i64),
     Null,
 }
 
 impl Value {
+    /// Returns the value as UTF-8 text. Fails for bulk strings that are not valid UTF-8.
     pub fn to_string(&self) -> Result<String, Error> {
         match self {
-            Value::SimpleString(s) | Value::BulkString(s) => Ok(s.to_string()),
+            Value::SimpleString(s) => Ok(s.to_string()),
+            Value::BulkString(b) => String::from_utf8(b.clone()).map_err(|_| Error::NotStringError),
+            _ => Err(Error::NotStringError),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&[u8], Error> {
+        match self {
+            Value::SimpleString(s) => Ok(s.as_bytes()),
+            Value::BulkString(b) => Ok(b),
             _ => Err(Error::NotStringError),
         }
     }
@@ -55,8 +65,8 @@ pub fn simple_string(s: &str) -> Value {
     Value::SimpleString(s.to_owned())
 }
 
-pub fn bulk_string(s: &str) -> Value {
-    Value::BulkString(s.to_owned())
+pub fn bulk_string<B: AsRef<[u8]> + ?Sized>(b: &B) -> Value {
+    Value::BulkString(b.as_ref().to_vec())
 }
 
 pub fn error(s: &str) -> Value {
@@ -67,68 +77,46 @@ pub fn integer(i: i64) -> Value {
     Value::Integer(i)
 }
 
-pub fn encode(value: &Value) -> String {
-    match value {
-        Value::SimpleString(_) => encode_simple_string(value),
-        Value::BulkString(_) => encode_bulk_string(value),
-        Value::Array(_) => encode_array(value),
-        Value::Error(_) => encode_error(value),
-        Value::Integer(_) => encode_integer(value),
-        Value::Null => "$-1\r\n".to_owned(),
-    }
-}
-
-fn encode_simple_string(value: &Value) -> String {
-    match value {
-        Value::SimpleString(s) => format!("+{}\r\n", s),
-        _ => panic!("Must be called with Value::SimpleString"),
-    }
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut buf = vec![];
+    encode_into(value, &mut buf);
+    buf
 }
 
-fn encode_error(value: &Value) -> String {
+fn encode_into(value: &Value, buf: &mut Vec<u8>) {
     match value {
-        Value::Error(s) => format!("-{}\r\n", s),
-        _ => panic!("Must be called with Value::Error"),
-    }
-}
-
-fn encode_bulk_string(value: &Value) -> String {
-    match value {
-        Value::BulkString(s) => {
-            let byte_count = s.bytes().len();
-            format!("${}\r\n{}\r\n", byte_count, s)
+        Value::SimpleString(s) => buf.extend(format!("+{}\r\n", s).as_bytes()),
+        Value::BulkString(b) => {
+            buf.extend(format!("${}\r\n", b.len()).as_bytes());
+            buf.extend(b);
+            buf.extend(DELIMITER);
         }
-        _ => panic!("Must be called with Value::BulkString"),
-    }
-}
-
-fn encode_integer(value: &Value) -> String {
-    match value {
-        Value::Integer(i) => format!(":{}\r\n", i),
-        _ => panic!("Must be called with Value::Integer"),
-    }
-}
-
-fn encode_array(value: &Value) -> String {
-    match value {
         Value::Array(array) => {
-            let mut string_buf = String::new();
-
+            buf.extend(format!("*{}\r\n", array.len()).as_bytes());
             for value in array.iter() {
-                string_buf.push_str(&encode(&value));
+                encode_into(value, buf);
             }
-
-            format!("*{}\r\n{}", array.len(), string_buf)
         }
-        _ => panic!("Must be called with Value::Array"),
+        Value::Error(s) => buf.extend(format!("-{}\r\n", s).as_bytes()),
+        Value::Integer(i) => buf.extend(format!(":{}\r\n", i).as_bytes()),
+        Value::Null => buf.extend(b"$-1\r\n"),
     }
 }
 
-pub fn decode(s: &str) -> Result<Value, Error> {
-    let mut buf_reader = BufReader::new(s.as_bytes());
+pub fn decode<B: AsRef<[u8]> + ?Sized>(b: &B) -> Result<Value, Error> {
+    let mut buf_reader = BufReader::new(b.as_ref());
     do_decode(&mut buf_reader)
 }
 
+/// Decodes the value at the start of `b` and returns it with the number of bytes it took,
+/// so that consecutive values can be decoded one after another.
+pub fn decode_prefix<B: AsRef<[u8]> + ?Sized>(b: &B) -> Result<(Value, usize), Error> {
+    let b = b.as_ref();
+    let mut buf_reader = BufReader::new(b);
+    let value = do_decode(&mut buf_reader)?;
+    Ok((value, b.len() - unread_len(&buf_reader)))
+}
+
 fn do_decode(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
     let mut buf = vec![0; 1];
     buf_reader.read_exact(&mut buf)?;
@@ -142,67 +130,65 @@ fn do_decode(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
     }
 }
 
-fn decode_simple_string(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
-    let mut buf = String::new();
-    buf_reader.read_line(&mut buf)?;
+/// The number of bytes left to decode.
+fn unread_len(buf_reader: &BufReader<&[u8]>) -> usize {
+    buf_reader.buffer().len() + buf_reader.get_ref().len()
+}
+
+/// Reads a line terminated by `\r\n`, returning it without the delimiter.
+fn read_line(buf_reader: &mut BufReader<&[u8]>) -> Result<String, Error> {
+    let mut buf = vec![];
+    buf_reader.read_until(b'\n', &mut buf)?;
 
     if buf.ends_with(DELIMITER) {
-        Ok(Value::SimpleString(buf.trim_end().to_owned()))
+        buf.truncate(buf.len() - DELIMITER.len());
+        Ok(std::str::from_utf8(&buf)?.to_owned())
     } else {
         Err(Error::IncompleteRespError)
     }
 }
 
-fn decode_error(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
-    let mut buf = String::new();
-    buf_reader.read_line(&mut buf)?;
+fn decode_simple_string(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
+    Ok(Value::SimpleString(read_line(buf_reader)?))
+}
 
-    if buf.ends_with(DELIMITER) {
-        Ok(Value::Error(buf.trim_end().to_owned()))
-    } else {
-        Err(Error::IncompleteRespError)
-    }
+fn decode_error(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
+    Ok(Value::Error(read_line(buf_reader)?))
 }
 
 fn decode_bulk_string(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
-    let byte_count = read_int_with_clrf(buf_reader)?;
+    let byte_count = read_line(buf_reader)?.parse::<isize>()?;
 
-    if byte_count > 0 {
-        let byte_count = byte_count as usize;
-        let mut buf = vec![0; byte_count];
-        buf_reader.read_exact(&mut buf)?;
-        let string = std::str::from_utf8(&buf)?;
-        if string.len() != byte_count {
+    if byte_count >= 0 {
+        // Checked before allocating, so that a partly received value costs nothing to retry.
+        if unread_len(buf_reader) < byte_count as usize + DELIMITER.len() {
             return Err(Error::IncompleteRespError);
         }
-
-        let mut buf = vec![0; DELIMITER.len()];
+        let mut buf = vec![0; byte_count as usize + DELIMITER.len()];
         buf_reader.read_exact(&mut buf)?;
-        let closing_delimiter = std::str::from_utf8(&buf)?;
-        if closing_delimiter != DELIMITER {
+        if !buf.ends_with(DELIMITER) {
             return Err(Error::IncompleteRespError);
         }
-        Ok(Value::BulkString(string.to_owned()))
+        buf.truncate(byte_count as usize);
+        Ok(Value::BulkString(buf))
     } else {
         Ok(Value::Null)
     }
 }
 
 fn decode_integer(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
-    let mut buf = String::new();
-    buf_reader.read_line(&mut buf)?;
-
-    if buf.ends_with(DELIMITER) {
-        Ok(Value::Integer(buf.trim_end().parse::<i64>()?))
-    } else {
-        Err(Error::IncompleteRespError)
-    }
+    Ok(Value::Integer(read_line(buf_reader)?.parse::<i64>()?))
 }
 
 fn decode_array(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
-    let element_count = read_int_with_clrf(buf_reader)?;
+    let element_count = read_line(buf_reader)?.parse::<isize>()?;
+    if element_count < 0 {
+        return Ok(Value::Null);
+    }
 
-    let mut resp_array = Vec::with_capacity(element_count as usize);
+    // Every element takes at least three bytes, which bounds what a bogus count can reserve.
+    let capacity = (element_count as usize).min(unread_len(buf_reader) / 3);
+    let mut resp_array = Vec::with_capacity(capacity);
 
     for _ in 0..element_count {
         let value = do_decode(buf_reader)?;
@@ -212,18 +198,6 @@ fn decode_array(buf_reader: &mut BufReader<&[u8]>) -> Result<Value, Error> {
     Ok(Value::Array(resp_array))
 }
 
-fn read_int_with_clrf(buf_reader: &mut BufReader<&[u8]>) -> Result<isize, Error> {
-    let mut int_with_clrf = String::new();
-    buf_reader.read_line(&mut int_with_clrf)?;
-
-    if int_with_clrf.ends_with(DELIMITER) {
-        let int = int_with_clrf.trim_end().parse::<isize>()?;
-        Ok(int)
-    } else {
-        Err(Error::IncompleteRespError)
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -231,15 +205,15 @@ mod tests {
     #[test]
     fn test_encode_simple_string() {
         assert_eq!(
-            "+OK\r\n".to_owned(),
+            b"+OK\r\n".to_vec(),
             encode(&Value::SimpleString("OK".to_owned()))
         );
         assert_eq!(
-            "+HEY\r\n".to_owned(),
+            b"+HEY\r\n".to_vec(),
             encode(&Value::SimpleString("HEY".to_owned()))
         );
         assert_eq!(
-            "+What's up\r\n".to_owned(),
+            b"+What's up\r\n".to_vec(),
             encode(&Value::SimpleString("What's up".to_owned()))
         );
     }
@@ -247,31 +221,31 @@ mod tests {
     #[test]
     fn test_encode_bulk_string() {
         assert_eq!(
-            "$2\r\nOK\r\n".to_owned(),
-            encode(&Value::BulkString("OK".to_owned()))
+            b"$2\r\nOK\r\n".to_vec(),
+            encode(&Value::BulkString(b"OK".to_vec()))
         );
         assert_eq!(
-            "$3\r\nHEY\r\n".to_owned(),
-            encode(&Value::BulkString("HEY".to_owned()))
+            b"$3\r\nHEY\r\n".to_vec(),
+            encode(&Value::BulkString(b"HEY".to_vec()))
         );
         assert_eq!(
-            "$7\r\nHEY\r\nYA\r\n".to_owned(),
-            encode(&Value::BulkString("HEY\r\nYA".to_owned()))
+            b"$7\r\nHEY\r\nYA\r\n".to_vec(),
+            encode(&Value::BulkString(b"HEY\r\nYA".to_vec()))
         );
     }
 
     #[test]
     fn test_encode_array() {
         assert_eq!(
-            "*1\r\n$4\r\nPING\r\n".to_owned(),
-            encode(&Value::Array(vec![Value::BulkString("PING".to_owned())])),
+            b"*1\r\n$4\r\nPING\r\n".to_vec(),
+            encode(&Value::Array(vec![Value::BulkString(b"PING".to_vec())])),
         );
 
         assert_eq!(
-            "*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n".to_owned(),
+            b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n".to_vec(),
             encode(&Value::Array(vec![
-                Value::BulkString("ECHO".to_owned()),
-                Value::BulkString("hey".to_owned())
+                Value::BulkString(b"ECHO".to_vec()),
+                Value::BulkString(b"hey".to_vec())
             ])),
         );
     }
@@ -279,14 +253,14 @@ mod tests {
     #[test]
     fn test_encode_errors() {
         assert_eq!(
-            "-ERR unknown command\r\n",
+            b"-ERR unknown command\r\n".to_vec(),
             encode(&Value::Error("ERR unknown command".to_owned()))
         );
     }
 
     #[test]
     fn test_encode_integers() {
-        assert_eq!(":10\r\n", encode(&Value::Integer(10)));
+        assert_eq!(b":10\r\n".to_vec(), encode(&Value::Integer(10)));
     }
 
     #[test]
@@ -305,15 +279,15 @@ mod tests {
     #[test]
     fn test_decode_bulk_string() {
         assert_eq!(
-            Ok(Value::BulkString("OK".to_owned())),
+            Ok(Value::BulkString(b"OK".to_vec())),
             decode("$2\r\nOK\r\n")
         );
         assert_eq!(
-            Ok(Value::BulkString("HEY".to_owned())),
+            Ok(Value::BulkString(b"HEY".to_vec())),
             decode("$3\r\nHEY\r\n")
         );
         assert_eq!(
-            Ok(Value::BulkString("HEY\r\nYA".to_owned())),
+            Ok(Value::BulkString(b"HEY\r\nYA".to_vec())),
             decode("$7\r\nHEY\r\nYA\r\n")
         );
         assert_eq!(Err(Error::IncompleteRespError), decode("$"));
@@ -329,14 +303,14 @@ mod tests {
     #[test]
     fn test_decode_arrays() {
         assert_eq!(
-            Ok(Value::Array(vec![Value::BulkString("PING".to_owned())])),
+            Ok(Value::Array(vec![Value::BulkString(b"PING".to_vec())])),
             decode("*1\r\n$4\r\nPING\r\n")
         );
 
         assert_eq!(
             Ok(Value::Array(vec![
-                Value::BulkString("ECHO".to_owned()),
-                Value::BulkString("hey".to_owned())
+                Value::BulkString(b"ECHO".to_vec()),
+                Value::BulkString(b"hey".to_vec())
             ])),
             decode("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n")
         );
@@ -351,6 +325,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_decode_prefix() {
+        let stream = b"*1\r\n$4\r\nPING\r\n:10\r\n*1\r\n$4\r\nPI";
+        let (value, len) = decode_prefix(&stream[..]).unwrap();
+        assert_eq!(
+            Value::Array(vec![Value::BulkString(b"PING".to_vec())]),
+            value
+        );
+        assert_eq!(14, len);
+        assert_eq!(Ok((Value::Integer(10), 5)), decode_prefix(&stream[14..]));
+        assert_eq!(
+            Err(Error::IncompleteRespError),
+            decode_prefix(&stream[19..])
+        );
+    }
+
     #[test]
     fn test_decode_errors() {
         assert_eq!(
@@ -373,6 +363,46 @@ mod tests {
     #[test]
     fn test_null() {
         assert_eq!(Ok(Value::Null), decode("$-1\r\n"));
-        assert_eq!("$-1\r\n".to_owned(), encode(&Value::Null));
+        assert_eq!(b"$-1\r\n".to_vec(), encode(&Value::Null));
+    }
+
+    #[test]
+    fn test_binary_bulk_string() {
+        let bytes = vec![0, 159, 146, 150, b'\r', b'\n', 255];
+        let encoded = encode(&Value::BulkString(bytes.clone()));
+        assert_eq!(Ok(Value::BulkString(bytes)), decode(&encoded));
+        assert_eq!(Ok(Value::BulkString(vec![])), decode("$0\r\n\r\n"));
+        assert_eq!(
+            Err(Error::NotStringError),
+            Value::BulkString(vec![255]).to_string()
+        );
+    }
+
+    #[test]
+    fn test_decode_large_binary_in_chunks() {
+        let bytes: Vec<u8> = (0..4 * 1024 * 1024)
+            .map(|i| if i % 3 == 0 { b'\n' } else { (i % 251) as u8 })
+            .collect();
+        let value = Value::Array(vec![
+            bulk_string("SET"),
+            bulk_string("k"),
+            bulk_string(&bytes),
+        ]);
+        let encoded = encode(&value);
+
+        let mut buf = vec![];
+        let mut decoded = None;
+        for chunk in encoded.chunks(64 * 1024) {
+            assert!(decoded.is_none());
+            buf.extend_from_slice(chunk);
+            match decode_prefix(&buf) {
+                Ok((value, len)) => {
+                    assert_eq!(encoded.len(), len);
+                    decoded = Some(value);
+                }
+                Err(e) => assert_eq!(Error::IncompleteRespError, e),
+            }
+        }
+        assert_eq!(Some(value), decoded);
     }
 }
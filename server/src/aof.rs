@@ -0,0 +1,349 @@
+use crate::snapshot::{self, Snapshot};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often the file is synced to disk under `appendfsync everysec`.
+pub const FSYNC_PERIOD: Duration = Duration::from_secs(1);
+
+/// A command as it is logged: its name followed by its arguments.
+pub type Argv = Vec<Vec<u8>>;
+
+/// When the append-only file is synced to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write, so that no acknowledged write is lost.
+    Always,
+    /// Once a second, so that at most a second of writes is lost.
+    #[default]
+    EverySec,
+    /// Whenever the operating system flushes its buffers.
+    No,
+}
+
+impl AppendFsync {
+    pub const NAMES: &'static [&'static str] = &["always", "everysec", "no"];
+}
+
+impl FromStr for AppendFsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("unknown appendfsync policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppendFsync::Always => "always".fmt(f),
+            AppendFsync::EverySec => "everysec".fmt(f),
+            AppendFsync::No => "no".fmt(f),
+        }
+    }
+}
+
+/// The log of executed write commands, replayed at startup to rebuild the store.
+///
+/// BGREWRITEAOF compacts it into a snapshot of the store followed by the commands logged
+/// while the snapshot was written.
+pub struct Aof {
+    file: File,
+    path: PathBuf,
+    fsync: AppendFsync,
+    /// The database the last logged command ran against, so SELECT is only logged when it
+    /// changes.
+    db: Option<usize>,
+    /// Commands logged while a rewrite runs, to be appended to the rewritten file.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl Aof {
+    /// Opens the file at `path` for appending, creating it if needed.
+    pub fn open(path: &Path, fsync: AppendFsync) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Aof {
+            file,
+            path: path.to_owned(),
+            fsync,
+            db: None,
+            rewrite_buffer: None,
+        })
+    }
+
+    /// Logs the commands run by one client command or transaction, each with the database it
+    /// ran against. A transaction's commands are wrapped in MULTI and EXEC so that replay
+    /// applies all of them or none.
+    pub fn append(&mut self, cmds: &[(usize, Argv)], transaction: bool) -> io::Result<()> {
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        let buf = encode_commands(&mut self.db, cmds, transaction);
+        if let Some(rewrite_buffer) = self.rewrite_buffer.as_mut() {
+            rewrite_buffer.extend_from_slice(&buf);
+        }
+        let len = self.file.metadata()?.len();
+        if let Err(err) = self.file.write_all(&buf) {
+            // Drop a partly written command so the commands appended after it stay readable.
+            let _ = self.file.set_len(len);
+            return Err(err);
+        }
+        if self.fsync == AppendFsync::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// A handle to sync the file with under `appendfsync everysec`, so that writers are
+    /// not held up while the disk catches up.
+    pub fn sync_handle(&self) -> io::Result<Option<File>> {
+        if self.fsync == AppendFsync::EverySec {
+            self.file.try_clone().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Starts buffering logged commands for a rewrite. Returns false if one already runs.
+    pub fn begin_rewrite(&mut self) -> bool {
+        if self.rewrite_buffer.is_some() {
+            return false;
+        }
+        self.rewrite_buffer = Some(vec![]);
+        // The rewritten file has its own notion of the current database.
+        self.db = None;
+        true
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// Where the rewritten file is written before it replaces the current one.
+    pub fn rewrite_path(&self) -> PathBuf {
+        self.path
+            .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()))
+    }
+
+    /// Appends the commands logged during the rewrite to the snapshot written at
+    /// [`Aof::rewrite_path`], then swaps it in for the current file.
+    pub fn finish_rewrite(&mut self) -> io::Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let tmp = self.rewrite_path();
+        let swap = || -> io::Result<File> {
+            let mut file = OpenOptions::new().append(true).open(&tmp)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(file)
+        };
+        match swap() {
+            Ok(file) => {
+                self.file = file;
+                Ok(())
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+        let _ = fs::remove_file(self.rewrite_path());
+    }
+}
+
+/// Encodes commands as they are logged and replicated: each as a RESP array, preceded by a
+/// SELECT when its database differs from `db`, the one last selected. A transaction of
+/// more than one command is wrapped in MULTI and EXEC.
+pub fn encode_commands(
+    db: &mut Option<usize>,
+    cmds: &[(usize, Argv)],
+    transaction: bool,
+) -> Vec<u8> {
+    let wrap = transaction && cmds.len() > 1;
+    let mut buf = vec![];
+    if wrap {
+        encode(&mut buf, &[b"MULTI".to_vec()]);
+    }
+    for (index, argv) in cmds.iter() {
+        if *db != Some(*index) {
+            encode(
+                &mut buf,
+                &[b"SELECT".to_vec(), index.to_string().into_bytes()],
+            );
+            *db = Some(*index);
+        }
+        encode(&mut buf, argv);
+    }
+    if wrap {
+        encode(&mut buf, &[b"EXEC".to_vec()]);
+    }
+    buf
+}
+
+fn encode(buf: &mut Vec<u8>, argv: &[Vec<u8>]) {
+    let array = argv.iter().map(resp::bulk_string).collect();
+    buf.extend_from_slice(&resp::encode(&resp::array(array)));
+}
+
+/// What an append-only file holds.
+pub struct AofContents {
+    /// The snapshot a rewrite left at the start of the file.
+    pub snapshot: Option<Snapshot>,
+    pub commands: Vec<resp::Value>,
+    /// The length of the readable part of the file. A command cut off by a crash, or a
+    /// transaction missing its EXEC, is left out.
+    pub valid_len: usize,
+    pub len: usize,
+}
+
+/// Reads the append-only file at `path`, or `None` if there is none. A truncated tail is
+/// tolerated; any other malformed content is an error.
+pub fn read(path: &Path) -> io::Result<Option<AofContents>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let (snapshot, mut pos) = if snapshot::is_snapshot(&bytes) {
+        let (snapshot, len) = snapshot::read_snapshot_prefix(&bytes)?;
+        (Some(snapshot), len)
+    } else {
+        (None, 0)
+    };
+
+    let mut commands = vec![];
+    let mut valid_len = pos;
+    // Where the open transaction starts, in commands and in bytes.
+    let mut transaction: Option<(usize, usize)> = None;
+    while pos < bytes.len() {
+        let (value, len) = match resp::decode_prefix(&bytes[pos..]) {
+            Ok(decoded) => decoded,
+            Err(resp::Error::IncompleteRespError) => break,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt append-only file at byte {}", pos),
+                ))
+            }
+        };
+        let name = match &value {
+            resp::Value::Array(args) => args.first().and_then(|arg| arg.to_string().ok()),
+            _ => None,
+        };
+        match name.map(|name| name.to_lowercase()).as_deref() {
+            Some("multi") => transaction = Some((commands.len(), pos)),
+            Some("exec") => transaction = None,
+            _ => (),
+        }
+        commands.push(value);
+        pos += len;
+        if transaction.is_none() {
+            valid_len = pos;
+        }
+    }
+    if let Some((start, _)) = transaction {
+        commands.truncate(start);
+    }
+
+    Ok(Some(AofContents {
+        snapshot,
+        commands,
+        valid_len,
+        len: bytes.len(),
+    }))
+}
+
+/// Cuts the file at `path` down to `len` bytes, dropping an unreadable tail.
+pub fn truncate(path: &Path, len: usize) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(len as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Argv {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn names(contents: &AofContents) -> Vec<String> {
+        contents
+            .commands
+            .iter()
+            .map(|value| match value {
+                resp::Value::Array(args) => args[0].to_string().unwrap(),
+                _ => panic!("expected an array"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let path = std::env::temp_dir().join(format!("kyev-test-{}.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut aof = Aof::open(&path, AppendFsync::Always).unwrap();
+        aof.append(&[(0, argv(&["SET", "a", "1"]))], false).unwrap();
+        aof.append(&[(0, argv(&["DEL", "a"]))], false).unwrap();
+        aof.append(
+            &[(2, argv(&["SET", "b", "1"])), (2, argv(&["INCR", "b"]))],
+            true,
+        )
+        .unwrap();
+
+        let contents = read(&path).unwrap().unwrap();
+        assert_eq!(
+            vec!["SELECT", "SET", "DEL", "MULTI", "SELECT", "SET", "INCR", "EXEC"],
+            names(&contents)
+        );
+        assert_eq!(contents.len, contents.valid_len);
+
+        // A transaction cut off before its EXEC is dropped whole, a cut-off command alone.
+        let full = contents.len;
+        truncate(&path, full - 3).unwrap();
+        let contents = read(&path).unwrap().unwrap();
+        assert_eq!(vec!["SELECT", "SET", "DEL"], names(&contents));
+        assert!(contents.valid_len < full - 3);
+        truncate(&path, contents.valid_len - 1).unwrap();
+        assert_eq!(vec!["SELECT", "SET"], names(&read(&path).unwrap().unwrap()));
+
+        fs::write(&path, b"*1\r\n$4\r\nPING\r\n?garbage\r\n").unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let path =
+            std::env::temp_dir().join(format!("kyev-test-rewrite-{}.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut aof = Aof::open(&path, AppendFsync::No).unwrap();
+        aof.append(&[(0, argv(&["SET", "a", "1"]))], false).unwrap();
+        assert!(aof.begin_rewrite());
+        assert!(!aof.begin_rewrite());
+        aof.append(&[(0, argv(&["SET", "b", "2"]))], false).unwrap();
+        snapshot::write_file(&Snapshot::default(), &aof.rewrite_path()).unwrap();
+        aof.finish_rewrite().unwrap();
+        assert!(!aof.is_rewriting());
+        aof.append(&[(1, argv(&["SET", "c", "3"]))], false).unwrap();
+
+        let contents = read(&path).unwrap().unwrap();
+        assert_eq!(Some(Snapshot::default()), contents.snapshot);
+        assert_eq!(vec!["SELECT", "SET", "SELECT", "SET"], names(&contents));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,839 @@
+use crate::clock;
+use crate::replication;
+use crate::scan::{self, CursorOrder};
+use crate::store::{StoreError, StoreErrorKind};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The number of hash slots the keys are partitioned into.
+pub const SLOTS: usize = 16384;
+
+/// How far above the client port a node listens for gossip, unless told otherwise.
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+/// How often a node gossips with every other node it knows of.
+pub const GOSSIP_PERIOD: Duration = Duration::from_millis(500);
+
+/// How long a node may go without answering gossip before it is flagged as possibly
+/// failing, and how long a meeting with a node that never answers is retried.
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The CRC16 of `bytes` as Redis Cluster computes it: the XMODEM variant, with polynomial
+/// 0x1021 and no initial value.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The slot `key` hashes to. If the key holds a non-empty `{hashtag}`, only the tag is
+/// hashed, so that related keys such as `{user1}.name` and `{user1}.email` share a slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        rest.iter()
+            .position(|&b| b == b'}')
+            .filter(|&len| len > 0)
+            .map(|len| &rest[..len])
+    });
+    crc16(tag.unwrap_or(key)) & (SLOTS as u16 - 1)
+}
+
+/// How far a slot is shifted into a [`SlotOrder`] position, leaving its top 14 bits for
+/// the slot.
+const SLOT_SHIFT: u32 = 50;
+
+/// Positions keys by their slot, then by [`scan::cursor_hash`] within it, so that the keys
+/// of one slot sit together in a keyspace's cursor index.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlotOrder;
+
+impl SlotOrder {
+    /// The first position of `slot`.
+    pub fn slot_start(slot: u16) -> u64 {
+        (slot as u64) << SLOT_SHIFT
+    }
+
+    /// The slot a position falls in.
+    pub fn slot_of(position: u64) -> u16 {
+        (position >> SLOT_SHIFT) as u16
+    }
+}
+
+impl CursorOrder for SlotOrder {
+    fn position(key: &[u8]) -> u64 {
+        SlotOrder::slot_start(key_slot(key)) | scan::cursor_hash(key) >> (64 - SLOT_SHIFT)
+    }
+}
+
+/// Parses a slot number, rejecting those out of range.
+pub fn parse_slot(slot: &[u8]) -> Option<u16> {
+    std::str::from_utf8(slot)
+        .ok()?
+        .parse::<u16>()
+        .ok()
+        .filter(|slot| (*slot as usize) < SLOTS)
+}
+
+/// Why a command cannot run on this node, and where it should be sent instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Redirect {
+    /// Another node serves the slot: the client should send this and later commands on the
+    /// slot there.
+    Moved(u16, String),
+    /// The slot is moving to another node, which may hold the keys: the client should send
+    /// only this command there, preceded by ASKING.
+    Ask(u16, String),
+    /// The keys hash to different slots.
+    CrossSlot,
+    /// The slot is moving and only some of the keys have moved with it yet.
+    TryAgain,
+    /// No node serves the slot.
+    Unserved,
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Redirect::Moved(slot, addr) => write!(f, "MOVED {} {}", slot, addr),
+            Redirect::Ask(slot, addr) => write!(f, "ASK {} {}", slot, addr),
+            Redirect::CrossSlot => "CROSSSLOT Keys in request don't hash to the same slot".fmt(f),
+            Redirect::TryAgain => "TRYAGAIN Multiple keys request during rehashing of slot".fmt(f),
+            Redirect::Unserved => "CLUSTERDOWN Hash slot not served".fmt(f),
+        }
+    }
+}
+
+/// A node of the cluster, as this node knows it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    pub host: String,
+    /// The port clients connect to.
+    pub port: u16,
+    /// The port the node gossips on.
+    pub bus_port: u16,
+    /// Orders the nodes' claims on slots: when two nodes claim a slot, the one with the
+    /// greater epoch serves it.
+    pub config_epoch: u64,
+    /// When the node last answered gossip, or `None` if it never did.
+    pub last_pong: Option<Instant>,
+}
+
+impl Node {
+    /// The address clients are redirected to.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Whether the node has not answered gossip for longer than [`NODE_TIMEOUT`].
+    pub fn is_failing(&self) -> bool {
+        self.last_pong
+            .is_some_and(|last_pong| last_pong.elapsed() > NODE_TIMEOUT)
+    }
+}
+
+/// How CLUSTER SETSLOT changes a slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SetSlot {
+    /// The slot is being moved from this node to the given one.
+    Migrating(String),
+    /// The slot is being moved to this node from the given one.
+    Importing(String),
+    /// The slot is no longer being moved.
+    Stable,
+    /// The slot is now served by the given node.
+    Node(String),
+}
+
+/// A node met with CLUSTER MEET that has not answered yet, so its ID is not known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Handshake {
+    host: String,
+    port: u16,
+    bus_port: u16,
+    since: Instant,
+}
+
+/// What one node tells another about the cluster on each gossip exchange: itself, the slots
+/// it serves and the other nodes it knows of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gossip {
+    pub sender: Node,
+    pub current_epoch: u64,
+    /// The ranges of slots the sender serves, inclusive.
+    pub slots: Vec<(u16, u16)>,
+    pub nodes: Vec<Node>,
+}
+
+impl Gossip {
+    pub fn to_resp(&self) -> resp::Value {
+        let slots = self
+            .slots
+            .iter()
+            .flat_map(|&(start, end)| vec![start, end])
+            .map(|slot| resp::integer(slot as i64))
+            .collect();
+        resp::array(vec![
+            resp::bulk_string("GOSSIP"),
+            node_to_resp(&self.sender),
+            resp::integer(self.current_epoch as i64),
+            resp::array(slots),
+            resp::array(self.nodes.iter().map(node_to_resp).collect()),
+        ])
+    }
+
+    pub fn from_resp(value: &resp::Value) -> Option<Gossip> {
+        let fields = match value {
+            resp::Value::Array(fields) if fields.len() == 5 => fields,
+            _ => return None,
+        };
+        if !fields[0].as_bytes().ok()?.eq_ignore_ascii_case(b"gossip") {
+            return None;
+        }
+        let slots = match &fields[3] {
+            resp::Value::Array(slots) if slots.len() % 2 == 0 => slots
+                .chunks(2)
+                .map(|range| Some((resp_slot(&range[0])?, resp_slot(&range[1])?)))
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+        let nodes = match &fields[4] {
+            resp::Value::Array(nodes) => nodes
+                .iter()
+                .map(node_from_resp)
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+        Some(Gossip {
+            sender: node_from_resp(&fields[1])?,
+            current_epoch: resp_u64(&fields[2])?,
+            slots,
+            nodes,
+        })
+    }
+}
+
+fn node_to_resp(node: &Node) -> resp::Value {
+    resp::array(vec![
+        resp::bulk_string(&node.id),
+        resp::bulk_string(&node.host),
+        resp::integer(node.port as i64),
+        resp::integer(node.bus_port as i64),
+        resp::integer(node.config_epoch as i64),
+    ])
+}
+
+fn node_from_resp(value: &resp::Value) -> Option<Node> {
+    match value {
+        resp::Value::Array(fields) if fields.len() == 5 => Some(Node {
+            id: fields[0].to_string().ok()?,
+            host: fields[1].to_string().ok()?,
+            port: resp_u64(&fields[2])? as u16,
+            bus_port: resp_u64(&fields[3])? as u16,
+            config_epoch: resp_u64(&fields[4])?,
+            last_pong: None,
+        }),
+        _ => None,
+    }
+}
+
+fn resp_u64(value: &resp::Value) -> Option<u64> {
+    match value {
+        resp::Value::Integer(n) if *n >= 0 => Some(*n as u64),
+        _ => None,
+    }
+}
+
+fn resp_slot(value: &resp::Value) -> Option<u16> {
+    resp_u64(value)
+        .filter(|slot| (*slot as usize) < SLOTS)
+        .map(|slot| slot as u16)
+}
+
+/// This node's view of the cluster: the nodes in it, which of them serves each slot and
+/// which slots are being moved to or from this node.
+///
+/// Slots are moved live. The source node is set MIGRATING the slot and the target
+/// IMPORTING it; while the keys are moved over with MIGRATE, the source still serves the
+/// keys it holds and sends clients asking for the others to the target with ASK. Once the
+/// slot is empty, both are told with SETSLOT NODE that the target serves it. The target
+/// takes a new epoch for its claim, so the rest of the cluster learns of it through gossip.
+#[derive(Debug)]
+pub struct Cluster {
+    myself: String,
+    nodes: HashMap<String, Node>,
+    /// The ID of the node serving each slot.
+    slots: Vec<Option<String>>,
+    /// The greatest epoch seen in the cluster.
+    current_epoch: u64,
+    /// The slots being moved from this node, and the nodes they are moving to.
+    migrating: HashMap<u16, String>,
+    /// The slots being moved to this node, and the nodes they are moving from.
+    importing: HashMap<u16, String>,
+    handshakes: Vec<Handshake>,
+}
+
+impl Cluster {
+    /// A cluster of one node, this one, serving no slots yet.
+    pub fn new(host: &str, port: u16, bus_port: u16) -> Cluster {
+        let myself = Node {
+            id: replication::new_replid(),
+            host: host.to_owned(),
+            port,
+            bus_port,
+            config_epoch: 0,
+            last_pong: None,
+        };
+        let mut nodes = HashMap::new();
+        let id = myself.id.clone();
+        nodes.insert(id.clone(), myself);
+        Cluster {
+            myself: id,
+            nodes,
+            slots: vec![None; SLOTS],
+            current_epoch: 0,
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            handshakes: vec![],
+        }
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    /// The nodes of the cluster, this one included, in no particular order.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.values()
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.current_epoch
+    }
+
+    /// The node serving `slot`, if any.
+    pub fn owner(&self, slot: u16) -> Option<&Node> {
+        self.slots[slot as usize]
+            .as_ref()
+            .and_then(|id| self.nodes.get(id))
+    }
+
+    /// The ranges of slots the node `id` serves, inclusive and in order.
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() != Some(id) {
+                continue;
+            }
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// How many slots are served by some node.
+    pub fn assigned_slots(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    /// The slots being moved from this node, and the IDs of the nodes they are moving to.
+    pub fn migrating(&self) -> &HashMap<u16, String> {
+        &self.migrating
+    }
+
+    /// The slots being moved to this node, and the IDs of the nodes they are moving from.
+    pub fn importing(&self) -> &HashMap<u16, String> {
+        &self.importing
+    }
+
+    /// Whether `slot` is being moved to or from this node, in which case routing a command
+    /// on it depends on which of its keys this node holds.
+    pub fn is_moving(&self, slot: u16) -> bool {
+        self.migrating.contains_key(&slot) || self.importing.contains_key(&slot)
+    }
+
+    /// Whether this node may run a command on `keys` keys of `slot`, `missing` of which it
+    /// does not hold. `asking` is whether the client sent ASKING just before the command.
+    pub fn route(
+        &self,
+        slot: u16,
+        asking: bool,
+        keys: usize,
+        missing: usize,
+    ) -> Result<(), Redirect> {
+        let owner = self.owner(slot).ok_or(Redirect::Unserved)?;
+        if owner.id == self.myself {
+            // Keys missing from a migrating slot may have moved already: all of them means
+            // the command can run on the target, only some means it can run nowhere yet.
+            return match self.migrating.get(&slot).and_then(|id| self.nodes.get(id)) {
+                Some(_) if missing > 0 && missing < keys => Err(Redirect::TryAgain),
+                Some(target) if missing > 0 => Err(Redirect::Ask(slot, target.addr())),
+                _ => Ok(()),
+            };
+        }
+        if asking && self.importing.contains_key(&slot) {
+            return if keys > 1 && missing > 0 {
+                Err(Redirect::TryAgain)
+            } else {
+                Ok(())
+            };
+        }
+        Err(Redirect::Moved(slot, owner.addr()))
+    }
+
+    /// Starts meeting the node gossiping at `host` and `bus_port`, which joins the two
+    /// nodes' clusters once it answers.
+    pub fn meet(&mut self, host: String, port: u16, bus_port: u16) {
+        let myself = self.myself();
+        if (myself.host == host && myself.bus_port == bus_port)
+            || self
+                .handshakes
+                .iter()
+                .any(|handshake| handshake.host == host && handshake.bus_port == bus_port)
+        {
+            return;
+        }
+        self.handshakes.push(Handshake {
+            host,
+            port,
+            bus_port,
+            since: Instant::now(),
+        });
+    }
+
+    /// Has this node serve `slots`, none of which may be served already.
+    pub fn add_slots(&mut self, slots: &[u16]) -> Result<(), StoreError> {
+        let mut given = vec![false; SLOTS];
+        for slot in slots.iter() {
+            if std::mem::replace(&mut given[*slot as usize], true) {
+                return Err(StoreError::new_with_context(
+                    StoreErrorKind::SlotRepeated,
+                    slot.to_string(),
+                ));
+            }
+            if self.slots[*slot as usize].is_some() {
+                return Err(StoreError::new_with_context(
+                    StoreErrorKind::SlotBusy,
+                    slot.to_string(),
+                ));
+            }
+        }
+        for slot in slots {
+            self.slots[*slot as usize] = Some(self.myself.clone());
+        }
+        Ok(())
+    }
+
+    /// Changes how `slot` is served. `held_keys` is how many keys of the slot this node
+    /// holds: it may only hand over a slot it serves once it holds none.
+    pub fn set_slot(
+        &mut self,
+        slot: u16,
+        set_slot: SetSlot,
+        held_keys: usize,
+    ) -> Result<(), StoreError> {
+        let owned = self.slots[slot as usize].as_deref() == Some(self.myself.as_str());
+        let slot_error = |kind| Err(StoreError::new_with_context(kind, slot.to_string()));
+        match set_slot {
+            SetSlot::Migrating(id) => {
+                self.expect_node(&id)?;
+                if !owned {
+                    return slot_error(StoreErrorKind::NotSlotOwner);
+                }
+                if id == self.myself {
+                    return slot_error(StoreErrorKind::MigrateToSelf);
+                }
+                self.migrating.insert(slot, id);
+            }
+            SetSlot::Importing(id) => {
+                self.expect_node(&id)?;
+                if owned {
+                    return slot_error(StoreErrorKind::AlreadySlotOwner);
+                }
+                if id == self.myself {
+                    return slot_error(StoreErrorKind::ImportFromSelf);
+                }
+                self.importing.insert(slot, id);
+            }
+            SetSlot::Stable => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                self.expect_node(&id)?;
+                if owned && id != self.myself && held_keys > 0 {
+                    return slot_error(StoreErrorKind::SlotNotEmpty);
+                }
+                self.migrating.remove(&slot);
+                if id == self.myself && self.importing.remove(&slot).is_some() {
+                    // Claim the slot with an epoch no other node has, so that the claim
+                    // wins over the former owner's as it spreads through the cluster.
+                    self.current_epoch += 1;
+                    let epoch = self.current_epoch;
+                    self.nodes.get_mut(&self.myself).unwrap().config_epoch = epoch;
+                }
+                self.slots[slot as usize] = Some(id);
+            }
+        }
+        Ok(())
+    }
+
+    fn expect_node(&self, id: &str) -> Result<(), StoreError> {
+        if self.nodes.contains_key(id) {
+            Ok(())
+        } else {
+            Err(StoreError::new_with_context(
+                StoreErrorKind::UnknownNode,
+                id.to_owned(),
+            ))
+        }
+    }
+
+    /// The bus addresses to gossip with: every other node, and the nodes being met. Gives
+    /// up on meetings that went unanswered for longer than [`NODE_TIMEOUT`].
+    pub fn gossip_targets(&mut self) -> Vec<(String, u16)> {
+        self.handshakes
+            .retain(|handshake| handshake.since.elapsed() <= NODE_TIMEOUT);
+        self.nodes
+            .values()
+            .filter(|node| node.id != self.myself)
+            .map(|node| (node.host.clone(), node.bus_port))
+            .chain(
+                self.handshakes
+                    .iter()
+                    .map(|handshake| (handshake.host.clone(), handshake.bus_port)),
+            )
+            .collect()
+    }
+
+    /// What this node tells the others on each gossip exchange.
+    pub fn gossip(&self) -> Gossip {
+        Gossip {
+            sender: self.myself().clone(),
+            current_epoch: self.current_epoch,
+            slots: self.slot_ranges(&self.myself),
+            nodes: self
+                .nodes
+                .values()
+                .filter(|node| node.id != self.myself)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Takes in what another node told this one: learns of the nodes it knows, and gives
+    /// it the slots it claims unless they are served under a greater epoch.
+    pub fn receive(&mut self, gossip: &Gossip) {
+        let sender = &gossip.sender;
+        if sender.id == self.myself {
+            return;
+        }
+        self.handshakes.retain(|handshake| {
+            handshake.host != sender.host || handshake.bus_port != sender.bus_port
+        });
+        self.current_epoch = self
+            .current_epoch
+            .max(gossip.current_epoch)
+            .max(sender.config_epoch);
+        self.nodes.insert(
+            sender.id.clone(),
+            Node {
+                last_pong: Some(Instant::now()),
+                ..sender.clone()
+            },
+        );
+
+        for &(start, end) in gossip.slots.iter() {
+            for slot in start..=end {
+                let claimed = match &self.slots[slot as usize] {
+                    None => true,
+                    Some(owner) if *owner == sender.id => false,
+                    Some(owner) => self
+                        .nodes
+                        .get(owner)
+                        .is_none_or(|owner| owner.config_epoch < sender.config_epoch),
+                };
+                if claimed {
+                    if self.slots[slot as usize].as_deref() == Some(self.myself.as_str()) {
+                        self.migrating.remove(&slot);
+                    }
+                    self.slots[slot as usize] = Some(sender.id.clone());
+                }
+            }
+        }
+
+        for node in gossip.nodes.iter() {
+            if node.id == self.myself || self.nodes.contains_key(&node.id) {
+                continue;
+            }
+            self.handshakes.retain(|handshake| {
+                handshake.host != node.host || handshake.bus_port != node.bus_port
+            });
+            self.nodes.insert(
+                node.id.clone(),
+                Node {
+                    last_pong: None,
+                    ..node.clone()
+                },
+            );
+        }
+    }
+
+    /// The node table as CLUSTER NODES lists it: one line per node, this one first.
+    pub fn describe_nodes(&self) -> String {
+        let mut nodes: Vec<&Node> = self.nodes.values().collect();
+        nodes.sort_by_key(|node| (node.id != self.myself, &node.id));
+        let mut lines = String::new();
+        for node in nodes {
+            let myself = node.id == self.myself;
+            let flags = if myself {
+                "myself,master"
+            } else if node.is_failing() {
+                "master,fail?"
+            } else {
+                "master"
+            };
+            let pong_ms = node.last_pong.map_or(0, |last_pong| {
+                clock::now_ms().saturating_sub(last_pong.elapsed().as_millis() as u64)
+            });
+            let connected = myself || node.last_pong.is_some() && !node.is_failing();
+            lines.push_str(&format!(
+                "{} {}@{} {} - 0 {} {} {}",
+                node.id,
+                node.addr(),
+                node.bus_port,
+                flags,
+                pong_ms,
+                node.config_epoch,
+                if connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                }
+            ));
+            for (start, end) in self.slot_ranges(&node.id) {
+                if start == end {
+                    lines.push_str(&format!(" {}", start));
+                } else {
+                    lines.push_str(&format!(" {}-{}", start, end));
+                }
+            }
+            if myself {
+                let mut moving: Vec<(u16, String)> = self
+                    .migrating
+                    .iter()
+                    .map(|(slot, id)| (*slot, format!(" [{}->-{}]", slot, id)))
+                    .chain(
+                        self.importing
+                            .iter()
+                            .map(|(slot, id)| (*slot, format!(" [{}-<-{}]", slot, id))),
+                    )
+                    .collect();
+                moving.sort();
+                for (_, annotation) in moving {
+                    lines.push_str(&annotation);
+                }
+            }
+            lines.push('\n');
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, port: u16, config_epoch: u64) -> Node {
+        Node {
+            id: id.to_owned(),
+            host: "127.0.0.1".to_owned(),
+            port,
+            bus_port: port + BUS_PORT_OFFSET,
+            config_epoch,
+            last_pong: None,
+        }
+    }
+
+    fn gossip(sender: Node, slots: Vec<(u16, u16)>, nodes: Vec<Node>) -> Gossip {
+        Gossip {
+            current_epoch: sender.config_epoch,
+            sender,
+            slots,
+            nodes,
+        }
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(5061, key_slot(b"bar"));
+        assert_eq!(0, key_slot(b""));
+        assert_eq!(
+            key_slot(b"{user1000}.following"),
+            key_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_slot(b"user1000"), key_slot(b"{user1000}.following"));
+        assert_eq!(key_slot(b"bar"), key_slot(b"foo{bar}{zap}"));
+        assert_eq!(crc16(b"foo{}{bar}") & 0x3fff, key_slot(b"foo{}{bar}"));
+        assert_eq!(key_slot(b"{bar"), key_slot(b"foo{{bar}}zap"));
+        assert_eq!(Some(16383), parse_slot(b"16383"));
+        assert_eq!(None, parse_slot(b"16384"));
+        assert_eq!(None, parse_slot(b"-1"));
+    }
+
+    #[test]
+    fn test_route() {
+        let mut cluster = Cluster::new("127.0.0.1", 7000, 17000);
+        assert_eq!(Err(Redirect::Unserved), cluster.route(5, false, 1, 0));
+        cluster.add_slots(&[5, 6]).unwrap();
+        assert_eq!(
+            StoreErrorKind::SlotBusy,
+            *cluster.add_slots(&[7, 6]).unwrap_err().kind()
+        );
+        assert_eq!(
+            StoreErrorKind::SlotRepeated,
+            *cluster.add_slots(&[8, 8]).unwrap_err().kind()
+        );
+        assert_eq!(
+            vec![(5, 6)],
+            cluster.slot_ranges(&cluster.myself().id.clone())
+        );
+        assert_eq!(Ok(()), cluster.route(5, false, 1, 1));
+
+        cluster.receive(&gossip(node("other", 7001, 0), vec![(7, 9)], vec![]));
+        assert_eq!(
+            Err(Redirect::Moved(8, "127.0.0.1:7001".to_owned())),
+            cluster.route(8, false, 1, 0)
+        );
+
+        cluster
+            .set_slot(5, SetSlot::Migrating("other".to_owned()), 0)
+            .unwrap();
+        assert_eq!(Ok(()), cluster.route(5, false, 2, 0));
+        assert_eq!(
+            Err(Redirect::Ask(5, "127.0.0.1:7001".to_owned())),
+            cluster.route(5, false, 2, 2)
+        );
+        assert_eq!(
+            Err(Redirect::Ask(5, "127.0.0.1:7001".to_owned())),
+            cluster.route(5, false, 1, 1)
+        );
+        assert_eq!(Err(Redirect::TryAgain), cluster.route(5, false, 2, 1));
+
+        cluster
+            .set_slot(8, SetSlot::Importing("other".to_owned()), 0)
+            .unwrap();
+        assert_eq!(
+            Err(Redirect::Moved(8, "127.0.0.1:7001".to_owned())),
+            cluster.route(8, false, 1, 1)
+        );
+        assert_eq!(Ok(()), cluster.route(8, true, 1, 1));
+        assert_eq!(Err(Redirect::TryAgain), cluster.route(8, true, 2, 1));
+    }
+
+    #[test]
+    fn test_set_slot() {
+        let mut cluster = Cluster::new("127.0.0.1", 7000, 17000);
+        let myself = cluster.myself().id.clone();
+        cluster.add_slots(&[1]).unwrap();
+        cluster.receive(&gossip(node("other", 7001, 3), vec![(2, 2)], vec![]));
+        let err = |result: Result<(), StoreError>| result.unwrap_err().kind().clone();
+
+        assert_eq!(
+            StoreErrorKind::UnknownNode,
+            err(cluster.set_slot(1, SetSlot::Migrating("nobody".to_owned()), 0))
+        );
+        assert_eq!(
+            StoreErrorKind::NotSlotOwner,
+            err(cluster.set_slot(2, SetSlot::Migrating("other".to_owned()), 0))
+        );
+        assert_eq!(
+            StoreErrorKind::AlreadySlotOwner,
+            err(cluster.set_slot(1, SetSlot::Importing("other".to_owned()), 0))
+        );
+        assert_eq!(
+            StoreErrorKind::SlotNotEmpty,
+            err(cluster.set_slot(1, SetSlot::Node("other".to_owned()), 1))
+        );
+
+        cluster
+            .set_slot(2, SetSlot::Importing("other".to_owned()), 0)
+            .unwrap();
+        assert!(cluster.is_moving(2));
+        cluster
+            .set_slot(2, SetSlot::Node(myself.clone()), 0)
+            .unwrap();
+        assert!(!cluster.is_moving(2));
+        assert_eq!(
+            Some(myself.as_str()),
+            cluster.owner(2).map(|n| n.id.as_str())
+        );
+        assert_eq!(4, cluster.myself().config_epoch);
+
+        // The former owner's stale claim loses to the new epoch.
+        cluster.receive(&gossip(node("other", 7001, 3), vec![(2, 2)], vec![]));
+        assert_eq!(vec![(1, 2)], cluster.slot_ranges(&myself));
+
+        cluster.set_slot(1, SetSlot::Stable, 0).unwrap();
+        cluster
+            .set_slot(1, SetSlot::Node("other".to_owned()), 0)
+            .unwrap();
+        assert_eq!(vec![(2, 2)], cluster.slot_ranges(&myself));
+    }
+
+    #[test]
+    fn test_gossip() {
+        let mut a = Cluster::new("127.0.0.1", 7000, 17000);
+        let mut b = Cluster::new("127.0.0.1", 7001, 17001);
+        let mut c = Cluster::new("127.0.0.1", 7002, 17002);
+        a.add_slots(&[0, 1, 2, 10]).unwrap();
+        c.add_slots(&[3]).unwrap();
+        a.meet("127.0.0.1".to_owned(), 7001, 17001);
+        assert_eq!(vec![("127.0.0.1".to_owned(), 17001)], a.gossip_targets());
+
+        b.receive(&a.gossip());
+        a.receive(&b.gossip());
+        assert_eq!(1, a.gossip_targets().len());
+        c.receive(&b.gossip());
+        assert_eq!(3, c.nodes().count());
+        // Nodes only learn of slots from the nodes serving them.
+        assert!(c.slot_ranges(&a.myself().id.clone()).is_empty());
+        c.receive(&a.gossip());
+        assert_eq!(
+            vec![(0, 2), (10, 10)],
+            c.slot_ranges(&a.myself().id.clone())
+        );
+        a.receive(&c.gossip());
+        assert_eq!(vec![(3, 3)], a.slot_ranges(&c.myself().id.clone()));
+
+        let encoded = resp::encode(&a.gossip().to_resp());
+        let decoded = Gossip::from_resp(&resp::decode(&encoded).unwrap()).unwrap();
+        let mut expected = a.gossip();
+        expected.sender.last_pong = None;
+        for node in expected.nodes.iter_mut() {
+            node.last_pong = None;
+        }
+        assert_eq!(expected, decoded);
+        assert_eq!(None, Gossip::from_resp(&resp::bulk_string("GOSSIP")));
+    }
+}
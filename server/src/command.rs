@@ -1,6 +1,12 @@
+use crate::cluster;
+use crate::lease;
+use crate::stream::{StreamId, TrimStrategy, XAddId};
+use crate::zset::{self, LexBound, ScoreBound};
 use resp;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
+use std::iter::Peekable;
 
 lazy_static! {
     static ref COMMAND_MAP: HashMap<&'static str, Action> = {
@@ -15,6 +21,12 @@ lazy_static! {
         map.insert("expire", Expire);
         map.insert("pexpire", PExpire);
         map.insert("ttl", Ttl);
+        map.insert("pttl", PTtl);
+        map.insert("persist", Persist);
+        map.insert("expireat", ExpireAt);
+        map.insert("pexpireat", PExpireAt);
+        map.insert("expiretime", ExpireTime);
+        map.insert("pexpiretime", PExpireTime);
         map.insert("multi", Multi);
         map.insert("exec", Exec);
         map.insert("discard", Discard);
@@ -23,13 +35,141 @@ lazy_static! {
         map.insert("client id", ClientId);
         map.insert("client list", ClientList);
         map.insert("memory usage", MemoryUsage);
+        map.insert("memory stats", MemoryStats);
+        map.insert("memory doctor", MemoryDoctor);
+        map.insert("lpush", LPush);
+        map.insert("rpush", RPush);
+        map.insert("lpop", LPop);
+        map.insert("rpop", RPop);
+        map.insert("lrange", LRange);
+        map.insert("lindex", LIndex);
+        map.insert("lset", LSet);
+        map.insert("lrem", LRem);
+        map.insert("ltrim", LTrim);
+        map.insert("llen", LLen);
+        map.insert("linsert", LInsert);
+        map.insert("hset", HSet);
+        map.insert("hget", HGet);
+        map.insert("hmget", HMGet);
+        map.insert("hdel", HDel);
+        map.insert("hgetall", HGetAll);
+        map.insert("hkeys", HKeys);
+        map.insert("hvals", HVals);
+        map.insert("hlen", HLen);
+        map.insert("hexists", HExists);
+        map.insert("hincrby", HIncrBy);
+        map.insert("hsetnx", HSetNx);
+        map.insert("sadd", SAdd);
+        map.insert("srem", SRem);
+        map.insert("smembers", SMembers);
+        map.insert("sismember", SIsMember);
+        map.insert("scard", SCard);
+        map.insert("spop", SPop);
+        map.insert("srandmember", SRandMember);
+        map.insert("sinter", SInter);
+        map.insert("sunion", SUnion);
+        map.insert("sdiff", SDiff);
+        map.insert("sinterstore", SInterStore);
+        map.insert("sunionstore", SUnionStore);
+        map.insert("sdiffstore", SDiffStore);
+        map.insert("zadd", ZAdd);
+        map.insert("zrange", ZRange);
+        map.insert("zrank", ZRank);
+        map.insert("zscore", ZScore);
+        map.insert("zrem", ZRem);
+        map.insert("zincrby", ZIncrBy);
+        map.insert("zpopmin", ZPopMin);
+        map.insert("zpopmax", ZPopMax);
+        map.insert("xadd", XAdd);
+        map.insert("xrange", XRange);
+        map.insert("xrevrange", XRevRange);
+        map.insert("xlen", XLen);
+        map.insert("xtrim", XTrim);
+        map.insert("xread", XRead);
+        map.insert("xgroup create", XGroupCreate);
+        map.insert("xgroup setid", XGroupSetId);
+        map.insert("xgroup destroy", XGroupDestroy);
+        map.insert("xgroup createconsumer", XGroupCreateConsumer);
+        map.insert("xgroup delconsumer", XGroupDelConsumer);
+        map.insert("xreadgroup", XReadGroup);
+        map.insert("xack", XAck);
+        map.insert("xpending", XPending);
+        map.insert("xclaim", XClaim);
+        map.insert("xautoclaim", XAutoClaim);
+        map.insert("incr", Incr);
+        map.insert("incrby", IncrBy);
+        map.insert("incrbyfloat", IncrByFloat);
+        map.insert("decr", Decr);
+        map.insert("decrby", DecrBy);
+        map.insert("append", Append);
+        map.insert("strlen", StrLen);
+        map.insert("getrange", GetRange);
+        map.insert("setrange", SetRange);
+        map.insert("getset", GetSet);
+        map.insert("getdel", GetDel);
+        map.insert("getex", GetEx);
+        map.insert("mget", MGet);
+        map.insert("mset", MSet);
+        map.insert("msetnx", MSetNx);
+        map.insert("del", Del);
+        map.insert("unlink", Unlink);
+        map.insert("exists", Exists);
+        map.insert("type", Type);
+        map.insert("rename", Rename);
+        map.insert("renamenx", RenameNx);
+        map.insert("copy", Copy);
+        map.insert("randomkey", RandomKey);
+        map.insert("dbsize", DbSize);
+        map.insert("touch", Touch);
+        map.insert("keys", Keys);
+        map.insert("scan", Scan);
+        map.insert("sscan", SScan);
+        map.insert("hscan", HScan);
+        map.insert("zscan", ZScan);
+        map.insert("select", Select);
+        map.insert("move", Move);
+        map.insert("swapdb", SwapDb);
+        map.insert("flushdb", FlushDb);
+        map.insert("flushall", FlushAll);
+        map.insert("info", Info);
+        map.insert("save", Save);
+        map.insert("bgsave", BgSave);
+        map.insert("lastsave", LastSave);
+        map.insert("bgrewriteaof", BgRewriteAof);
+        map.insert("sync", Sync);
+        map.insert("dump", Dump);
+        map.insert("restore", Restore);
+        map.insert("migrate", Migrate);
+        map.insert("replicaof", ReplicaOf);
+        map.insert("psync", PSync);
+        map.insert("lease", Lease);
+        map.insert("release", Release);
+        map.insert("leases", Leases);
+        map.insert("withlease", WithLease);
+        map.insert("begin", Begin);
+        map.insert("commit", Commit);
+        map.insert("rollback", Rollback);
+        map.insert("cluster info", ClusterInfo);
+        map.insert("cluster myid", ClusterMyId);
+        map.insert("cluster meet", ClusterMeet);
+        map.insert("cluster nodes", ClusterNodes);
+        map.insert("cluster slots", ClusterSlots);
+        map.insert("cluster shards", ClusterShards);
+        map.insert("cluster keyslot", ClusterKeySlot);
+        map.insert("cluster addslots", ClusterAddSlots);
+        map.insert("cluster setslot", ClusterSetSlot);
+        map.insert("cluster countkeysinslot", ClusterCountKeysInSlot);
+        map.insert("cluster getkeysinslot", ClusterGetKeysInSlot);
+        map.insert("asking", Asking);
 
         map
     };
     static ref COMMAND_PREFIX: HashSet<&'static str> = {
         let mut set = HashSet::new();
         set.insert("client");
+        set.insert("cluster");
         set.insert("memory");
+        set.insert("xgroup");
 
         set
     };
@@ -38,13 +178,7 @@ lazy_static! {
 #[macro_export]
 macro_rules! cmd {
     ($( $x:expr ),* ) => {
-        {
-            let mut temp_vec = Vec::new();
-            $(
-                temp_vec.push(resp::bulk_string($x));
-            )*
-            temp_vec
-        }
+        [$( resp::bulk_string($x) ),*]
     };
 }
 
@@ -59,6 +193,12 @@ pub enum Action {
     Expire,
     PExpire,
     Ttl,
+    PTtl,
+    Persist,
+    ExpireAt,
+    PExpireAt,
+    ExpireTime,
+    PExpireTime,
     Multi,
     Exec,
     Discard,
@@ -67,10 +207,136 @@ pub enum Action {
     ClientId,
     ClientList,
     MemoryUsage,
+    MemoryStats,
+    MemoryDoctor,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    LRange,
+    LIndex,
+    LSet,
+    LRem,
+    LTrim,
+    LLen,
+    LInsert,
+    HSet,
+    HGet,
+    HMGet,
+    HDel,
+    HGetAll,
+    HKeys,
+    HVals,
+    HLen,
+    HExists,
+    HIncrBy,
+    HSetNx,
+    SAdd,
+    SRem,
+    SMembers,
+    SIsMember,
+    SCard,
+    SPop,
+    SRandMember,
+    SInter,
+    SUnion,
+    SDiff,
+    SInterStore,
+    SUnionStore,
+    SDiffStore,
+    ZAdd,
+    ZRange,
+    ZRank,
+    ZScore,
+    ZRem,
+    ZIncrBy,
+    ZPopMin,
+    ZPopMax,
+    XAdd,
+    XRange,
+    XRevRange,
+    XLen,
+    XTrim,
+    XRead,
+    XGroupCreate,
+    XGroupSetId,
+    XGroupDestroy,
+    XGroupCreateConsumer,
+    XGroupDelConsumer,
+    XReadGroup,
+    XAck,
+    XPending,
+    XClaim,
+    XAutoClaim,
+    Incr,
+    IncrBy,
+    IncrByFloat,
+    Decr,
+    DecrBy,
+    Append,
+    StrLen,
+    GetRange,
+    SetRange,
+    GetSet,
+    GetDel,
+    GetEx,
+    MGet,
+    MSet,
+    MSetNx,
+    Del,
+    Unlink,
+    Exists,
+    Type,
+    Rename,
+    RenameNx,
+    Copy,
+    RandomKey,
+    DbSize,
+    Touch,
+    Keys,
+    Scan,
+    SScan,
+    HScan,
+    ZScan,
+    Select,
+    Move,
+    SwapDb,
+    FlushDb,
+    FlushAll,
+    Info,
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    Sync,
+    Dump,
+    Restore,
+    Migrate,
+    ReplicaOf,
+    PSync,
+    Lease,
+    Release,
+    Leases,
+    WithLease,
+    Begin,
+    Commit,
+    Rollback,
+    ClusterInfo,
+    ClusterMyId,
+    ClusterMeet,
+    ClusterNodes,
+    ClusterSlots,
+    ClusterShards,
+    ClusterKeySlot,
+    ClusterAddSlots,
+    ClusterSetSlot,
+    ClusterCountKeysInSlot,
+    ClusterGetKeysInSlot,
+    Asking,
 }
 
 impl Action {
-    pub fn parse(s: &String) -> Result<Action, ParseCommandError> {
+    pub fn parse(s: &str) -> Result<Action, ParseCommandError> {
         let s = s.to_lowercase();
         let s = s.as_str();
         if let Some(action) = COMMAND_MAP.get(s) {
@@ -83,6 +349,45 @@ impl Action {
             ))
         }
     }
+
+    /// Whether the command may grow the dataset. These are refused while memory use is over
+    /// maxmemory and nothing can be evicted.
+    pub fn grows_dataset(&self) -> bool {
+        use Action::*;
+        matches!(
+            self,
+            Set | SetEx
+                | SetNx
+                | Incr
+                | Decr
+                | IncrBy
+                | DecrBy
+                | IncrByFloat
+                | Append
+                | SetRange
+                | GetSet
+                | MSet
+                | MSetNx
+                | Copy
+                | Restore
+                | LPush
+                | RPush
+                | LSet
+                | LInsert
+                | HSet
+                | HSetNx
+                | HIncrBy
+                | SAdd
+                | SInterStore
+                | SUnionStore
+                | SDiffStore
+                | ZAdd
+                | ZIncrBy
+                | XAdd
+                | XGroupCreate
+                | XGroupCreateConsumer
+        )
+    }
 }
 
 impl fmt::Display for Action {
@@ -98,6 +403,12 @@ impl fmt::Display for Action {
             Expire => "expire".fmt(f),
             PExpire => "pexpire".fmt(f),
             Ttl => "ttl".fmt(f),
+            PTtl => "pttl".fmt(f),
+            Persist => "persist".fmt(f),
+            ExpireAt => "expireat".fmt(f),
+            PExpireAt => "pexpireat".fmt(f),
+            ExpireTime => "expiretime".fmt(f),
+            PExpireTime => "pexpiretime".fmt(f),
             Multi => "multi".fmt(f),
             Exec => "exec".fmt(f),
             Discard => "discard".fmt(f),
@@ -106,6 +417,132 @@ impl fmt::Display for Action {
             ClientId => "client id".fmt(f),
             ClientList => "client list".fmt(f),
             MemoryUsage => "memory usage".fmt(f),
+            MemoryStats => "memory stats".fmt(f),
+            MemoryDoctor => "memory doctor".fmt(f),
+            LPush => "lpush".fmt(f),
+            RPush => "rpush".fmt(f),
+            LPop => "lpop".fmt(f),
+            RPop => "rpop".fmt(f),
+            LRange => "lrange".fmt(f),
+            LIndex => "lindex".fmt(f),
+            LSet => "lset".fmt(f),
+            LRem => "lrem".fmt(f),
+            LTrim => "ltrim".fmt(f),
+            LLen => "llen".fmt(f),
+            LInsert => "linsert".fmt(f),
+            HSet => "hset".fmt(f),
+            HGet => "hget".fmt(f),
+            HMGet => "hmget".fmt(f),
+            HDel => "hdel".fmt(f),
+            HGetAll => "hgetall".fmt(f),
+            HKeys => "hkeys".fmt(f),
+            HVals => "hvals".fmt(f),
+            HLen => "hlen".fmt(f),
+            HExists => "hexists".fmt(f),
+            HIncrBy => "hincrby".fmt(f),
+            HSetNx => "hsetnx".fmt(f),
+            SAdd => "sadd".fmt(f),
+            SRem => "srem".fmt(f),
+            SMembers => "smembers".fmt(f),
+            SIsMember => "sismember".fmt(f),
+            SCard => "scard".fmt(f),
+            SPop => "spop".fmt(f),
+            SRandMember => "srandmember".fmt(f),
+            SInter => "sinter".fmt(f),
+            SUnion => "sunion".fmt(f),
+            SDiff => "sdiff".fmt(f),
+            SInterStore => "sinterstore".fmt(f),
+            SUnionStore => "sunionstore".fmt(f),
+            SDiffStore => "sdiffstore".fmt(f),
+            ZAdd => "zadd".fmt(f),
+            ZRange => "zrange".fmt(f),
+            ZRank => "zrank".fmt(f),
+            ZScore => "zscore".fmt(f),
+            ZRem => "zrem".fmt(f),
+            ZIncrBy => "zincrby".fmt(f),
+            ZPopMin => "zpopmin".fmt(f),
+            ZPopMax => "zpopmax".fmt(f),
+            XAdd => "xadd".fmt(f),
+            XRange => "xrange".fmt(f),
+            XRevRange => "xrevrange".fmt(f),
+            XLen => "xlen".fmt(f),
+            XTrim => "xtrim".fmt(f),
+            XRead => "xread".fmt(f),
+            XGroupCreate => "xgroup create".fmt(f),
+            XGroupSetId => "xgroup setid".fmt(f),
+            XGroupDestroy => "xgroup destroy".fmt(f),
+            XGroupCreateConsumer => "xgroup createconsumer".fmt(f),
+            XGroupDelConsumer => "xgroup delconsumer".fmt(f),
+            XReadGroup => "xreadgroup".fmt(f),
+            XAck => "xack".fmt(f),
+            XPending => "xpending".fmt(f),
+            XClaim => "xclaim".fmt(f),
+            XAutoClaim => "xautoclaim".fmt(f),
+            Incr => "incr".fmt(f),
+            IncrBy => "incrby".fmt(f),
+            IncrByFloat => "incrbyfloat".fmt(f),
+            Decr => "decr".fmt(f),
+            DecrBy => "decrby".fmt(f),
+            Append => "append".fmt(f),
+            StrLen => "strlen".fmt(f),
+            GetRange => "getrange".fmt(f),
+            SetRange => "setrange".fmt(f),
+            GetSet => "getset".fmt(f),
+            GetDel => "getdel".fmt(f),
+            GetEx => "getex".fmt(f),
+            MGet => "mget".fmt(f),
+            MSet => "mset".fmt(f),
+            MSetNx => "msetnx".fmt(f),
+            Del => "del".fmt(f),
+            Unlink => "unlink".fmt(f),
+            Exists => "exists".fmt(f),
+            Type => "type".fmt(f),
+            Rename => "rename".fmt(f),
+            RenameNx => "renamenx".fmt(f),
+            Copy => "copy".fmt(f),
+            RandomKey => "randomkey".fmt(f),
+            DbSize => "dbsize".fmt(f),
+            Touch => "touch".fmt(f),
+            Keys => "keys".fmt(f),
+            Scan => "scan".fmt(f),
+            SScan => "sscan".fmt(f),
+            HScan => "hscan".fmt(f),
+            ZScan => "zscan".fmt(f),
+            Select => "select".fmt(f),
+            Move => "move".fmt(f),
+            SwapDb => "swapdb".fmt(f),
+            FlushDb => "flushdb".fmt(f),
+            FlushAll => "flushall".fmt(f),
+            Info => "info".fmt(f),
+            Save => "save".fmt(f),
+            BgSave => "bgsave".fmt(f),
+            LastSave => "lastsave".fmt(f),
+            BgRewriteAof => "bgrewriteaof".fmt(f),
+            Sync => "sync".fmt(f),
+            Dump => "dump".fmt(f),
+            Restore => "restore".fmt(f),
+            Migrate => "migrate".fmt(f),
+            ReplicaOf => "replicaof".fmt(f),
+            PSync => "psync".fmt(f),
+            Lease => "lease".fmt(f),
+            Release => "release".fmt(f),
+            Leases => "leases".fmt(f),
+            WithLease => "withlease".fmt(f),
+            Begin => "begin".fmt(f),
+            Commit => "commit".fmt(f),
+            Rollback => "rollback".fmt(f),
+            ClusterInfo => "cluster info".fmt(f),
+            ClusterMyId => "cluster myid".fmt(f),
+            ClusterMeet => "cluster meet".fmt(f),
+            ClusterNodes => "cluster nodes".fmt(f),
+            ClusterSlots => "cluster slots".fmt(f),
+            ClusterShards => "cluster shards".fmt(f),
+            ClusterKeySlot => "cluster keyslot".fmt(f),
+            ClusterAddSlots => "cluster addslots".fmt(f),
+            ClusterSetSlot => "cluster setslot".fmt(f),
+            ClusterCountKeysInSlot => "cluster countkeysinslot".fmt(f),
+            ClusterGetKeysInSlot => "cluster getkeysinslot".fmt(f),
+            Asking => "asking".fmt(f),
         }
     }
 }
@@ -119,18 +556,22 @@ pub enum Lock {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Command {
     action: Action,
-    args: Vec<String>,
+    args: Vec<Vec<u8>>,
     opts: HashSet<CommandOpt>,
     lock: Option<Lock>,
+    /// The command as the client sent it, kept for commands that access the store so that
+    /// writes can be logged and the keys a command touches found.
+    argv: Vec<Vec<u8>>,
 }
 
 impl Command {
-    pub fn new(action: Action, args: Vec<String>, lock: Option<Lock>) -> Command {
+    pub fn new(action: Action, args: Vec<Vec<u8>>, lock: Option<Lock>) -> Command {
         Command {
             action,
             args,
             lock,
             opts: HashSet::new(),
+            argv: vec![],
         }
     }
 
@@ -143,11 +584,13 @@ impl Command {
                 let action_resp = array.first().ok_or(ParseCommandError::new(IsEmpty, None))?;
 
                 match action_resp {
-                    resp::Value::BulkString(cmd) => {
-                        let action = if COMMAND_PREFIX.contains(cmd.as_str()) {
+                    resp::Value::BulkString(_) => {
+                        let cmd = action_resp
+                            .to_string()
+                            .map_err(|_| ParseCommandError::new(InvalidCommand, None))?;
+                        let action = if COMMAND_PREFIX.contains(cmd.to_lowercase().as_str()) {
                             let next = array
-                                .iter()
-                                .nth(1)
+                                .get(1)
                                 .ok_or(ParseCommandError::new_with_context(
                                     UnknownCommand,
                                     None,
@@ -157,10 +600,10 @@ impl Command {
                                 .map_err(|_| ParseCommandError::new(InvalidCommand, None))?;
                             Action::parse(&format!("{} {}", cmd, next))?
                         } else {
-                            Action::parse(cmd)?
+                            Action::parse(&cmd)?
                         };
 
-                        match action {
+                        let mut cmd = match action {
                             Ping => parse_ping(&array),
                             Echo => parse_echo(&array),
                             Set => parse_set(&array),
@@ -170,6 +613,12 @@ impl Command {
                             Expire => parse_expire(&array),
                             PExpire => parse_pexpire(&array),
                             Ttl => parse_ttl(&array),
+                            PTtl => parse_pttl(&array),
+                            Persist => parse_persist(&array),
+                            ExpireAt => parse_expireat(&array),
+                            PExpireAt => parse_pexpireat(&array),
+                            ExpireTime => parse_expiretime(&array),
+                            PExpireTime => parse_pexpiretime(&array),
                             Multi => parse_multi(&array),
                             Exec => parse_exec(&array),
                             Discard => parse_discard(&array),
@@ -178,7 +627,142 @@ impl Command {
                             ClientId => Ok(Command::new(ClientId, vec![], None)),
                             ClientList => Ok(Command::new(ClientList, vec![], Some(Lock::Read))),
                             MemoryUsage => parse_memory_usage(&array),
+                            MemoryStats => Ok(Command::new(MemoryStats, vec![], Some(Lock::Read))),
+                            MemoryDoctor => {
+                                Ok(Command::new(MemoryDoctor, vec![], Some(Lock::Read)))
+                            }
+                            LPush => parse_lpush(&array),
+                            RPush => parse_rpush(&array),
+                            LPop => parse_lpop(&array),
+                            RPop => parse_rpop(&array),
+                            LRange => parse_lrange(&array),
+                            LIndex => parse_lindex(&array),
+                            LSet => parse_lset(&array),
+                            LRem => parse_lrem(&array),
+                            LTrim => parse_ltrim(&array),
+                            LLen => parse_llen(&array),
+                            LInsert => parse_linsert(&array),
+                            HSet => parse_hset(&array),
+                            HGet => parse_hget(&array),
+                            HMGet => parse_hmget(&array),
+                            HDel => parse_hdel(&array),
+                            HGetAll => parse_hgetall(&array),
+                            HKeys => parse_hkeys(&array),
+                            HVals => parse_hvals(&array),
+                            HLen => parse_hlen(&array),
+                            HExists => parse_hexists(&array),
+                            HIncrBy => parse_hincrby(&array),
+                            HSetNx => parse_hsetnx(&array),
+                            SAdd => parse_sadd(&array),
+                            SRem => parse_srem(&array),
+                            SMembers => parse_smembers(&array),
+                            SIsMember => parse_sismember(&array),
+                            SCard => parse_scard(&array),
+                            SPop => parse_spop(&array),
+                            SRandMember => parse_srandmember(&array),
+                            SInter => parse_sinter(&array),
+                            SUnion => parse_sunion(&array),
+                            SDiff => parse_sdiff(&array),
+                            SInterStore => parse_sinterstore(&array),
+                            SUnionStore => parse_sunionstore(&array),
+                            SDiffStore => parse_sdiffstore(&array),
+                            ZAdd => parse_zadd(&array),
+                            ZRange => parse_zrange(&array),
+                            ZRank => parse_zrank(&array),
+                            ZScore => parse_zscore(&array),
+                            ZRem => parse_zrem(&array),
+                            ZIncrBy => parse_zincrby(&array),
+                            ZPopMin => parse_zpopmin(&array),
+                            ZPopMax => parse_zpopmax(&array),
+                            XAdd => parse_xadd(&array),
+                            XRange => parse_xrange(&array),
+                            XRevRange => parse_xrevrange(&array),
+                            XLen => parse_xlen(&array),
+                            XTrim => parse_xtrim(&array),
+                            XRead => parse_xread(&array),
+                            XGroupCreate => parse_xgroup_create(&array),
+                            XGroupSetId => parse_xgroup_setid(&array),
+                            XGroupDestroy => parse_xgroup_destroy(&array),
+                            XGroupCreateConsumer => parse_xgroup_createconsumer(&array),
+                            XGroupDelConsumer => parse_xgroup_delconsumer(&array),
+                            XReadGroup => parse_xreadgroup(&array),
+                            XAck => parse_xack(&array),
+                            XPending => parse_xpending(&array),
+                            XClaim => parse_xclaim(&array),
+                            XAutoClaim => parse_xautoclaim(&array),
+                            Incr => parse_incr(&array),
+                            IncrBy => parse_incrby(&array),
+                            IncrByFloat => parse_incrbyfloat(&array),
+                            Decr => parse_decr(&array),
+                            DecrBy => parse_decrby(&array),
+                            Append => parse_append(&array),
+                            StrLen => parse_strlen(&array),
+                            GetRange => parse_getrange(&array),
+                            SetRange => parse_setrange(&array),
+                            GetSet => parse_getset(&array),
+                            GetDel => parse_getdel(&array),
+                            GetEx => parse_getex(&array),
+                            MGet => parse_mget(&array),
+                            MSet => parse_mset(&array),
+                            MSetNx => parse_msetnx(&array),
+                            Del => parse_del(&array),
+                            Unlink => parse_unlink(&array),
+                            Exists => parse_exists(&array),
+                            Type => parse_type(&array),
+                            Rename => parse_rename(&array),
+                            RenameNx => parse_renamenx(&array),
+                            Copy => parse_copy(&array),
+                            RandomKey => parse_randomkey(&array),
+                            DbSize => parse_dbsize(&array),
+                            Touch => parse_touch(&array),
+                            Keys => parse_keys(&array),
+                            Scan => parse_scan(&array),
+                            SScan => parse_sscan(&array),
+                            HScan => parse_hscan(&array),
+                            ZScan => parse_zscan(&array),
+                            Select => parse_select(&array),
+                            Move => parse_move(&array),
+                            SwapDb => parse_swapdb(&array),
+                            FlushDb => parse_flushdb(&array),
+                            FlushAll => parse_flushall(&array),
+                            Info => parse_info(&array),
+                            Save => parse_save(&array),
+                            BgSave => parse_bgsave(&array),
+                            LastSave => parse_lastsave(&array),
+                            BgRewriteAof => parse_bgrewriteaof(&array),
+                            Sync => parse_sync(&array),
+                            Dump => parse_dump(&array),
+                            Restore => parse_restore(&array),
+                            Migrate => parse_migrate(&array),
+                            ReplicaOf => parse_replicaof(&array),
+                            PSync => parse_psync(&array),
+                            Lease => parse_lease(&array),
+                            Release => parse_release(&array),
+                            Leases => parse_leases(&array),
+                            WithLease => return parse_withlease(&array),
+                            Begin => parse_begin(&array),
+                            Commit => parse_commit(&array),
+                            Rollback => parse_rollback(&array),
+                            ClusterInfo => parse_cluster_info(&array),
+                            ClusterMyId => parse_cluster_myid(&array),
+                            ClusterMeet => parse_cluster_meet(&array),
+                            ClusterNodes => parse_cluster_nodes(&array),
+                            ClusterSlots => parse_cluster_slots(&array),
+                            ClusterShards => parse_cluster_shards(&array),
+                            ClusterKeySlot => parse_cluster_keyslot(&array),
+                            ClusterAddSlots => parse_cluster_addslots(&array),
+                            ClusterSetSlot => parse_cluster_setslot(&array),
+                            ClusterCountKeysInSlot => parse_cluster_countkeysinslot(&array),
+                            ClusterGetKeysInSlot => parse_cluster_getkeysinslot(&array),
+                            Asking => parse_asking(&array),
+                        }?;
+                        if cmd.lock.is_some() {
+                            cmd.argv = array
+                                .iter()
+                                .filter_map(|arg| arg.as_bytes().ok().map(<[u8]>::to_vec))
+                                .collect();
                         }
+                        Ok(cmd)
                     }
                     _ => Err(ParseCommandError::new(InvalidCommand, None)),
                 }
@@ -191,11 +775,11 @@ impl Command {
         &self.action
     }
 
-    pub fn args(&self) -> &Vec<String> {
+    pub fn args(&self) -> &Vec<Vec<u8>> {
         &self.args
     }
 
-    pub fn args_mut(&mut self) -> &mut Vec<String> {
+    pub fn args_mut(&mut self) -> &mut Vec<Vec<u8>> {
         &mut self.args
     }
 
@@ -203,19 +787,148 @@ impl Command {
         self.lock
     }
 
-    pub fn drain_args(&mut self) -> std::vec::Drain<String> {
+    pub fn drain_args(&mut self) -> std::vec::Drain<'_, Vec<u8>> {
         self.args.drain(..)
     }
 
+    /// The command as the client sent it. Only kept for commands that access the store.
+    pub fn argv(&self) -> &[Vec<u8>] {
+        &self.argv
+    }
+
+    pub fn take_argv(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.argv)
+    }
+
     pub fn opts(&self) -> &HashSet<CommandOpt> {
         &self.opts
     }
 
+    /// The keys a write command changes in the database it runs against, as named in the
+    /// command the client sent. Commands that empty or swap whole databases name none;
+    /// [`Command::other_writes`] covers them.
+    pub fn written_keys(&self) -> Vec<&[u8]> {
+        use Action::*;
+
+        let argv = &self.argv;
+        let keys: Vec<&Vec<u8>> = match self.action {
+            _ if self.lock != Some(Lock::Write) || argv.len() < 2 => vec![],
+            Lease | Release | Save | FlushDb | FlushAll | SwapDb => vec![],
+            MSet | MSetNx => argv[1..].iter().step_by(2).collect(),
+            Del | Unlink => argv[1..].iter().collect(),
+            Rename | RenameNx => argv[1..3].iter().collect(),
+            Copy => vec![&argv[2]],
+            XGroupCreate | XGroupSetId | XGroupDestroy | XGroupCreateConsumer
+            | XGroupDelConsumer => vec![&argv[2]],
+            XReadGroup => stream_keys(argv),
+            Migrate if self.opts.contains(&CommandOpt::MigrateCopy) => vec![],
+            Migrate => match argv[6..]
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case(b"keys"))
+            {
+                Some(index) => argv[6 + index + 1..].iter().collect(),
+                None => vec![&argv[3]],
+            },
+            _ => vec![&argv[1]],
+        };
+        keys.into_iter().map(Vec::as_slice).collect()
+    }
+
+    /// What a write command changes beyond the keys it names in `db`, the database it runs
+    /// against, as a database index and a key, or `None` for every key of that database:
+    /// MOVE writes its key in the destination, while FLUSHDB, FLUSHALL and SWAPDB write
+    /// every key of the databases they empty or swap. Indexes past `databases` are left out,
+    /// as the command fails on them anyway.
+    pub fn other_writes(&self, db: usize, databases: usize) -> Vec<(usize, Option<&[u8]>)> {
+        use Action::*;
+
+        let argv = &self.argv;
+        let index = |arg: &[u8]| -> Option<usize> { std::str::from_utf8(arg).ok()?.parse().ok() };
+        let writes = match self.action {
+            _ if self.lock != Some(Lock::Write) => vec![],
+            Move if argv.len() == 3 => index(&argv[2])
+                .map(|to| (to, Some(argv[1].as_slice())))
+                .into_iter()
+                .collect(),
+            FlushDb => vec![(db, None)],
+            FlushAll => (0..databases).map(|db| (db, None)).collect(),
+            SwapDb if argv.len() == 3 => argv[1..]
+                .iter()
+                .filter_map(|arg| index(arg))
+                .map(|db| (db, None))
+                .collect(),
+            _ => vec![],
+        };
+        writes
+            .into_iter()
+            .filter(|(db, _)| *db < databases)
+            .collect()
+    }
+
+    /// Every key the command reads or writes in the database it runs against, or `None` for
+    /// commands that reach past the keys they name, such as SCAN, FLUSHDB or MOVE, and for
+    /// commands that do not access the store.
+    pub fn keys(&self) -> Option<Vec<&[u8]>> {
+        use Action::*;
+
+        let argv = &self.argv;
+        let keys: Vec<&Vec<u8>> = match self.action {
+            _ if self.lock.is_none() => return None,
+            ClientList
+            | MemoryStats
+            | MemoryDoctor
+            | Keys
+            | Scan
+            | RandomKey
+            | DbSize
+            | Move
+            | SwapDb
+            | FlushDb
+            | FlushAll
+            | Info
+            | Save
+            | BgSave
+            | LastSave
+            | BgRewriteAof
+            | Sync
+            | Migrate
+            | Lease
+            | Release
+            | Leases
+            | ClusterSetSlot
+            | ClusterCountKeysInSlot
+            | ClusterGetKeysInSlot => return None,
+            MemoryUsage | XGroupCreate | XGroupSetId | XGroupDestroy | XGroupCreateConsumer
+            | XGroupDelConsumer => vec![&argv[2]],
+            MGet | Exists | Touch | Del | Unlink | SInter | SUnion | SDiff | SInterStore
+            | SUnionStore | SDiffStore => argv[1..].iter().collect(),
+            MSet | MSetNx => argv[1..].iter().step_by(2).collect(),
+            Rename | RenameNx | Copy => argv[1..3].iter().collect(),
+            XRead | XReadGroup => stream_keys(argv),
+            _ => vec![&argv[1]],
+        };
+        Some(keys.into_iter().map(Vec::as_slice).collect())
+    }
+
     fn set_options(&mut self, opts: HashSet<CommandOpt>) {
         self.opts = opts;
     }
 }
 
+/// The keys of an XREAD or XREADGROUP: the first half of the arguments after STREAMS.
+fn stream_keys(argv: &[Vec<u8>]) -> Vec<&Vec<u8>> {
+    match argv
+        .iter()
+        .position(|arg| arg.eq_ignore_ascii_case(b"streams"))
+    {
+        Some(index) => {
+            let streams = &argv[index + 1..];
+            streams[..streams.len() / 2].iter().collect()
+        }
+        None => vec![],
+    }
+}
+
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.action.fmt(f)
@@ -226,9 +939,60 @@ impl fmt::Display for Command {
 pub enum CommandOpt {
     SetEx(u64),
     SetPx(u64),
+    SetExAt(u64),
+    SetPxAt(u64),
     SetNx,
     SetXx,
     SetKeepTtl,
+    GetExEx(u64),
+    GetExPx(u64),
+    GetExExAt(u64),
+    GetExPxAt(u64),
+    GetExPersist,
+    ExpireNx,
+    ExpireXx,
+    ExpireGt,
+    ExpireLt,
+    CopyReplace,
+    RestoreReplace,
+    RestoreAbsTtl,
+    RestoreIdleTime(u64),
+    RestoreFreq(u8),
+    MigrateCopy,
+    MigrateReplace,
+    LeaseToken(u64),
+    ScanMatch,
+    ScanCount(usize),
+    MemorySamples(usize),
+    ScanType(&'static str),
+    FlushAsync,
+    LInsertBefore,
+    LInsertAfter,
+    ZAddNx,
+    ZAddXx,
+    ZAddGt,
+    ZAddLt,
+    ZAddCh,
+    ZAddIncr,
+    ZRangeByScore,
+    ZRangeByLex,
+    ZRangeRev,
+    ZRangeLimit(i64, i64),
+    ZRangeWithScores,
+    XAddNoMkStream,
+    XTrim(TrimStrategy),
+    XTrimLimit(usize),
+    XCount(usize),
+    XBlock(u64),
+    XReadNoAck,
+    XGroupMkStream,
+    XPendingIdle(u64),
+    XClaimIdle(u64),
+    XClaimTime(u64),
+    XClaimRetryCount(u64),
+    XClaimForce,
+    XClaimJustId,
+    XClaimLastId(StreamId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -248,6 +1012,15 @@ pub enum ParseCommandErrorKind {
     WrongNumberArgs,
     InvalidTtl,
     SyntaxError,
+    NotAnInteger,
+    OutOfRange,
+    NotAFloat,
+    MinMaxNotFloat,
+    MinMaxNotString,
+    InvalidOptions,
+    InvalidStreamId,
+    OffsetOutOfRange,
+    InvalidCursor,
 }
 
 impl ParseCommandError {
@@ -301,10 +1074,19 @@ impl fmt::Display for ParseCommandError {
             ),
             InvalidTtl => write!(
                 f,
-                "ERR invalid expire time in {}",
+                "ERR invalid expire time in '{}' command",
                 self.action.as_ref().unwrap()
             ),
             SyntaxError => write!(f, "ERR syntax error"),
+            NotAnInteger => "ERR value is not an integer or out of range".fmt(f),
+            OutOfRange => "ERR value is out of range, must be positive".fmt(f),
+            NotAFloat => "ERR value is not a valid float".fmt(f),
+            MinMaxNotFloat => "ERR min or max is not a float".fmt(f),
+            MinMaxNotString => "ERR min or max not valid string range item".fmt(f),
+            InvalidOptions => write!(f, "ERR {}", self.other_context.as_ref().unwrap()),
+            InvalidStreamId => "ERR Invalid stream ID specified as stream command argument".fmt(f),
+            OffsetOutOfRange => "ERR offset is out of range".fmt(f),
+            InvalidCursor => "ERR invalid cursor".fmt(f),
         }
     }
 }
@@ -315,11 +1097,7 @@ impl std::convert::From<resp::Error> for ParseCommandError {
     }
 }
 
-fn expect_max_args(
-    action: Action,
-    v: &Vec<resp::Value>,
-    max: usize,
-) -> Result<(), ParseCommandError> {
+fn expect_max_args(action: Action, v: &[resp::Value], max: usize) -> Result<(), ParseCommandError> {
     if v.len() > max + 1 {
         Err(ParseCommandError::new(
             ParseCommandErrorKind::WrongNumberArgs,
@@ -330,21 +1108,77 @@ fn expect_max_args(
     }
 }
 
-fn next_arg<'a, I>(mut iter: I, action: Action) -> Result<String, ParseCommandError>
+fn next_arg<'a, I>(mut iter: I, action: Action) -> Result<Vec<u8>, ParseCommandError>
 where
     I: Iterator<Item = &'a resp::Value>,
 {
-    iter.next()
+    Ok(iter
+        .next()
         .ok_or(ParseCommandError::new(
             ParseCommandErrorKind::WrongNumberArgs,
             Some(action),
         ))?
-        .to_string()
-        .map_err(|err| ParseCommandError::from(err))
+        .as_bytes()?
+        .to_vec())
+}
+
+/// Like [`next_arg`], but for keywords such as options: returns the argument lowercased.
+fn next_keyword<'a, I>(iter: I, action: Action) -> Result<String, ParseCommandError>
+where
+    I: Iterator<Item = &'a resp::Value>,
+{
+    Ok(String::from_utf8_lossy(&next_arg(iter, action)?).to_lowercase())
 }
 
-fn parse_ping(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Ping, &array, 1)?;
+fn rest_args<'a, I>(iter: I) -> Result<Vec<Vec<u8>>, ParseCommandError>
+where
+    I: Iterator<Item = &'a resp::Value>,
+{
+    iter.map(|arg| Ok(arg.as_bytes()?.to_vec())).collect()
+}
+
+fn expect_int(arg: &[u8], action: Action) -> Result<i64, ParseCommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<i64>().ok())
+        .ok_or_else(|| ParseCommandError::new(ParseCommandErrorKind::NotAnInteger, Some(action)))
+}
+
+fn expect_float(arg: &[u8], action: Action) -> Result<f64, ParseCommandError> {
+    zset::parse_score(arg)
+        .ok_or_else(|| ParseCommandError::new(ParseCommandErrorKind::NotAFloat, Some(action)))
+}
+
+fn expect_uint(arg: &[u8], action: Action) -> Result<u64, ParseCommandError> {
+    let int = expect_int(arg, action)?;
+    if int < 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::OutOfRange,
+            Some(action),
+        ));
+    }
+    Ok(int as u64)
+}
+
+fn expect_stream_id(
+    arg: &[u8],
+    action: Action,
+    parse: fn(&[u8]) -> Option<StreamId>,
+) -> Result<StreamId, ParseCommandError> {
+    parse(arg)
+        .ok_or_else(|| ParseCommandError::new(ParseCommandErrorKind::InvalidStreamId, Some(action)))
+}
+
+fn invalid_options(action: Action, msg: &str) -> ParseCommandError {
+    ParseCommandError::new_with_context(
+        ParseCommandErrorKind::InvalidOptions,
+        Some(action),
+        msg.to_owned(),
+    )
+}
+
+fn parse_ping(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Ping, array, 1)?;
     let arg = next_arg(array.iter().skip(1), Action::Ping);
     let args = if let Ok(arg) = arg {
         vec![arg]
@@ -355,13 +1189,13 @@ fn parse_ping(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
     Ok(Command::new(Action::Ping, args, None))
 }
 
-fn parse_echo(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Echo, &array, 1)?;
+fn parse_echo(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Echo, array, 1)?;
     let arg = next_arg(array.iter().skip(1), Action::Echo)?;
     Ok(Command::new(Action::Echo, vec![arg], None))
 }
 
-fn parse_set(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
+fn parse_set(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
     let mut iter = array.iter().skip(1);
     let key = next_arg(&mut iter, Action::Set)?;
     let val = next_arg(&mut iter, Action::Set)?;
@@ -369,63 +1203,65 @@ fn parse_set(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
 
     let mut has_expire = false;
     let mut has_keepttl = false;
-    loop {
-        if let Some(next) = iter.next() {
-            let opt = next.to_string()?.to_lowercase();
-            let opt_str = opt.as_str();
-            if "ex" == opt_str || "px" == opt_str {
-                has_expire = true;
-                if has_keepttl {
-                    return Err(ParseCommandError::new(
-                        ParseCommandErrorKind::SyntaxError,
-                        Some(Action::Set),
-                    ));
-                }
+    while let Some(next) = iter.next() {
+        let opt = next.to_string()?.to_lowercase();
+        let opt_str = opt.as_str();
+        if matches!(opt_str, "ex" | "px" | "exat" | "pxat") {
+            has_expire = true;
+            if has_keepttl {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(Action::Set),
+                ));
+            }
 
-                if let Some(ttl) = iter.next() {
-                    let ttl = ttl.to_string()?.parse::<u64>().map_err(|_| {
+            if let Some(ttl) = iter.next() {
+                let ttl = ttl
+                    .to_string()?
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| {
                         ParseCommandError::new(ParseCommandErrorKind::InvalidTtl, Some(Action::Set))
                     })?;
-                    let opt = if "ex" == opt_str {
-                        CommandOpt::SetEx(ttl)
-                    } else {
-                        CommandOpt::SetPx(ttl)
-                    };
-                    options.insert(opt);
-                } else {
-                    return Err(ParseCommandError::new(
-                        ParseCommandErrorKind::SyntaxError,
-                        Some(Action::Set),
-                    ));
-                }
-            } else if "nx" == opt_str {
-                if options.contains(&CommandOpt::SetXx) {
-                    return Err(ParseCommandError::new(
-                        ParseCommandErrorKind::SyntaxError,
-                        Some(Action::Set),
-                    ));
-                }
-                options.insert(CommandOpt::SetNx);
-            } else if "xx" == opt_str {
-                if options.contains(&CommandOpt::SetNx) {
-                    return Err(ParseCommandError::new(
-                        ParseCommandErrorKind::SyntaxError,
-                        Some(Action::Set),
-                    ));
-                }
-                options.insert(CommandOpt::SetXx);
-            } else if "keepttl" == opt_str {
-                has_keepttl = true;
-                if has_expire {
-                    return Err(ParseCommandError::new(
-                        ParseCommandErrorKind::SyntaxError,
-                        Some(Action::Set),
-                    ));
-                }
-                options.insert(CommandOpt::SetKeepTtl);
+                let opt = match opt_str {
+                    "ex" => CommandOpt::SetEx(ttl),
+                    "px" => CommandOpt::SetPx(ttl),
+                    "exat" => CommandOpt::SetExAt(ttl),
+                    _ => CommandOpt::SetPxAt(ttl),
+                };
+                options.insert(opt);
+            } else {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(Action::Set),
+                ));
             }
-        } else {
-            break;
+        } else if "nx" == opt_str {
+            if options.contains(&CommandOpt::SetXx) {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(Action::Set),
+                ));
+            }
+            options.insert(CommandOpt::SetNx);
+        } else if "xx" == opt_str {
+            if options.contains(&CommandOpt::SetNx) {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(Action::Set),
+                ));
+            }
+            options.insert(CommandOpt::SetXx);
+        } else if "keepttl" == opt_str {
+            has_keepttl = true;
+            if has_expire {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(Action::Set),
+                ));
+            }
+            options.insert(CommandOpt::SetKeepTtl);
         }
     }
     let mut cmd = Command::new(Action::Set, vec![key, val], Some(Lock::Write));
@@ -434,19 +1270,21 @@ fn parse_set(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
     Ok(cmd)
 }
 
-fn parse_setex(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
+fn parse_setex(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
     let action = Action::SetEx;
-    expect_max_args(action, &array, 3)?;
+    expect_max_args(action, array, 3)?;
     let mut iter = array.iter().skip(1);
     let key = next_arg(&mut iter, action)?;
     let ttl = next_arg(&mut iter, action)?;
-    ttl.parse::<u64>()
-        .map_err(|_| ParseCommandError::new(ParseCommandErrorKind::InvalidTtl, Some(action)))?;
+    expect_uint(&ttl, action)
+        .ok()
+        .filter(|ttl| *ttl > 0)
+        .ok_or_else(|| ParseCommandError::new(ParseCommandErrorKind::InvalidTtl, Some(action)))?;
     let val = next_arg(&mut iter, action)?;
     Ok(Command::new(action, vec![key, ttl, val], Some(Lock::Write)))
 }
 
-fn parse_setnx(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
+fn parse_setnx(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
     let action = Action::SetNx;
     expect_max_args(action, array, 2)?;
     let mut iter = array.iter().skip(1);
@@ -457,233 +1295,2965 @@ fn parse_setnx(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
     ))
 }
 
-fn parse_get(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Echo, &array, 1)?;
+fn parse_get(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Echo, array, 1)?;
     let mut iter = array.iter().skip(1);
     let key = next_arg(&mut iter, Action::Get)?;
 
     Ok(Command::new(Action::Get, vec![key], Some(Lock::Read)))
 }
 
-fn parse_expire(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Expire, &array, 2)?;
-    let mut iter = array.iter().skip(1);
-    let key = next_arg(&mut iter, Action::Expire)?;
-    let ttl = next_arg(&mut iter, Action::Expire)?;
+fn parse_append(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::Append, Lock::Write)
+}
+
+fn parse_strlen(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::StrLen)
+}
 
+fn parse_getrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::GetRange;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let start = next_arg(&mut iter, action)?;
+    let end = next_arg(&mut iter, action)?;
+    expect_int(&start, action)?;
+    expect_int(&end, action)?;
     Ok(Command::new(
-        Action::Expire,
-        vec![key, ttl],
-        Some(Lock::Write),
+        action,
+        vec![key, start, end],
+        Some(Lock::Read),
     ))
 }
 
-fn parse_pexpire(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    let action = Action::PExpire;
-    expect_max_args(action, &array, 2)?;
+fn parse_setrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::SetRange;
+    expect_max_args(action, array, 3)?;
     let mut iter = array.iter().skip(1);
     let key = next_arg(&mut iter, action)?;
-    let ttl = next_arg(&mut iter, action)?;
-
+    let offset = next_arg(&mut iter, action)?;
+    let value = next_arg(&mut iter, action)?;
+    if expect_int(&offset, action)? < 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::OffsetOutOfRange,
+            Some(action),
+        ));
+    }
     Ok(Command::new(
-        Action::Expire,
-        vec![key, ttl],
+        action,
+        vec![key, offset, value],
         Some(Lock::Write),
     ))
 }
 
-fn parse_ttl(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Ttl, array, 1)?;
-    let key = next_arg(array.iter().skip(1), Action::Ttl)?;
-    Ok(Command::new(Action::Ttl, vec![key], Some(Lock::Read)))
+fn parse_getset(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::GetSet, Lock::Write)
 }
 
-fn parse_multi(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Multi, array, 0)?;
-    Ok(Command::new(Action::Multi, vec![], None))
+fn parse_getdel(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::GetDel, array, 1)?;
+    let key = next_arg(array.iter().skip(1), Action::GetDel)?;
+    Ok(Command::new(Action::GetDel, vec![key], Some(Lock::Write)))
 }
 
-fn parse_exec(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Exec, array, 0)?;
-    Ok(Command::new(Action::Exec, vec![], None))
+fn parse_getex(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::GetEx;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        let opt = match next_keyword(&mut iter, action)?.as_str() {
+            "persist" => CommandOpt::GetExPersist,
+            keyword @ ("ex" | "px" | "exat" | "pxat") => {
+                let ttl = next_arg(&mut iter, action).map_err(|_| {
+                    ParseCommandError::new(ParseCommandErrorKind::SyntaxError, Some(action))
+                })?;
+                let ttl = expect_int(&ttl, action)?;
+                if ttl <= 0 {
+                    return Err(ParseCommandError::new(
+                        ParseCommandErrorKind::InvalidTtl,
+                        Some(action),
+                    ));
+                }
+                let ttl = ttl as u64;
+                match keyword {
+                    "ex" => CommandOpt::GetExEx(ttl),
+                    "px" => CommandOpt::GetExPx(ttl),
+                    "exat" => CommandOpt::GetExExAt(ttl),
+                    _ => CommandOpt::GetExPxAt(ttl),
+                }
+            }
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        if !options.is_empty() {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(action),
+            ));
+        }
+        options.insert(opt);
+    }
+
+    let mut cmd = Command::new(action, vec![key], Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
 }
 
-fn parse_discard(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Discard, array, 0)?;
-    Ok(Command::new(Action::Discard, vec![], None))
+fn parse_mget(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::MGet, Lock::Read)
 }
 
-fn parse_watch(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    let mut keys = vec![];
-    for key in array.iter().skip(1) {
-        keys.push(key.to_string().map_err(|_| {
-            ParseCommandError::new(ParseCommandErrorKind::InvalidArgs, Some(Action::Watch))
-        })?);
-    }
-    Ok(Command::new(Action::Watch, keys, None))
+fn parse_mset(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_value_pairs(array, Action::MSet)
 }
 
-fn parse_unwatch(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    expect_max_args(Action::Unwatch, array, 0)?;
-    Ok(Command::new(Action::Unwatch, vec![], None))
+fn parse_msetnx(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_value_pairs(array, Action::MSetNx)
 }
 
-fn parse_memory_usage(array: &Vec<resp::Value>) -> Result<Command, ParseCommandError> {
-    let action = Action::MemoryUsage;
-    if array.len() != 3 {
-        Err(ParseCommandError::new(
+fn parse_key_value_pairs(
+    array: &[resp::Value],
+    action: Action,
+) -> Result<Command, ParseCommandError> {
+    let args = rest_args(array.iter().skip(1))?;
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(ParseCommandError::new(
             ParseCommandErrorKind::WrongNumberArgs,
             Some(action),
-        ))
-    } else {
-        Ok(Command::new(
-            action,
-            vec![next_arg(array.iter().skip(2), action)?],
-            Some(Lock::Read),
-        ))
+        ));
     }
+    Ok(Command::new(action, args, Some(Lock::Write)))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn parse_del(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::Del, Lock::Write)
+}
 
-    #[test]
-    fn test_parse_ping() {
-        assert_eq!(
-            Ok(Command::new(Action::Ping, vec![], None)),
-            parse_ping(&cmd!["PING"])
-        );
-        assert_eq!(
-            Ok(Command::new(Action::Ping, vec!["hello".to_owned()], None)),
-            parse_ping(&cmd!["PING", "hello"])
-        );
-        assert_eq!(
-            Err(ParseCommandError::new(
-                ParseCommandErrorKind::WrongNumberArgs,
-                Some(Action::Ping)
-            )),
-            parse_ping(&cmd!["PING", "foo", "bar"])
-        );
-    }
+fn parse_unlink(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::Unlink, Lock::Write)
+}
 
-    #[test]
-    fn test_parse_echo() {
-        assert_eq!(
-            Err(ParseCommandError::new(
-                ParseCommandErrorKind::WrongNumberArgs,
-                Some(Action::Echo)
-            )),
-            parse_echo(&cmd!["ECHO"])
-        );
-        assert_eq!(
-            Ok(Command::new(Action::Echo, vec!["hello".to_owned()], None)),
-            parse_echo(&cmd!["ECHO", "hello"])
-        );
-        assert_eq!(
-            Err(ParseCommandError::new(
-                ParseCommandErrorKind::WrongNumberArgs,
-                Some(Action::Echo)
-            )),
-            parse_echo(&cmd!["ECHO", "foo", "bar"])
-        );
-    }
+fn parse_exists(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::Exists, Lock::Read)
+}
 
-    #[test]
-    fn test_parse_set() {
-        use ParseCommandErrorKind::*;
-        assert_eq!(
-            Ok(Command::new(
-                Action::Set,
-                vec!["foo".to_owned(), "bar".to_owned()],
-                Some(Lock::Write)
-            )),
-            parse_set(&cmd!["SET", "foo", "bar"])
-        );
+fn parse_touch(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::Touch, Lock::Read)
+}
 
-        let cmd_with_opts = parse_set(&cmd!["SET", "foo", "bar", "EX", "60", "NX"]).unwrap();
-        assert_eq!(
-            vec!["foo".to_owned(), "bar".to_owned(),],
-            cmd_with_opts.args
-        );
-        assert!(cmd_with_opts.opts().contains(&CommandOpt::SetNx));
-        assert!(cmd_with_opts.opts().contains(&CommandOpt::SetEx(60)));
+fn parse_type(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::Type)
+}
 
-        assert_eq!(
-            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
-            parse_set(&cmd!["SET", "foo", "bar", "EX"])
-        );
-        assert_eq!(
-            Err(ParseCommandError::new(InvalidTtl, Some(Action::Set))),
-            parse_set(&cmd!["SET", "foo", "bar", "EX", "-1"])
-        );
-        assert_eq!(
-            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
-            parse_set(&cmd!["SET", "foo", "bar", "NX", "XX"])
-        );
-        assert_eq!(
-            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
-            parse_set(&cmd!["SET", "foo", "bar", "EX", "20", "KEEPTTL"])
-        );
-    }
+fn parse_rename(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::Rename, Lock::Write)
+}
 
-    #[test]
-    fn test_parse_get() {
-        assert_eq!(
-            Ok(Command::new(
-                Action::Get,
-                vec!["foo".to_owned()],
-                Some(Lock::Read)
-            )),
-            parse_get(&cmd!["GET", "foo"])
-        );
-    }
+fn parse_renamenx(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::RenameNx, Lock::Write)
+}
 
-    #[test]
-    fn test_parse_expire() {
-        assert_eq!(
-            Ok(Command::new(
-                Action::Expire,
-                vec!["foo".to_owned(), "5".to_owned()],
-                Some(Lock::Write)
-            )),
-            parse_expire(&cmd!["EXPIRE", "foo", "5"])
-        );
+fn parse_copy(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::Copy;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let dest = next_arg(&mut iter, action)?;
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        match next_keyword(&mut iter, action)?.as_str() {
+            "replace" => options.insert(CommandOpt::CopyReplace),
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
     }
 
-    #[test]
-    fn test_parse_ttl() {
-        assert_eq!(
-            Ok(Command::new(
-                Action::Ttl,
-                vec!["foo".to_owned()],
-                Some(Lock::Read)
-            )),
-            parse_ttl(&cmd!["TTL", "foo"])
-        );
-    }
+    let mut cmd = Command::new(action, vec![key, dest], Some(Lock::Write));
+    cmd.set_options(options);
 
-    #[test]
-    fn test_setex() {
-        assert_eq!(
-            Ok(Command::new(
-                Action::SetEx,
-                vec!["foo".to_owned(), "10".to_owned(), "bar".to_owned()],
-                Some(Lock::Write)
-            )),
-            parse_setex(&cmd!["SETEX", "foo", "10", "bar"])
-        );
-    }
+    Ok(cmd)
+}
 
-    #[test]
+fn parse_dump(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::Dump)
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]. A ttl of
+/// 0 keeps the deadline recorded in the payload, if any. IDLETIME and FREQ exclude each other.
+fn parse_restore(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::Restore;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let ttl = next_arg(&mut iter, action)?;
+    let payload = next_arg(&mut iter, action)?;
+    if expect_int(&ttl, action)? < 0 {
+        return Err(invalid_options(action, "Invalid TTL value, must be >= 0"));
+    }
+
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        match next_keyword(&mut iter, action)?.as_str() {
+            "replace" => options.insert(CommandOpt::RestoreReplace),
+            "absttl" => options.insert(CommandOpt::RestoreAbsTtl),
+            "idletime" => {
+                let idle = expect_int(&next_arg(&mut iter, action)?, action)?;
+                if idle < 0 {
+                    return Err(invalid_options(
+                        action,
+                        "Invalid IDLETIME value, must be >= 0",
+                    ));
+                }
+                if options
+                    .iter()
+                    .any(|opt| matches!(opt, CommandOpt::RestoreFreq(_)))
+                {
+                    return Err(ParseCommandError::new(
+                        ParseCommandErrorKind::SyntaxError,
+                        Some(action),
+                    ));
+                }
+                options.insert(CommandOpt::RestoreIdleTime(idle as u64))
+            }
+            "freq" => {
+                let idle_given = options
+                    .iter()
+                    .any(|opt| matches!(opt, CommandOpt::RestoreIdleTime(_)));
+                if iter.len() == 0 || idle_given {
+                    return Err(ParseCommandError::new(
+                        ParseCommandErrorKind::SyntaxError,
+                        Some(action),
+                    ));
+                }
+                let freq = expect_int(&next_arg(&mut iter, action)?, action)?;
+                let freq = u8::try_from(freq).map_err(|_| {
+                    invalid_options(action, "Invalid FREQ value, must be >= 0 and <= 255")
+                })?;
+                options.insert(CommandOpt::RestoreFreq(freq))
+            }
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+    }
+
+    let mut cmd = Command::new(action, vec![key, ttl, payload], Some(Lock::Write));
+    cmd.set_options(options);
+    Ok(cmd)
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [KEYS key...]. The
+/// arguments are kept as host, port, destination-db and timeout, followed by the keys.
+fn parse_migrate(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::Migrate;
+    let mut iter = array.iter().skip(1);
+    let host = next_arg(&mut iter, action)?;
+    let port = next_arg(&mut iter, action)?;
+    let key = next_arg(&mut iter, action)?;
+    let db = next_arg(&mut iter, action)?;
+    let timeout = next_arg(&mut iter, action)?;
+    if !(0..=u16::MAX as i64).contains(&expect_int(&port, action)?) {
+        return Err(invalid_options(action, "Invalid port"));
+    }
+    expect_uint(&db, action)?;
+    expect_int(&timeout, action)?;
+
+    let mut options = HashSet::new();
+    let mut keys = vec![];
+    while iter.len() > 0 {
+        match next_keyword(&mut iter, action)?.as_str() {
+            "copy" => options.insert(CommandOpt::MigrateCopy),
+            "replace" => options.insert(CommandOpt::MigrateReplace),
+            "keys" => {
+                if !key.is_empty() {
+                    return Err(invalid_options(
+                        action,
+                        "When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    ));
+                }
+                keys = rest_args(&mut iter)?;
+                if keys.is_empty() {
+                    return Err(ParseCommandError::new(
+                        ParseCommandErrorKind::SyntaxError,
+                        Some(action),
+                    ));
+                }
+                true
+            }
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+    }
+    if keys.is_empty() {
+        keys.push(key);
+    }
+
+    let mut args = vec![host, port, db, timeout];
+    args.append(&mut keys);
+    let mut cmd = Command::new(action, args, Some(Lock::Write));
+    cmd.set_options(options);
+    Ok(cmd)
+}
+
+fn parse_randomkey(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::RandomKey, array, 0)?;
+    Ok(Command::new(Action::RandomKey, vec![], Some(Lock::Read)))
+}
+
+fn parse_dbsize(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::DbSize, array, 0)?;
+    Ok(Command::new(Action::DbSize, vec![], Some(Lock::Read)))
+}
+
+fn parse_keys(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Keys, array, 1)?;
+    let pattern = next_arg(array.iter().skip(1), Action::Keys)?;
+    Ok(Command::new(Action::Keys, vec![pattern], Some(Lock::Read)))
+}
+
+fn parse_scan(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_scan_args(array, Action::Scan, false)
+}
+
+fn parse_sscan(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_scan_args(array, Action::SScan, true)
+}
+
+fn parse_hscan(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_scan_args(array, Action::HScan, true)
+}
+
+fn parse_zscan(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_scan_args(array, Action::ZScan, true)
+}
+
+/// Parses `[key] cursor [MATCH pattern] [COUNT count]`, plus `[TYPE type]` for SCAN. The
+/// MATCH pattern, if given, becomes the last argument.
+fn parse_scan_args(
+    array: &[resp::Value],
+    action: Action,
+    has_key: bool,
+) -> Result<Command, ParseCommandError> {
+    let mut iter = array.iter().skip(1);
+    let mut args = vec![];
+    if has_key {
+        args.push(next_arg(&mut iter, action)?);
+    }
+    let cursor = next_arg(&mut iter, action)?;
+    std::str::from_utf8(&cursor)
+        .ok()
+        .and_then(|cursor| cursor.parse::<u64>().ok())
+        .ok_or_else(|| {
+            ParseCommandError::new(ParseCommandErrorKind::InvalidCursor, Some(action))
+        })?;
+    args.push(cursor);
+
+    let syntax_error = || ParseCommandError::new(ParseCommandErrorKind::SyntaxError, Some(action));
+    let mut options = HashSet::new();
+    let mut pattern = None;
+    while iter.len() > 0 {
+        let keyword = next_keyword(&mut iter, action)?;
+        let value = next_arg(&mut iter, action).map_err(|_| syntax_error())?;
+        match keyword.as_str() {
+            "match" => pattern = Some(value),
+            "count" => {
+                let count = expect_int(&value, action)?;
+                if count < 1 {
+                    return Err(syntax_error());
+                }
+                options.retain(|opt| !matches!(opt, CommandOpt::ScanCount(_)));
+                options.insert(CommandOpt::ScanCount(count as usize));
+            }
+            "type" if action == Action::Scan => {
+                let name = String::from_utf8_lossy(&value).to_lowercase();
+                let type_name = ["string", "list", "hash", "set", "zset", "stream"]
+                    .iter()
+                    .find(|type_name| **type_name == name)
+                    .ok_or_else(|| {
+                        ParseCommandError::new_with_context(
+                            ParseCommandErrorKind::InvalidOptions,
+                            Some(action),
+                            format!("unknown type name '{}'", name),
+                        )
+                    })?;
+                options.retain(|opt| !matches!(opt, CommandOpt::ScanType(_)));
+                options.insert(CommandOpt::ScanType(type_name));
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    if let Some(pattern) = pattern {
+        args.push(pattern);
+        options.insert(CommandOpt::ScanMatch);
+    }
+
+    let mut cmd = Command::new(action, args, Some(Lock::Read));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_select(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Select, array, 1)?;
+    let index = next_arg(array.iter().skip(1), Action::Select)?;
+    expect_int(&index, Action::Select)?;
+    Ok(Command::new(Action::Select, vec![index], None))
+}
+
+fn parse_move(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let cmd = parse_key_and_arg(array, Action::Move, Lock::Write)?;
+    expect_int(&cmd.args()[1], Action::Move)?;
+    Ok(cmd)
+}
+
+fn parse_swapdb(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::SwapDb;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let a = next_arg(&mut iter, action)?;
+    let b = next_arg(&mut iter, action)?;
+    expect_int(&a, action)?;
+    expect_int(&b, action)?;
+    Ok(Command::new(action, vec![a, b], Some(Lock::Write)))
+}
+
+/// INFO takes any number of section names; none means the default sections.
+fn parse_info(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let sections = rest_args(array.iter().skip(1))?;
+    Ok(Command::new(Action::Info, sections, Some(Lock::Read)))
+}
+
+fn parse_flushdb(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_flush(array, Action::FlushDb)
+}
+
+fn parse_flushall(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_flush(array, Action::FlushAll)
+}
+
+fn parse_flush(array: &[resp::Value], action: Action) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 1)?;
+    let mut options = HashSet::new();
+    if array.len() > 1 {
+        match next_keyword(array.iter().skip(1), action)?.as_str() {
+            "async" => {
+                options.insert(CommandOpt::FlushAsync);
+            }
+            "sync" => (),
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        }
+    }
+
+    let mut cmd = Command::new(action, vec![], Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_save(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Save, array, 0)?;
+    Ok(Command::new(Action::Save, vec![], Some(Lock::Write)))
+}
+
+/// BGSAVE only needs to read the store: it copies the data out and writes it elsewhere.
+fn parse_bgsave(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::BgSave, array, 0)?;
+    Ok(Command::new(Action::BgSave, vec![], Some(Lock::Read)))
+}
+
+fn parse_bgrewriteaof(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::BgRewriteAof, array, 0)?;
+    Ok(Command::new(Action::BgRewriteAof, vec![], Some(Lock::Read)))
+}
+
+/// SYNC sends a copy of the whole store as an RDB file, so it only needs to read it.
+fn parse_sync(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Sync, array, 0)?;
+    Ok(Command::new(Action::Sync, vec![], Some(Lock::Read)))
+}
+
+/// REPLICAOF host port follows a leader; REPLICAOF NO ONE stops following it.
+fn parse_replicaof(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ReplicaOf;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let host = next_arg(&mut iter, action)?;
+    let port = next_arg(&mut iter, action)?;
+    let no_one = host.eq_ignore_ascii_case(b"no") && port.eq_ignore_ascii_case(b"one");
+    if !no_one && !(0..=u16::MAX as i64).contains(&expect_int(&port, action)?) {
+        return Err(invalid_options(action, "Invalid master port"));
+    }
+    Ok(Command::new(action, vec![host, port], None))
+}
+
+/// PSYNC replication-id offset, sent by a replica to sync with its leader.
+fn parse_psync(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::PSync;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let replid = next_arg(&mut iter, action)?;
+    let offset = next_arg(&mut iter, action)?;
+    expect_int(&offset, action)?;
+    Ok(Command::new(action, vec![replid, offset], None))
+}
+
+/// LEASE key milliseconds [TOKEN token]. With the token of the key's lease, renews it.
+fn parse_lease(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::Lease;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let ttl = next_arg(&mut iter, action)?;
+    if expect_int(&ttl, action)? <= 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::InvalidTtl,
+            Some(action),
+        ));
+    }
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        match next_keyword(&mut iter, action)?.as_str() {
+            "token" => {
+                let token = expect_lease_token(&next_arg(&mut iter, action)?, action)?;
+                options.insert(CommandOpt::LeaseToken(token));
+            }
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        }
+    }
+    let mut cmd = Command::new(action, vec![key, ttl], Some(Lock::Write));
+    cmd.set_options(options);
+    Ok(cmd)
+}
+
+/// RELEASE key token
+fn parse_release(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::Release;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let token = next_arg(&mut iter, action)?;
+    expect_lease_token(&token, action)?;
+    Ok(Command::new(action, vec![key, token], Some(Lock::Write)))
+}
+
+fn parse_leases(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Leases, array, 0)?;
+    Ok(Command::new(Action::Leases, vec![], Some(Lock::Read)))
+}
+
+/// WITHLEASE token command [arg ...] runs the command with the token of the lease on the
+/// keys it writes to. The command is parsed, and logged, as if sent on its own.
+fn parse_withlease(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::WithLease;
+    let token = expect_lease_token(&next_arg(array.iter().skip(1), action)?, action)?;
+    if array.len() < 3 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+    let mut cmd = Command::from_resp(resp::Value::Array(array[2..].to_vec()))?;
+    cmd.opts.insert(CommandOpt::LeaseToken(token));
+    Ok(cmd)
+}
+
+fn expect_lease_token(arg: &[u8], action: Action) -> Result<u64, ParseCommandError> {
+    lease::parse_token(arg).ok_or_else(|| invalid_options(action, "Invalid lease token"))
+}
+
+fn parse_cluster_info(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::ClusterInfo, array, 1)?;
+    Ok(Command::new(Action::ClusterInfo, vec![], None))
+}
+
+fn parse_cluster_myid(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::ClusterMyId, array, 1)?;
+    Ok(Command::new(Action::ClusterMyId, vec![], None))
+}
+
+/// CLUSTER MEET host port [bus-port]. Without a bus port, the node is expected to gossip
+/// on its port plus 10000.
+fn parse_cluster_meet(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterMeet;
+    expect_max_args(action, array, 4)?;
+    let mut iter = array.iter().skip(2);
+    let host = next_arg(&mut iter, action)?;
+    let mut args = vec![host];
+    for arg in rest_args(&mut iter)?.into_iter().take(2) {
+        if !(0..=u16::MAX as i64).contains(&expect_int(&arg, action)?) {
+            return Err(invalid_options(action, "Invalid node address specified"));
+        }
+        args.push(arg);
+    }
+    if args.len() < 2 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+    Ok(Command::new(action, args, None))
+}
+
+fn parse_cluster_nodes(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::ClusterNodes, array, 1)?;
+    Ok(Command::new(Action::ClusterNodes, vec![], None))
+}
+
+fn parse_cluster_slots(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::ClusterSlots, array, 1)?;
+    Ok(Command::new(Action::ClusterSlots, vec![], None))
+}
+
+fn parse_cluster_shards(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::ClusterShards, array, 1)?;
+    Ok(Command::new(Action::ClusterShards, vec![], None))
+}
+
+fn parse_cluster_keyslot(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterKeySlot;
+    expect_max_args(action, array, 2)?;
+    let key = next_arg(array.iter().skip(2), action)?;
+    Ok(Command::new(action, vec![key], None))
+}
+
+fn parse_cluster_addslots(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterAddSlots;
+    let slots = rest_args(array.iter().skip(2))?;
+    if slots.is_empty() {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+    for slot in slots.iter() {
+        expect_slot(slot, action)?;
+    }
+    Ok(Command::new(action, slots, None))
+}
+
+/// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id, or CLUSTER SETSLOT slot STABLE.
+/// The arguments are kept as the slot, the lowercased subcommand and the node ID. It reads
+/// the store to check that a slot handed over is empty.
+fn parse_cluster_setslot(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterSetSlot;
+    expect_max_args(action, array, 4)?;
+    let mut iter = array.iter().skip(2);
+    let slot = next_arg(&mut iter, action)?;
+    expect_slot(&slot, action)?;
+    let subcommand = next_keyword(&mut iter, action)?;
+    let mut args = vec![slot, subcommand.clone().into_bytes()];
+    match subcommand.as_str() {
+        "importing" | "migrating" | "node" => args.push(next_arg(&mut iter, action)?),
+        "stable" if iter.len() == 0 => (),
+        _ => {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(action),
+            ))
+        }
+    }
+    Ok(Command::new(action, args, Some(Lock::Read)))
+}
+
+fn parse_cluster_countkeysinslot(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterCountKeysInSlot;
+    expect_max_args(action, array, 2)?;
+    let slot = next_arg(array.iter().skip(2), action)?;
+    expect_slot(&slot, action)?;
+    Ok(Command::new(action, vec![slot], Some(Lock::Read)))
+}
+
+fn parse_cluster_getkeysinslot(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ClusterGetKeysInSlot;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(2);
+    let slot = next_arg(&mut iter, action)?;
+    expect_slot(&slot, action)?;
+    let count = next_arg(&mut iter, action)?;
+    expect_uint(&count, action)?;
+    Ok(Command::new(action, vec![slot, count], Some(Lock::Read)))
+}
+
+fn expect_slot(arg: &[u8], action: Action) -> Result<u16, ParseCommandError> {
+    cluster::parse_slot(arg).ok_or_else(|| invalid_options(action, "Invalid or out of range slot"))
+}
+
+/// ASKING lets the next command run on a slot this node is importing.
+fn parse_asking(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Asking, array, 0)?;
+    Ok(Command::new(Action::Asking, vec![], None))
+}
+
+fn parse_lastsave(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::LastSave, array, 0)?;
+    Ok(Command::new(Action::LastSave, vec![], Some(Lock::Read)))
+}
+
+fn parse_incr(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Incr, array, 1)?;
+    let key = next_arg(array.iter().skip(1), Action::Incr)?;
+    Ok(Command::new(Action::Incr, vec![key], Some(Lock::Write)))
+}
+
+fn parse_decr(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Decr, array, 1)?;
+    let key = next_arg(array.iter().skip(1), Action::Decr)?;
+    Ok(Command::new(Action::Decr, vec![key], Some(Lock::Write)))
+}
+
+fn parse_incrby(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let cmd = parse_key_and_arg(array, Action::IncrBy, Lock::Write)?;
+    expect_int(&cmd.args()[1], Action::IncrBy)?;
+    Ok(cmd)
+}
+
+fn parse_decrby(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let cmd = parse_key_and_arg(array, Action::DecrBy, Lock::Write)?;
+    expect_int(&cmd.args()[1], Action::DecrBy)?;
+    Ok(cmd)
+}
+
+fn parse_incrbyfloat(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let cmd = parse_key_and_arg(array, Action::IncrByFloat, Lock::Write)?;
+    expect_float(&cmd.args()[1], Action::IncrByFloat)?;
+    Ok(cmd)
+}
+
+fn parse_expire(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_expire_family(array, Action::Expire, 1000)
+}
+
+fn parse_pexpire(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_expire_family(array, Action::PExpire, 1)
+}
+
+fn parse_expireat(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_expire_family(array, Action::ExpireAt, 1000)
+}
+
+fn parse_pexpireat(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_expire_family(array, Action::PExpireAt, 1)
+}
+
+/// Parses a key, a time in units of `unit_ms` milliseconds and the NX, XX, GT and LT
+/// conditions shared by the EXPIRE family.
+fn parse_expire_family(
+    array: &[resp::Value],
+    action: Action,
+    unit_ms: i64,
+) -> Result<Command, ParseCommandError> {
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let time = next_arg(&mut iter, action)?;
+    expect_int(&time, action)?
+        .checked_mul(unit_ms)
+        .ok_or_else(|| ParseCommandError::new(ParseCommandErrorKind::InvalidTtl, Some(action)))?;
+
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        let keyword = next_keyword(&mut iter, action)?;
+        options.insert(match keyword.as_str() {
+            "nx" => CommandOpt::ExpireNx,
+            "xx" => CommandOpt::ExpireXx,
+            "gt" => CommandOpt::ExpireGt,
+            "lt" => CommandOpt::ExpireLt,
+            _ => {
+                return Err(invalid_options(
+                    action,
+                    &format!("Unsupported option {}", keyword),
+                ))
+            }
+        });
+    }
+    if options.contains(&CommandOpt::ExpireNx) && options.len() > 1 {
+        return Err(invalid_options(
+            action,
+            "NX and XX, GT or LT options at the same time are not compatible",
+        ));
+    }
+    if options.contains(&CommandOpt::ExpireGt) && options.contains(&CommandOpt::ExpireLt) {
+        return Err(invalid_options(
+            action,
+            "GT and LT options at the same time are not compatible",
+        ));
+    }
+
+    let mut cmd = Command::new(action, vec![key, time], Some(Lock::Write));
+    cmd.set_options(options);
+    Ok(cmd)
+}
+
+fn parse_ttl(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Ttl, array, 1)?;
+    let key = next_arg(array.iter().skip(1), Action::Ttl)?;
+    Ok(Command::new(Action::Ttl, vec![key], Some(Lock::Read)))
+}
+
+fn parse_pttl(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::PTtl)
+}
+
+fn parse_expiretime(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::ExpireTime)
+}
+
+fn parse_pexpiretime(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::PExpireTime)
+}
+
+fn parse_persist(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Persist, array, 1)?;
+    let key = next_arg(array.iter().skip(1), Action::Persist)?;
+    Ok(Command::new(Action::Persist, vec![key], Some(Lock::Write)))
+}
+
+fn parse_multi(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Multi, array, 0)?;
+    Ok(Command::new(Action::Multi, vec![], None))
+}
+
+fn parse_exec(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Exec, array, 0)?;
+    Ok(Command::new(Action::Exec, vec![], None))
+}
+
+fn parse_begin(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Begin, array, 0)?;
+    Ok(Command::new(Action::Begin, vec![], None))
+}
+
+fn parse_commit(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Commit, array, 0)?;
+    Ok(Command::new(Action::Commit, vec![], None))
+}
+
+fn parse_rollback(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Rollback, array, 0)?;
+    Ok(Command::new(Action::Rollback, vec![], None))
+}
+
+fn parse_discard(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Discard, array, 0)?;
+    Ok(Command::new(Action::Discard, vec![], None))
+}
+
+fn parse_watch(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let mut keys = vec![];
+    for key in array.iter().skip(1) {
+        keys.push(key.as_bytes().map(|key| key.to_vec()).map_err(|_| {
+            ParseCommandError::new(ParseCommandErrorKind::InvalidArgs, Some(Action::Watch))
+        })?);
+    }
+    Ok(Command::new(Action::Watch, keys, None))
+}
+
+fn parse_unwatch(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    expect_max_args(Action::Unwatch, array, 0)?;
+    Ok(Command::new(Action::Unwatch, vec![], None))
+}
+
+/// Parses `MEMORY USAGE key [SAMPLES count]`. A count of 0 measures every element.
+fn parse_memory_usage(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::MemoryUsage;
+    let mut iter = array.iter().skip(2);
+    let key = next_arg(&mut iter, action)?;
+    let syntax_error = || ParseCommandError::new(ParseCommandErrorKind::SyntaxError, Some(action));
+    let mut options = HashSet::new();
+    while iter.len() > 0 {
+        let keyword = next_keyword(&mut iter, action)?;
+        let value = next_arg(&mut iter, action).map_err(|_| syntax_error())?;
+        match keyword.as_str() {
+            "samples" => {
+                let samples = expect_uint(&value, action)?;
+                options.insert(CommandOpt::MemorySamples(samples as usize));
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    let mut cmd = Command::new(action, vec![key], Some(Lock::Read));
+    cmd.set_options(options);
+    Ok(cmd)
+}
+
+fn parse_push(array: &[resp::Value], action: Action) -> Result<Command, ParseCommandError> {
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let first = next_arg(&mut iter, action)?;
+    let mut args = vec![key, first];
+    args.append(&mut rest_args(iter)?);
+    Ok(Command::new(action, args, Some(Lock::Write)))
+}
+
+fn parse_lpush(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_push(array, Action::LPush)
+}
+
+fn parse_rpush(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_push(array, Action::RPush)
+}
+
+fn parse_pop(array: &[resp::Value], action: Action) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let mut args = vec![key];
+    if let Some(count) = iter.next() {
+        let count = count.as_bytes()?.to_vec();
+        if expect_int(&count, action)? < 0 {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::OutOfRange,
+                Some(action),
+            ));
+        }
+        args.push(count);
+    }
+    Ok(Command::new(action, args, Some(Lock::Write)))
+}
+
+fn parse_lpop(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_pop(array, Action::LPop)
+}
+
+fn parse_rpop(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_pop(array, Action::RPop)
+}
+
+fn parse_lrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LRange;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let start = next_arg(&mut iter, action)?;
+    let stop = next_arg(&mut iter, action)?;
+    expect_int(&start, action)?;
+    expect_int(&stop, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, start, stop],
+        Some(Lock::Read),
+    ))
+}
+
+fn parse_lindex(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LIndex;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let index = next_arg(&mut iter, action)?;
+    expect_int(&index, action)?;
+    Ok(Command::new(action, vec![key, index], Some(Lock::Read)))
+}
+
+fn parse_lset(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LSet;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let index = next_arg(&mut iter, action)?;
+    expect_int(&index, action)?;
+    let val = next_arg(&mut iter, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, index, val],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_lrem(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LRem;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let count = next_arg(&mut iter, action)?;
+    expect_int(&count, action)?;
+    let val = next_arg(&mut iter, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, count, val],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_ltrim(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LTrim;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let start = next_arg(&mut iter, action)?;
+    let stop = next_arg(&mut iter, action)?;
+    expect_int(&start, action)?;
+    expect_int(&stop, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, start, stop],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_llen(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::LLen)
+}
+
+fn parse_linsert(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::LInsert;
+    expect_max_args(action, array, 4)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let opt = match next_keyword(&mut iter, action)?.as_str() {
+        "before" => CommandOpt::LInsertBefore,
+        "after" => CommandOpt::LInsertAfter,
+        _ => {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(action),
+            ))
+        }
+    };
+    let pivot = next_arg(&mut iter, action)?;
+    let val = next_arg(&mut iter, action)?;
+
+    let mut options = HashSet::new();
+    options.insert(opt);
+    let mut cmd = Command::new(action, vec![key, pivot, val], Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_hset(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::HSet;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let field = next_arg(&mut iter, action)?;
+    let val = next_arg(&mut iter, action)?;
+    let mut args = vec![key, field, val];
+    args.append(&mut rest_args(iter)?);
+    if args.len() % 2 == 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+    Ok(Command::new(action, args, Some(Lock::Write)))
+}
+
+fn parse_hsetnx(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::HSetNx;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let field = next_arg(&mut iter, action)?;
+    let val = next_arg(&mut iter, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, field, val],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_key_and_arg(
+    array: &[resp::Value],
+    action: Action,
+    lock: Lock,
+) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let arg = next_arg(&mut iter, action)?;
+    Ok(Command::new(action, vec![key, arg], Some(lock)))
+}
+
+fn parse_hget(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::HGet, Lock::Read)
+}
+
+fn parse_key_and_fields(
+    array: &[resp::Value],
+    action: Action,
+    lock: Lock,
+) -> Result<Command, ParseCommandError> {
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let field = next_arg(&mut iter, action)?;
+    let mut args = vec![key, field];
+    args.append(&mut rest_args(iter)?);
+    Ok(Command::new(action, args, Some(lock)))
+}
+
+fn parse_hmget(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::HMGet, Lock::Read)
+}
+
+fn parse_hdel(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::HDel, Lock::Write)
+}
+
+fn parse_key_only(array: &[resp::Value], action: Action) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 1)?;
+    let key = next_arg(array.iter().skip(1), action)?;
+    Ok(Command::new(action, vec![key], Some(Lock::Read)))
+}
+
+fn parse_hgetall(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::HGetAll)
+}
+
+fn parse_hkeys(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::HKeys)
+}
+
+fn parse_hvals(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::HVals)
+}
+
+fn parse_hlen(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::HLen)
+}
+
+fn parse_hexists(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::HExists, Lock::Read)
+}
+
+fn parse_hincrby(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::HIncrBy;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let field = next_arg(&mut iter, action)?;
+    let increment = next_arg(&mut iter, action)?;
+    expect_int(&increment, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, field, increment],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_sadd(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::SAdd, Lock::Write)
+}
+
+fn parse_srem(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::SRem, Lock::Write)
+}
+
+fn parse_smembers(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::SMembers)
+}
+
+fn parse_sismember(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::SIsMember, Lock::Read)
+}
+
+fn parse_scard(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::SCard)
+}
+
+/// Rejects a SPOP or SRANDMEMBER count beyond what Redis takes, half of i64's range, as
+/// the reply for one could never be built.
+fn expect_random_count(count: i64, action: Action) -> Result<(), ParseCommandError> {
+    if count.unsigned_abs() > (i64::MAX / 2) as u64 {
+        return Err(invalid_options(action, "value is out of range"));
+    }
+    Ok(())
+}
+
+fn parse_spop(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let cmd = parse_pop(array, Action::SPop)?;
+    if let Some(count) = cmd.args().get(1) {
+        expect_random_count(expect_int(count, Action::SPop)?, Action::SPop)?;
+    }
+    Ok(cmd)
+}
+
+fn parse_srandmember(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::SRandMember;
+    expect_max_args(action, array, 2)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let mut args = vec![key];
+    if let Some(count) = iter.next() {
+        let count = count.as_bytes()?.to_vec();
+        expect_random_count(expect_int(&count, action)?, action)?;
+        args.push(count);
+    }
+    Ok(Command::new(action, args, Some(Lock::Read)))
+}
+
+fn parse_key_list(
+    array: &[resp::Value],
+    action: Action,
+    lock: Lock,
+) -> Result<Command, ParseCommandError> {
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let mut args = vec![key];
+    args.append(&mut rest_args(iter)?);
+    Ok(Command::new(action, args, Some(lock)))
+}
+
+fn parse_sinter(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::SInter, Lock::Read)
+}
+
+fn parse_sunion(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::SUnion, Lock::Read)
+}
+
+fn parse_sdiff(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_list(array, Action::SDiff, Lock::Read)
+}
+
+fn parse_sinterstore(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::SInterStore, Lock::Write)
+}
+
+fn parse_sunionstore(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::SUnionStore, Lock::Write)
+}
+
+fn parse_sdiffstore(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::SDiffStore, Lock::Write)
+}
+
+fn parse_zadd(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    use CommandOpt::*;
+    let action = Action::ZAdd;
+    let mut iter = array.iter().skip(1).peekable();
+    let key = next_arg(&mut iter, action)?;
+
+    let mut options = HashSet::new();
+    while let Some(next) = iter.peek() {
+        let opt = match next.to_string()?.to_lowercase().as_str() {
+            "nx" => ZAddNx,
+            "xx" => ZAddXx,
+            "gt" => ZAddGt,
+            "lt" => ZAddLt,
+            "ch" => ZAddCh,
+            "incr" => ZAddIncr,
+            _ => break,
+        };
+        options.insert(opt);
+        iter.next();
+    }
+
+    let mut args = vec![key];
+    args.append(&mut rest_args(iter)?);
+    if args.len() < 3 || args.len() % 2 == 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+    if options.contains(&ZAddNx) && options.contains(&ZAddXx) {
+        return Err(invalid_options(
+            action,
+            "XX and NX options at the same time are not compatible",
+        ));
+    }
+    let exclusive = [ZAddNx, ZAddGt, ZAddLt];
+    if exclusive.iter().filter(|opt| options.contains(opt)).count() > 1 {
+        return Err(invalid_options(
+            action,
+            "GT, LT, and/or NX options at the same time are not compatible",
+        ));
+    }
+    if options.contains(&ZAddIncr) && args.len() > 3 {
+        return Err(invalid_options(
+            action,
+            "INCR option supports a single increment-element pair",
+        ));
+    }
+    for score in args.iter().skip(1).step_by(2) {
+        expect_float(score, action)?;
+    }
+
+    let mut cmd = Command::new(action, args, Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_zrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    use CommandOpt::*;
+    let action = Action::ZRange;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let start = next_arg(&mut iter, action)?;
+    let stop = next_arg(&mut iter, action)?;
+
+    let mut options = HashSet::new();
+    while let Some(next) = iter.next() {
+        let opt = match next.to_string()?.to_lowercase().as_str() {
+            "byscore" => ZRangeByScore,
+            "bylex" => ZRangeByLex,
+            "rev" => ZRangeRev,
+            "withscores" => ZRangeWithScores,
+            "limit" => {
+                let offset = expect_int(&next_arg(&mut iter, action)?, action)?;
+                let count = expect_int(&next_arg(&mut iter, action)?, action)?;
+                ZRangeLimit(offset, count)
+            }
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        options.insert(opt);
+    }
+
+    let by_score = options.contains(&ZRangeByScore);
+    let by_lex = options.contains(&ZRangeByLex);
+    let has_limit = options.iter().any(|opt| matches!(opt, ZRangeLimit(_, _)));
+    if by_score && by_lex {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+    if has_limit && !by_score && !by_lex {
+        return Err(invalid_options(
+            action,
+            "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if by_lex && options.contains(&ZRangeWithScores) {
+        return Err(invalid_options(
+            action,
+            "syntax error, WITHSCORES not supported in combination with BYLEX",
+        ));
+    }
+
+    if by_score {
+        if ScoreBound::parse(&start).is_none() || ScoreBound::parse(&stop).is_none() {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::MinMaxNotFloat,
+                Some(action),
+            ));
+        }
+    } else if by_lex {
+        if LexBound::parse(&start).is_none() || LexBound::parse(&stop).is_none() {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::MinMaxNotString,
+                Some(action),
+            ));
+        }
+    } else {
+        expect_int(&start, action)?;
+        expect_int(&stop, action)?;
+    }
+
+    let mut cmd = Command::new(action, vec![key, start, stop], Some(Lock::Read));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_zrank(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::ZRank, Lock::Read)
+}
+
+fn parse_zscore(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_arg(array, Action::ZScore, Lock::Read)
+}
+
+fn parse_zrem(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_and_fields(array, Action::ZRem, Lock::Write)
+}
+
+fn parse_zincrby(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::ZIncrBy;
+    expect_max_args(action, array, 3)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let increment = next_arg(&mut iter, action)?;
+    expect_float(&increment, action)?;
+    let member = next_arg(&mut iter, action)?;
+    Ok(Command::new(
+        action,
+        vec![key, increment, member],
+        Some(Lock::Write),
+    ))
+}
+
+fn parse_zpopmin(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_pop(array, Action::ZPopMin)
+}
+
+fn parse_zpopmax(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_pop(array, Action::ZPopMax)
+}
+
+/// Parses the `[=|~] threshold [LIMIT count]` remainder of a `MAXLEN` or `MINID` trimming
+/// clause, shared by XADD and XTRIM.
+fn parse_trim<'a, I>(
+    strategy: &str,
+    iter: &mut Peekable<I>,
+    action: Action,
+    options: &mut HashSet<CommandOpt>,
+) -> Result<(), ParseCommandError>
+where
+    I: Iterator<Item = &'a resp::Value>,
+{
+    let mut threshold = next_arg(&mut *iter, action)?;
+    let approx = threshold == b"~";
+    if approx || threshold == b"=" {
+        threshold = next_arg(&mut *iter, action)?;
+    }
+    let strategy = if strategy == "maxlen" {
+        TrimStrategy::MaxLen(expect_uint(&threshold, action)? as usize)
+    } else {
+        TrimStrategy::MinId(expect_stream_id(&threshold, action, StreamId::parse)?)
+    };
+    options.insert(CommandOpt::XTrim(strategy));
+
+    if let Some(next) = iter.peek() {
+        if next.to_string()?.eq_ignore_ascii_case("limit") {
+            iter.next();
+            let limit = expect_uint(&next_arg(&mut *iter, action)?, action)?;
+            if !approx {
+                return Err(invalid_options(
+                    action,
+                    "syntax error, LIMIT cannot be used without the special ~ option",
+                ));
+            }
+            options.insert(CommandOpt::XTrimLimit(limit as usize));
+        }
+    }
+    Ok(())
+}
+
+fn parse_xadd(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XAdd;
+    let mut iter = array.iter().skip(1).peekable();
+    let key = next_arg(&mut iter, action)?;
+
+    let mut options = HashSet::new();
+    let id = loop {
+        let arg = next_arg(&mut iter, action)?;
+        match String::from_utf8_lossy(&arg).to_lowercase().as_str() {
+            "nomkstream" => {
+                options.insert(CommandOpt::XAddNoMkStream);
+            }
+            strategy @ ("maxlen" | "minid") => {
+                parse_trim(strategy, &mut iter, action, &mut options)?
+            }
+            _ => break arg,
+        }
+    };
+    if XAddId::parse(&id).is_none() {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::InvalidStreamId,
+            Some(action),
+        ));
+    }
+
+    let mut args = vec![key, id];
+    args.append(&mut rest_args(iter)?);
+    if args.len() < 4 || args.len() % 2 != 0 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+
+    let mut cmd = Command::new(action, args, Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xrange_cmd(array: &[resp::Value], action: Action) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 5)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let first = next_arg(&mut iter, action)?;
+    let second = next_arg(&mut iter, action)?;
+    if action == Action::XRange {
+        expect_stream_id(&first, action, StreamId::parse_start)?;
+        expect_stream_id(&second, action, StreamId::parse_end)?;
+    } else {
+        expect_stream_id(&first, action, StreamId::parse_end)?;
+        expect_stream_id(&second, action, StreamId::parse_start)?;
+    }
+
+    let mut options = HashSet::new();
+    if let Some(next) = iter.next() {
+        if !next.to_string()?.eq_ignore_ascii_case("count") {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(action),
+            ));
+        }
+        let count = expect_uint(&next_arg(&mut iter, action)?, action)?;
+        options.insert(CommandOpt::XCount(count as usize));
+    }
+
+    let mut cmd = Command::new(action, vec![key, first, second], Some(Lock::Read));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_xrange_cmd(array, Action::XRange)
+}
+
+fn parse_xrevrange(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_xrange_cmd(array, Action::XRevRange)
+}
+
+fn parse_xlen(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_key_only(array, Action::XLen)
+}
+
+fn parse_xtrim(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XTrim;
+    let mut iter = array.iter().skip(1).peekable();
+    let key = next_arg(&mut iter, action)?;
+    let strategy = next_keyword(&mut iter, action)?;
+    if strategy != "maxlen" && strategy != "minid" {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+
+    let mut options = HashSet::new();
+    parse_trim(&strategy, &mut iter, action, &mut options)?;
+    if iter.next().is_some() {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+
+    let mut cmd = Command::new(action, vec![key], Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+/// Parses the `key [key ...] id [id ...]` list following `STREAMS`, where `special` is the
+/// ID placeholder the command accepts besides explicit IDs.
+fn parse_streams<'a, I>(
+    iter: I,
+    action: Action,
+    special: &str,
+) -> Result<Vec<Vec<u8>>, ParseCommandError>
+where
+    I: Iterator<Item = &'a resp::Value>,
+{
+    let args = rest_args(iter)?;
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(invalid_options(
+            action,
+            &format!(
+                "Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+                action, special
+            ),
+        ));
+    }
+    for id in args.iter().skip(args.len() / 2) {
+        if id != special.as_bytes() {
+            expect_stream_id(id, action, StreamId::parse)?;
+        }
+    }
+    Ok(args)
+}
+
+fn parse_xread(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XRead;
+    let mut iter = array.iter().skip(1);
+
+    let mut options = HashSet::new();
+    loop {
+        let opt = match next_keyword(&mut iter, action)?.as_str() {
+            "count" => {
+                CommandOpt::XCount(expect_uint(&next_arg(&mut iter, action)?, action)? as usize)
+            }
+            "block" => CommandOpt::XBlock(expect_uint(&next_arg(&mut iter, action)?, action)?),
+            "streams" => break,
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        options.insert(opt);
+    }
+
+    let mut cmd = Command::new(action, parse_streams(iter, action, "$")?, Some(Lock::Read));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xreadgroup(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XReadGroup;
+    let mut iter = array.iter().skip(1);
+    if next_keyword(&mut iter, action)? != "group" {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+    let group = next_arg(&mut iter, action)?;
+    let consumer = next_arg(&mut iter, action)?;
+
+    let mut options = HashSet::new();
+    loop {
+        let opt = match next_keyword(&mut iter, action)?.as_str() {
+            "count" => {
+                CommandOpt::XCount(expect_uint(&next_arg(&mut iter, action)?, action)? as usize)
+            }
+            "block" => CommandOpt::XBlock(expect_uint(&next_arg(&mut iter, action)?, action)?),
+            "noack" => CommandOpt::XReadNoAck,
+            "streams" => break,
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        options.insert(opt);
+    }
+
+    let mut args = vec![group, consumer];
+    args.append(&mut parse_streams(iter, action, ">")?);
+    let mut cmd = Command::new(action, args, Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xgroup_create(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XGroupCreate;
+    expect_max_args(action, array, 5)?;
+    let mut iter = array.iter().skip(2);
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let id = next_arg(&mut iter, action)?;
+    if id != b"$" {
+        expect_stream_id(&id, action, StreamId::parse)?;
+    }
+
+    let mut options = HashSet::new();
+    if let Some(next) = iter.next() {
+        if !next.to_string()?.eq_ignore_ascii_case("mkstream") {
+            return Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(action),
+            ));
+        }
+        options.insert(CommandOpt::XGroupMkStream);
+    }
+
+    let mut cmd = Command::new(action, vec![key, group, id], Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xgroup_setid(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XGroupSetId;
+    expect_max_args(action, array, 4)?;
+    let mut iter = array.iter().skip(2);
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let id = next_arg(&mut iter, action)?;
+    if id != b"$" {
+        expect_stream_id(&id, action, StreamId::parse)?;
+    }
+    Ok(Command::new(
+        action,
+        vec![key, group, id],
+        Some(Lock::Write),
+    ))
+}
+
+/// Parses the XGROUP subcommands taking a key, a group and `extra` further arguments.
+fn parse_xgroup_args(
+    array: &[resp::Value],
+    action: Action,
+    extra: usize,
+) -> Result<Command, ParseCommandError> {
+    expect_max_args(action, array, 3 + extra)?;
+    let mut iter = array.iter().skip(2);
+    let mut args = vec![];
+    for _ in 0..2 + extra {
+        args.push(next_arg(&mut iter, action)?);
+    }
+    Ok(Command::new(action, args, Some(Lock::Write)))
+}
+
+fn parse_xgroup_destroy(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_xgroup_args(array, Action::XGroupDestroy, 0)
+}
+
+fn parse_xgroup_createconsumer(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_xgroup_args(array, Action::XGroupCreateConsumer, 1)
+}
+
+fn parse_xgroup_delconsumer(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    parse_xgroup_args(array, Action::XGroupDelConsumer, 1)
+}
+
+fn parse_xack(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XAck;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let ids = rest_args(iter)?;
+    if ids.is_empty() {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+    for id in ids.iter() {
+        expect_stream_id(id, action, StreamId::parse)?;
+    }
+
+    let mut args = vec![key, group];
+    args.extend(ids);
+    Ok(Command::new(action, args, Some(Lock::Write)))
+}
+
+fn parse_xpending(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XPending;
+    let mut iter = array.iter().skip(1).peekable();
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let mut args = vec![key, group];
+    let mut options = HashSet::new();
+    if iter.peek().is_none() {
+        return Ok(Command::new(action, args, Some(Lock::Read)));
+    }
+
+    let mut start = next_arg(&mut iter, action)?;
+    if start.eq_ignore_ascii_case(b"idle") {
+        let min_idle = expect_uint(&next_arg(&mut iter, action)?, action)?;
+        options.insert(CommandOpt::XPendingIdle(min_idle));
+        start = next_arg(&mut iter, action)?;
+    }
+    let end = next_arg(&mut iter, action)?;
+    let count = next_arg(&mut iter, action)?;
+    expect_stream_id(&start, action, StreamId::parse_start)?;
+    expect_stream_id(&end, action, StreamId::parse_end)?;
+    expect_int(&count, action)?;
+    args.extend(vec![start, end, count]);
+    if let Some(consumer) = iter.next() {
+        args.push(consumer.as_bytes()?.to_vec());
+    }
+    if iter.next().is_some() {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::SyntaxError,
+            Some(action),
+        ));
+    }
+
+    let mut cmd = Command::new(action, args, Some(Lock::Read));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xclaim(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    use CommandOpt::*;
+    let action = Action::XClaim;
+    let mut iter = array.iter().skip(1).peekable();
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let consumer = next_arg(&mut iter, action)?;
+    let min_idle = next_arg(&mut iter, action)?;
+    expect_uint(&min_idle, action)?;
+
+    let mut args = vec![key, group, consumer, min_idle];
+    while let Some(next) = iter.peek() {
+        let id = next.as_bytes()?.to_vec();
+        if StreamId::parse(&id).is_none() {
+            break;
+        }
+        args.push(id);
+        iter.next();
+    }
+    if args.len() == 4 {
+        return Err(ParseCommandError::new(
+            ParseCommandErrorKind::WrongNumberArgs,
+            Some(action),
+        ));
+    }
+
+    let mut options = HashSet::new();
+    while let Some(next) = iter.next() {
+        let opt = match next.to_string()?.to_lowercase().as_str() {
+            "idle" => XClaimIdle(expect_uint(&next_arg(&mut iter, action)?, action)?),
+            "time" => XClaimTime(expect_uint(&next_arg(&mut iter, action)?, action)?),
+            "retrycount" => XClaimRetryCount(expect_uint(&next_arg(&mut iter, action)?, action)?),
+            "force" => XClaimForce,
+            "justid" => XClaimJustId,
+            "lastid" => XClaimLastId(expect_stream_id(
+                &next_arg(&mut iter, action)?,
+                action,
+                StreamId::parse,
+            )?),
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        options.insert(opt);
+    }
+
+    let mut cmd = Command::new(action, args, Some(Lock::Write));
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+fn parse_xautoclaim(array: &[resp::Value]) -> Result<Command, ParseCommandError> {
+    let action = Action::XAutoClaim;
+    expect_max_args(action, array, 8)?;
+    let mut iter = array.iter().skip(1);
+    let key = next_arg(&mut iter, action)?;
+    let group = next_arg(&mut iter, action)?;
+    let consumer = next_arg(&mut iter, action)?;
+    let min_idle = next_arg(&mut iter, action)?;
+    expect_uint(&min_idle, action)?;
+    let start = next_arg(&mut iter, action)?;
+    expect_stream_id(&start, action, StreamId::parse_start)?;
+
+    let mut options = HashSet::new();
+    while let Some(next) = iter.next() {
+        let opt = match next.to_string()?.to_lowercase().as_str() {
+            "count" => {
+                let count = expect_uint(&next_arg(&mut iter, action)?, action)?;
+                if count == 0 {
+                    return Err(invalid_options(action, "COUNT must be > 0"));
+                }
+                CommandOpt::XCount(count as usize)
+            }
+            "justid" => CommandOpt::XClaimJustId,
+            _ => {
+                return Err(ParseCommandError::new(
+                    ParseCommandErrorKind::SyntaxError,
+                    Some(action),
+                ))
+            }
+        };
+        options.insert(opt);
+    }
+
+    let mut cmd = Command::new(
+        action,
+        vec![key, group, consumer, min_idle, start],
+        Some(Lock::Write),
+    );
+    cmd.set_options(options);
+
+    Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ping() {
+        assert_eq!(
+            Ok(Command::new(Action::Ping, vec![], None)),
+            parse_ping(&cmd!["PING"])
+        );
+        assert_eq!(
+            Ok(Command::new(Action::Ping, vec![b"hello".to_vec()], None)),
+            parse_ping(&cmd!["PING", "hello"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::Ping)
+            )),
+            parse_ping(&cmd!["PING", "foo", "bar"])
+        );
+    }
+
+    #[test]
+    fn test_parse_echo() {
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::Echo)
+            )),
+            parse_echo(&cmd!["ECHO"])
+        );
+        assert_eq!(
+            Ok(Command::new(Action::Echo, vec![b"hello".to_vec()], None)),
+            parse_echo(&cmd!["ECHO", "hello"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::Echo)
+            )),
+            parse_echo(&cmd!["ECHO", "foo", "bar"])
+        );
+    }
+
+    #[test]
+    fn test_parse_set() {
+        use ParseCommandErrorKind::*;
+        assert_eq!(
+            Ok(Command::new(
+                Action::Set,
+                vec![b"foo".to_vec(), b"bar".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_set(&cmd!["SET", "foo", "bar"])
+        );
+
+        let cmd_with_opts = parse_set(&cmd!["SET", "foo", "bar", "EX", "60", "NX"]).unwrap();
+        assert_eq!(vec![b"foo".to_vec(), b"bar".to_vec(),], cmd_with_opts.args);
+        assert!(cmd_with_opts.opts().contains(&CommandOpt::SetNx));
+        assert!(cmd_with_opts.opts().contains(&CommandOpt::SetEx(60)));
+
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
+            parse_set(&cmd!["SET", "foo", "bar", "EX"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidTtl, Some(Action::Set))),
+            parse_set(&cmd!["SET", "foo", "bar", "EX", "-1"])
+        );
+        for opt in ["EX", "PX", "EXAT", "PXAT"] {
+            assert_eq!(
+                Err(ParseCommandError::new(InvalidTtl, Some(Action::Set))),
+                parse_set(&cmd!["SET", "foo", "bar", opt, "0"])
+            );
+        }
+        assert_eq!(
+            "ERR invalid expire time in 'set' command",
+            ParseCommandError::new(InvalidTtl, Some(Action::Set)).to_string()
+        );
+        let cmd_with_at = parse_set(&cmd!["SET", "foo", "bar", "PXAT", "1700000000000"]).unwrap();
+        assert!(cmd_with_at
+            .opts()
+            .contains(&CommandOpt::SetPxAt(1_700_000_000_000)));
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
+            parse_set(&cmd!["SET", "foo", "bar", "NX", "XX"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::Set))),
+            parse_set(&cmd!["SET", "foo", "bar", "EX", "20", "KEEPTTL"])
+        );
+    }
+
+    #[test]
+    fn test_parse_get() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::Get,
+                vec![b"foo".to_vec()],
+                Some(Lock::Read)
+            )),
+            parse_get(&cmd!["GET", "foo"])
+        );
+    }
+
+    #[test]
+    fn test_parse_expire() {
+        use ParseCommandErrorKind::*;
+
+        assert_eq!(
+            Ok(Command::new(
+                Action::Expire,
+                vec![b"foo".to_vec(), b"5".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_expire(&cmd!["EXPIRE", "foo", "5"])
+        );
+        assert_eq!(
+            Action::PExpire,
+            *parse_pexpire(&cmd!["PEXPIRE", "foo", "5"])
+                .unwrap()
+                .action()
+        );
+        assert!(parse_expireat(&cmd!["EXPIREAT", "foo", "1700000000", "GT"])
+            .unwrap()
+            .opts()
+            .contains(&CommandOpt::ExpireGt));
+        assert_eq!(
+            Err(ParseCommandError::new(NotAnInteger, Some(Action::Expire))),
+            parse_expire(&cmd!["EXPIRE", "foo", "soon"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidTtl, Some(Action::Expire))),
+            parse_expire(&cmd!["EXPIRE", "foo", "9223372036854775807"])
+        );
+        assert!(parse_expire(&cmd!["EXPIRE", "foo", "5", "NX", "XX"]).is_err());
+        assert!(parse_pexpireat(&cmd!["PEXPIREAT", "foo", "5", "GT", "LT"]).is_err());
+        assert!(parse_expire(&cmd!["EXPIRE", "foo", "5", "SOON"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::Ttl,
+                vec![b"foo".to_vec()],
+                Some(Lock::Read)
+            )),
+            parse_ttl(&cmd!["TTL", "foo"])
+        );
+    }
+
+    #[test]
+    fn test_setex() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::SetEx,
+                vec![b"foo".to_vec(), b"10".to_vec(), b"bar".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_setex(&cmd!["SETEX", "foo", "10", "bar"])
+        );
+    }
+
+    #[test]
     fn test_watch() {
         assert_eq!(
             Ok(Command::new(
-                Action::Watch,
-                vec!["foo".to_owned(), "bar".to_owned(), "mykey".to_owned()],
-                None
+                Action::Watch,
+                vec![b"foo".to_vec(), b"bar".to_vec(), b"mykey".to_vec()],
+                None
+            )),
+            parse_watch(&cmd!["WATCH", "foo", "bar", "mykey"])
+        );
+    }
+
+    #[test]
+    fn test_parse_lpush() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::LPush,
+                vec![b"list".to_vec(), b"a".to_vec(), b"b".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_lpush(&cmd!["LPUSH", "list", "a", "b"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::LPush)
+            )),
+            parse_lpush(&cmd!["LPUSH", "list"])
+        );
+    }
+
+    #[test]
+    fn test_parse_lpop() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::LPop,
+                vec![b"list".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_lpop(&cmd!["LPOP", "list"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::OutOfRange,
+                Some(Action::RPop)
+            )),
+            parse_rpop(&cmd!["RPOP", "list", "-1"])
+        );
+    }
+
+    #[test]
+    fn test_parse_lrange() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::LRange,
+                vec![b"list".to_vec(), b"0".to_vec(), b"-1".to_vec()],
+                Some(Lock::Read)
+            )),
+            parse_lrange(&cmd!["LRANGE", "list", "0", "-1"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::NotAnInteger,
+                Some(Action::LRange)
+            )),
+            parse_lrange(&cmd!["LRANGE", "list", "0", "end"])
+        );
+    }
+
+    #[test]
+    fn test_parse_linsert() {
+        let cmd = parse_linsert(&cmd!["LINSERT", "list", "BEFORE", "b", "a"]).unwrap();
+        assert_eq!(
+            vec![b"list".to_vec(), b"b".to_vec(), b"a".to_vec()],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::LInsertBefore));
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(Action::LInsert)
+            )),
+            parse_linsert(&cmd!["LINSERT", "list", "BESIDE", "b", "a"])
+        );
+    }
+
+    #[test]
+    fn test_parse_hset() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::HSet,
+                vec![
+                    b"h".to_vec(),
+                    b"a".to_vec(),
+                    b"1".to_vec(),
+                    b"b".to_vec(),
+                    b"2".to_vec()
+                ],
+                Some(Lock::Write)
+            )),
+            parse_hset(&cmd!["HSET", "h", "a", "1", "b", "2"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::HSet)
+            )),
+            parse_hset(&cmd!["HSET", "h", "a", "1", "b"])
+        );
+    }
+
+    #[test]
+    fn test_parse_hmget() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::HMGet,
+                vec![b"h".to_vec(), b"a".to_vec(), b"b".to_vec()],
+                Some(Lock::Read)
+            )),
+            parse_hmget(&cmd!["HMGET", "h", "a", "b"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::HDel)
+            )),
+            parse_hdel(&cmd!["HDEL", "h"])
+        );
+    }
+
+    #[test]
+    fn test_parse_hincrby() {
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::NotAnInteger,
+                Some(Action::HIncrBy)
+            )),
+            parse_hincrby(&cmd!["HINCRBY", "h", "a", "1.5"])
+        );
+    }
+
+    #[test]
+    fn test_parse_incr() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::Incr,
+                vec![b"n".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_incr(&cmd!["INCR", "n"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::NotAnInteger,
+                Some(Action::DecrBy)
+            )),
+            parse_decrby(&cmd!["DECRBY", "n", "1.5"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::NotAFloat,
+                Some(Action::IncrByFloat)
+            )),
+            parse_incrbyfloat(&cmd!["INCRBYFLOAT", "n", "abc"])
+        );
+    }
+
+    #[test]
+    fn test_parse_getex() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_getex(&cmd!["GETEX", "foo", "PX", "500"]).unwrap();
+        assert_eq!(vec![b"foo".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::GetExPx(500)));
+        assert!(parse_getex(&cmd!["GETEX", "foo", "persist"])
+            .unwrap()
+            .opts()
+            .contains(&CommandOpt::GetExPersist));
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::GetEx))),
+            parse_getex(&cmd!["GETEX", "foo", "EX", "10", "PERSIST"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidTtl, Some(Action::GetEx))),
+            parse_getex(&cmd!["GETEX", "foo", "EXAT", "0"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::GetEx))),
+            parse_getex(&cmd!["GETEX", "foo", "EX"])
+        );
+    }
+
+    #[test]
+    fn test_parse_setrange() {
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::OffsetOutOfRange,
+                Some(Action::SetRange)
+            )),
+            parse_setrange(&cmd!["SETRANGE", "foo", "-1", "bar"])
+        );
+    }
+
+    #[test]
+    fn test_parse_mset() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::MSet,
+                vec![b"a".to_vec(), b"1".to_vec(), b"b".to_vec(), b"2".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_mset(&cmd!["MSET", "a", "1", "b", "2"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::MSetNx)
+            )),
+            parse_msetnx(&cmd!["MSETNX", "a", "1", "b"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::MGet)
+            )),
+            parse_mget(&cmd!["MGET"])
+        );
+    }
+
+    #[test]
+    fn test_parse_copy() {
+        let cmd = parse_copy(&cmd!["COPY", "a", "b", "REPLACE"]).unwrap();
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::CopyReplace));
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(Action::Copy)
+            )),
+            parse_copy(&cmd!["COPY", "a", "b", "NOPE"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::Del)
+            )),
+            parse_del(&cmd!["DEL"])
+        );
+    }
+
+    #[test]
+    fn test_parse_dump_restore() {
+        use ParseCommandErrorKind::*;
+        assert_eq!(
+            Some(Lock::Read),
+            parse_dump(&cmd!["DUMP", "k"]).unwrap().lock()
+        );
+        let cmd = parse_restore(&cmd![
+            "RESTORE", "k", "100", "payload", "REPLACE", "ABSTTL", "IDLETIME", "30"
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![b"k".to_vec(), b"100".to_vec(), b"payload".to_vec()],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::RestoreReplace));
+        assert!(cmd.opts().contains(&CommandOpt::RestoreAbsTtl));
+        assert!(cmd.opts().contains(&CommandOpt::RestoreIdleTime(30)));
+        assert_eq!(
+            Err(invalid_options(
+                Action::Restore,
+                "Invalid TTL value, must be >= 0"
+            )),
+            parse_restore(&cmd!["RESTORE", "k", "-1", "payload"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::Restore))),
+            parse_restore(&cmd!["RESTORE", "k", "0", "payload", "FREQ"])
+        );
+        assert!(
+            parse_restore(&cmd!["RESTORE", "k", "0", "payload", "FREQ", "255"])
+                .unwrap()
+                .opts()
+                .contains(&CommandOpt::RestoreFreq(255))
+        );
+        for freq in ["300", "-1"] {
+            assert_eq!(
+                Err(invalid_options(
+                    Action::Restore,
+                    "Invalid FREQ value, must be >= 0 and <= 255"
+                )),
+                parse_restore(&cmd!["RESTORE", "k", "0", "payload", "FREQ", freq])
+            );
+        }
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::Restore))),
+            parse_restore(&cmd![
+                "RESTORE", "k", "0", "payload", "IDLETIME", "1", "FREQ", "5"
+            ])
+        );
+
+        let cmd = parse_migrate(&cmd![
+            "MIGRATE",
+            "127.0.0.1",
+            "6380",
+            "",
+            "2",
+            "500",
+            "COPY",
+            "KEYS",
+            "a",
+            "b"
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                b"127.0.0.1".to_vec(),
+                b"6380".to_vec(),
+                b"2".to_vec(),
+                b"500".to_vec(),
+                b"a".to_vec(),
+                b"b".to_vec()
+            ],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::MigrateCopy));
+        let cmd = parse_migrate(&cmd!["MIGRATE", "h", "1", "k", "0", "0", "REPLACE"]).unwrap();
+        assert_eq!(b"k".to_vec(), cmd.args[4]);
+        assert!(cmd.opts().contains(&CommandOpt::MigrateReplace));
+        assert!(parse_migrate(&cmd!["MIGRATE", "h", "1", "k", "0", "0", "KEYS", "a"]).is_err());
+        assert!(parse_migrate(&cmd!["MIGRATE", "h", "99999", "k", "0", "0"]).is_err());
+        assert_eq!(
+            Err(ParseCommandError::new(
+                WrongNumberArgs,
+                Some(Action::Migrate)
+            )),
+            parse_migrate(&cmd!["MIGRATE", "h", "1", "k", "0"])
+        );
+    }
+
+    #[test]
+    fn test_parse_scan() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_scan(&cmd![
+            "SCAN", "0", "MATCH", "user:*", "COUNT", "100", "TYPE", "hash"
+        ])
+        .unwrap();
+        assert_eq!(vec![b"0".to_vec(), b"user:*".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::ScanMatch));
+        assert!(cmd.opts().contains(&CommandOpt::ScanCount(100)));
+        assert!(cmd.opts().contains(&CommandOpt::ScanType("hash")));
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidCursor, Some(Action::Scan))),
+            parse_scan(&cmd!["SCAN", "-1"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::HScan))),
+            parse_hscan(&cmd!["HSCAN", "h", "0", "TYPE", "hash"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::SScan))),
+            parse_sscan(&cmd!["SSCAN", "s", "0", "COUNT", "0"])
+        );
+    }
+
+    #[test]
+    fn test_parse_save() {
+        use ParseCommandErrorKind::*;
+        assert_eq!(Some(Lock::Write), parse_save(&cmd!["SAVE"]).unwrap().lock());
+        assert_eq!(
+            Some(Lock::Read),
+            parse_bgsave(&cmd!["BGSAVE"]).unwrap().lock()
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                WrongNumberArgs,
+                Some(Action::LastSave)
+            )),
+            parse_lastsave(&cmd!["LASTSAVE", "now"])
+        );
+        assert_eq!(
+            Some(Lock::Read),
+            parse_bgrewriteaof(&cmd!["BGREWRITEAOF"]).unwrap().lock()
+        );
+        assert_eq!(Some(Lock::Read), parse_sync(&cmd!["SYNC"]).unwrap().lock());
+    }
+
+    #[test]
+    fn test_parse_replication() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_replicaof(&cmd!["REPLICAOF", "127.0.0.1", "6379"]).unwrap();
+        assert_eq!(vec![b"127.0.0.1".to_vec(), b"6379".to_vec()], cmd.args);
+        assert_eq!(None, cmd.lock());
+        assert!(parse_replicaof(&cmd!["REPLICAOF", "NO", "ONE"]).is_ok());
+        assert_eq!(
+            Err(ParseCommandError::new(
+                NotAnInteger,
+                Some(Action::ReplicaOf)
+            )),
+            parse_replicaof(&cmd!["REPLICAOF", "host", "port"])
+        );
+        assert!(parse_replicaof(&cmd!["REPLICAOF", "host", "70000"]).is_err());
+        assert!(parse_psync(&cmd!["PSYNC", "?", "-1"]).is_ok());
+        assert_eq!(
+            Err(ParseCommandError::new(WrongNumberArgs, Some(Action::PSync))),
+            parse_psync(&cmd!["PSYNC", "?"])
+        );
+    }
+
+    #[test]
+    fn test_parse_lease() {
+        use ParseCommandErrorKind::*;
+        let token = "00000000000000ff";
+        let cmd = parse_lease(&cmd!["LEASE", "k", "5000", "TOKEN", token]).unwrap();
+        assert_eq!(vec![b"k".to_vec(), b"5000".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::LeaseToken(255)));
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidTtl, Some(Action::Lease))),
+            parse_lease(&cmd!["LEASE", "k", "0"])
+        );
+        assert_eq!(
+            Err(invalid_options(Action::Lease, "Invalid lease token")),
+            parse_lease(&cmd!["LEASE", "k", "5000", "TOKEN", "abc"])
+        );
+        assert!(parse_release(&cmd!["RELEASE", "k", token]).is_ok());
+        assert!(parse_release(&cmd!["RELEASE", "k", "abc"]).is_err());
+
+        let cmd = Command::from_resp(resp::array(
+            cmd!["WITHLEASE", token, "MSET", "a", "1", "b", "2"].to_vec(),
+        ))
+        .unwrap();
+        assert_eq!(Action::MSet, *cmd.action());
+        assert!(cmd.opts().contains(&CommandOpt::LeaseToken(255)));
+        assert_eq!(vec![&b"a"[..], &b"b"[..]], cmd.written_keys());
+        assert_eq!(b"MSET".to_vec(), cmd.argv()[0]);
+        assert_eq!(
+            Err(ParseCommandError::new(
+                WrongNumberArgs,
+                Some(Action::WithLease)
+            )),
+            parse_withlease(&cmd!["WITHLEASE", token])
+        );
+    }
+
+    #[test]
+    fn test_written_keys() {
+        let keys = |args: &[&str]| -> Vec<Vec<u8>> {
+            let array = args.iter().map(resp::bulk_string).collect();
+            let cmd = Command::from_resp(resp::array(array)).unwrap();
+            cmd.written_keys().into_iter().map(<[u8]>::to_vec).collect()
+        };
+        assert_eq!(vec![b"k".to_vec()], keys(&["SET", "k", "v"]));
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec()],
+            keys(&["RENAME", "a", "b"])
+        );
+        assert_eq!(vec![b"b".to_vec()], keys(&["COPY", "a", "b"]));
+        assert_eq!(
+            vec![b"s".to_vec()],
+            keys(&["XGROUP", "CREATE", "s", "g", "$"])
+        );
+        assert_eq!(
+            vec![b"s".to_vec(), b"t".to_vec()],
+            keys(&[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "c",
+                "STREAMS",
+                "s",
+                "t",
+                ">",
+                ">"
+            ])
+        );
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec()],
+            keys(&["MIGRATE", "h", "1", "", "0", "10", "KEYS", "a", "b"])
+        );
+        assert!(keys(&["MIGRATE", "h", "1", "a", "0", "10", "COPY"]).is_empty());
+        assert!(keys(&["FLUSHALL"]).is_empty());
+        assert!(keys(&["GET", "k"]).is_empty());
+    }
+
+    #[test]
+    fn test_other_writes() {
+        let writes = |args: &[&str]| -> Vec<(usize, Option<Vec<u8>>)> {
+            let array = args.iter().map(resp::bulk_string).collect();
+            let cmd = Command::from_resp(resp::array(array)).unwrap();
+            cmd.other_writes(1, 4)
+                .into_iter()
+                .map(|(db, key)| (db, key.map(<[u8]>::to_vec)))
+                .collect()
+        };
+        assert_eq!(vec![(2, Some(b"k".to_vec()))], writes(&["MOVE", "k", "2"]));
+        assert!(writes(&["MOVE", "k", "9"]).is_empty());
+        assert_eq!(vec![(1, None)], writes(&["FLUSHDB"]));
+        assert_eq!(4, writes(&["FLUSHALL", "ASYNC"]).len());
+        assert_eq!(vec![(0, None), (3, None)], writes(&["SWAPDB", "0", "3"]));
+        assert!(writes(&["SET", "k", "v"]).is_empty());
+        assert!(writes(&["GET", "k"]).is_empty());
+    }
+
+    #[test]
+    fn test_keys() {
+        let keys = |args: &[&str]| -> Option<Vec<Vec<u8>>> {
+            let array = args.iter().map(resp::bulk_string).collect();
+            let cmd = Command::from_resp(resp::array(array)).unwrap();
+            cmd.keys()
+                .map(|keys| keys.into_iter().map(<[u8]>::to_vec).collect())
+        };
+        assert_eq!(Some(vec![b"k".to_vec()]), keys(&["GET", "k"]));
+        assert_eq!(
+            Some(vec![b"a".to_vec(), b"b".to_vec()]),
+            keys(&["SINTERSTORE", "a", "b"])
+        );
+        assert_eq!(
+            Some(vec![b"a".to_vec(), b"b".to_vec()]),
+            keys(&["COPY", "a", "b"])
+        );
+        assert_eq!(Some(vec![b"k".to_vec()]), keys(&["MEMORY", "USAGE", "k"]));
+        assert_eq!(
+            Some(vec![b"s".to_vec()]),
+            keys(&["XREAD", "COUNT", "1", "STREAMS", "s", "0"])
+        );
+        assert_eq!(None, keys(&["SCAN", "0"]));
+        assert_eq!(None, keys(&["MOVE", "k", "1"]));
+        assert_eq!(None, keys(&["PING"]));
+        assert_eq!(None, keys(&["CLUSTER", "COUNTKEYSINSLOT", "0"]));
+    }
+
+    #[test]
+    fn test_parse_cluster() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_cluster_setslot(&cmd!["CLUSTER", "SETSLOT", "7", "Node", "abc"]).unwrap();
+        assert_eq!(
+            vec![b"7".to_vec(), b"node".to_vec(), b"abc".to_vec()],
+            cmd.args
+        );
+        assert!(parse_cluster_setslot(&cmd!["CLUSTER", "SETSLOT", "7", "STABLE"]).is_ok());
+        assert_eq!(
+            Err(ParseCommandError::new(
+                SyntaxError,
+                Some(Action::ClusterSetSlot)
+            )),
+            parse_cluster_setslot(&cmd!["CLUSTER", "SETSLOT", "7", "STABLE", "abc"])
+        );
+        assert_eq!(
+            Err(invalid_options(
+                Action::ClusterAddSlots,
+                "Invalid or out of range slot"
+            )),
+            parse_cluster_addslots(&cmd!["CLUSTER", "ADDSLOTS", "1", "16384"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                WrongNumberArgs,
+                Some(Action::ClusterAddSlots)
+            )),
+            parse_cluster_addslots(&cmd!["CLUSTER", "ADDSLOTS"])
+        );
+        let cmd = parse_cluster_meet(&cmd!["CLUSTER", "MEET", "127.0.0.1", "7001"]).unwrap();
+        assert_eq!(vec![b"127.0.0.1".to_vec(), b"7001".to_vec()], cmd.args);
+        assert!(parse_cluster_meet(&cmd!["CLUSTER", "MEET", "127.0.0.1"]).is_err());
+        assert!(parse_cluster_meet(&cmd!["CLUSTER", "MEET", "127.0.0.1", "99999"]).is_err());
+        assert_eq!(
+            Some(Action::ClusterKeySlot),
+            Command::from_resp(resp::array(cmd!["cluster", "keyslot", "k"].to_vec()))
+                .ok()
+                .map(|cmd| *cmd.action())
+        );
+    }
+
+    #[test]
+    fn test_parse_memory_usage() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_memory_usage(&cmd!["MEMORY", "USAGE", "k", "SAMPLES", "0"]).unwrap();
+        assert_eq!(vec![b"k".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::MemorySamples(0)));
+        assert_eq!(
+            Err(ParseCommandError::new(
+                WrongNumberArgs,
+                Some(Action::MemoryUsage)
+            )),
+            parse_memory_usage(&cmd!["MEMORY", "USAGE"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                SyntaxError,
+                Some(Action::MemoryUsage)
+            )),
+            parse_memory_usage(&cmd!["MEMORY", "USAGE", "k", "SAMPLES"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                OutOfRange,
+                Some(Action::MemoryUsage)
+            )),
+            parse_memory_usage(&cmd!["MEMORY", "USAGE", "k", "SAMPLES", "-1"])
+        );
+    }
+
+    #[test]
+    fn test_parse_flush() {
+        assert!(parse_flushall(&cmd!["FLUSHALL", "async"])
+            .unwrap()
+            .opts()
+            .contains(&CommandOpt::FlushAsync));
+        assert!(parse_flushdb(&cmd!["FLUSHDB", "SYNC"])
+            .unwrap()
+            .opts()
+            .is_empty());
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::SyntaxError,
+                Some(Action::FlushDb)
+            )),
+            parse_flushdb(&cmd!["FLUSHDB", "later"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::NotAnInteger,
+                Some(Action::Select)
+            )),
+            parse_select(&cmd!["SELECT", "one"])
+        );
+    }
+
+    #[test]
+    fn test_parse_set_algebra() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::SInter,
+                vec![b"a".to_vec()],
+                Some(Lock::Read)
             )),
-            parse_watch(&cmd!["WATCH", "foo", "bar", "mykey"])
+            parse_sinter(&cmd!["SINTER", "a"])
+        );
+        assert_eq!(
+            Ok(Command::new(
+                Action::SDiffStore,
+                vec![b"dest".to_vec(), b"a".to_vec(), b"b".to_vec()],
+                Some(Lock::Write)
+            )),
+            parse_sdiffstore(&cmd!["SDIFFSTORE", "dest", "a", "b"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::SUnionStore)
+            )),
+            parse_sunionstore(&cmd!["SUNIONSTORE", "dest"])
+        );
+    }
+
+    #[test]
+    fn test_parse_srandmember() {
+        assert_eq!(
+            Ok(Command::new(
+                Action::SRandMember,
+                vec![b"s".to_vec(), b"-3".to_vec()],
+                Some(Lock::Read)
+            )),
+            parse_srandmember(&cmd!["SRANDMEMBER", "s", "-3"])
+        );
+        let out_of_range = |action| Err(invalid_options(action, "value is out of range"));
+        assert_eq!(
+            out_of_range(Action::SRandMember),
+            parse_srandmember(&cmd!["SRANDMEMBER", "s", "-9223372036854775808"])
+        );
+        assert_eq!(
+            out_of_range(Action::SRandMember),
+            parse_srandmember(&cmd!["SRANDMEMBER", "s", "9223372036854775807"])
+        );
+        assert_eq!(
+            out_of_range(Action::SPop),
+            parse_spop(&cmd!["SPOP", "s", "9223372036854775807"])
+        );
+        assert!(parse_srandmember(&cmd!["SRANDMEMBER", "s", "-4611686018427387903"]).is_ok());
+        assert!(parse_spop(&cmd!["SPOP", "s", "4611686018427387903"]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_zadd() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_zadd(&cmd!["ZADD", "z", "NX", "CH", "1", "a", "2.5", "b"]).unwrap();
+        assert_eq!(
+            vec![
+                b"z".to_vec(),
+                b"1".to_vec(),
+                b"a".to_vec(),
+                b"2.5".to_vec(),
+                b"b".to_vec()
+            ],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::ZAddNx));
+        assert!(cmd.opts().contains(&CommandOpt::ZAddCh));
+
+        assert_eq!(
+            Err(ParseCommandError::new(SyntaxError, Some(Action::ZAdd))),
+            parse_zadd(&cmd!["ZADD", "z", "1", "a", "2"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(NotAFloat, Some(Action::ZAdd))),
+            parse_zadd(&cmd!["ZADD", "z", "one", "a"])
+        );
+        assert_eq!(
+            Err(invalid_options(
+                Action::ZAdd,
+                "GT, LT, and/or NX options at the same time are not compatible"
+            )),
+            parse_zadd(&cmd!["ZADD", "z", "GT", "NX", "1", "a"])
+        );
+        assert_eq!(
+            Err(invalid_options(
+                Action::ZAdd,
+                "INCR option supports a single increment-element pair"
+            )),
+            parse_zadd(&cmd!["ZADD", "z", "INCR", "1", "a", "2", "b"])
+        );
+    }
+
+    #[test]
+    fn test_parse_zrange() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_zrange(&cmd![
+            "ZRANGE",
+            "z",
+            "(1",
+            "+inf",
+            "BYSCORE",
+            "REV",
+            "LIMIT",
+            "0",
+            "10",
+            "WITHSCORES"
+        ])
+        .unwrap();
+        assert!(cmd.opts().contains(&CommandOpt::ZRangeByScore));
+        assert!(cmd.opts().contains(&CommandOpt::ZRangeRev));
+        assert!(cmd.opts().contains(&CommandOpt::ZRangeLimit(0, 10)));
+        assert!(cmd.opts().contains(&CommandOpt::ZRangeWithScores));
+
+        assert_eq!(
+            Err(ParseCommandError::new(MinMaxNotFloat, Some(Action::ZRange))),
+            parse_zrange(&cmd!["ZRANGE", "z", "a", "b", "BYSCORE"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(
+                MinMaxNotString,
+                Some(Action::ZRange)
+            )),
+            parse_zrange(&cmd!["ZRANGE", "z", "a", "b", "BYLEX"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(NotAnInteger, Some(Action::ZRange))),
+            parse_zrange(&cmd!["ZRANGE", "z", "(1", "2"])
+        );
+        assert!(parse_zrange(&cmd!["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_xadd() {
+        use ParseCommandErrorKind::*;
+        let cmd = parse_xadd(&cmd![
+            "XADD",
+            "s",
+            "NOMKSTREAM",
+            "MAXLEN",
+            "~",
+            "10",
+            "LIMIT",
+            "5",
+            "*",
+            "f",
+            "v"
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![b"s".to_vec(), b"*".to_vec(), b"f".to_vec(), b"v".to_vec()],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::XAddNoMkStream));
+        assert!(cmd
+            .opts()
+            .contains(&CommandOpt::XTrim(TrimStrategy::MaxLen(10))));
+        assert!(cmd.opts().contains(&CommandOpt::XTrimLimit(5)));
+
+        assert_eq!(
+            Err(ParseCommandError::new(InvalidStreamId, Some(Action::XAdd))),
+            parse_xadd(&cmd!["XADD", "s", "abc", "f", "v"])
+        );
+        assert_eq!(
+            Err(ParseCommandError::new(WrongNumberArgs, Some(Action::XAdd))),
+            parse_xadd(&cmd!["XADD", "s", "*", "f"])
+        );
+        assert_eq!(
+            Err(invalid_options(
+                Action::XAdd,
+                "syntax error, LIMIT cannot be used without the special ~ option"
+            )),
+            parse_xadd(&cmd![
+                "XADD", "s", "MINID", "5", "LIMIT", "1", "*", "f", "v"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_xrange() {
+        let cmd = parse_xrevrange(&cmd!["XREVRANGE", "s", "+", "(1-0", "COUNT", "2"]).unwrap();
+        assert!(cmd.opts().contains(&CommandOpt::XCount(2)));
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::InvalidStreamId,
+                Some(Action::XRange)
+            )),
+            parse_xrange(&cmd!["XRANGE", "s", "x", "+"])
+        );
+    }
+
+    #[test]
+    fn test_parse_xread() {
+        let cmd = parse_xread(&cmd![
+            "XREAD", "COUNT", "2", "BLOCK", "0", "STREAMS", "a", "b", "0", "$"
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"0".to_vec(), b"$".to_vec()],
+            cmd.args
+        );
+        assert!(cmd.opts().contains(&CommandOpt::XBlock(0)));
+        assert_eq!(
+            Err(invalid_options(
+                Action::XRead,
+                "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+            )),
+            parse_xread(&cmd!["XREAD", "STREAMS", "a", "b", "0"])
+        );
+
+        let cmd = parse_xreadgroup(&cmd![
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "NOACK",
+            "STREAMS",
+            "a",
+            ">"
+        ])
+        .unwrap();
+        assert_eq!(Some(Lock::Write), cmd.lock());
+        assert!(cmd.opts().contains(&CommandOpt::XReadNoAck));
+        assert!(parse_xreadgroup(&cmd!["XREADGROUP", "g", "c", "STREAMS", "a", ">"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_xgroup() {
+        let cmd = Command::from_resp(resp::array(
+            cmd!["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"].into(),
+        ))
+        .unwrap();
+        assert_eq!(Action::XGroupCreate, cmd.action);
+        assert_eq!(6, cmd.argv().len());
+        assert_eq!(vec![b"s".to_vec(), b"g".to_vec(), b"$".to_vec()], cmd.args);
+        assert!(cmd.opts().contains(&CommandOpt::XGroupMkStream));
+        assert_eq!(
+            Err(ParseCommandError::new(
+                ParseCommandErrorKind::WrongNumberArgs,
+                Some(Action::XGroupDelConsumer)
+            )),
+            parse_xgroup_delconsumer(&cmd!["XGROUP", "DELCONSUMER", "s", "g"])
+        );
+    }
+
+    #[test]
+    fn test_parse_xpending_xclaim() {
+        let cmd = parse_xpending(&cmd![
+            "XPENDING", "s", "g", "IDLE", "100", "-", "+", "10", "c"
+        ])
+        .unwrap();
+        assert_eq!(6, cmd.args.len());
+        assert!(cmd.opts().contains(&CommandOpt::XPendingIdle(100)));
+
+        let cmd = parse_xclaim(&cmd![
+            "XCLAIM",
+            "s",
+            "g",
+            "c",
+            "10",
+            "1-0",
+            "2-0",
+            "FORCE",
+            "RETRYCOUNT",
+            "3"
+        ])
+        .unwrap();
+        assert_eq!(6, cmd.args.len());
+        assert!(cmd.opts().contains(&CommandOpt::XClaimForce));
+        assert!(cmd.opts().contains(&CommandOpt::XClaimRetryCount(3)));
+
+        assert_eq!(
+            Err(invalid_options(Action::XAutoClaim, "COUNT must be > 0")),
+            parse_xautoclaim(&cmd!["XAUTOCLAIM", "s", "g", "c", "10", "0", "COUNT", "0"])
         );
     }
 }
@@ -0,0 +1,419 @@
+use crate::aof::AppendFsync;
+use crate::cluster;
+use crate::evict::EvictionPolicy;
+use crate::memory::MemoryAccounting;
+use crate::snapshot::SaveRule;
+use crate::store::DEFAULT_DATABASES;
+use clap::{App, Arg};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Save after an hour if a key changed, after 5 minutes if 100 did, and after a minute if
+/// 10000 did.
+const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+const DEFAULT_PORT: u16 = 8080;
+
+const DEFAULT_REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+
+const DEFAULT_TRANSACTION_TIMEOUT_MS: u64 = 5000;
+
+/// Server settings read from the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// The port to listen on, on 127.0.0.1.
+    pub port: u16,
+    pub databases: usize,
+    /// The memory budget in bytes, or 0 for none.
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub memory_accounting: MemoryAccounting,
+    /// The directory the snapshot is saved in.
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// When to save a snapshot in the background. Empty to only save on request.
+    pub save: Vec<SaveRule>,
+    /// Whether write commands are logged to the append-only file.
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    /// A Redis RDB file to load at startup instead of the snapshot or append-only file.
+    pub import_rdb: Option<PathBuf>,
+    /// The leader to replicate from at startup.
+    pub replicaof: Option<(String, u16)>,
+    /// How much of the replication stream is kept for replicas that reconnect, in bytes.
+    pub repl_backlog_size: usize,
+    /// How long an interactive transaction may hold its locks before it is rolled back.
+    pub transaction_timeout: Duration,
+    /// Whether the server is a node of a cluster, serving the hash slots assigned to it.
+    pub cluster_enabled: bool,
+    /// The port the node gossips with the rest of the cluster on.
+    pub cluster_port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: DEFAULT_PORT,
+            databases: DEFAULT_DATABASES,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            memory_accounting: MemoryAccounting::default(),
+            dir: PathBuf::from("."),
+            dbfilename: "dump.kyev".to_owned(),
+            save: parse_save_rules(DEFAULT_SAVE_RULES).unwrap(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_owned(),
+            appendfsync: AppendFsync::default(),
+            import_rdb: None,
+            replicaof: None,
+            repl_backlog_size: DEFAULT_REPL_BACKLOG_SIZE,
+            transaction_timeout: Duration::from_millis(DEFAULT_TRANSACTION_TIMEOUT_MS),
+            cluster_enabled: false,
+            cluster_port: DEFAULT_PORT + cluster::BUS_PORT_OFFSET,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Config {
+        let default_port = DEFAULT_PORT.to_string();
+        let default_transaction_timeout = DEFAULT_TRANSACTION_TIMEOUT_MS.to_string();
+        let default_databases = DEFAULT_DATABASES.to_string();
+        let matches = App::new("kyev")
+            .version("0.1.0")
+            .author("Jeff Smith")
+            .arg(
+                Arg::with_name("port")
+                    .long("port")
+                    .value_name("PORT")
+                    .help("Port to listen on")
+                    .default_value(&default_port),
+            )
+            .arg(
+                Arg::with_name("databases")
+                    .long("databases")
+                    .value_name("COUNT")
+                    .help("Number of databases")
+                    .default_value(&default_databases),
+            )
+            .arg(
+                Arg::with_name("maxmemory")
+                    .long("maxmemory")
+                    .value_name("BYTES")
+                    .help("Memory budget for the dataset, such as 100mb; 0 for none")
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("maxmemory-policy")
+                    .long("maxmemory-policy")
+                    .value_name("POLICY")
+                    .help("Which keys to evict when over the memory budget")
+                    .possible_values(EvictionPolicy::NAMES)
+                    .case_insensitive(true)
+                    .default_value("noeviction"),
+            )
+            .arg(
+                Arg::with_name("memory-accounting")
+                    .long("memory-accounting")
+                    .value_name("MODE")
+                    .help("Measure memory by estimating the store's structures or by counting allocations")
+                    .possible_values(MemoryAccounting::NAMES)
+                    .case_insensitive(true)
+                    .default_value("estimate"),
+            )
+            .arg(
+                Arg::with_name("dir")
+                    .long("dir")
+                    .value_name("DIR")
+                    .help("Directory the snapshot is saved in and loaded from")
+                    .default_value("."),
+            )
+            .arg(
+                Arg::with_name("dbfilename")
+                    .long("dbfilename")
+                    .value_name("FILE")
+                    .help("File name of the snapshot")
+                    .default_value("dump.kyev"),
+            )
+            .arg(
+                Arg::with_name("save")
+                    .long("save")
+                    .value_name("RULES")
+                    .help("Pairs of <seconds> <changes> after which to save a snapshot; \"\" for none")
+                    .default_value(DEFAULT_SAVE_RULES),
+            )
+            .arg(
+                Arg::with_name("appendonly")
+                    .long("appendonly")
+                    .value_name("yes|no")
+                    .help("Log write commands to the append-only file and replay it on startup")
+                    .possible_values(&["yes", "no"])
+                    .case_insensitive(true)
+                    .default_value("no"),
+            )
+            .arg(
+                Arg::with_name("appendfilename")
+                    .long("appendfilename")
+                    .value_name("FILE")
+                    .help("File name of the append-only file, in --dir")
+                    .default_value("appendonly.aof"),
+            )
+            .arg(
+                Arg::with_name("appendfsync")
+                    .long("appendfsync")
+                    .value_name("POLICY")
+                    .help("When to sync the append-only file to disk")
+                    .possible_values(AppendFsync::NAMES)
+                    .case_insensitive(true)
+                    .default_value("everysec"),
+            )
+            .arg(
+                Arg::with_name("import-rdb")
+                    .long("import-rdb")
+                    .value_name("FILE")
+                    .help("Load a Redis RDB file at startup instead of the snapshot or append-only file"),
+            )
+            .arg(
+                Arg::with_name("replicaof")
+                    .long("replicaof")
+                    .value_name("HOST PORT")
+                    .help("Replicate from the leader at HOST and PORT"),
+            )
+            .arg(
+                Arg::with_name("repl-backlog-size")
+                    .long("repl-backlog-size")
+                    .value_name("BYTES")
+                    .help("How much of the replication stream to keep for replicas that reconnect")
+                    .default_value("1mb"),
+            )
+            .arg(
+                Arg::with_name("transaction-timeout")
+                    .long("transaction-timeout")
+                    .value_name("MILLISECONDS")
+                    .help("How long a transaction started with BEGIN may run before it is rolled back")
+                    .default_value(&default_transaction_timeout),
+            )
+            .arg(
+                Arg::with_name("cluster-enabled")
+                    .long("cluster-enabled")
+                    .value_name("yes|no")
+                    .help("Run as a node of a cluster that partitions the keys into hash slots")
+                    .possible_values(&["yes", "no"])
+                    .case_insensitive(true)
+                    .default_value("no"),
+            )
+            .arg(
+                Arg::with_name("cluster-port")
+                    .long("cluster-port")
+                    .value_name("PORT")
+                    .help("Port to gossip with the other nodes on; defaults to --port plus 10000"),
+            )
+            .get_matches();
+
+        let port = matches
+            .value_of("port")
+            .and_then(|port| port.parse::<u16>().ok())
+            .unwrap_or_else(|| exit_with("--port must be a port number"));
+        let databases = matches
+            .value_of("databases")
+            .and_then(|count| count.parse::<usize>().ok())
+            .filter(|count| *count > 0)
+            .unwrap_or_else(|| exit_with("--databases must be a positive integer"));
+        let maxmemory = matches
+            .value_of("maxmemory")
+            .and_then(parse_memory)
+            .unwrap_or_else(|| exit_with("--maxmemory must be a size in bytes, such as 100mb"));
+        let maxmemory_policy = matches
+            .value_of("maxmemory-policy")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e: String| exit_with(&e));
+        let memory_accounting = matches
+            .value_of("memory-accounting")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e: String| exit_with(&e));
+        let dir = PathBuf::from(matches.value_of("dir").unwrap());
+        let dbfilename = matches.value_of("dbfilename").unwrap().to_owned();
+        let save = matches
+            .value_of("save")
+            .and_then(parse_save_rules)
+            .unwrap_or_else(|| exit_with("--save must be pairs of <seconds> <changes>"));
+        let appendonly = matches
+            .value_of("appendonly")
+            .unwrap()
+            .eq_ignore_ascii_case("yes");
+        let appendfilename = matches.value_of("appendfilename").unwrap().to_owned();
+        let appendfsync = matches
+            .value_of("appendfsync")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e: String| exit_with(&e));
+        let import_rdb = matches.value_of("import-rdb").map(PathBuf::from);
+        let replicaof = matches.value_of("replicaof").map(|leader| {
+            parse_host_port(leader)
+                .unwrap_or_else(|| exit_with("--replicaof must be a host and a port"))
+        });
+        let repl_backlog_size = matches
+            .value_of("repl-backlog-size")
+            .and_then(parse_memory)
+            .filter(|size| *size > 0)
+            .unwrap_or_else(|| exit_with("--repl-backlog-size must be a positive size in bytes"));
+        let transaction_timeout = matches
+            .value_of("transaction-timeout")
+            .and_then(|timeout| timeout.parse::<u64>().ok())
+            .filter(|timeout| *timeout > 0)
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| {
+                exit_with("--transaction-timeout must be a positive number of milliseconds")
+            });
+        let cluster_enabled = matches
+            .value_of("cluster-enabled")
+            .unwrap()
+            .eq_ignore_ascii_case("yes");
+        let cluster_port = match matches.value_of("cluster-port") {
+            Some(port) => port.parse::<u16>().ok(),
+            None => port.checked_add(cluster::BUS_PORT_OFFSET),
+        }
+        .unwrap_or_else(|| exit_with("--cluster-port must be a port number"));
+
+        Config {
+            port,
+            databases,
+            maxmemory,
+            maxmemory_policy,
+            memory_accounting,
+            dir,
+            dbfilename,
+            save,
+            appendonly,
+            appendfilename,
+            appendfsync,
+            import_rdb,
+            replicaof,
+            repl_backlog_size,
+            transaction_timeout,
+            cluster_enabled,
+            cluster_port,
+        }
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+}
+
+fn exit_with<T>(message: &str) -> T {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Parses a memory size such as `1024`, `64k`, `100mb` or `2gb`. Units ending in `b` are
+/// powers of 1024, the others powers of 1000.
+pub fn parse_memory(size: &str) -> Option<usize> {
+    let size = size.to_lowercase();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses a leader's address given as `host port` or `host:port`.
+pub fn parse_host_port(addr: &str) -> Option<(String, u16)> {
+    let (host, port) = addr
+        .trim()
+        .rsplit_once(' ')
+        .or_else(|| addr.trim().rsplit_once(':'))?;
+    let host = host.trim();
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+/// Parses save rules such as `3600 1 300 100`: pairs of seconds and changes.
+pub fn parse_save_rules(rules: &str) -> Option<Vec<SaveRule>> {
+    let numbers = rules
+        .split_whitespace()
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(Some(1024), parse_memory("1024"));
+        assert_eq!(Some(64_000), parse_memory("64k"));
+        assert_eq!(Some(100 * 1024 * 1024), parse_memory("100MB"));
+        assert_eq!(Some(2 * 1024 * 1024 * 1024), parse_memory("2gb"));
+        assert_eq!(None, parse_memory("mb"));
+        assert_eq!(None, parse_memory("10tb"));
+        assert_eq!(None, parse_memory("-1"));
+    }
+
+    #[test]
+    fn test_parse_host_port() {
+        assert_eq!(
+            Some(("127.0.0.1".to_owned(), 6379)),
+            parse_host_port("127.0.0.1 6379")
+        );
+        assert_eq!(
+            Some(("leader".to_owned(), 8080)),
+            parse_host_port("leader:8080")
+        );
+        assert_eq!(None, parse_host_port("leader"));
+        assert_eq!(None, parse_host_port(" 8080"));
+        assert_eq!(None, parse_host_port("leader 99999"));
+    }
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            Some(vec![
+                SaveRule {
+                    seconds: 900,
+                    changes: 1
+                },
+                SaveRule {
+                    seconds: 60,
+                    changes: 10000
+                }
+            ]),
+            parse_save_rules("900 1  60 10000")
+        );
+        assert_eq!(Some(vec![]), parse_save_rules(""));
+        assert_eq!(None, parse_save_rules("900"));
+        assert_eq!(None, parse_save_rules("900 -1"));
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicU8, Ordering};
+use std::time::Instant;
+
+/// How many keys each database offers as eviction candidates per evicted key.
+pub const EVICTION_SAMPLES: usize = 5;
+
+/// The frequency a new entry starts with, so it is not evicted before it had a chance to be
+/// accessed again.
+const LFU_INIT_VAL: u8 = 5;
+
+/// How quickly the logarithmic frequency counter saturates. Higher values need more
+/// accesses per increment.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// How many minutes of idleness decrement the frequency counter by one.
+const LFU_DECAY_MINUTES: u64 = 1;
+
+lazy_static! {
+    static ref CLOCK_START: Instant = Instant::now();
+}
+
+/// Milliseconds since the process started, on the monotonic clock. Signed, so that RESTORE
+/// can place an access before the process started.
+fn clock_ms() -> i64 {
+    CLOCK_START.elapsed().as_millis() as i64
+}
+
+/// Which keys are evicted once memory use goes over maxmemory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub const NAMES: &'static [&'static str] = &[
+        "noeviction",
+        "allkeys-lru",
+        "allkeys-lfu",
+        "allkeys-random",
+        "volatile-lru",
+        "volatile-lfu",
+        "volatile-random",
+        "volatile-ttl",
+    ];
+
+    /// Whether only keys with a TTL may be evicted.
+    pub fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileRandom
+                | EvictionPolicy::VolatileTtl
+        )
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::EvictionPolicy::*;
+        match s.to_lowercase().as_str() {
+            "noeviction" => Ok(NoEviction),
+            "allkeys-lru" => Ok(AllKeysLru),
+            "allkeys-lfu" => Ok(AllKeysLfu),
+            "allkeys-random" => Ok(AllKeysRandom),
+            "volatile-lru" => Ok(VolatileLru),
+            "volatile-lfu" => Ok(VolatileLfu),
+            "volatile-random" => Ok(VolatileRandom),
+            "volatile-ttl" => Ok(VolatileTtl),
+            _ => Err(format!("unknown eviction policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EvictionPolicy::*;
+        let name = match self {
+            NoEviction => "noeviction",
+            AllKeysLru => "allkeys-lru",
+            AllKeysLfu => "allkeys-lfu",
+            AllKeysRandom => "allkeys-random",
+            VolatileLru => "volatile-lru",
+            VolatileLfu => "volatile-lfu",
+            VolatileRandom => "volatile-random",
+            VolatileTtl => "volatile-ttl",
+        };
+        name.fmt(f)
+    }
+}
+
+/// When an entry was last accessed and roughly how often, for the LRU and LFU policies.
+/// Atomic so that reads holding a shared lock on the store can record their access.
+#[derive(Debug)]
+pub struct AccessStats {
+    last_access_ms: AtomicI64,
+    frequency: AtomicU8,
+}
+
+impl AccessStats {
+    pub fn new() -> AccessStats {
+        AccessStats {
+            last_access_ms: AtomicI64::new(clock_ms()),
+            frequency: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    /// Stats for an entry last accessed `idle_ms` ago, as RESTORE with IDLETIME creates.
+    pub fn with_idle(idle_ms: u64) -> AccessStats {
+        AccessStats {
+            last_access_ms: AtomicI64::new(
+                clock_ms().saturating_sub(idle_ms.min(i64::MAX as u64) as i64),
+            ),
+            frequency: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    /// Stats for an entry with the frequency counter at `frequency`, as RESTORE with FREQ
+    /// creates.
+    pub fn with_frequency(frequency: u8) -> AccessStats {
+        AccessStats {
+            last_access_ms: AtomicI64::new(clock_ms()),
+            frequency: AtomicU8::new(frequency),
+        }
+    }
+
+    /// Records an access. Concurrent accesses may overwrite each other's update, which only
+    /// makes the statistics a little less precise.
+    pub fn record(&self) {
+        let frequency = log_increment(self.frequency());
+        self.frequency.store(frequency, Ordering::Relaxed);
+        self.last_access_ms.store(clock_ms(), Ordering::Relaxed);
+    }
+
+    pub fn idle_ms(&self) -> u64 {
+        clock_ms()
+            .saturating_sub(self.last_access_ms.load(Ordering::Relaxed))
+            .max(0) as u64
+    }
+
+    /// The access frequency counter, decayed by the time since the last access.
+    pub fn frequency(&self) -> u8 {
+        let decay = self.idle_ms() / 60_000 / LFU_DECAY_MINUTES;
+        let frequency = self.frequency.load(Ordering::Relaxed) as u64;
+        frequency.saturating_sub(decay) as u8
+    }
+}
+
+impl Default for AccessStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Increments the frequency counter with a probability that falls as it grows, so that the
+/// 8-bit counter can tell apart keys accessed millions of times.
+fn log_increment(frequency: u8) -> u8 {
+    if frequency == u8::MAX {
+        return frequency;
+    }
+    let base = frequency.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+    if rand::random::<f64>() < probability {
+        frequency + 1
+    } else {
+        frequency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_names() {
+        for name in EvictionPolicy::NAMES {
+            let policy: EvictionPolicy = name.parse().unwrap();
+            assert_eq!(*name, policy.to_string());
+        }
+        assert!("allkeys-mru".parse::<EvictionPolicy>().is_err());
+        assert!("VOLATILE-TTL"
+            .parse::<EvictionPolicy>()
+            .unwrap()
+            .is_volatile());
+    }
+
+    #[test]
+    fn test_frequency() {
+        let stats = AccessStats::new();
+        assert_eq!(LFU_INIT_VAL, stats.frequency());
+        for _ in 0..1000 {
+            stats.record();
+        }
+        let frequency = stats.frequency();
+        assert!(frequency > LFU_INIT_VAL && frequency < u8::MAX);
+        assert_eq!(u8::MAX, log_increment(u8::MAX));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::clock;
+use crate::memory::{alloc_size, index_map_size};
+use indexmap::IndexMap;
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+/// How often the active expire cycle runs when no expired keys are left behind.
+pub const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+
+/// How long one active expire cycle may hold the store before yielding to clients.
+pub const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(25);
+
+/// How soon the next cycle runs when the previous one ran out of budget with expired keys
+/// still left behind.
+pub const ACTIVE_EXPIRE_FAST_PERIOD: Duration = Duration::from_millis(1);
+
+/// How many expired keys a cycle removes from each database before moving on to the next.
+pub const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+
+/// Converts a Unix time in milliseconds to a TTL from now. Deadlines live on the monotonic
+/// clock so wall-clock jumps do not move them; Unix times are only converted as commands
+/// such as EXPIREAT come in and replies such as EXPIRETIME go out.
+pub fn unix_ms_to_ttl(unix_ms: i64) -> i64 {
+    unix_ms.saturating_sub(clock::now_ms() as i64)
+}
+
+/// Converts a TTL in milliseconds to the Unix time it ends at.
+pub fn ttl_to_unix_ms(ttl_ms: i64) -> i64 {
+    ttl_ms.saturating_add(clock::now_ms() as i64)
+}
+
+/// The keys of one database that have a TTL, ordered by when they expire. Each key appears
+/// at most once, so the index grows only with the number of keys that have a TTL.
+///
+/// Keys are held by position so that eviction draws random ones in O(1); the deadline order
+/// refers to those positions, so each key is stored once.
+#[derive(Debug, Default)]
+pub struct ExpiryIndex {
+    keys: IndexMap<Vec<u8>, Instant>,
+    /// Positions in `keys`, soonest deadline first.
+    deadlines: BTreeSet<(Instant, usize)>,
+    /// The heap memory of the index's copies of the keys.
+    key_bytes: usize,
+}
+
+impl ExpiryIndex {
+    pub fn new() -> ExpiryIndex {
+        ExpiryIndex::default()
+    }
+
+    /// Sets the deadline of `key`, replacing any it had.
+    pub fn schedule(&mut self, key: Vec<u8>, expires_at: Instant) {
+        if let Some((position, _, deadline)) = self.keys.get_full_mut(&key) {
+            self.deadlines.remove(&(*deadline, position));
+            *deadline = expires_at;
+            self.deadlines.insert((expires_at, position));
+            return;
+        }
+        self.key_bytes += alloc_size(key.len());
+        self.deadlines.insert((expires_at, self.keys.len()));
+        self.keys.insert(key, expires_at);
+    }
+
+    /// Removes `key` if its deadline is `expires_at`.
+    pub fn cancel(&mut self, key: &[u8], expires_at: Instant) {
+        if self.keys.get(key) != Some(&expires_at) {
+            return;
+        }
+        let (position, key, _) = self.keys.swap_remove_full(key).unwrap();
+        self.key_bytes -= alloc_size(key.len());
+        self.deadlines.remove(&(expires_at, position));
+        // The last key took the removed one's place.
+        if let Some((_, moved)) = self.keys.get_index(position) {
+            self.deadlines.remove(&(*moved, self.keys.len()));
+            self.deadlines.insert((*moved, position));
+        }
+    }
+
+    /// Returns up to `limit` keys whose deadline is not after `now`, soonest first.
+    pub fn due(&self, now: Instant, limit: usize) -> Vec<Vec<u8>> {
+        self.deadlines
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(limit)
+            .map(|(_, position)| self.key_at(*position).clone())
+            .collect()
+    }
+
+    /// The keys, soonest deadline first.
+    pub fn soonest(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.deadlines
+            .iter()
+            .map(move |(_, position)| self.key_at(*position))
+    }
+
+    /// Draws `count` keys at random, possibly repeating some.
+    pub fn sample<R: Rng>(&self, rng: &mut R, count: usize) -> Vec<&Vec<u8>> {
+        if self.keys.is_empty() {
+            return vec![];
+        }
+        (0..count)
+            .map(|_| self.key_at(rng.gen_range(0, self.keys.len())))
+            .collect()
+    }
+
+    fn key_at(&self, position: usize) -> &Vec<u8> {
+        self.keys.get_index(position).unwrap().0
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.keys.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Estimates the memory the index takes, counting its B-tree nodes as two thirds full.
+    pub fn memory(&self) -> usize {
+        index_map_size::<(Vec<u8>, Instant)>(self.keys.capacity())
+            + self.deadlines.len() * size_of::<(Instant, usize)>() * 3 / 2
+            + self.key_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let now = Instant::now();
+        let mut index = ExpiryIndex::new();
+        index.schedule(b"later".to_vec(), now + Duration::from_secs(10));
+        index.schedule(b"second".to_vec(), now - Duration::from_secs(1));
+        index.schedule(b"first".to_vec(), now - Duration::from_secs(2));
+        assert_eq!(
+            vec![b"first".to_vec(), b"second".to_vec()],
+            index.due(now, 10)
+        );
+        assert_eq!(vec![b"first".to_vec()], index.due(now, 1));
+
+        index.cancel(b"first", now - Duration::from_secs(2));
+        assert_eq!(vec![b"second".to_vec()], index.due(now, 10));
+        assert_eq!(2, index.len());
+
+        let memory = index.memory();
+        index.cancel(b"later", now + Duration::from_secs(10));
+        assert!(index.memory() < memory);
+    }
+
+    #[test]
+    fn test_positions_follow_removals() {
+        let now = Instant::now();
+        let mut index = ExpiryIndex::new();
+        for (i, key) in [b"a", b"b", b"c"].iter().enumerate() {
+            index.schedule(key.to_vec(), now - Duration::from_secs(3 - i as u64));
+        }
+        index.cancel(b"a", now + Duration::from_secs(1));
+        assert_eq!(3, index.len());
+        index.cancel(b"a", now - Duration::from_secs(3));
+        index.schedule(b"b".to_vec(), now + Duration::from_secs(1));
+        assert_eq!(Some(&b"c".to_vec()), index.soonest().next());
+        assert_eq!(vec![b"c".to_vec()], index.due(now, 10));
+
+        let mut rng = rand::thread_rng();
+        let sample = index.sample(&mut rng, 20);
+        assert_eq!(20, sample.len());
+        assert!(sample.iter().all(|key| *key == b"b" || *key == b"c"));
+        assert!(ExpiryIndex::new().sample(&mut rng, 5).is_empty());
+    }
+
+    #[test]
+    fn test_unix_ms_round_trip() {
+        // The clock may tick between conversions, so allow a little slack.
+        let close = |expected: i64, actual: i64| (expected - actual).abs() <= 50;
+
+        let unix_ms = clock::now_ms() as i64 + 60_000;
+        assert!(close(60_000, unix_ms_to_ttl(unix_ms)));
+        assert!(close(unix_ms, ttl_to_unix_ms(unix_ms_to_ttl(unix_ms))));
+        assert!(close(5_000, unix_ms_to_ttl(ttl_to_unix_ms(5_000))));
+
+        let past = clock::now_ms() as i64 - 10_000;
+        assert!(close(-10_000, unix_ms_to_ttl(past)));
+        assert!(unix_ms_to_ttl(0) < 0);
+        assert!(unix_ms_to_ttl(-1) < unix_ms_to_ttl(0));
+
+        assert_eq!(i64::MAX, ttl_to_unix_ms(i64::MAX));
+        assert_eq!(i64::MIN, unix_ms_to_ttl(i64::MIN));
+    }
+}
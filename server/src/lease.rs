@@ -0,0 +1,167 @@
+use crate::expire::ExpiryIndex;
+use crate::store::{StoreError, StoreErrorKind};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Formats a lease token as it is handed to clients: 16 hex digits.
+pub fn format_token(token: u64) -> String {
+    format!("{:016x}", token)
+}
+
+pub fn parse_token(token: &[u8]) -> Option<u64> {
+    let token = std::str::from_utf8(token).ok()?;
+    if token.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(token, 16).ok()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lease {
+    pub token: u64,
+    pub expires_at: Instant,
+}
+
+/// The leased keys of one database. While a key is leased, only commands that carry the
+/// lease's token may write to it. Leases are named by key, whether or not the key exists,
+/// and expire like keys do: they are ignored once their deadline passes and reclaimed by
+/// the active expire cycle. They are not persisted or replicated.
+#[derive(Debug, Default)]
+pub struct Leases {
+    leases: HashMap<Vec<u8>, Lease>,
+    deadlines: ExpiryIndex,
+}
+
+impl Leases {
+    pub fn new() -> Leases {
+        Leases::default()
+    }
+
+    /// The lease on `key`, unless it has none or it expired.
+    pub fn get(&self, key: &[u8]) -> Option<&Lease> {
+        let now = Instant::now();
+        self.leases.get(key).filter(|lease| lease.expires_at > now)
+    }
+
+    /// Leases `key` for `ttl`, or renews its lease if `token` is the lease's token.
+    /// Returns the lease's token. Fails if another token holds the key, or if `token` is
+    /// given but no longer holds it: the lease expired or was released, so others may have
+    /// written to the key since.
+    pub fn acquire(
+        &mut self,
+        key: Vec<u8>,
+        ttl: Duration,
+        token: Option<u64>,
+    ) -> Result<u64, StoreError> {
+        let held = self.get(&key).map(|lease| lease.token);
+        let token = match (held, token) {
+            (Some(held), Some(token)) if held == token => token,
+            (Some(_), _) => return Err(StoreError::new(StoreErrorKind::Leased)),
+            (None, Some(_)) => return Err(StoreError::new(StoreErrorKind::NoSuchLease)),
+            (None, None) => rand::random(),
+        };
+        self.remove(&key);
+        let expires_at = Instant::now() + ttl;
+        self.deadlines.schedule(key.clone(), expires_at);
+        self.leases.insert(key, Lease { token, expires_at });
+        Ok(token)
+    }
+
+    /// Releases the lease on `key` if `token` holds it. Returns whether it did.
+    pub fn release(&mut self, key: &[u8], token: u64) -> bool {
+        if self.get(key).is_some_and(|lease| lease.token == token) {
+            self.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether a command carrying `token`, if any, may write to `key`.
+    pub fn allows(&self, key: &[u8], token: Option<u64>) -> bool {
+        self.get(key).is_none_or(|lease| Some(lease.token) == token)
+    }
+
+    /// Removes up to `limit` expired leases. Returns how many it removed.
+    pub fn remove_expired(&mut self, limit: usize) -> usize {
+        let due = self.deadlines.due(Instant::now(), limit);
+        for key in due.iter() {
+            self.remove(key);
+        }
+        due.len()
+    }
+
+    /// The leases that have not expired, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Lease)> {
+        let now = Instant::now();
+        self.leases
+            .iter()
+            .filter(move |(_, lease)| lease.expires_at > now)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((key, lease)) = self.leases.remove_entry(key) {
+            self.deadlines.cancel(&key, lease.expires_at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        assert_eq!("00000000000000ff", format_token(255));
+        assert_eq!(Some(255), parse_token(b"00000000000000ff"));
+        assert_eq!(Some(u64::MAX), parse_token(b"ffffffffffffffff"));
+        assert_eq!(None, parse_token(b"ff"));
+        assert_eq!(None, parse_token(b"00000000000000fg"));
+    }
+
+    #[test]
+    fn test_leases() {
+        let mut leases = Leases::new();
+        let ttl = Duration::from_secs(10);
+        let token = leases.acquire(b"k".to_vec(), ttl, None).unwrap();
+        assert!(leases.allows(b"k", Some(token)));
+        assert!(!leases.allows(b"k", None));
+        assert!(!leases.allows(b"k", Some(token.wrapping_add(1))));
+        assert!(leases.allows(b"other", None));
+
+        let err = leases.acquire(b"k".to_vec(), ttl, None).unwrap_err();
+        assert_eq!(StoreErrorKind::Leased, *err.kind());
+        assert_eq!(
+            Ok(token),
+            leases.acquire(b"k".to_vec(), ttl * 2, Some(token))
+        );
+        assert_eq!(1, leases.iter().count());
+
+        assert!(!leases.release(b"k", token.wrapping_add(1)));
+        assert!(leases.release(b"k", token));
+        assert!(leases.allows(b"k", None));
+        let err = leases.acquire(b"k".to_vec(), ttl, Some(token)).unwrap_err();
+        assert_eq!(StoreErrorKind::NoSuchLease, *err.kind());
+    }
+
+    #[test]
+    fn test_expired_leases() {
+        let mut leases = Leases::new();
+        leases
+            .acquire(b"k".to_vec(), Duration::from_millis(0), None)
+            .unwrap();
+        assert!(leases.get(b"k").is_none());
+        assert!(leases.allows(b"k", None));
+        assert_eq!(0, leases.iter().count());
+        assert!(leases
+            .acquire(b"k".to_vec(), Duration::from_secs(10), None)
+            .is_ok());
+
+        leases
+            .acquire(b"j".to_vec(), Duration::from_millis(0), None)
+            .unwrap();
+        assert_eq!(1, leases.remove_expired(10));
+        assert_eq!(0, leases.remove_expired(10));
+        assert!(leases.get(b"k").is_some());
+    }
+}
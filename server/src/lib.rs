@@ -1,6 +1,21 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod aof;
+pub mod clock;
+pub mod cluster;
 pub mod command;
+pub mod config;
+pub mod evict;
+pub mod expire;
+pub mod lease;
+pub mod memory;
+pub mod migrate;
+pub mod rdb;
+pub mod replication;
+pub mod scan;
+pub mod snapshot;
 pub mod store;
+pub mod stream;
 pub mod transaction;
+pub mod zset;
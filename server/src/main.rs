@@ -1,29 +1,95 @@
 use async_std::{
-    io::BufReader,
+    io::{self, BufReader},
     net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     prelude::*,
     sync::{Arc, RwLock},
     task,
 };
-use std::convert::TryFrom;
-use time::PrimitiveDateTime;
+use std::time::Instant;
 
 #[macro_use]
 extern crate lazy_static;
 
+use kyev::aof::{self, Aof, Argv};
+use kyev::clock;
+use kyev::cluster::{self, Cluster, Gossip, SetSlot};
 use kyev::command::{self, Action, Command, CommandOpt};
-use kyev::store::{self, Expiration, Store, TTL};
-use kyev::transaction::Transaction;
+use kyev::config::Config;
+use kyev::evict::AccessStats;
+use kyev::expire;
+use kyev::lease;
+use kyev::memory::{self, CountingAllocator, MEMORY_SAMPLES};
+use kyev::migrate::{self, Migration};
+use kyev::rdb;
+use kyev::replication::{self, Psync, Replication, Role};
+use kyev::scan;
+use kyev::snapshot;
+use kyev::store::{self, Db, ListEnd, SetOp, Store, StoreError, StoreErrorKind, TTL};
+use kyev::stream::{ClaimOptions, EntryRef, Fields, GroupReadFrom, StreamId, XAddId};
+use kyev::transaction::{InteractiveTransaction, LockOutcome, Transaction};
+use kyev::zset::{self, AddOutcome, LexBound, ScoreBound, ZAddOptions};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 lazy_static! {
-    static ref STORE: RwLock<Store> = RwLock::new(Store::new());
+    static ref CONFIG: Config = Config::from_args();
+    static ref STORE: RwLock<Store> = {
+        let mut store = Store::with_databases(CONFIG.databases);
+        store.set_maxmemory(CONFIG.maxmemory, CONFIG.maxmemory_policy);
+        store.set_memory_accounting(CONFIG.memory_accounting);
+        RwLock::new(store)
+    };
+    /// The append-only file, when `--appendonly yes`. Only written while the store is
+    /// locked for writing, or read-locked to start a rewrite.
+    static ref AOF: std::sync::Mutex<Option<Aof>> = std::sync::Mutex::new(None);
+    /// Only locked after the store, when both are needed, and never across an await.
+    static ref REPLICATION: std::sync::Mutex<Replication> =
+        std::sync::Mutex::new(Replication::new(CONFIG.repl_backlog_size));
+    /// This node's view of the cluster, when `--cluster-enabled yes`. Only locked after the
+    /// store, when both are needed, and never across an await.
+    static ref CLUSTER: std::sync::Mutex<Cluster> =
+        std::sync::Mutex::new(Cluster::new(HOST, CONFIG.port, CONFIG.cluster_port));
 }
 
+/// The address the server listens on, for clients and for the cluster bus.
+const HOST: &str = "127.0.0.1";
+
 fn main() -> Result<()> {
-    let fut = accept_loop("127.0.0.1:8080");
-    println!("Listening on port 8080");
+    lazy_static::initialize(&CONFIG);
+    if let Some(path) = &CONFIG.import_rdb {
+        import_rdb(path)?;
+        if CONFIG.appendonly {
+            create_aof(&task::block_on(STORE.read()))?;
+        }
+    } else if CONFIG.appendonly {
+        load_aof()?;
+    } else {
+        load_snapshot()?;
+    }
+    if CONFIG.appendonly {
+        std::thread::spawn(aof_fsync_loop);
+    }
+    task::spawn(active_expire_loop());
+    task::spawn(replication_ping_loop());
+    if let Some((host, port)) = CONFIG.replicaof.clone() {
+        let generation = REPLICATION
+            .lock()
+            .unwrap()
+            .replicate_from(host.clone(), port);
+        task::spawn(replica_loop(host, port, generation));
+    }
+    if !CONFIG.save.is_empty() {
+        task::spawn(save_rules_loop());
+    }
+    if CONFIG.cluster_enabled {
+        spawn_and_log_error(cluster_bus_loop());
+        task::spawn(cluster_gossip_loop());
+    }
+    let fut = accept_loop((HOST, CONFIG.port));
+    println!("Listening on port {}", CONFIG.port);
     task::block_on(fut)
 }
 
@@ -50,105 +116,204 @@ where
     })
 }
 
-type WatchKey = (String, PrimitiveDateTime);
+/// How many bytes a connection reads from its client at a time.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// A watched key: its database index, the key and when the watch started.
+type WatchKey = (usize, Vec<u8>, Instant);
 
 async fn connection_loop(client_addr: SocketAddr, stream: TcpStream) -> Result<()> {
     let stream = Arc::new(stream);
     let mut reader = BufReader::new(&*stream);
-    let mut string_buf = String::new();
+    let mut buf = Vec::new();
     let mut transaction: Option<Transaction> = None;
+    let mut interactive: Option<InteractiveTransaction> = None;
     let mut watch: Vec<WatchKey> = Vec::new();
+    let mut db = 0;
+    // Whether the client sent ASKING, which only lasts until its next command.
+    let mut asking = false;
     let client_id = {
         let mut store = STORE.write().await;
         store.add_client(client_addr)
     };
 
-    while let Ok(bytes_read) = reader.read_line(&mut string_buf).await {
-        if bytes_read == 0 {
-            break;
-        }
+    // Decodes once per read of up to READ_CHUNK bytes rather than once per line, so a large
+    // bulk string full of newlines is not decoded over and over as it arrives.
+    let mut chunk = vec![0; READ_CHUNK];
+    loop {
+        let value = match resp::decode_prefix(&buf) {
+            Ok((value, len)) => {
+                buf.drain(..len);
+                value
+            }
+            Err(resp::Error::IncompleteRespError) => match reader.read(&mut chunk).await {
+                Ok(read) if read > 0 => {
+                    buf.extend_from_slice(&chunk[..read]);
+                    continue;
+                }
+                _ => break,
+            },
+            _ => {
+                println!("{}", String::from_utf8_lossy(&buf));
+                println!("Invalid resp!");
+                buf.clear();
+                continue;
+            }
+        };
 
-        match resp::decode(&string_buf) {
-            Ok(value) => {
-                let response = match Command::from_resp(value) {
-                    Ok(mut cmd) => match cmd.action() {
-                        Action::ClientId => resp::integer(client_id as i64),
-                        Action::Multi => {
-                            if let None = transaction {
-                                transaction = Some(Transaction::new());
-                            }
-                            resp::simple_string("OK")
-                        }
-                        Action::Exec => {
-                            if let Some(trx) = transaction.take() {
-                                let value = execute_transaction(trx, &watch).await;
-                                watch.clear();
-                                value
-                            } else {
-                                resp::Value::Null
-                            }
-                        }
-                        Action::Discard => {
-                            if transaction.is_some() {
-                                transaction.take();
-                                resp::simple_string("OK")
-                            } else {
-                                resp::Value::Null
-                            }
-                        }
-                        Action::Watch => {
-                            for key_to_watch in cmd
-                                .args_mut()
-                                .drain(..)
-                                .map(|key| (key, PrimitiveDateTime::now()))
-                            {
-                                watch.push(key_to_watch);
-                            }
-                            resp::simple_string("OK")
-                        }
-                        Action::Unwatch => {
-                            watch.clear();
+        let asked = std::mem::take(&mut asking);
+        let parsed = Command::from_resp(value);
+        let redirect = match &parsed {
+            Ok(cmd) if CONFIG.cluster_enabled => cluster_redirect(cmd, db, asked).await,
+            _ => None,
+        };
+        let response = match (parsed, redirect) {
+            (Ok(_), Some(redirect)) => {
+                if let Some(trx) = transaction.as_mut() {
+                    trx.set_error();
+                }
+                redirect
+            }
+            (Ok(mut cmd), None) => match cmd.action() {
+                Action::ClientId => resp::integer(client_id as i64),
+                Action::Asking => {
+                    if CONFIG.cluster_enabled {
+                        asking = true;
+                        resp::simple_string("OK")
+                    } else {
+                        store_error(StoreError::new(StoreErrorKind::ClusterDisabled))
+                    }
+                }
+                Action::Begin => {
+                    if transaction.is_some() || interactive.is_some() {
+                        resp::error("ERR BEGIN inside a transaction is not allowed")
+                    } else {
+                        let deadline = Instant::now() + CONFIG.transaction_timeout;
+                        STORE
+                            .write()
+                            .await
+                            .key_locks_mut()
+                            .begin(client_id, deadline);
+                        interactive = Some(InteractiveTransaction::new(CONFIG.databases));
+                        resp::simple_string("OK")
+                    }
+                }
+                Action::Commit => match interactive.take() {
+                    Some(txn) => execute_commit(txn, client_id).await,
+                    None => resp::error("ERR COMMIT without BEGIN"),
+                },
+                Action::Rollback => match interactive.take() {
+                    Some(_) => {
+                        STORE.write().await.key_locks_mut().end(client_id);
+                        resp::simple_string("OK")
+                    }
+                    None => resp::error("ERR ROLLBACK without BEGIN"),
+                },
+                _ if interactive.is_some() => {
+                    let txn = interactive.as_mut().unwrap();
+                    let (reply, ended) = execute_interactive(txn, client_id, &mut db, cmd).await;
+                    if ended {
+                        interactive = None;
+                    }
+                    reply
+                }
+                Action::Multi => {
+                    if transaction.is_none() {
+                        transaction = Some(Transaction::new());
+                    }
+                    resp::simple_string("OK")
+                }
+                Action::Exec => {
+                    if let Some(trx) = transaction.take() {
+                        let value = if trx.has_error() {
+                            resp::error(
+                                "EXECABORT Transaction discarded because of previous errors.",
+                            )
+                        } else {
+                            execute_transaction(trx, &watch, &mut db).await
+                        };
+                        watch.clear();
+                        value
+                    } else {
+                        resp::Value::Null
+                    }
+                }
+                Action::Discard => {
+                    if transaction.is_some() {
+                        transaction.take();
+                        resp::simple_string("OK")
+                    } else {
+                        resp::Value::Null
+                    }
+                }
+                Action::Watch => {
+                    for key_to_watch in cmd
+                        .args_mut()
+                        .drain(..)
+                        .map(|key| (db, key, Instant::now()))
+                    {
+                        watch.push(key_to_watch);
+                    }
+                    resp::simple_string("OK")
+                }
+                Action::Unwatch => {
+                    watch.clear();
+                    resp::simple_string("OK")
+                }
+                Action::PSync if transaction.is_none() => {
+                    if let Err(e) = serve_replica(&stream, client_addr, client_id, &cmd).await {
+                        eprintln!("Replica {} disconnected: {}", client_addr, e);
+                    }
+                    break;
+                }
+                Action::Select if transaction.is_none() => {
+                    let store = STORE.read().await;
+                    match select_db(&store, &cmd) {
+                        Ok(index) => {
+                            db = index;
                             resp::simple_string("OK")
                         }
-                        _ => {
-                            if let Some(mut trx) = transaction.take() {
-                                trx.push(cmd);
-                                transaction = Some(trx);
-                                resp::simple_string("QUEUED")
-                            } else {
-                                if let Some(lock) = cmd.lock() {
-                                    match lock {
-                                        command::Lock::Read => {
-                                            let store = STORE.read().await;
-                                            execute_read_cmd(&store, cmd)
-                                        }
-                                        command::Lock::Write => {
-                                            let mut store = STORE.write().await;
-                                            execute_write_cmd(&mut store, cmd)
-                                        }
-                                    }
-                                } else {
-                                    execute_cmd(cmd)
+                        Err(e) => store_error(e),
+                    }
+                }
+                _ => {
+                    if let Some(mut trx) = transaction.take() {
+                        trx.push(cmd);
+                        transaction = Some(trx);
+                        resp::simple_string("QUEUED")
+                    } else if let Some(timeout) = block_timeout(&cmd) {
+                        execute_blocking(cmd, db, timeout).await
+                    } else {
+                        if let Some(lock) = cmd.lock() {
+                            match lock {
+                                command::Lock::Read => {
+                                    let store = STORE.read().await;
+                                    execute_read_cmd(&store, db, cmd)
+                                }
+                                command::Lock::Write if *cmd.action() == Action::Migrate => {
+                                    execute_migrate(db, cmd).await
+                                }
+                                command::Lock::Write => {
+                                    let mut store = STORE.write().await;
+                                    execute_and_propagate(&mut store, db, cmd)
                                 }
                             }
+                        } else {
+                            execute_cmd(cmd)
                         }
-                    },
-                    Err(e) => {
-                        let msg = format!("{}", e);
-                        resp::error(msg.as_str())
                     }
-                };
-                let mut stream = &*stream;
-                stream.write_all(resp::encode(&response).as_bytes()).await?;
-                string_buf.clear();
-            }
-            Err(resp::Error::IncompleteRespError) => continue,
-            _ => {
-                println!("{}", string_buf);
-                println!("Invalid resp!");
-                string_buf.clear();
+                }
+            },
+            (Err(e), _) => {
+                if let Some(trx) = transaction.as_mut() {
+                    trx.set_error();
+                }
+                let msg = format!("{}", e);
+                resp::error(msg.as_str())
             }
-        }
+        };
+        let mut stream = &*stream;
+        stream.write_all(&resp::encode(&response)).await?;
     }
 
     STORE.write().await.remove_client(client_id);
@@ -157,245 +322,3125 @@ async fn connection_loop(client_addr: SocketAddr, stream: TcpStream) -> Result<(
     Ok(())
 }
 
-async fn execute_transaction(mut trx: Transaction, watch: &Vec<WatchKey>) -> resp::Value {
+/// How often a blocked stream read re-checks the store for new entries.
+const BLOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+fn lease_token(cmd: &Command) -> Option<u64> {
+    cmd.opts().iter().find_map(|opt| match opt {
+        CommandOpt::LeaseToken(token) => Some(*token),
+        _ => None,
+    })
+}
+
+fn block_timeout(cmd: &Command) -> Option<u64> {
+    cmd.opts().iter().find_map(|opt| match opt {
+        CommandOpt::XBlock(timeout) => Some(*timeout),
+        _ => None,
+    })
+}
+
+/// Re-runs a blocking XREAD or XREADGROUP until it returns entries or `timeout_ms` elapses.
+/// A timeout of 0 blocks indefinitely.
+async fn execute_blocking(mut cmd: Command, db_index: usize, timeout_ms: u64) -> resp::Value {
+    if *cmd.action() == Action::XRead {
+        let store = STORE.read().await;
+        resolve_last_ids(store.db(db_index), &mut cmd);
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(timeout_ms);
+    loop {
+        let response = match cmd.lock() {
+            Some(command::Lock::Write) => {
+                let mut store = STORE.write().await;
+                execute_and_propagate(&mut store, db_index, cmd.clone())
+            }
+            _ => {
+                let store = STORE.read().await;
+                execute_read_cmd(&store, db_index, cmd.clone())
+            }
+        };
+        let timed_out = timeout_ms > 0 && std::time::Instant::now() >= deadline;
+        if response != resp::Value::Null || timed_out {
+            return response;
+        }
+        task::sleep(BLOCK_POLL_INTERVAL).await;
+    }
+}
+
+async fn execute_transaction(
+    mut trx: Transaction,
+    watch: &[WatchKey],
+    db_index: &mut usize,
+) -> resp::Value {
     let mut store = STORE.write().await;
 
-    for (key, watch_start) in watch.iter() {
-        if let Some(last_touched) = store.last_touched(key) {
+    for (db, key, watch_start) in watch.iter() {
+        if let Some(last_touched) = store.db(*db).last_touched(key) {
             if last_touched >= watch_start {
                 return resp::Value::Null;
             }
         }
     }
 
-    let results: Vec<resp::Value> = trx
-        .drain_queue()
-        .map(move |cmd| {
-            if let Some(lock) = cmd.lock() {
-                match lock {
-                    command::Lock::Read => execute_read_cmd(&store, cmd),
-                    command::Lock::Write => execute_write_cmd(&mut store, cmd),
+    let mut log = vec![];
+    let mut results = vec![];
+    for cmd in trx.drain_queue() {
+        let result = if *cmd.action() == Action::Select {
+            match select_db(&store, &cmd) {
+                Ok(index) => {
+                    *db_index = index;
+                    resp::simple_string("OK")
                 }
-            } else {
-                execute_cmd(cmd)
+                Err(e) => store_error(e),
             }
-        })
-        .collect();
+        } else if let Some(lock) = cmd.lock() {
+            match lock {
+                command::Lock::Read => execute_read_cmd(&store, *db_index, cmd),
+                command::Lock::Write if *cmd.action() == Action::Migrate => {
+                    execute_migrate_locked(&mut store, *db_index, cmd, &mut log).await
+                }
+                command::Lock::Write => execute_write_cmd(&mut store, *db_index, cmd, &mut log),
+            }
+        } else {
+            execute_cmd(cmd)
+        };
+        results.push(result);
+    }
+    propagate(&log, true);
 
     resp::array(results)
 }
 
+const TRANSACTION_TIMED_OUT: &str = "ERR Transaction timed out and was rolled back";
+
+/// Runs a command in an interactive transaction once the keys it touches are locked,
+/// waiting while another transaction holds them. Returns the reply and whether the
+/// transaction ended, as it does when it times out or would deadlock.
+async fn execute_interactive(
+    txn: &mut InteractiveTransaction,
+    client_id: usize,
+    db_index: &mut usize,
+    cmd: Command,
+) -> (resp::Value, bool) {
+    match cmd.action() {
+        Action::Ping | Action::Echo => return (execute_cmd(cmd), false),
+        Action::Select => {
+            let store = STORE.read().await;
+            let reply = match select_db(&store, &cmd) {
+                Ok(index) => {
+                    *db_index = index;
+                    resp::simple_string("OK")
+                }
+                Err(e) => store_error(e),
+            };
+            return (reply, false);
+        }
+        _ => (),
+    }
+    let keys = match cmd.keys() {
+        Some(keys) if block_timeout(&cmd).is_none() && *cmd.action() != Action::Migrate => keys,
+        _ => {
+            let msg = format!(
+                "ERR '{}' is not allowed in a transaction started with BEGIN",
+                cmd
+            );
+            return (resp::error(&msg), false);
+        }
+    };
+
+    let store = loop {
+        let mut store = STORE.write().await;
+        match store
+            .key_locks_mut()
+            .lock(client_id, *db_index, &keys, Instant::now())
+        {
+            LockOutcome::Acquired => break store,
+            LockOutcome::Wait => (),
+            LockOutcome::Deadlock => {
+                return (
+                    resp::error("DEADLOCK Deadlock detected, the transaction was rolled back"),
+                    true,
+                )
+            }
+            LockOutcome::Ended => return (resp::error(TRANSACTION_TIMED_OUT), true),
+        }
+        drop(store);
+        task::sleep(BLOCK_POLL_INTERVAL).await;
+    };
+    txn.copy_in(&store, *db_index, &keys);
+
+    let reply = if cmd.lock() == Some(command::Lock::Write) {
+        let token = lease_token(&cmd);
+        if let Err(e) = store.check_write_leases(*db_index, &cmd, token) {
+            return (store_error(e), false);
+        }
+        let mut log = vec![];
+        let reply = execute_write_cmd(txn.workspace_mut(), *db_index, cmd, &mut log);
+        txn.buffer(log.into_iter().map(|(db, argv)| (db, argv, token)));
+        reply
+    } else {
+        execute_read_cmd(txn.workspace(), *db_index, cmd)
+    };
+    (reply, false)
+}
+
+/// Applies the writes of an interactive transaction to the store all at once, releasing
+/// its locks, and logs them as one transaction. Nothing is applied if a lease now holds
+/// one of the keys written, or if memory cannot be freed for them.
+async fn execute_commit(mut txn: InteractiveTransaction, client_id: usize) -> resp::Value {
+    let mut store = STORE.write().await;
+    if !store.key_locks_mut().is_active(client_id, Instant::now()) {
+        return resp::error(TRANSACTION_TIMED_OUT);
+    }
+    store.key_locks_mut().end(client_id);
+
+    if REPLICATION.lock().unwrap().is_replica() {
+        return resp::error("READONLY You can't write against a read only replica.");
+    }
+    let taken = txn.take_commands(&mut store);
+    let mut log = vec![];
+    log_removed(&mut store, &mut log);
+    let cmds = match taken {
+        Ok(cmds) => cmds,
+        Err(e) => {
+            propagate(&log, false);
+            return store_error(e);
+        }
+    };
+    // Every check was made up front, so the writes are applied all together or not at all.
+    let mut error = None;
+    for (db, cmd) in cmds {
+        if let reply @ resp::Value::Error(_) = apply_and_log(&mut store, db, cmd, &mut log) {
+            error.get_or_insert(reply);
+        }
+    }
+    propagate(&log, true);
+    error.unwrap_or_else(|| resp::simple_string("OK"))
+}
+
 fn execute_cmd(cmd: Command) -> resp::Value {
     use kyev::command::Action::*;
     match cmd.action() {
         Ping => {
             if let Some(arg) = cmd.args().first() {
-                resp::bulk_string(&arg)
+                resp::bulk_string(arg)
             } else {
                 resp::simple_string("PONG")
             }
         }
 
-        Echo => resp::bulk_string(&cmd.args().first().unwrap_or(&String::new())),
+        Echo => resp::bulk_string(cmd.args().first().map_or(&b""[..], |arg| arg)),
+        ReplicaOf => execute_replicaof(cmd),
+        PSync => resp::error("ERR PSYNC is not allowed in a transaction"),
+        ClusterKeySlot => resp::integer(cluster::key_slot(&cmd.args()[0]) as i64),
+        _ if !CONFIG.cluster_enabled => {
+            store_error(StoreError::new(StoreErrorKind::ClusterDisabled))
+        }
+        ClusterInfo => execute_cluster_info(),
+        ClusterMyId => resp::bulk_string(&CLUSTER.lock().unwrap().myself().id),
+        ClusterMeet => execute_cluster_meet(cmd),
+        ClusterNodes => resp::bulk_string(&CLUSTER.lock().unwrap().describe_nodes()),
+        ClusterSlots => execute_cluster_slots(),
+        ClusterShards => execute_cluster_shards(),
+        ClusterAddSlots => execute_cluster_addslots(cmd),
         _ => panic!("Command '{}' requires store access", cmd),
     }
 }
 
-fn execute_read_cmd(store: &Store, cmd: Command) -> resp::Value {
+fn execute_read_cmd(store: &Store, db_index: usize, cmd: Command) -> resp::Value {
     use kyev::command::Action::*;
 
     match cmd.action() {
-        Get => execute_get(store, cmd),
-        Ttl => execute_ttl(store, cmd),
-        ClientList => execute_client_list(store),
-        MemoryUsage => resp::integer(store.mem_usage() as i64),
+        ClientList => return execute_client_list(store),
+        Info => return execute_info(store, cmd),
+        BgSave => return execute_bgsave(store),
+        LastSave => return resp::integer(store.last_save() as i64),
+        BgRewriteAof => return execute_bgrewriteaof(store),
+        Sync => return execute_sync(store),
+        MemoryStats => return execute_memory_stats(store),
+        MemoryDoctor => {
+            return resp::bulk_string(&memory::doctor(&store.memory_stats(), store.maxmemory()))
+        }
+        ClusterSetSlot | ClusterCountKeysInSlot | ClusterGetKeysInSlot
+            if !CONFIG.cluster_enabled =>
+        {
+            return store_error(StoreError::new(StoreErrorKind::ClusterDisabled))
+        }
+        _ => (),
+    }
+
+    let db = store.db(db_index);
+    match cmd.action() {
+        Get => execute_get(db, cmd),
+        StrLen => execute_strlen(db, cmd),
+        MGet => execute_mget(db, cmd),
+        Exists | Touch => resp::integer(db.exists(cmd.args()) as i64),
+        Type => resp::simple_string(db.key_type(&cmd.args()[0])),
+        Dump => execute_dump(db, cmd),
+        Leases => execute_leases(db),
+        RandomKey => match db.random_key() {
+            Some(key) => resp::bulk_string(key),
+            None => resp::Value::Null,
+        },
+        DbSize => resp::integer(db.dbsize() as i64),
+        MemoryUsage => execute_memory_usage(db, cmd),
+        Keys => bulk_string_array(db.keys(&cmd.args()[0])),
+        Scan => execute_scan(db, cmd),
+        SScan => execute_sscan(db, cmd),
+        HScan => execute_hscan(db, cmd),
+        ZScan => execute_zscan(db, cmd),
+        GetRange => execute_getrange(db, cmd),
+        Ttl => execute_ttl(db, cmd, 1000, false),
+        PTtl => execute_ttl(db, cmd, 1, false),
+        ExpireTime => execute_ttl(db, cmd, 1000, true),
+        PExpireTime => execute_ttl(db, cmd, 1, true),
+        LRange => execute_lrange(db, cmd),
+        LIndex => execute_lindex(db, cmd),
+        LLen => execute_llen(db, cmd),
+        HGet => execute_hget(db, cmd),
+        HMGet => execute_hmget(db, cmd),
+        HGetAll => execute_hgetall(db, cmd),
+        HKeys => execute_hkeys(db, cmd),
+        HVals => execute_hvals(db, cmd),
+        HLen => execute_hlen(db, cmd),
+        HExists => execute_hexists(db, cmd),
+        SMembers => execute_smembers(db, cmd),
+        SIsMember => execute_sismember(db, cmd),
+        SCard => execute_scard(db, cmd),
+        SRandMember => execute_srandmember(db, cmd),
+        SInter => execute_set_op(db, cmd, SetOp::Inter),
+        SUnion => execute_set_op(db, cmd, SetOp::Union),
+        SDiff => execute_set_op(db, cmd, SetOp::Diff),
+        ZRange => execute_zrange(db, cmd),
+        ZRank => execute_zrank(db, cmd),
+        ZScore => execute_zscore(db, cmd),
+        XRange => execute_xrange(db, cmd, false),
+        XRevRange => execute_xrange(db, cmd, true),
+        XLen => execute_xlen(db, cmd),
+        XRead => execute_xread(db, cmd),
+        XPending => execute_xpending(db, cmd),
+        ClusterSetSlot => execute_cluster_setslot(db, cmd),
+        ClusterCountKeysInSlot => {
+            let slot = cluster::parse_slot(&cmd.args()[0]).unwrap();
+            resp::integer(db.keys_in_slot(slot).count() as i64)
+        }
+        ClusterGetKeysInSlot => {
+            let slot = cluster::parse_slot(&cmd.args()[0]).unwrap();
+            let count = parse_arg::<usize>(&cmd.args()[1]);
+            bulk_string_array(db.keys_in_slot(slot).take(count).collect())
+        }
         _ => panic!("Command '{}' should be executed with write access", cmd),
     }
 }
 
-fn execute_write_cmd(store: &mut Store, cmd: Command) -> resp::Value {
-    use kyev::command::Action::*;
+/// Runs a write command outside a transaction and logs what it changed.
+fn execute_and_propagate(store: &mut Store, db_index: usize, cmd: Command) -> resp::Value {
+    let mut log = vec![];
+    let reply = execute_write_cmd(store, db_index, cmd, &mut log);
+    propagate(&log, false);
+    reply
+}
 
-    match cmd.action() {
-        Set => execute_set(store, cmd),
-        SetEx => execute_setex(store, cmd),
-        SetNx => execute_setnx(store, cmd),
-        Expire => execute_expire(store, cmd, false),
-        PExpire => execute_expire(store, cmd, true),
-        _ => panic!("Command '{}' should be executed with read access", cmd),
+/// Runs a write command once memory use is back within maxmemory, evicting keys if the
+/// policy allows, and accounts for the memory it used. Adds commands that replay what it
+/// changed to `log`, each with the database it runs against.
+fn execute_write_cmd(
+    store: &mut Store,
+    db_index: usize,
+    cmd: Command,
+    log: &mut Vec<(usize, Argv)>,
+) -> resp::Value {
+    if let Err(reply) = check_write_cmd(store, db_index, &cmd) {
+        return reply;
+    }
+    let evicted = store.evict();
+    log_removed(store, log);
+    if let Err(e) = evicted {
+        if cmd.action().grows_dataset() {
+            return store_error(e);
+        }
     }
+    apply_and_log(store, db_index, cmd, log)
 }
 
-async fn create_expiration_task(ttl: std::time::Duration, key: String) {
-    task::sleep(ttl).await;
-    let mut store = STORE.write().await;
-    if let TTL::Expires(ttl) = store.ttl(&key) {
-        if ttl > 0 {
-            return;
-        }
+/// Runs a write command without checking it may, and adds commands that replay what it
+/// changed to `log`.
+fn apply_and_log(
+    store: &mut Store,
+    db_index: usize,
+    mut cmd: Command,
+    log: &mut Vec<(usize, Argv)>,
+) -> resp::Value {
+    let action = *cmd.action();
+    let argv = cmd.take_argv();
+    let changes = store.changes();
+    let reply = apply_write_cmd(store, db_index, cmd);
+    store.settle_memory();
+    // Keys that expired as the command touched them were removed before it ran.
+    log_removed(store, log);
+    if store.changes() > changes {
+        let replay = replay_argvs(store.db(db_index), action, argv, &reply);
+        log.extend(replay.into_iter().map(|argv| (db_index, argv)));
     }
-    store.remove(&key);
+    reply
 }
 
-fn execute_set(store: &mut Store, mut cmd: Command) -> resp::Value {
-    let key: String;
-    let val: String;
-    {
-        let mut drain = cmd.drain_args();
-        key = drain.next().unwrap();
-        val = drain.next().unwrap();
+/// Turns a write away with the error to reply with if this is a replica, or a lease or a
+/// transaction started with BEGIN holds a key it writes.
+fn check_write_cmd(
+    store: &mut Store,
+    db_index: usize,
+    cmd: &Command,
+) -> std::result::Result<(), resp::Value> {
+    check_write_access(store, db_index, cmd)?;
+    store.check_write_locks(db_index, cmd).map_err(store_error)
+}
+
+/// The checks of `check_write_cmd` that need no more than a read lock: the replica and
+/// lease ones.
+fn check_write_access(
+    store: &Store,
+    db_index: usize,
+    cmd: &Command,
+) -> std::result::Result<(), resp::Value> {
+    if *cmd.action() != Action::Save && REPLICATION.lock().unwrap().is_replica() {
+        return Err(resp::error(
+            "READONLY You can't write against a read only replica.",
+        ));
     }
+    store
+        .check_write_leases(db_index, cmd, lease_token(cmd))
+        .map_err(store_error)
+}
 
-    let mut maybe_ttl = None;
-    let mut keep_ttl = false;
-    let mut xx = false;
-    let mut nx = false;
-    for opt in cmd.opts().iter() {
-        match opt {
-            CommandOpt::SetEx(ttl_sec) => maybe_ttl = Some(ttl_sec * 1000),
-            CommandOpt::SetPx(ttl_ms) => maybe_ttl = Some(*ttl_ms),
-            CommandOpt::SetKeepTtl => keep_ttl = true,
-            CommandOpt::SetXx => xx = true,
-            CommandOpt::SetNx => nx = true,
-            // _ => continue,
-        };
+/// Logs the keys the store removed because they expired or were evicted as DELs.
+fn log_removed(store: &mut Store, log: &mut Vec<(usize, Argv)>) {
+    log.extend(
+        store
+            .take_removed()
+            .into_iter()
+            .map(|(db, key)| (db, vec![b"DEL".to_vec(), key])),
+    );
+}
+
+/// Appends logged commands to the append-only file, if it is on, and to the replication
+/// stream. Called with the store still locked, so that both record changes in the order
+/// they were made.
+fn propagate(log: &[(usize, Argv)], transaction: bool) {
+    if log.is_empty() {
+        return;
     }
+    append_aof(log, transaction);
+    REPLICATION.lock().unwrap().feed(log, transaction);
+}
 
-    if xx {
-        if let Some(_) = store.get(&key) {
-            store.set(key.clone(), val, keep_ttl);
-            if let Some(ttl) = maybe_ttl {
-                let join_handle = task::spawn(create_expiration_task(
-                    std::time::Duration::from_millis(ttl),
-                    key.clone(),
-                ));
-                store.expire(
-                    &key,
-                    Expiration::new(time::Duration::milliseconds(ttl as i64), join_handle),
-                );
-            }
-            return resp::integer(1);
-        } else {
-            return resp::integer(0);
+fn append_aof(log: &[(usize, Argv)], transaction: bool) {
+    if log.is_empty() {
+        return;
+    }
+    if let Some(aof) = AOF.lock().unwrap().as_mut() {
+        if let Err(e) = aof.append(log, transaction) {
+            eprintln!("Failed to write the append-only file: {}", e);
         }
     }
+}
 
-    if nx {
-        if let None = store.get(&key) {
-            store.set(key.clone(), val, keep_ttl);
-            if let Some(ttl) = maybe_ttl {
-                let join_handle = task::spawn(create_expiration_task(
-                    std::time::Duration::from_millis(ttl),
-                    key.clone(),
-                ));
-                store.expire(
-                    &key,
-                    Expiration::new(time::Duration::milliseconds(ttl as i64), join_handle),
-                );
+/// The commands that redo what a write command did, however the clock and random choices
+/// turn out on replay: relative TTLs become Unix times, and random picks, generated stream
+/// IDs and idle-time claims become what was actually picked, generated and claimed.
+fn replay_argvs(db: &Db, action: Action, mut argv: Argv, reply: &resp::Value) -> Vec<Argv> {
+    use kyev::command::Action::*;
+
+    match action {
+        Set if argv.len() > 3 => {
+            argv.truncate(3);
+            let expiry = expiry_argv(db, &argv[1]);
+            let mut argvs = vec![argv];
+            if expiry[0] == b"PEXPIREAT" {
+                argvs.push(expiry);
             }
-            return resp::integer(1);
-        } else {
-            return resp::integer(0);
+            argvs
         }
+        SetEx => {
+            let value = argv.pop().unwrap();
+            let key = argv.swap_remove(1);
+            let expiry = expiry_argv(db, &key);
+            vec![vec![b"SET".to_vec(), key, value], expiry]
+        }
+        Expire | PExpire | ExpireAt | PExpireAt | GetEx => vec![expiry_argv(db, &argv[1])],
+        SPop => {
+            let members: Vec<Vec<u8>> = match reply {
+                resp::Value::BulkString(member) => vec![member.clone()],
+                resp::Value::Array(members) => members
+                    .iter()
+                    .filter_map(|member| member.as_bytes().ok().map(<[u8]>::to_vec))
+                    .collect(),
+                _ => vec![],
+            };
+            if members.is_empty() {
+                return vec![];
+            }
+            let mut srem = vec![b"SREM".to_vec(), argv.swap_remove(1)];
+            srem.extend(members);
+            vec![srem]
+        }
+        XAdd => {
+            if let resp::Value::BulkString(id) = reply {
+                let index = xadd_id_index(&argv);
+                argv[index] = id.clone();
+            }
+            vec![argv]
+        }
+        Restore => {
+            argv.truncate(4);
+            argv[2] = b"0".to_vec();
+            argv.push(b"REPLACE".to_vec());
+            let expiry = expiry_argv(db, &argv[1]);
+            vec![argv, expiry]
+        }
+        XReadGroup if *reply == resp::Value::Null => vec![],
+        XClaim | XAutoClaim => claim_argvs(db, action, &argv, reply),
+        _ => vec![argv],
     }
-
-    store.set(key.clone(), val, keep_ttl);
-    if let Some(ttl) = maybe_ttl {
-        let join_handle = task::spawn(create_expiration_task(
-            std::time::Duration::from_millis(ttl),
-            key.clone(),
-        ));
-        store.expire(
-            &key,
-            Expiration::new(time::Duration::milliseconds(ttl as i64), join_handle),
-        );
-    }
-    resp::simple_string("OK")
 }
 
-fn execute_setex(store: &mut Store, mut cmd: Command) -> resp::Value {
-    let mut drain = cmd.drain_args();
-    let key = drain.next().unwrap();
-    let ttl = drain.next().unwrap().parse::<i64>().unwrap();
-    let val = drain.next().unwrap();
-    store.set(key.clone(), val, false);
-    let join_handle = task::spawn(create_expiration_task(
-        std::time::Duration::from_secs(ttl as u64),
-        key.clone(),
-    ));
-    store.expire(
-        &key,
-        Expiration::new(time::Duration::seconds(ttl), join_handle),
-    );
-    resp::simple_string("OK")
+/// The command that leaves `key` with the TTL it has now: PEXPIREAT the Unix time it ends,
+/// PERSIST if it has none, or DEL if the key is gone.
+fn expiry_argv(db: &Db, key: &[u8]) -> Argv {
+    match db.ttl(key) {
+        TTL::Expires(ttl_ms) => vec![
+            b"PEXPIREAT".to_vec(),
+            key.to_vec(),
+            expire::ttl_to_unix_ms(ttl_ms).to_string().into_bytes(),
+        ],
+        TTL::NoExpiration => vec![b"PERSIST".to_vec(), key.to_vec()],
+        TTL::KeyNotFound => vec![b"DEL".to_vec(), key.to_vec()],
+    }
 }
 
-fn execute_setnx(store: &mut Store, mut cmd: Command) -> resp::Value {
-    let mut drain = cmd.drain_args();
-    let key = drain.next().unwrap();
-    if let Some(_) = store.get(&key) {
-        resp::integer(0)
-    } else {
-        let val = drain.next().unwrap();
-        store.set(key, val, false);
-        resp::integer(1)
+/// Where the ID is among the arguments of an XADD: after the key and any options.
+fn xadd_id_index(argv: &[Vec<u8>]) -> usize {
+    let mut index = 2;
+    loop {
+        match argv[index].to_ascii_lowercase().as_slice() {
+            b"nomkstream" => index += 1,
+            b"maxlen" | b"minid" => {
+                index += 1;
+                if argv[index] == b"~" || argv[index] == b"=" {
+                    index += 1;
+                }
+                index += 1;
+                if argv[index].eq_ignore_ascii_case(b"limit") {
+                    index += 2;
+                }
+            }
+            _ => return index,
+        }
     }
 }
 
-fn execute_get(store: &Store, cmd: Command) -> resp::Value {
-    let key = cmd.args().first().unwrap();
-    let val = store.get(key);
-    match val {
-        Some(v) => match v {
-            store::Value::Int(i) => resp::bulk_string(i.to_string().as_str()),
-            store::Value::Str(s) => resp::bulk_string(s.as_str()),
+/// XCLAIM and XAUTOCLAIM claim entries that have been idle long enough, which is down to
+/// the clock. They are logged as a forced XCLAIM of each entry claimed, with its delivery
+/// time and count, and an XACK of the entries they dropped from the pending list because
+/// the entries were deleted.
+fn claim_argvs(db: &Db, action: Action, argv: &[Vec<u8>], reply: &resp::Value) -> Vec<Argv> {
+    let reply_ids = |values: &[resp::Value]| -> Vec<StreamId> {
+        values
+            .iter()
+            .filter_map(|value| match value {
+                resp::Value::Array(entry) => entry.first()?.as_bytes().ok(),
+                value => value.as_bytes().ok(),
+            })
+            .filter_map(StreamId::parse)
+            .collect()
+    };
+    let (claimed, candidates) = match (action, reply) {
+        (Action::XClaim, resp::Value::Array(claimed)) => {
+            let ids = argv[5..].iter().map_while(StreamId::parse).collect();
+            (reply_ids(claimed), ids)
+        }
+        (Action::XAutoClaim, resp::Value::Array(parts)) => match parts.as_slice() {
+            [_, resp::Value::Array(claimed), resp::Value::Array(deleted)] => {
+                (reply_ids(claimed), reply_ids(deleted))
+            }
+            _ => return vec![],
         },
-        None => resp::Value::Null,
+        _ => return vec![],
+    };
+
+    let (key, group, consumer) = (&argv[1], &argv[2], &argv[3]);
+    let pending = |id: StreamId| {
+        db.xpending_range(key, group, id, id, 1, None, None)
+            .ok()
+            .and_then(|pending| pending.first().map(|info| (info.idle, info.delivery_count)))
+    };
+    let last_id = argv
+        .iter()
+        .position(|arg| arg.eq_ignore_ascii_case(b"lastid"))
+        .map(|index| argv[index..=index + 1].to_vec());
+
+    let mut argvs = vec![vec![
+        b"XGROUP".to_vec(),
+        b"CREATECONSUMER".to_vec(),
+        key.clone(),
+        group.clone(),
+        consumer.clone(),
+    ]];
+    for id in claimed.iter() {
+        if let Some((idle, delivery_count)) = pending(*id) {
+            let mut claim = vec![
+                b"XCLAIM".to_vec(),
+                key.clone(),
+                group.clone(),
+                consumer.clone(),
+                b"0".to_vec(),
+                id.to_string().into_bytes(),
+                b"TIME".to_vec(),
+                clock::now_ms()
+                    .saturating_sub(idle)
+                    .to_string()
+                    .into_bytes(),
+                b"RETRYCOUNT".to_vec(),
+                delivery_count.to_string().into_bytes(),
+                b"FORCE".to_vec(),
+                b"JUSTID".to_vec(),
+            ];
+            claim.extend(last_id.iter().flatten().cloned());
+            argvs.push(claim);
+        }
+    }
+    let dropped: Vec<Vec<u8>> = candidates
+        .into_iter()
+        .filter(|id| !claimed.contains(id) && pending(*id).is_none())
+        .map(|id| id.to_string().into_bytes())
+        .collect();
+    if !dropped.is_empty() {
+        let mut xack = vec![b"XACK".to_vec(), key.clone(), group.clone()];
+        xack.extend(dropped);
+        argvs.push(xack);
     }
+    argvs
 }
 
-fn execute_expire(store: &mut Store, mut cmd: Command, as_ms: bool) -> resp::Value {
-    let mut drain = cmd.drain_args();
-    let key = drain.next().unwrap();
-    let ttl = drain.next().unwrap().parse::<i64>().unwrap();
+fn apply_write_cmd(store: &mut Store, db_index: usize, cmd: Command) -> resp::Value {
+    use kyev::command::Action::*;
 
-    if ttl < 0 {
-        resp::integer(match store.remove(&key) {
-            Some(_) => 1,
-            None => 0,
-        })
-    } else {
-        let duration = if as_ms {
-            std::time::Duration::from_millis(ttl as u64)
-        } else {
-            std::time::Duration::from_secs(ttl as u64)
+    match cmd.action() {
+        Move => return execute_move(store, db_index, cmd),
+        SwapDb => return execute_swapdb(store, cmd),
+        FlushAll => return execute_flushall(store, cmd),
+        Save => return execute_save(store),
+        _ => (),
+    }
+
+    let db = store.db_mut(db_index);
+    match cmd.action() {
+        FlushDb => execute_flushdb(db, cmd),
+        Set => execute_set(db, cmd),
+        SetEx => execute_setex(db, cmd),
+        SetNx => execute_setnx(db, cmd),
+        Incr => execute_incr_by(db, cmd, 1),
+        Decr => execute_incr_by(db, cmd, -1),
+        IncrBy => execute_incr_by(db, cmd, 1),
+        DecrBy => execute_incr_by(db, cmd, -1),
+        IncrByFloat => execute_incrbyfloat(db, cmd),
+        Append => execute_append(db, cmd),
+        SetRange => execute_setrange(db, cmd),
+        GetSet => execute_getset(db, cmd),
+        GetDel => execute_getdel(db, cmd),
+        GetEx => execute_getex(db, cmd),
+        MSet => execute_mset(db, cmd),
+        MSetNx => execute_msetnx(db, cmd),
+        Del | Unlink => resp::integer(db.del(cmd.args()) as i64),
+        Rename => execute_rename(db, cmd, false),
+        RenameNx => execute_rename(db, cmd, true),
+        Copy => execute_copy(db, cmd),
+        Restore => execute_restore(db, cmd),
+        Lease => execute_lease(db, cmd),
+        Release => {
+            let token = lease::parse_token(&cmd.args()[1]).unwrap();
+            resp::integer(db.release_lease(&cmd.args()[0], token) as i64)
+        }
+        Expire => execute_expire(db, cmd, 1000, false),
+        PExpire => execute_expire(db, cmd, 1, false),
+        ExpireAt => execute_expire(db, cmd, 1000, true),
+        PExpireAt => execute_expire(db, cmd, 1, true),
+        Persist => resp::integer(db.persist(&cmd.args()[0]) as i64),
+        LPush => execute_push(db, cmd, ListEnd::Left),
+        RPush => execute_push(db, cmd, ListEnd::Right),
+        LPop => execute_pop(db, cmd, ListEnd::Left),
+        RPop => execute_pop(db, cmd, ListEnd::Right),
+        LSet => execute_lset(db, cmd),
+        LRem => execute_lrem(db, cmd),
+        LTrim => execute_ltrim(db, cmd),
+        LInsert => execute_linsert(db, cmd),
+        HSet => execute_hset(db, cmd),
+        HSetNx => execute_hsetnx(db, cmd),
+        HDel => execute_hdel(db, cmd),
+        HIncrBy => execute_hincrby(db, cmd),
+        SAdd => execute_sadd(db, cmd),
+        SRem => execute_srem(db, cmd),
+        SPop => execute_spop(db, cmd),
+        SInterStore => execute_set_op_store(db, cmd, SetOp::Inter),
+        SUnionStore => execute_set_op_store(db, cmd, SetOp::Union),
+        SDiffStore => execute_set_op_store(db, cmd, SetOp::Diff),
+        ZAdd => execute_zadd(db, cmd),
+        ZRem => execute_zrem(db, cmd),
+        ZIncrBy => execute_zincrby(db, cmd),
+        ZPopMin => execute_zpop(db, cmd, false),
+        ZPopMax => execute_zpop(db, cmd, true),
+        XAdd => execute_xadd(db, cmd),
+        XTrim => execute_xtrim(db, cmd),
+        XGroupCreate => execute_xgroup_create(db, cmd),
+        XGroupSetId => execute_xgroup_setid(db, cmd),
+        XGroupDestroy => execute_xgroup_destroy(db, cmd),
+        XGroupCreateConsumer => execute_xgroup_createconsumer(db, cmd),
+        XGroupDelConsumer => execute_xgroup_delconsumer(db, cmd),
+        XReadGroup => execute_xreadgroup(db, cmd),
+        XAck => execute_xack(db, cmd),
+        XClaim => execute_xclaim(db, cmd),
+        XAutoClaim => execute_xautoclaim(db, cmd),
+        _ => panic!("Command '{}' should be executed with read access", cmd),
+    }
+}
+
+/// Reclaims keys whose TTL has passed but that nobody accesses again. Runs more often
+/// while a cycle leaves expired keys behind. A replica leaves this to its leader, which
+/// sends the DELs.
+async fn active_expire_loop() {
+    loop {
+        let backlog = {
+            let mut store = STORE.write().await;
+            if REPLICATION.lock().unwrap().is_replica() {
+                false
+            } else {
+                let backlog = store.active_expire_cycle(expire::ACTIVE_EXPIRE_BUDGET);
+                let mut log = vec![];
+                log_removed(&mut store, &mut log);
+                propagate(&log, false);
+                backlog
+            }
         };
-        let join_handle = task::spawn(create_expiration_task(duration, key.clone()));
-        if let Some(_) = store.expire(
-            &key,
-            Expiration::new(time::Duration::try_from(duration).unwrap(), join_handle),
-        ) {
-            resp::integer(1)
+        if backlog {
+            task::sleep(expire::ACTIVE_EXPIRE_FAST_PERIOD).await;
         } else {
-            resp::integer(0)
+            task::sleep(expire::ACTIVE_EXPIRE_PERIOD).await;
         }
     }
 }
 
-fn execute_ttl(store: &Store, cmd: Command) -> resp::Value {
-    let key = cmd.args().first().unwrap();
-    resp::integer(match store.ttl(key) {
-        TTL::Expires(ttl) => ttl,
-        TTL::NoExpiration => -1,
-        TTL::KeyNotFound => -2,
-    })
+/// Loads the snapshot file into the store, if there is one.
+fn load_snapshot() -> Result<()> {
+    let path = CONFIG.snapshot_path();
+    if let Some(snapshot) = snapshot::load(&path)? {
+        let keys = snapshot.keys();
+        let mut store = task::block_on(STORE.write());
+        store.load(snapshot).map_err(|e| e.to_string())?;
+        println!("Loaded {} keys from {}", keys, path.display());
+    }
+    Ok(())
 }
 
-fn execute_client_list(store: &Store) -> resp::Value {
-    let clients: Vec<String> = store
-        .clients()
-        .map(|client| format!("{}", client))
-        .collect();
-    let clients = clients.join("\n");
-    resp::bulk_string(&clients)
+/// Loads a Redis RDB file into the store in place of the snapshot or append-only file.
+fn import_rdb(path: &std::path::Path) -> Result<()> {
+    let bytes = std::fs::read(path)?;
+    let snapshot = rdb::read_rdb(&bytes)?;
+    let keys = snapshot.keys();
+    let mut store = task::block_on(STORE.write());
+    store.load(snapshot).map_err(|e| e.to_string())?;
+    println!("Imported {} keys from {}", keys, path.display());
+    Ok(())
+}
+
+/// Starts a new append-only file from the contents of the store.
+fn create_aof(store: &Store) -> Result<()> {
+    let path = CONFIG.aof_path();
+    snapshot::write_file(&store.snapshot(), &path)?;
+    *AOF.lock().unwrap() = Some(Aof::open(&path, CONFIG.appendfsync)?);
+    Ok(())
+}
+
+/// Rebuilds the store by replaying the append-only file, dropping a tail that a crash cut
+/// off. Without the file, the snapshot is loaded instead and written out as the file's
+/// starting point.
+fn load_aof() -> Result<()> {
+    let path = CONFIG.aof_path();
+    let contents = match aof::read(&path)? {
+        Some(contents) => contents,
+        None => {
+            load_snapshot()?;
+            return create_aof(&task::block_on(STORE.read()));
+        }
+    };
+    if contents.valid_len < contents.len {
+        eprintln!(
+            "Dropping {} bytes of incomplete commands from the end of {}",
+            contents.len - contents.valid_len,
+            path.display()
+        );
+        aof::truncate(&path, contents.valid_len)?;
+    }
+
+    let mut store = task::block_on(STORE.write());
+    store.set_loading(true);
+    if let Some(snapshot) = contents.snapshot {
+        store.load(snapshot).map_err(|e| e.to_string())?;
+    }
+    let commands = contents.commands.len();
+    let mut db_index = 0;
+    for value in contents.commands {
+        let cmd = Command::from_resp(value)
+            .map_err(|e| format!("Bad command in {}: {}", path.display(), e))?;
+        match cmd.action() {
+            Action::Select => db_index = select_db(&store, &cmd).map_err(|e| e.to_string())?,
+            Action::Multi | Action::Exec => (),
+            _ if cmd.lock() == Some(command::Lock::Write) => {
+                apply_write_cmd(&mut store, db_index, cmd);
+                store.settle_memory();
+            }
+            _ => (),
+        }
+    }
+    store.set_loading(false);
+    println!("Replayed {} commands from {}", commands, path.display());
+
+    *AOF.lock().unwrap() = Some(Aof::open(&path, CONFIG.appendfsync)?);
+    Ok(())
+}
+
+/// Syncs the append-only file once a second under `appendfsync everysec`. The sync runs on
+/// a handle of its own, so writers are not held up while it waits on the disk.
+fn aof_fsync_loop() {
+    loop {
+        std::thread::sleep(aof::FSYNC_PERIOD);
+        let handle = match AOF.lock().unwrap().as_ref().map(Aof::sync_handle) {
+            Some(Ok(Some(handle))) => handle,
+            Some(Err(e)) => {
+                eprintln!("Failed to sync the append-only file: {}", e);
+                continue;
+            }
+            _ => return,
+        };
+        if let Err(e) = handle.sync_data() {
+            eprintln!("Failed to sync the append-only file: {}", e);
+        }
+    }
+}
+
+/// Starts a background save whenever one of the save rules is met. A failed save is only
+/// retried after a delay.
+async fn save_rules_loop() {
+    let mut last_attempt: Option<Instant> = None;
+    loop {
+        task::sleep(snapshot::SAVE_CHECK_PERIOD).await;
+        let store = STORE.read().await;
+        let retry = store.last_save_ok()
+            || last_attempt.is_none_or(|at| at.elapsed() >= snapshot::SAVE_RETRY_DELAY);
+        let changes = store.changes_since_save();
+        let secs_since_save = (clock::now_ms() / 1000).saturating_sub(store.last_save());
+        let due = CONFIG
+            .save
+            .iter()
+            .any(|rule| rule.is_due(changes, secs_since_save));
+        if retry && due && start_bgsave(&store) {
+            last_attempt = Some(Instant::now());
+        }
+    }
+}
+
+/// Copies the data out of the store and writes it to the snapshot file on another thread,
+/// so the store is only held while copying. The copy is deep: clients pause while it is
+/// made, and it takes as much memory again as the dataset until written, which MEMORY
+/// STATS and INFO report but eviction leaves out. Returns false if a save is already
+/// running.
+fn start_bgsave(store: &Store) -> bool {
+    if !store.begin_save() {
+        return false;
+    }
+    let snapshot = store.background_snapshot();
+    let changes = store.changes_since_save();
+    std::thread::spawn(move || {
+        let result = snapshot::save(&snapshot, &CONFIG.snapshot_path());
+        if let Err(e) = &result {
+            eprintln!("Background save failed: {}", e);
+        }
+        task::block_on(STORE.write()).finish_save(changes, result.is_ok());
+    });
+    true
+}
+
+/// Replies with the whole store encoded as a Redis RDB file, as a bulk string.
+fn execute_sync(store: &Store) -> resp::Value {
+    let mut rdb = vec![];
+    match rdb::write_rdb(&store.snapshot(), &mut rdb) {
+        Ok(skipped) => {
+            for key in skipped {
+                eprintln!(
+                    "SYNC left out stream '{}', which RDB files can not hold here",
+                    String::from_utf8_lossy(&key)
+                );
+            }
+            resp::bulk_string(&rdb)
+        }
+        Err(e) => resp::error(&format!("ERR {}", e)),
+    }
+}
+
+fn execute_save(store: &mut Store) -> resp::Value {
+    if !store.begin_save() {
+        return resp::error("ERR Background save already in progress");
+    }
+    let changes = store.changes_since_save();
+    let result = snapshot::save(&store.snapshot(), &CONFIG.snapshot_path());
+    store.finish_save(changes, result.is_ok());
+    match result {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => resp::error(&format!("ERR {}", e)),
+    }
+}
+
+/// Writes a snapshot of the store to a new append-only file on another thread. Commands
+/// logged meanwhile are kept aside and added to the new file before it replaces the old.
+fn execute_bgrewriteaof(store: &Store) -> resp::Value {
+    let mut aof = AOF.lock().unwrap();
+    let aof = match aof.as_mut() {
+        Some(aof) => aof,
+        None => return resp::error("ERR Append only file is disabled"),
+    };
+    if !aof.begin_rewrite() {
+        return resp::error("ERR Background append only file rewriting already in progress");
+    }
+    let snapshot = store.snapshot();
+    let tmp = aof.rewrite_path();
+    std::thread::spawn(move || {
+        let result = snapshot::write_file(&snapshot, &tmp);
+        let mut aof = AOF.lock().unwrap();
+        let aof = aof.as_mut().unwrap();
+        if let Err(e) = result.and_then(|()| aof.finish_rewrite()) {
+            eprintln!("Background append only file rewrite failed: {}", e);
+            aof.abort_rewrite();
+        }
+    });
+    resp::simple_string("Background append only file rewriting started")
+}
+
+fn execute_bgsave(store: &Store) -> resp::Value {
+    if start_bgsave(store) {
+        resp::simple_string("Background saving started")
+    } else {
+        resp::error("ERR Background save already in progress")
+    }
+}
+
+/// Pings replicas through the replication stream every so often, so that they can tell a
+/// quiet leader from a lost one.
+async fn replication_ping_loop() {
+    loop {
+        task::sleep(replication::PING_PERIOD).await;
+        REPLICATION.lock().unwrap().feed_ping();
+    }
+}
+
+/// Answers a replica's PSYNC, either continuing from the backlog or with a snapshot of the
+/// store, then streams the commands this server runs to the replica until it disconnects,
+/// falls behind the backlog or the stream starts over.
+async fn serve_replica(
+    stream: &TcpStream,
+    client_addr: SocketAddr,
+    client_id: usize,
+    cmd: &Command,
+) -> Result<()> {
+    let args = cmd.args();
+    let requested: i64 = parse_arg(&args[1]);
+    let (reply, snapshot, replid, offset, epoch) = {
+        let store = STORE.read().await;
+        let mut replication = REPLICATION.lock().unwrap();
+        // The replica asks for the first byte it lacks.
+        let psync = match requested {
+            requested if requested > 0 => {
+                replication.psync(&String::from_utf8_lossy(&args[0]), requested as u64 - 1)
+            }
+            _ => Psync::Full,
+        };
+        let (reply, snapshot, offset) = match psync {
+            Psync::Continue(offset) => (
+                format!("+CONTINUE {}\r\n", replication.replid()),
+                None,
+                offset,
+            ),
+            Psync::Full => {
+                let (replid, offset) = replication.start_full_sync();
+                let reply = format!("+FULLRESYNC {} {}\r\n", replid, offset);
+                (reply, Some(store.snapshot()), offset)
+            }
+        };
+        replication.add_replica(client_id, client_addr, offset);
+        let replid = replication.replid().to_owned();
+        (reply, snapshot, replid, offset, replication.backlog_epoch())
+    };
+
+    let result = async {
+        let mut stream = stream;
+        let mut head = reply.into_bytes();
+        if let Some(snapshot) = snapshot {
+            let mut payload = vec![];
+            snapshot::write_snapshot(&snapshot, &mut payload)?;
+            head.extend_from_slice(format!("${}\r\n", payload.len()).as_bytes());
+            head.extend_from_slice(&payload);
+        }
+        stream.write_all(&head).await?;
+        stream_to_replica(stream, client_id, &replid, offset, epoch).await
+    }
+    .await;
+    REPLICATION.lock().unwrap().remove_replica(client_id);
+    result
+}
+
+/// Sends the replication stream from `offset` on as it grows.
+async fn stream_to_replica(
+    mut stream: &TcpStream,
+    client_id: usize,
+    replid: &str,
+    mut offset: u64,
+    epoch: u64,
+) -> Result<()> {
+    loop {
+        let chunk = {
+            let mut replication = REPLICATION.lock().unwrap();
+            if replication.replid() != replid || replication.backlog_epoch() != epoch {
+                return Err("the replication stream started over".into());
+            }
+            let chunk = replication
+                .backlog()
+                .and_then(|backlog| backlog.read_from(offset))
+                .ok_or("the replica fell behind the backlog")?;
+            offset += chunk.len() as u64;
+            replication.set_replica_offset(client_id, offset);
+            chunk
+        };
+        if chunk.is_empty() {
+            task::sleep(replication::REPLICA_POLL_INTERVAL).await;
+        } else {
+            stream.write_all(&chunk).await?;
+        }
+    }
+}
+
+fn execute_replicaof(cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let mut replication = REPLICATION.lock().unwrap();
+    if args[0].eq_ignore_ascii_case(b"no") && args[1].eq_ignore_ascii_case(b"one") {
+        replication.promote();
+        return resp::simple_string("OK");
+    }
+    let host = String::from_utf8_lossy(&args[0]).into_owned();
+    let port: u16 = parse_arg(&args[1]);
+    if *replication.role()
+        == (Role::Replica {
+            host: host.clone(),
+            port,
+        })
+    {
+        return resp::simple_string("OK Already connected to specified master");
+    }
+    let generation = replication.replicate_from(host.clone(), port);
+    task::spawn(replica_loop(host, port, generation));
+    resp::simple_string("OK")
+}
+
+/// Whether this server still follows the leader it was told to replicate from under
+/// `generation`.
+fn follows(generation: u64) -> bool {
+    REPLICATION.lock().unwrap().generation() == generation
+}
+
+/// Follows the leader at `host` and `port` until REPLICAOF points elsewhere, reconnecting
+/// whenever the link drops.
+async fn replica_loop(host: String, port: u16, generation: u64) {
+    // The database the stream last selected, which a partial resync carries on with.
+    let mut db_index = 0;
+    while follows(generation) {
+        if let Err(e) = sync_with_leader(&host, port, generation, &mut db_index).await {
+            eprintln!("Lost the link to leader {}:{}: {}", host, port, e);
+        }
+        {
+            let mut replication = REPLICATION.lock().unwrap();
+            if replication.generation() == generation {
+                replication.set_link_up(false);
+            }
+        }
+        task::sleep(replication::RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects to the leader, syncs with it and applies the commands it streams until the
+/// link drops or this server stops following it.
+async fn sync_with_leader(
+    host: &str,
+    port: u16,
+    generation: u64,
+    db_index: &mut usize,
+) -> Result<()> {
+    let stream = io::timeout(replication::TIMEOUT, TcpStream::connect((host, port))).await?;
+    let (replid, offset) = REPLICATION.lock().unwrap().psync_request();
+    let psync = [
+        b"PSYNC".to_vec(),
+        replid.into_bytes(),
+        (offset + 1).to_string().into_bytes(),
+    ];
+    (&stream).write_all(&encode_argv(&psync)).await?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    io::timeout(replication::TIMEOUT, reader.read_line(&mut line)).await?;
+    let reply: Vec<String> = line.split_whitespace().map(str::to_owned).collect();
+    match reply
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset.parse()?;
+            line.clear();
+            io::timeout(replication::TIMEOUT, reader.read_line(&mut line)).await?;
+            let len: usize = line
+                .trim_end()
+                .strip_prefix('$')
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| format!("Bad snapshot header from leader: {}", line.trim_end()))?;
+            let mut payload = vec![0; len];
+            io::timeout(replication::TIMEOUT, reader.read_exact(&mut payload)).await?;
+            let snapshot = snapshot::read_snapshot(&payload)?;
+
+            let mut store = STORE.write().await;
+            let mut replication = REPLICATION.lock().unwrap();
+            if replication.generation() != generation {
+                return Ok(());
+            }
+            let keys = snapshot.keys();
+            store.load(snapshot).map_err(|e| e.to_string())?;
+            replication.finish_full_sync(replid.to_string(), offset);
+            *db_index = 0;
+            if CONFIG.appendonly {
+                create_aof(&store)?;
+            }
+            println!("Synced {} keys from leader {}:{}", keys, host, port);
+        }
+        ["+CONTINUE"] => (),
+        ["+CONTINUE", replid] => REPLICATION.lock().unwrap().continue_as(replid.to_string()),
+        _ => return Err(format!("Unexpected reply to PSYNC: {}", line.trim_end()).into()),
+    }
+    {
+        let mut replication = REPLICATION.lock().unwrap();
+        if replication.generation() != generation {
+            return Ok(());
+        }
+        replication.set_link_up(true);
+    }
+
+    let mut buf = vec![];
+    let mut chunk = vec![0; 16 * 1024];
+    loop {
+        let read = io::timeout(replication::TIMEOUT, reader.read(&mut chunk)).await?;
+        if read == 0 {
+            return Err("the leader closed the connection".into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        match apply_from_leader(&buf, generation, db_index).await? {
+            Some(applied) => buf.drain(..applied),
+            None => return Ok(()),
+        };
+    }
+}
+
+/// Applies the complete commands at the start of `buf`, as streamed by the leader, and
+/// relays them to this server's own replicas. A transaction is only applied once its EXEC
+/// has arrived. Returns how many bytes were applied, or `None` if this server no longer
+/// follows the leader.
+async fn apply_from_leader(
+    buf: &[u8],
+    generation: u64,
+    db_index: &mut usize,
+) -> Result<Option<usize>> {
+    let mut store = STORE.write().await;
+    let mut replication = REPLICATION.lock().unwrap();
+    if replication.generation() != generation {
+        return Ok(None);
+    }
+    let mut applied = 0;
+    let mut pos = 0;
+    let mut transaction: Option<Vec<Command>> = None;
+    loop {
+        let (value, len) = match resp::decode_prefix(&buf[pos..]) {
+            Ok(decoded) => decoded,
+            Err(resp::Error::IncompleteRespError) => return Ok(Some(applied)),
+            Err(_) => return Err("Invalid RESP from leader".into()),
+        };
+        pos += len;
+        let cmd =
+            Command::from_resp(value).map_err(|e| format!("Bad command from leader: {}", e))?;
+        let mut log = vec![];
+        match (cmd.action(), transaction.as_mut()) {
+            (Action::Multi, _) => {
+                transaction = Some(vec![]);
+                continue;
+            }
+            (Action::Exec, _) => {
+                for cmd in transaction.take().unwrap_or_default() {
+                    apply_leader_cmd(&mut store, db_index, cmd, &mut log)?;
+                }
+                append_aof(&log, true);
+            }
+            (_, Some(queued)) => {
+                queued.push(cmd);
+                continue;
+            }
+            (_, None) => {
+                apply_leader_cmd(&mut store, db_index, cmd, &mut log)?;
+                append_aof(&log, false);
+            }
+        }
+        replication.feed_from_leader(&buf[applied..pos]);
+        applied = pos;
+    }
+}
+
+/// Applies one command from the leader's stream. The leader only streams commands that
+/// replay the same way anywhere, so they are logged as they are.
+fn apply_leader_cmd(
+    store: &mut Store,
+    db_index: &mut usize,
+    mut cmd: Command,
+    log: &mut Vec<(usize, Argv)>,
+) -> Result<()> {
+    match cmd.action() {
+        Action::Select => *db_index = select_db(store, &cmd).map_err(|e| e.to_string())?,
+        _ if cmd.lock() == Some(command::Lock::Write) => {
+            let argv = cmd.take_argv();
+            apply_write_cmd(store, *db_index, cmd);
+            store.settle_memory();
+            log_removed(store, log);
+            log.push((*db_index, argv));
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// The redirect for a command on keys this node does not serve, or for keys spread over
+/// several slots. Commands that do not name keys run on any node.
+async fn cluster_redirect(cmd: &Command, db_index: usize, asking: bool) -> Option<resp::Value> {
+    let keys = cmd.keys().filter(|keys| !keys.is_empty())?;
+    let slot = cluster::key_slot(keys[0]);
+    if keys.iter().any(|key| cluster::key_slot(key) != slot) {
+        return Some(resp::error(&cluster::Redirect::CrossSlot.to_string()));
+    }
+    // Only a slot on the move is routed by which of its keys this node holds.
+    let moving = CLUSTER.lock().unwrap().is_moving(slot);
+    let missing = if moving {
+        let store = STORE.read().await;
+        let db = store.db(db_index);
+        keys.iter().filter(|key| db.get(key).is_none()).count()
+    } else {
+        0
+    };
+    let route = CLUSTER
+        .lock()
+        .unwrap()
+        .route(slot, asking, keys.len(), missing);
+    route
+        .err()
+        .map(|redirect| resp::error(&redirect.to_string()))
+}
+
+fn execute_cluster_info() -> resp::Value {
+    let cluster = CLUSTER.lock().unwrap();
+    let assigned = cluster.assigned_slots();
+    let size = cluster
+        .nodes()
+        .filter(|node| !cluster.slot_ranges(&node.id).is_empty())
+        .count();
+    let state = if assigned == cluster::SLOTS {
+        "ok"
+    } else {
+        "fail"
+    };
+    let fields = info_fields(vec![
+        ("cluster_state", state.to_owned()),
+        ("cluster_slots_assigned", assigned.to_string()),
+        ("cluster_known_nodes", cluster.nodes().count().to_string()),
+        ("cluster_size", size.to_string()),
+        ("cluster_current_epoch", cluster.current_epoch().to_string()),
+        (
+            "cluster_my_epoch",
+            cluster.myself().config_epoch.to_string(),
+        ),
+    ]);
+    let info: String = fields
+        .iter()
+        .map(|(field, value)| format!("{}:{}\r\n", field, value))
+        .collect();
+    resp::bulk_string(&info)
+}
+
+fn execute_cluster_meet(cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let host = String::from_utf8_lossy(&args[0]).into_owned();
+    let port = parse_arg::<u16>(&args[1]);
+    let bus_port = match args.get(2) {
+        Some(bus_port) => Some(parse_arg::<u16>(bus_port)),
+        None => port.checked_add(cluster::BUS_PORT_OFFSET),
+    };
+    match bus_port {
+        Some(bus_port) => {
+            CLUSTER.lock().unwrap().meet(host, port, bus_port);
+            resp::simple_string("OK")
+        }
+        None => resp::error("ERR Invalid node address specified"),
+    }
+}
+
+fn execute_cluster_addslots(cmd: Command) -> resp::Value {
+    let slots: Vec<u16> = cmd
+        .args()
+        .iter()
+        .map(|slot| cluster::parse_slot(slot).unwrap())
+        .collect();
+    match CLUSTER.lock().unwrap().add_slots(&slots) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_cluster_setslot(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let slot = cluster::parse_slot(&args[0]).unwrap();
+    let node = || String::from_utf8_lossy(&args[2]).into_owned();
+    let (set_slot, held_keys) = match args[1].as_slice() {
+        b"migrating" => (SetSlot::Migrating(node()), 0),
+        b"importing" => (SetSlot::Importing(node()), 0),
+        b"stable" => (SetSlot::Stable, 0),
+        _ => (SetSlot::Node(node()), db.keys_in_slot(slot).count()),
+    };
+    match CLUSTER.lock().unwrap().set_slot(slot, set_slot, held_keys) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_cluster_slots() -> resp::Value {
+    let cluster = CLUSTER.lock().unwrap();
+    let mut ranges: Vec<(u16, u16, &cluster::Node)> = cluster
+        .nodes()
+        .flat_map(|node| {
+            cluster
+                .slot_ranges(&node.id)
+                .into_iter()
+                .map(move |(start, end)| (start, end, node))
+        })
+        .collect();
+    ranges.sort_by_key(|(start, _, _)| *start);
+    resp::array(
+        ranges
+            .into_iter()
+            .map(|(start, end, node)| {
+                resp::array(vec![
+                    resp::integer(start as i64),
+                    resp::integer(end as i64),
+                    resp::array(vec![
+                        resp::bulk_string(&node.host),
+                        resp::integer(node.port as i64),
+                        resp::bulk_string(&node.id),
+                    ]),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn execute_cluster_shards() -> resp::Value {
+    let offset = REPLICATION.lock().unwrap().offset();
+    let cluster = CLUSTER.lock().unwrap();
+    let mut shards: Vec<(Vec<(u16, u16)>, &cluster::Node)> = cluster
+        .nodes()
+        .map(|node| (cluster.slot_ranges(&node.id), node))
+        .collect();
+    shards.sort_by_key(|(ranges, node)| (ranges.first().copied(), node.id.clone()));
+    resp::array(
+        shards
+            .into_iter()
+            .map(|(ranges, node)| {
+                let myself = node.id == cluster.myself().id;
+                let slots = ranges
+                    .into_iter()
+                    .flat_map(|(start, end)| vec![start, end])
+                    .map(|slot| resp::integer(slot as i64))
+                    .collect();
+                let health = if node.is_failing() { "fail" } else { "online" };
+                let node = resp::array(vec![
+                    resp::bulk_string("id"),
+                    resp::bulk_string(&node.id),
+                    resp::bulk_string("port"),
+                    resp::integer(node.port as i64),
+                    resp::bulk_string("ip"),
+                    resp::bulk_string(&node.host),
+                    resp::bulk_string("endpoint"),
+                    resp::bulk_string(&node.host),
+                    resp::bulk_string("role"),
+                    resp::bulk_string("master"),
+                    resp::bulk_string("replication-offset"),
+                    resp::integer(if myself { offset as i64 } else { 0 }),
+                    resp::bulk_string("health"),
+                    resp::bulk_string(health),
+                ]);
+                resp::array(vec![
+                    resp::bulk_string("slots"),
+                    resp::array(slots),
+                    resp::bulk_string("nodes"),
+                    resp::array(vec![node]),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Answers the other nodes' gossip on the cluster bus port.
+async fn cluster_bus_loop() -> Result<()> {
+    let listener = TcpListener::bind((HOST, CONFIG.cluster_port)).await?;
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        spawn_and_log_error(serve_cluster_peer(stream?));
+    }
+    Ok(())
+}
+
+/// Takes in each gossip message another node sends and answers with this node's own.
+async fn serve_cluster_peer(stream: TcpStream) -> Result<()> {
+    let mut buf = vec![];
+    while let Some(gossip) = read_gossip(&stream, &mut buf).await? {
+        let reply = {
+            let mut cluster = CLUSTER.lock().unwrap();
+            cluster.receive(&gossip);
+            cluster.gossip()
+        };
+        (&stream).write_all(&resp::encode(&reply.to_resp())).await?;
+    }
+    Ok(())
+}
+
+/// Gossips with every other node each period, so that nodes learn of each other and of
+/// which slots each serves.
+async fn cluster_gossip_loop() {
+    loop {
+        task::sleep(cluster::GOSSIP_PERIOD).await;
+        let targets = CLUSTER.lock().unwrap().gossip_targets();
+        for (host, bus_port) in targets {
+            task::spawn(async move {
+                // An unreachable node is simply tried again next period.
+                let _ = io::timeout(cluster::GOSSIP_PERIOD, gossip_with(&host, bus_port)).await;
+            });
+        }
+    }
+}
+
+/// Sends this node's gossip to the node at `host` and `bus_port` and takes in its answer.
+async fn gossip_with(host: &str, bus_port: u16) -> io::Result<()> {
+    let stream = TcpStream::connect((host, bus_port)).await?;
+    let gossip = CLUSTER.lock().unwrap().gossip();
+    (&stream)
+        .write_all(&resp::encode(&gossip.to_resp()))
+        .await?;
+    let reply = read_gossip(&stream, &mut vec![])
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    CLUSTER.lock().unwrap().receive(&reply);
+    Ok(())
+}
+
+/// Reads the next gossip message from another node, or `None` once it hangs up.
+async fn read_gossip(mut stream: &TcpStream, buf: &mut Vec<u8>) -> Result<Option<Gossip>> {
+    let mut chunk = [0; 4096];
+    loop {
+        match resp::decode_prefix(&buf[..]) {
+            Ok((value, len)) => {
+                buf.drain(..len);
+                return match Gossip::from_resp(&value) {
+                    Some(gossip) => Ok(Some(gossip)),
+                    None => Err("Invalid gossip message".into()),
+                };
+            }
+            Err(resp::Error::IncompleteRespError) => {
+                let read = stream.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                buf.extend_from_slice(&chunk[..read]);
+            }
+            Err(_) => return Err("Invalid gossip message".into()),
+        }
+    }
+}
+
+/// The longest TTL a key is given; longer ones are capped so deadlines stay representable.
+const MAX_TTL_MS: i64 = 1000 * 365 * 24 * 60 * 60 * 1000;
+
+/// Expires `key` after `ttl_ms`, deleting it straight away if the TTL is not positive.
+/// While the store is loading, the key is kept and expires once loading ends instead.
+/// Returns whether the key exists.
+fn expire_in(db: &mut Db, key: Vec<u8>, ttl_ms: i64) -> bool {
+    if ttl_ms <= 0 && !db.is_loading() {
+        return db.remove(&key).is_some();
+    }
+    let ttl_ms = ttl_ms.clamp(0, MAX_TTL_MS);
+    let expires_at = Instant::now() + std::time::Duration::from_millis(ttl_ms as u64);
+    db.expire(&key, expires_at).is_some()
+}
+
+fn execute_set(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let key: Vec<u8>;
+    let val: Vec<u8>;
+    {
+        let mut drain = cmd.drain_args();
+        key = drain.next().unwrap();
+        val = drain.next().unwrap();
+    }
+
+    let mut maybe_ttl = None;
+    let mut keep_ttl = false;
+    let mut xx = false;
+    let mut nx = false;
+    for opt in cmd.opts().iter() {
+        match opt {
+            CommandOpt::SetEx(ttl_sec) => maybe_ttl = Some((*ttl_sec as i64).saturating_mul(1000)),
+            CommandOpt::SetPx(ttl_ms) => maybe_ttl = Some(*ttl_ms as i64),
+            CommandOpt::SetExAt(at_sec) => {
+                maybe_ttl = Some(expire::unix_ms_to_ttl(
+                    (*at_sec as i64).saturating_mul(1000),
+                ))
+            }
+            CommandOpt::SetPxAt(at_ms) => maybe_ttl = Some(expire::unix_ms_to_ttl(*at_ms as i64)),
+            CommandOpt::SetKeepTtl => keep_ttl = true,
+            CommandOpt::SetXx => xx = true,
+            CommandOpt::SetNx => nx = true,
+            _ => continue,
+        };
+    }
+
+    if xx {
+        if db.get(&key).is_some() {
+            db.set(key.clone(), val, keep_ttl);
+            if let Some(ttl) = maybe_ttl {
+                expire_in(db, key, ttl);
+            }
+            return resp::integer(1);
+        } else {
+            return resp::integer(0);
+        }
+    }
+
+    if nx {
+        if db.get(&key).is_none() {
+            db.set(key.clone(), val, keep_ttl);
+            if let Some(ttl) = maybe_ttl {
+                expire_in(db, key, ttl);
+            }
+            return resp::integer(1);
+        } else {
+            return resp::integer(0);
+        }
+    }
+
+    db.set(key.clone(), val, keep_ttl);
+    if let Some(ttl) = maybe_ttl {
+        expire_in(db, key, ttl);
+    }
+    resp::simple_string("OK")
+}
+
+fn execute_setex(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let ttl = parse_arg::<i64>(&drain.next().unwrap());
+    let val = drain.next().unwrap();
+    db.set(key.clone(), val, false);
+    expire_in(db, key, ttl.saturating_mul(1000));
+    resp::simple_string("OK")
+}
+
+fn execute_setnx(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    if db.get(&key).is_some() {
+        resp::integer(0)
+    } else {
+        let val = drain.next().unwrap();
+        db.set(key, val, false);
+        resp::integer(1)
+    }
+}
+
+fn execute_get(db: &Db, cmd: Command) -> resp::Value {
+    let key = cmd.args().first().unwrap();
+    let val = db.get(key);
+    match val {
+        Some(v) => match v {
+            store::Value::Int(i) => resp::bulk_string(&i.to_string()),
+            store::Value::Str(s) => resp::bulk_string(s),
+            _ => store_error(StoreError::new(store::StoreErrorKind::WrongType)),
+        },
+        None => resp::Value::Null,
+    }
+}
+
+/// Executes the INCR family. `sign` is -1 for the DECR variants; INCR and DECR carry no
+/// increment argument and step by one.
+fn execute_incr_by(db: &mut Db, mut cmd: Command, sign: i64) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let increment = drain.next().map_or(1, |arg| parse_arg::<i64>(&arg));
+    let increment = match increment.checked_mul(sign) {
+        Some(increment) => increment,
+        None => return store_error(StoreError::new(store::StoreErrorKind::Overflow)),
+    };
+    match db.incr_by(key, increment) {
+        Ok(value) => resp::integer(value),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_incrbyfloat(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let increment = parse_arg::<f64>(&drain.next().unwrap());
+    match db.incr_by_float(key, increment) {
+        Ok(value) => resp::bulk_string(&value),
+        Err(e) => store_error(e),
+    }
+}
+
+fn string_reply(result: std::result::Result<Option<Vec<u8>>, StoreError>) -> resp::Value {
+    match result {
+        Ok(Some(value)) => resp::bulk_string(&value),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_append(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let value = drain.next().unwrap();
+    match db.append(key, &value) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_strlen(db: &Db, cmd: Command) -> resp::Value {
+    match db.strlen(&cmd.args()[0]) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_getrange(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let start = parse_arg::<i64>(&args[1]);
+    let end = parse_arg::<i64>(&args[2]);
+    match db.getrange(&args[0], start, end) {
+        Ok(range) => resp::bulk_string(&range),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_setrange(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let offset = parse_arg::<usize>(&drain.next().unwrap());
+    let value = drain.next().unwrap();
+    match db.setrange(key, offset, &value) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_getset(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let value = drain.next().unwrap();
+    string_reply(db.getset(key, value))
+}
+
+fn execute_getdel(db: &mut Db, cmd: Command) -> resp::Value {
+    string_reply(db.getdel(&cmd.args()[0]))
+}
+
+fn execute_getex(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let key = cmd.drain_args().next().unwrap();
+    let value = match db.get_string(&key) {
+        Ok(Some(value)) => value,
+        result => return string_reply(result),
+    };
+    for opt in cmd.opts().iter() {
+        let ttl_ms = match *opt {
+            CommandOpt::GetExEx(ttl_sec) => ttl_sec as i64 * 1000,
+            CommandOpt::GetExPx(ttl_ms) => ttl_ms as i64,
+            CommandOpt::GetExExAt(at_sec) => expire::unix_ms_to_ttl(at_sec as i64 * 1000),
+            CommandOpt::GetExPxAt(at_ms) => expire::unix_ms_to_ttl(at_ms as i64),
+            CommandOpt::GetExPersist => {
+                db.persist(&key);
+                continue;
+            }
+            _ => continue,
+        };
+        expire_in(db, key.clone(), ttl_ms);
+    }
+    resp::bulk_string(&value)
+}
+
+fn execute_mget(db: &Db, cmd: Command) -> resp::Value {
+    resp::array(
+        db.mget(cmd.args())
+            .into_iter()
+            .map(|value| match value {
+                Some(value) => resp::bulk_string(&value),
+                None => resp::Value::Null,
+            })
+            .collect(),
+    )
+}
+
+fn key_value_pairs(mut cmd: Command) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut pairs = vec![];
+    let mut drain = cmd.drain_args();
+    while let (Some(key), Some(value)) = (drain.next(), drain.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+fn execute_mset(db: &mut Db, cmd: Command) -> resp::Value {
+    db.mset(key_value_pairs(cmd));
+    resp::simple_string("OK")
+}
+
+fn execute_msetnx(db: &mut Db, cmd: Command) -> resp::Value {
+    resp::integer(db.msetnx(key_value_pairs(cmd)) as i64)
+}
+
+fn execute_rename(db: &mut Db, mut cmd: Command, nx: bool) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let new_key = drain.next().unwrap();
+    match db.rename(&key, new_key, nx) {
+        Ok(renamed) => {
+            if nx {
+                resp::integer(renamed as i64)
+            } else {
+                resp::simple_string("OK")
+            }
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_copy(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let replace = cmd.opts().contains(&CommandOpt::CopyReplace);
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let dest = drain.next().unwrap();
+    resp::integer(db.copy(&key, dest, replace) as i64)
+}
+
+fn execute_dump(db: &Db, cmd: Command) -> resp::Value {
+    let key = &cmd.args()[0];
+    match db.get(key) {
+        Some(value) => {
+            let expires_at_ms = match db.ttl(key) {
+                TTL::Expires(ttl_ms) => Some(expire::ttl_to_unix_ms(ttl_ms)),
+                _ => None,
+            };
+            resp::bulk_string(&snapshot::dump_value(value, expires_at_ms))
+        }
+        None => resp::Value::Null,
+    }
+}
+
+fn execute_restore(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut replace = false;
+    let mut absttl = false;
+    let mut access = None;
+    for opt in cmd.opts().iter() {
+        match opt {
+            CommandOpt::RestoreReplace => replace = true,
+            CommandOpt::RestoreAbsTtl => absttl = true,
+            CommandOpt::RestoreIdleTime(secs) => {
+                access = Some(AccessStats::with_idle(secs.saturating_mul(1000)))
+            }
+            CommandOpt::RestoreFreq(freq) => access = Some(AccessStats::with_frequency(*freq)),
+            _ => (),
+        }
+    }
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let ttl = parse_arg::<i64>(&drain.next().unwrap());
+    let payload = drain.next().unwrap();
+
+    let (value, expires_at_ms) = match snapshot::restore_value(&payload) {
+        Ok(restored) => restored,
+        Err(_) => return resp::error("ERR DUMP payload version or checksum are wrong"),
+    };
+    let ttl_ms = match ttl {
+        0 => expires_at_ms.map(expire::unix_ms_to_ttl),
+        _ if absttl => Some(expire::unix_ms_to_ttl(ttl)),
+        _ => Some(ttl),
+    };
+    if let Err(e) = db.restore(key.clone(), value, access, replace) {
+        return store_error(e);
+    }
+    if let Some(ttl_ms) = ttl_ms {
+        expire_in(db, key, ttl_ms);
+    }
+    resp::simple_string("OK")
+}
+
+fn execute_lease(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let token = lease_token(&cmd);
+    let ttl_ms = parse_arg(&cmd.args()[1]);
+    let key = cmd.args_mut().swap_remove(0);
+    match db.lease(key, ttl_ms, token) {
+        Ok(token) => resp::bulk_string(lease::format_token(token).as_bytes()),
+        Err(e) => store_error(e),
+    }
+}
+
+/// Replies with each current lease as its key, token and remaining TTL in milliseconds.
+fn execute_leases(db: &Db) -> resp::Value {
+    let now = Instant::now();
+    let mut leases: Vec<_> = db.leases().iter().collect();
+    leases.sort_by_key(|(key, _)| *key);
+    resp::array(
+        leases
+            .into_iter()
+            .map(|(key, lease)| {
+                let ttl = lease.expires_at.saturating_duration_since(now);
+                resp::array(vec![
+                    resp::bulk_string(key),
+                    resp::bulk_string(lease::format_token(lease.token).as_bytes()),
+                    resp::integer(ttl.as_millis() as i64),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Moves keys to another instance by restoring them there, then deletes the ones restored
+/// unless COPY is given. The values are dumped under a read lock and the store is unlocked
+/// while the target replies, so a slow target holds up no other client; a key written in
+/// the meantime is kept rather than deleted.
+async fn execute_migrate(db_index: usize, cmd: Command) -> resp::Value {
+    if migrate::targets_self(&cmd, listen_addr()).await {
+        return resp::error("ERR Target instance is this instance");
+    }
+    let (migration, dumped_at) = match dump_migration(&*STORE.read().await, db_index, &cmd) {
+        Ok(dumped) => dumped,
+        Err(reply) => return reply,
+    };
+    let replies = match transfer_migration(&migration).await {
+        Ok(replies) => replies,
+        Err(reply) => return reply,
+    };
+    let mut store = STORE.write().await;
+    let mut log = vec![];
+    let reply = finish_migration(
+        &mut store,
+        db_index,
+        &cmd,
+        (&migration, dumped_at),
+        &replies,
+        &mut log,
+    );
+    propagate(&log, false);
+    reply
+}
+
+/// MIGRATE queued in MULTI, run with the store locked for all of EXEC, target replies
+/// included, since no other client may see the transaction half applied.
+async fn execute_migrate_locked(
+    store: &mut Store,
+    db_index: usize,
+    cmd: Command,
+    log: &mut Vec<(usize, Argv)>,
+) -> resp::Value {
+    if migrate::targets_self(&cmd, listen_addr()).await {
+        return resp::error("ERR Target instance is this instance");
+    }
+    let (migration, dumped_at) = match dump_migration(store, db_index, &cmd) {
+        Ok(dumped) => dumped,
+        Err(reply) => return reply,
+    };
+    match transfer_migration(&migration).await {
+        Ok(replies) => finish_migration(
+            store,
+            db_index,
+            &cmd,
+            (&migration, dumped_at),
+            &replies,
+            log,
+        ),
+        Err(reply) => reply,
+    }
+}
+
+/// The address this instance listens on, which MIGRATE may not target.
+fn listen_addr() -> SocketAddr {
+    SocketAddr::new(HOST.parse().unwrap(), CONFIG.port)
+}
+
+/// Dumps the keys MIGRATE moves, along with when, or the reply to turn it away with.
+fn dump_migration(
+    store: &Store,
+    db_index: usize,
+    cmd: &Command,
+) -> std::result::Result<(Migration, Instant), resp::Value> {
+    check_write_access(store, db_index, cmd)?;
+    match Migration::new(store.db(db_index), cmd, CONFIG.cluster_enabled) {
+        Some(migration) => Ok((migration, Instant::now())),
+        None => Err(resp::simple_string("NOKEY")),
+    }
+}
+
+async fn transfer_migration(
+    migration: &Migration,
+) -> std::result::Result<Vec<resp::Value>, resp::Value> {
+    migration.transfer().await.map_err(|e| {
+        resp::error(&format!(
+            "IOERR error or timeout talking to the target instance: {}",
+            e
+        ))
+    })
+}
+
+/// Deletes the keys the target restored that are unchanged since `dumped`, logging them as
+/// DELs. Checks the write again first, as a lease or lock may have been taken meanwhile.
+fn finish_migration(
+    store: &mut Store,
+    db_index: usize,
+    cmd: &Command,
+    (migration, dumped_at): (&Migration, Instant),
+    replies: &[resp::Value],
+    log: &mut Vec<(usize, Argv)>,
+) -> resp::Value {
+    if let Err(reply) = check_write_cmd(store, db_index, cmd) {
+        return reply;
+    }
+    let (reply, removed) = migration.finish(store.db_mut(db_index), replies, dumped_at);
+    store.settle_memory();
+    log_removed(store, log);
+    log.extend(
+        removed
+            .into_iter()
+            .map(|key| (db_index, vec![b"DEL".to_vec(), key])),
+    );
+    reply
+}
+
+fn encode_argv(argv: &[Vec<u8>]) -> Vec<u8> {
+    resp::encode(&resp::array(argv.iter().map(resp::bulk_string).collect()))
+}
+
+fn select_db(store: &Store, cmd: &Command) -> std::result::Result<usize, StoreError> {
+    let index = store.db_index(parse_arg::<i64>(&cmd.args()[0]))?;
+    if CONFIG.cluster_enabled && index != 0 {
+        return Err(StoreError::new(StoreErrorKind::SelectInCluster));
+    }
+    Ok(index)
+}
+
+fn execute_move(store: &mut Store, db_index: usize, cmd: Command) -> resp::Value {
+    let key = &cmd.args()[0];
+    let to = match store.db_index(parse_arg::<i64>(&cmd.args()[1])) {
+        Ok(to) => to,
+        Err(e) => return store_error(e),
+    };
+    match store.move_key(key, db_index, to) {
+        Ok(moved) => resp::integer(moved as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_swapdb(store: &mut Store, cmd: Command) -> resp::Value {
+    let a = store.db_index(parse_arg::<i64>(&cmd.args()[0]));
+    let b = store.db_index(parse_arg::<i64>(&cmd.args()[1]));
+    match (a, b) {
+        (Ok(a), Ok(b)) => {
+            store.swap_dbs(a, b);
+            resp::simple_string("OK")
+        }
+        (Err(e), _) | (_, Err(e)) => store_error(e),
+    }
+}
+
+/// Drops flushed data, on a separate thread for the ASYNC option so large databases do not
+/// hold up the store.
+fn drop_flushed<T: Send + 'static>(cmd: &Command, flushed: T) -> resp::Value {
+    if cmd.opts().contains(&CommandOpt::FlushAsync) {
+        std::thread::spawn(move || drop(flushed));
+    } else {
+        drop(flushed);
+    }
+    resp::simple_string("OK")
+}
+
+fn execute_flushdb(db: &mut Db, cmd: Command) -> resp::Value {
+    let flushed = db.flush();
+    drop_flushed(&cmd, flushed)
+}
+
+fn execute_flushall(store: &mut Store, cmd: Command) -> resp::Value {
+    let flushed = store.flush_all();
+    drop_flushed(&cmd, flushed)
+}
+
+/// Reads the cursor at `cursor_index` and the COUNT and MATCH options of a SCAN-family
+/// command.
+fn scan_args(cmd: &Command, cursor_index: usize) -> (u64, usize, Option<&[u8]>) {
+    let args = cmd.args();
+    let cursor = parse_arg::<u64>(&args[cursor_index]);
+    let mut count = scan::DEFAULT_COUNT;
+    let mut pattern = None;
+    for opt in cmd.opts().iter() {
+        match opt {
+            CommandOpt::ScanCount(c) => count = *c,
+            CommandOpt::ScanMatch => pattern = args.last().map(|p| p.as_slice()),
+            _ => continue,
+        }
+    }
+    (cursor, count, pattern)
+}
+
+fn scan_reply(cursor: u64, items: resp::Value) -> resp::Value {
+    resp::array(vec![resp::bulk_string(&cursor.to_string()), items])
+}
+
+fn execute_scan(db: &Db, cmd: Command) -> resp::Value {
+    let (cursor, count, pattern) = scan_args(&cmd, 0);
+    let type_name = cmd.opts().iter().find_map(|opt| match opt {
+        CommandOpt::ScanType(name) => Some(*name),
+        _ => None,
+    });
+    let (next, keys) = db.scan(cursor, count, pattern, type_name);
+    scan_reply(next, bulk_string_array(keys))
+}
+
+fn execute_sscan(db: &Db, cmd: Command) -> resp::Value {
+    let (cursor, count, pattern) = scan_args(&cmd, 1);
+    match db.sscan(&cmd.args()[0], cursor, count, pattern) {
+        Ok((next, members)) => scan_reply(next, bulk_string_array(members)),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hscan(db: &Db, cmd: Command) -> resp::Value {
+    let (cursor, count, pattern) = scan_args(&cmd, 1);
+    match db.hscan(&cmd.args()[0], cursor, count, pattern) {
+        Ok((next, pairs)) => scan_reply(
+            next,
+            bulk_string_array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(field, value)| vec![field, value])
+                    .collect(),
+            ),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zscan(db: &Db, cmd: Command) -> resp::Value {
+    let (cursor, count, pattern) = scan_args(&cmd, 1);
+    match db.zscan(&cmd.args()[0], cursor, count, pattern) {
+        Ok((next, members)) => scan_reply(next, scored_array(members, true)),
+        Err(e) => store_error(e),
+    }
+}
+
+/// Handles the EXPIRE family, whose time argument is in units of `unit_ms` milliseconds
+/// and is a Unix time rather than a TTL when `absolute` is set.
+fn execute_expire(db: &mut Db, mut cmd: Command, unit_ms: i64, absolute: bool) -> resp::Value {
+    let (key, time) = {
+        let mut drain = cmd.drain_args();
+        (
+            drain.next().unwrap(),
+            parse_arg::<i64>(&drain.next().unwrap()),
+        )
+    };
+    let mut ttl_ms = time * unit_ms;
+    if absolute {
+        ttl_ms = expire::unix_ms_to_ttl(ttl_ms);
+    }
+
+    let current = match db.ttl(&key) {
+        TTL::KeyNotFound => return resp::integer(0),
+        TTL::NoExpiration => None,
+        TTL::Expires(current) => Some(current),
+    };
+    // A key without a TTL counts as never expiring.
+    let allowed = cmd.opts().iter().all(|opt| match opt {
+        CommandOpt::ExpireNx => current.is_none(),
+        CommandOpt::ExpireXx => current.is_some(),
+        CommandOpt::ExpireGt => current.is_some_and(|current| ttl_ms > current),
+        CommandOpt::ExpireLt => current.is_none_or(|current| ttl_ms < current),
+        _ => true,
+    });
+    if !allowed {
+        return resp::integer(0);
+    }
+    resp::integer(expire_in(db, key, ttl_ms) as i64)
+}
+
+/// Handles the TTL family, replying in units of `unit_ms` milliseconds, rounded to the
+/// nearest, and with a Unix time rather than a TTL when `absolute` is set.
+fn execute_ttl(db: &Db, cmd: Command, unit_ms: i64, absolute: bool) -> resp::Value {
+    let key = cmd.args().first().unwrap();
+    resp::integer(match db.ttl(key) {
+        TTL::Expires(mut ttl_ms) => {
+            if absolute {
+                ttl_ms = expire::ttl_to_unix_ms(ttl_ms);
+            }
+            (ttl_ms + unit_ms / 2) / unit_ms
+        }
+        TTL::NoExpiration => -1,
+        TTL::KeyNotFound => -2,
+    })
+}
+
+/// Replies with server information in `section: value` lines, grouped by section. Only the
+/// sections named in the arguments are included, or all of them when none are named.
+fn execute_info(store: &Store, cmd: Command) -> resp::Value {
+    let sections = vec![
+        (
+            "memory",
+            vec![
+                ("used_memory", store.mem_usage().to_string()),
+                ("used_memory_snapshot", store.snapshot_memory().to_string()),
+                ("maxmemory", store.maxmemory().to_string()),
+                ("maxmemory_policy", store.eviction_policy().to_string()),
+            ],
+        ),
+        (
+            "persistence",
+            vec![
+                (
+                    "rdb_changes_since_last_save",
+                    store.changes_since_save().to_string(),
+                ),
+                (
+                    "rdb_bgsave_in_progress",
+                    (store.is_saving() as u8).to_string(),
+                ),
+                ("rdb_last_save_time", store.last_save().to_string()),
+                (
+                    "rdb_last_bgsave_status",
+                    if store.last_save_ok() { "ok" } else { "err" }.to_string(),
+                ),
+                ("aof_enabled", (CONFIG.appendonly as u8).to_string()),
+                (
+                    "aof_rewrite_in_progress",
+                    (AOF.lock().unwrap().as_ref().is_some_and(Aof::is_rewriting) as u8).to_string(),
+                ),
+            ],
+        ),
+        (
+            "stats",
+            vec![("evicted_keys", store.evicted_keys().to_string())],
+        ),
+        (
+            "cluster",
+            vec![(
+                "cluster_enabled",
+                (CONFIG.cluster_enabled as u8).to_string(),
+            )],
+        ),
+    ];
+    let sections = sections
+        .into_iter()
+        .map(|(name, fields)| (name, info_fields(fields)))
+        .chain(std::iter::once(("replication", replication_info())));
+    let wanted: Vec<String> = cmd
+        .args()
+        .iter()
+        .map(|section| String::from_utf8_lossy(section).to_lowercase())
+        .collect();
+    let mut info = String::new();
+    for (name, fields) in sections {
+        if !wanted.is_empty()
+            && !wanted
+                .iter()
+                .any(|section| section == name || section == "all" || section == "everything")
+        {
+            continue;
+        }
+        if !info.is_empty() {
+            info.push_str("\r\n");
+        }
+        info.push_str(&format!("# {}{}\r\n", name[..1].to_uppercase(), &name[1..]));
+        for (field, value) in fields.iter() {
+            info.push_str(&format!("{}:{}\r\n", field, value));
+        }
+    }
+    resp::bulk_string(&info)
+}
+
+fn replication_info() -> Vec<(String, String)> {
+    let replication = REPLICATION.lock().unwrap();
+    let mut fields = vec![];
+    match replication.role() {
+        Role::Leader => fields.push(("role", "master".to_owned())),
+        Role::Replica { host, port } => {
+            fields.push(("role", "slave".to_owned()));
+            fields.push(("master_host", host.clone()));
+            fields.push(("master_port", port.to_string()));
+            let status = if replication.link_up() { "up" } else { "down" };
+            fields.push(("master_link_status", status.to_owned()));
+        }
+    }
+    let mut replicas: Vec<_> = replication.replicas().collect();
+    replicas.sort_by_key(|(id, _)| **id);
+    fields.push(("connected_slaves", replicas.len().to_string()));
+    let mut fields = info_fields(fields);
+    for (index, (_, replica)) in replicas.iter().enumerate() {
+        fields.push((
+            format!("slave{}", index),
+            format!(
+                "ip={},port={},offset={}",
+                replica.addr.ip(),
+                replica.addr.port(),
+                replica.offset
+            ),
+        ));
+    }
+    let backlog = replication.backlog();
+    fields.extend(info_fields(vec![
+        ("master_replid", replication.replid().to_owned()),
+        ("master_repl_offset", replication.offset().to_string()),
+        ("repl_backlog_active", (backlog.is_some() as u8).to_string()),
+        ("repl_backlog_size", CONFIG.repl_backlog_size.to_string()),
+        (
+            "repl_backlog_first_byte_offset",
+            backlog.map_or(0, |backlog| backlog.start()).to_string(),
+        ),
+    ]));
+    fields
+}
+
+fn info_fields(fields: Vec<(&str, String)>) -> Vec<(String, String)> {
+    fields
+        .into_iter()
+        .map(|(field, value)| (field.to_owned(), value))
+        .collect()
+}
+
+fn execute_memory_usage(db: &Db, cmd: Command) -> resp::Value {
+    let mut samples = MEMORY_SAMPLES;
+    for opt in cmd.opts() {
+        if let CommandOpt::MemorySamples(count) = opt {
+            samples = *count;
+        }
+    }
+    match db.memory_usage(&cmd.args()[0], samples) {
+        Some(size) => resp::integer(size as i64),
+        None => resp::Value::Null,
+    }
+}
+
+/// Replies with the memory breakdown as a flat list of names and values, with the
+/// databases that hold keys nested under `db.<index>`.
+fn execute_memory_stats(store: &Store) -> resp::Value {
+    let stats = store.memory_stats();
+    let mut reply = vec![];
+    let mut field = |name: &str, value: resp::Value| {
+        reply.push(resp::bulk_string(name));
+        reply.push(value);
+    };
+    field("total.allocated", resp::integer(stats.total as i64));
+    field("allocator.allocated", resp::integer(stats.allocated as i64));
+    field(
+        "accounting",
+        resp::bulk_string(&stats.accounting.to_string()),
+    );
+    field("maxmemory", resp::integer(store.maxmemory() as i64));
+    field("overhead.total", resp::integer(stats.overhead() as i64));
+    field("clients.normal", resp::integer(stats.clients as i64));
+    field("snapshot.bytes", resp::integer(stats.snapshot as i64));
+    for db in stats.dbs.iter() {
+        field(
+            &format!("db.{}", db.index),
+            resp::array(vec![
+                resp::bulk_string("overhead.hashtable.main"),
+                resp::integer(db.keyspace_overhead as i64),
+                resp::bulk_string("overhead.hashtable.expires"),
+                resp::integer(db.expires_overhead as i64),
+            ]),
+        );
+    }
+    field("keys.count", resp::integer(stats.keys as i64));
+    let per_key = stats.dataset.checked_div(stats.keys).unwrap_or(0);
+    field("keys.bytes-per-key", resp::integer(per_key as i64));
+    field("dataset.bytes", resp::integer(stats.dataset as i64));
+    field(
+        "dataset.percentage",
+        resp::bulk_string(&format!("{:.2}", stats.dataset_percentage())),
+    );
+    resp::array(reply)
+}
+
+fn execute_client_list(store: &Store) -> resp::Value {
+    let clients: Vec<String> = store
+        .clients()
+        .map(|client| format!("{}", client))
+        .collect();
+    let clients = clients.join("\n");
+    resp::bulk_string(&clients)
+}
+
+/// Parses an argument that was already validated when the command was parsed.
+fn parse_arg<T>(arg: &[u8]) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Debug,
+{
+    std::str::from_utf8(arg).unwrap().parse::<T>().unwrap()
+}
+
+fn store_error(err: StoreError) -> resp::Value {
+    let msg = format!("{}", err);
+    resp::error(msg.as_str())
+}
+
+fn bulk_string_array<S: AsRef<[u8]>>(values: Vec<S>) -> resp::Value {
+    resp::array(
+        values
+            .iter()
+            .map(|s| resp::bulk_string(s.as_ref()))
+            .collect(),
+    )
+}
+
+fn execute_push(db: &mut Db, mut cmd: Command, end: ListEnd) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    match db.push(key, drain.collect(), end) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_pop(db: &mut Db, cmd: Command, end: ListEnd) -> resp::Value {
+    let key = &cmd.args()[0];
+    let count = cmd.args().get(1).map(|c| parse_arg::<usize>(c));
+    match db.pop(key, count.unwrap_or(1), end) {
+        Ok(Some(values)) => {
+            if count.is_some() {
+                bulk_string_array(values)
+            } else {
+                resp::bulk_string(&values[0])
+            }
+        }
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_lrange(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let start = parse_arg::<i64>(&args[1]);
+    let stop = parse_arg::<i64>(&args[2]);
+    match db.lrange(&args[0], start, stop) {
+        Ok(values) => bulk_string_array(values),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_lindex(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let index = parse_arg::<i64>(&args[1]);
+    match db.lindex(&args[0], index) {
+        Ok(Some(value)) => resp::bulk_string(value),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_lset(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let index = parse_arg::<i64>(&drain.next().unwrap());
+    let val = drain.next().unwrap();
+    match db.lset(&key, index, val) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_lrem(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let count = parse_arg::<i64>(&args[1]);
+    match db.lrem(&args[0], count, &args[2]) {
+        Ok(removed) => resp::integer(removed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_ltrim(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let start = parse_arg::<i64>(&args[1]);
+    let stop = parse_arg::<i64>(&args[2]);
+    match db.ltrim(&args[0], start, stop) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_llen(db: &Db, cmd: Command) -> resp::Value {
+    match db.llen(&cmd.args()[0]) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_linsert(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let before = cmd.opts().contains(&CommandOpt::LInsertBefore);
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let pivot = drain.next().unwrap();
+    let val = drain.next().unwrap();
+    match db.linsert(&key, before, &pivot, val) {
+        Ok(len) => resp::integer(len),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hset(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let mut pairs = vec![];
+    while let (Some(field), Some(val)) = (drain.next(), drain.next()) {
+        pairs.push((field, val));
+    }
+    match db.hset(key, pairs) {
+        Ok(added) => resp::integer(added as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hsetnx(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let field = drain.next().unwrap();
+    let val = drain.next().unwrap();
+    match db.hsetnx(key, field, val) {
+        Ok(set) => resp::integer(set as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hget(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.hget(&args[0], &args[1]) {
+        Ok(Some(value)) => resp::bulk_string(value),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hmget(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.hmget(&args[0], &args[1..]) {
+        Ok(values) => resp::array(
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Some(value) => resp::bulk_string(value),
+                    None => resp::Value::Null,
+                })
+                .collect(),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hdel(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.hdel(&args[0], &args[1..]) {
+        Ok(removed) => resp::integer(removed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hgetall(db: &Db, cmd: Command) -> resp::Value {
+    match db.hgetall(&cmd.args()[0]) {
+        Ok(pairs) => resp::array(
+            pairs
+                .into_iter()
+                .flat_map(|(field, value)| vec![resp::bulk_string(field), resp::bulk_string(value)])
+                .collect(),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hkeys(db: &Db, cmd: Command) -> resp::Value {
+    match db.hkeys(&cmd.args()[0]) {
+        Ok(fields) => bulk_string_array(fields),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hvals(db: &Db, cmd: Command) -> resp::Value {
+    match db.hvals(&cmd.args()[0]) {
+        Ok(values) => bulk_string_array(values),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hlen(db: &Db, cmd: Command) -> resp::Value {
+    match db.hlen(&cmd.args()[0]) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hexists(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.hexists(&args[0], &args[1]) {
+        Ok(exists) => resp::integer(exists as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_hincrby(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let field = drain.next().unwrap();
+    let increment = parse_arg::<i64>(&drain.next().unwrap());
+    match db.hincrby(key, field, increment) {
+        Ok(value) => resp::integer(value),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_sadd(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    match db.sadd(key, drain.collect()) {
+        Ok(added) => resp::integer(added as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_srem(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.srem(&args[0], &args[1..]) {
+        Ok(removed) => resp::integer(removed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_smembers(db: &Db, cmd: Command) -> resp::Value {
+    match db.smembers(&cmd.args()[0]) {
+        Ok(members) => bulk_string_array(members),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_sismember(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.sismember(&args[0], &args[1]) {
+        Ok(is_member) => resp::integer(is_member as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_scard(db: &Db, cmd: Command) -> resp::Value {
+    match db.scard(&cmd.args()[0]) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_spop(db: &mut Db, cmd: Command) -> resp::Value {
+    let key = &cmd.args()[0];
+    let count = cmd.args().get(1).map(|c| parse_arg::<usize>(c));
+    match db.spop(key, count.unwrap_or(1)) {
+        Ok(Some(members)) => {
+            if count.is_some() {
+                bulk_string_array(members)
+            } else {
+                resp::bulk_string(&members[0])
+            }
+        }
+        Ok(None) => {
+            if count.is_some() {
+                resp::array(vec![])
+            } else {
+                resp::Value::Null
+            }
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_srandmember(db: &Db, cmd: Command) -> resp::Value {
+    let key = &cmd.args()[0];
+    let count = cmd.args().get(1).map(|c| parse_arg::<i64>(c));
+    match db.srandmember(key, count.unwrap_or(1)) {
+        Ok(members) => {
+            if count.is_some() {
+                bulk_string_array(members)
+            } else if let Some(member) = members.first() {
+                resp::bulk_string(member)
+            } else {
+                resp::Value::Null
+            }
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_set_op(db: &Db, cmd: Command, op: SetOp) -> resp::Value {
+    match db.set_op(op, cmd.args()) {
+        Ok(members) => bulk_string_array(members.into_iter().collect()),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_set_op_store(db: &mut Db, mut cmd: Command, op: SetOp) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let dest = drain.next().unwrap();
+    let keys: Vec<Vec<u8>> = drain.collect();
+    match db.set_op_store(op, dest, &keys) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn format_score(score: f64) -> String {
+    format!("{}", score)
+}
+
+fn scored_array<S: AsRef<[u8]>>(entries: Vec<(S, f64)>, with_scores: bool) -> resp::Value {
+    let mut values = vec![];
+    for (member, score) in entries.iter() {
+        values.push(resp::bulk_string(member.as_ref()));
+        if with_scores {
+            values.push(resp::bulk_string(&format_score(*score)));
+        }
+    }
+    resp::array(values)
+}
+
+fn execute_zadd(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let opts = cmd.opts();
+    let zadd_opts = ZAddOptions {
+        nx: opts.contains(&CommandOpt::ZAddNx),
+        xx: opts.contains(&CommandOpt::ZAddXx),
+        gt: opts.contains(&CommandOpt::ZAddGt),
+        lt: opts.contains(&CommandOpt::ZAddLt),
+        incr: opts.contains(&CommandOpt::ZAddIncr),
+    };
+    let ch = opts.contains(&CommandOpt::ZAddCh);
+
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let mut members = vec![];
+    while let (Some(score), Some(member)) = (drain.next(), drain.next()) {
+        members.push((zset::parse_score(&score).unwrap(), member));
+    }
+
+    match db.zadd(key, members, &zadd_opts) {
+        Ok(outcomes) => {
+            if zadd_opts.incr {
+                match outcomes.first() {
+                    Some(AddOutcome::Added(score))
+                    | Some(AddOutcome::Updated(score))
+                    | Some(AddOutcome::Unchanged(score)) => {
+                        resp::bulk_string(&format_score(*score))
+                    }
+                    _ => resp::Value::Null,
+                }
+            } else {
+                let count = outcomes
+                    .iter()
+                    .filter(|outcome| match outcome {
+                        AddOutcome::Added(_) => true,
+                        AddOutcome::Updated(_) => ch,
+                        _ => false,
+                    })
+                    .count();
+                resp::integer(count as i64)
+            }
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zrange(db: &Db, cmd: Command) -> resp::Value {
+    let opts = cmd.opts();
+    let rev = opts.contains(&CommandOpt::ZRangeRev);
+    let with_scores = opts.contains(&CommandOpt::ZRangeWithScores);
+    let limit = opts.iter().find_map(|opt| match opt {
+        CommandOpt::ZRangeLimit(offset, count) => Some((*offset, *count)),
+        _ => None,
+    });
+    let args = cmd.args();
+    let (key, start, stop) = (&args[0], &args[1], &args[2]);
+
+    let result = if opts.contains(&CommandOpt::ZRangeByScore) {
+        let start = ScoreBound::parse(start).unwrap();
+        let stop = ScoreBound::parse(stop).unwrap();
+        if rev {
+            db.zrange_by_score(key, &stop, &start, rev, limit)
+        } else {
+            db.zrange_by_score(key, &start, &stop, rev, limit)
+        }
+    } else if opts.contains(&CommandOpt::ZRangeByLex) {
+        let start = LexBound::parse(start).unwrap();
+        let stop = LexBound::parse(stop).unwrap();
+        if rev {
+            db.zrange_by_lex(key, &stop, &start, rev, limit)
+        } else {
+            db.zrange_by_lex(key, &start, &stop, rev, limit)
+        }
+    } else {
+        let start = parse_arg::<i64>(start);
+        let stop = parse_arg::<i64>(stop);
+        db.zrange(key, start, stop, rev)
+    };
+
+    match result {
+        Ok(entries) => scored_array(entries, with_scores),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zrank(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.zrank(&args[0], &args[1], false) {
+        Ok(Some(rank)) => resp::integer(rank as i64),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zscore(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.zscore(&args[0], &args[1]) {
+        Ok(Some(score)) => resp::bulk_string(&format_score(score)),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zrem(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.zrem(&args[0], &args[1..]) {
+        Ok(removed) => resp::integer(removed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zincrby(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let increment = zset::parse_score(&drain.next().unwrap()).unwrap();
+    let member = drain.next().unwrap();
+    match db.zincrby(key, increment, member) {
+        Ok(score) => resp::bulk_string(&format_score(score)),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_zpop(db: &mut Db, cmd: Command, max: bool) -> resp::Value {
+    let key = &cmd.args()[0];
+    let count = cmd
+        .args()
+        .get(1)
+        .map(|c| parse_arg::<usize>(c))
+        .unwrap_or(1);
+    match db.zpop(key, count, max) {
+        Ok(entries) => scored_array(entries, true),
+        Err(e) => store_error(e),
+    }
+}
+
+fn stream_entry(id: StreamId, fields: Option<&Fields>) -> resp::Value {
+    let fields = match fields {
+        Some(fields) => resp::array(
+            fields
+                .iter()
+                .flat_map(|(f, v)| vec![resp::bulk_string(f), resp::bulk_string(v)])
+                .collect(),
+        ),
+        None => resp::Value::Null,
+    };
+    resp::array(vec![resp::bulk_string(&id.to_string()), fields])
+}
+
+fn stream_entries(entries: Vec<EntryRef>) -> resp::Value {
+    resp::array(
+        entries
+            .into_iter()
+            .map(|(id, fields)| stream_entry(id, Some(fields)))
+            .collect(),
+    )
+}
+
+fn stream_ids(ids: Vec<StreamId>) -> resp::Value {
+    bulk_string_array(ids.iter().map(|id| id.to_string()).collect())
+}
+
+fn count_opt(cmd: &Command) -> Option<usize> {
+    cmd.opts().iter().find_map(|opt| match opt {
+        CommandOpt::XCount(count) => Some(*count),
+        _ => None,
+    })
+}
+
+fn execute_xadd(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let opts = cmd.opts();
+    let no_mk_stream = opts.contains(&CommandOpt::XAddNoMkStream);
+    let limit = opts.iter().find_map(|opt| match opt {
+        CommandOpt::XTrimLimit(limit) => Some(*limit),
+        _ => None,
+    });
+    let trim = opts.iter().find_map(|opt| match opt {
+        CommandOpt::XTrim(strategy) => Some((*strategy, limit)),
+        _ => None,
+    });
+
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let id = XAddId::parse(&drain.next().unwrap()).unwrap();
+    let mut fields = vec![];
+    while let (Some(field), Some(value)) = (drain.next(), drain.next()) {
+        fields.push((field, value));
+    }
+
+    match db.xadd(key, id, fields, no_mk_stream, trim) {
+        Ok(Some(id)) => resp::bulk_string(&id.to_string()),
+        Ok(None) => resp::Value::Null,
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xrange(db: &Db, cmd: Command, rev: bool) -> resp::Value {
+    let args = cmd.args();
+    let (start, end) = if rev {
+        (&args[2], &args[1])
+    } else {
+        (&args[1], &args[2])
+    };
+    let start = StreamId::parse_start(start).unwrap();
+    let end = StreamId::parse_end(end).unwrap();
+    match db.xrange(&args[0], start, end, count_opt(&cmd), rev) {
+        Ok(entries) => stream_entries(entries),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xlen(db: &Db, cmd: Command) -> resp::Value {
+    match db.xlen(&cmd.args()[0]) {
+        Ok(len) => resp::integer(len as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xtrim(db: &mut Db, cmd: Command) -> resp::Value {
+    let mut strategy = None;
+    let mut limit = None;
+    for opt in cmd.opts().iter() {
+        match opt {
+            CommandOpt::XTrim(s) => strategy = Some(*s),
+            CommandOpt::XTrimLimit(l) => limit = Some(*l),
+            _ => continue,
+        }
+    }
+    match db.xtrim(&cmd.args()[0], &strategy.unwrap(), limit) {
+        Ok(removed) => resp::integer(removed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+/// Replaces `$` IDs in an XREAD command with the current last ID of each stream, so that a
+/// blocked read only returns entries added after it started.
+fn resolve_last_ids(db: &Db, cmd: &mut Command) {
+    let args = cmd.args_mut();
+    let half = args.len() / 2;
+    for i in 0..half {
+        if args[half + i] == b"$" {
+            if let Ok(id) = db.stream_last_id(&args[i]) {
+                args[half + i] = id.to_string().into_bytes();
+            }
+        }
+    }
+}
+
+fn execute_xread(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let half = args.len() / 2;
+    let mut streams = Vec::with_capacity(half);
+    for (key, id) in args[..half].iter().zip(args[half..].iter()) {
+        let id = if id == b"$" {
+            match db.stream_last_id(key) {
+                Ok(id) => id,
+                Err(e) => return store_error(e),
+            }
+        } else {
+            StreamId::parse(id).unwrap()
+        };
+        streams.push((key.clone(), id));
+    }
+
+    match db.xread(&streams, count_opt(&cmd)) {
+        Ok(result) if result.is_empty() => resp::Value::Null,
+        Ok(result) => resp::array(
+            result
+                .into_iter()
+                .map(|(key, entries)| {
+                    resp::array(vec![resp::bulk_string(key), stream_entries(entries)])
+                })
+                .collect(),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xreadgroup(db: &mut Db, cmd: Command) -> resp::Value {
+    let no_ack = cmd.opts().contains(&CommandOpt::XReadNoAck);
+    let args = cmd.args();
+    let (group, consumer) = (&args[0], &args[1]);
+    let streams = &args[2..];
+    let half = streams.len() / 2;
+    let streams: Vec<(Vec<u8>, GroupReadFrom)> = streams[..half]
+        .iter()
+        .zip(streams[half..].iter())
+        .map(|(key, id)| {
+            let from = if id == b">" {
+                GroupReadFrom::New
+            } else {
+                GroupReadFrom::History(StreamId::parse(id).unwrap())
+            };
+            (key.clone(), from)
+        })
+        .collect();
+
+    match db.xreadgroup(group, consumer, &streams, count_opt(&cmd), no_ack) {
+        Ok(result) if result.is_empty() => resp::Value::Null,
+        Ok(result) => resp::array(
+            result
+                .into_iter()
+                .map(|(key, entries)| {
+                    let entries = entries
+                        .iter()
+                        .map(|(id, fields)| stream_entry(*id, fields.as_ref()))
+                        .collect();
+                    resp::array(vec![resp::bulk_string(&key), resp::array(entries)])
+                })
+                .collect(),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn parse_group_id(id: &[u8]) -> Option<StreamId> {
+    if id == b"$" {
+        None
+    } else {
+        StreamId::parse(id)
+    }
+}
+
+fn execute_xgroup_create(db: &mut Db, mut cmd: Command) -> resp::Value {
+    let mk_stream = cmd.opts().contains(&CommandOpt::XGroupMkStream);
+    let mut drain = cmd.drain_args();
+    let key = drain.next().unwrap();
+    let group = drain.next().unwrap();
+    let id = parse_group_id(&drain.next().unwrap());
+    match db.xgroup_create(key, group, id, mk_stream) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xgroup_setid(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.xgroup_setid(&args[0], &args[1], parse_group_id(&args[2])) {
+        Ok(()) => resp::simple_string("OK"),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xgroup_destroy(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.xgroup_destroy(&args[0], &args[1]) {
+        Ok(destroyed) => resp::integer(destroyed as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xgroup_createconsumer(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.xgroup_createconsumer(&args[0], &args[1], &args[2]) {
+        Ok(created) => resp::integer(created as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xgroup_delconsumer(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    match db.xgroup_delconsumer(&args[0], &args[1], &args[2]) {
+        Ok(pending) => resp::integer(pending as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xack(db: &mut Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let ids: Vec<StreamId> = args[2..]
+        .iter()
+        .map(|id| StreamId::parse(id).unwrap())
+        .collect();
+    match db.xack(&args[0], &args[1], &ids) {
+        Ok(acked) => resp::integer(acked as i64),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xpending(db: &Db, cmd: Command) -> resp::Value {
+    let args = cmd.args();
+    let (key, group) = (&args[0], &args[1]);
+    if args.len() == 2 {
+        return match db.xpending_summary(key, group) {
+            Ok(summary) => {
+                let id = |id: Option<StreamId>| match id {
+                    Some(id) => resp::bulk_string(&id.to_string()),
+                    None => resp::Value::Null,
+                };
+                let consumers = if summary.consumers.is_empty() {
+                    resp::Value::Null
+                } else {
+                    resp::array(
+                        summary
+                            .consumers
+                            .iter()
+                            .map(|(consumer, count)| {
+                                resp::array(vec![
+                                    resp::bulk_string(consumer),
+                                    resp::bulk_string(&count.to_string()),
+                                ])
+                            })
+                            .collect(),
+                    )
+                };
+                resp::array(vec![
+                    resp::integer(summary.count as i64),
+                    id(summary.first),
+                    id(summary.last),
+                    consumers,
+                ])
+            }
+            Err(e) => store_error(e),
+        };
+    }
+
+    let min_idle = cmd.opts().iter().find_map(|opt| match opt {
+        CommandOpt::XPendingIdle(idle) => Some(*idle),
+        _ => None,
+    });
+    let start = StreamId::parse_start(&args[2]).unwrap();
+    let end = StreamId::parse_end(&args[3]).unwrap();
+    let count = parse_arg::<i64>(&args[4]).max(0) as usize;
+    let consumer = args.get(5).map(|c| c.as_slice());
+    match db.xpending_range(key, group, start, end, count, consumer, min_idle) {
+        Ok(pending) => resp::array(
+            pending
+                .iter()
+                .map(|info| {
+                    resp::array(vec![
+                        resp::bulk_string(&info.id.to_string()),
+                        resp::bulk_string(info.consumer),
+                        resp::integer(info.idle as i64),
+                        resp::integer(info.delivery_count as i64),
+                    ])
+                })
+                .collect(),
+        ),
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xclaim(db: &mut Db, cmd: Command) -> resp::Value {
+    let mut opts = ClaimOptions::default();
+    for opt in cmd.opts().iter() {
+        match opt {
+            CommandOpt::XClaimIdle(idle) => opts.idle = Some(*idle),
+            CommandOpt::XClaimTime(time) => opts.time = Some(*time),
+            CommandOpt::XClaimRetryCount(count) => opts.retry_count = Some(*count),
+            CommandOpt::XClaimForce => opts.force = true,
+            CommandOpt::XClaimJustId => opts.just_id = true,
+            CommandOpt::XClaimLastId(id) => opts.last_id = Some(*id),
+            _ => continue,
+        }
+    }
+    let args = cmd.args();
+    let min_idle = parse_arg::<u64>(&args[3]);
+    let ids: Vec<StreamId> = args[4..]
+        .iter()
+        .map(|id| StreamId::parse(id).unwrap())
+        .collect();
+
+    match db.xclaim(&args[0], &args[1], &args[2], min_idle, &ids, &opts) {
+        Ok(claimed) => {
+            if opts.just_id {
+                stream_ids(claimed.into_iter().map(|(id, _)| id).collect())
+            } else {
+                stream_entries(claimed.iter().map(|(id, fields)| (*id, fields)).collect())
+            }
+        }
+        Err(e) => store_error(e),
+    }
+}
+
+fn execute_xautoclaim(db: &mut Db, cmd: Command) -> resp::Value {
+    let just_id = cmd.opts().contains(&CommandOpt::XClaimJustId);
+    let count = count_opt(&cmd).unwrap_or(100);
+    let args = cmd.args();
+    let min_idle = parse_arg::<u64>(&args[3]);
+    let start = StreamId::parse_start(&args[4]).unwrap();
+
+    match db.xautoclaim(
+        &args[0], &args[1], &args[2], min_idle, start, count, just_id,
+    ) {
+        Ok(result) => {
+            let claimed = if just_id {
+                stream_ids(result.claimed.into_iter().map(|(id, _)| id).collect())
+            } else {
+                stream_entries(
+                    result
+                        .claimed
+                        .iter()
+                        .map(|(id, fields)| (*id, fields))
+                        .collect(),
+                )
+            };
+            resp::array(vec![
+                resp::bulk_string(&result.next.to_string()),
+                claimed,
+                stream_ids(result.deleted),
+            ])
+        }
+        Err(e) => store_error(e),
+    }
 }
@@ -0,0 +1,358 @@
+use crate::store::Value;
+use crate::stream::{Fields, StreamId};
+use crate::zset;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt;
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many elements of an aggregate value are measured to estimate its size, unless a
+/// command asks for another number.
+pub const MEMORY_SAMPLES: usize = 5;
+
+/// The read buffer each connection is served through.
+pub const CLIENT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Below this memory use, the fixed cost of clients and the process itself dominates and
+/// MEMORY DOCTOR does not report it.
+const DOCTOR_MIN_MEMORY: usize = 1024 * 1024;
+
+/// How memory use is measured against maxmemory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryAccounting {
+    /// Add up estimates of the keyspace, expiry and client structures.
+    #[default]
+    Estimate,
+    /// Use every byte the process has allocated, as counted by [`CountingAllocator`].
+    Allocator,
+}
+
+impl MemoryAccounting {
+    pub const NAMES: &'static [&'static str] = &["estimate", "allocator"];
+}
+
+impl FromStr for MemoryAccounting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "estimate" => Ok(MemoryAccounting::Estimate),
+            "allocator" => Ok(MemoryAccounting::Allocator),
+            _ => Err(format!("unknown memory accounting mode '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MemoryAccounting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryAccounting::Estimate => "estimate".fmt(f),
+            MemoryAccounting::Allocator => "allocator".fmt(f),
+        }
+    }
+}
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Wraps the system allocator to count the bytes allocated, rounded up to size classes,
+/// for [`MemoryAccounting::Allocator`]. The server installs it with `#[global_allocator]`.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(alloc_size(layout.size()), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(alloc_size(layout.size()), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(alloc_size(layout.size()), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_add(alloc_size(new_size), Ordering::Relaxed);
+            ALLOCATED.fetch_sub(alloc_size(layout.size()), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// The bytes currently allocated through [`CountingAllocator`], or 0 if it is not installed.
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Rounds an allocation of `size` bytes up to the size class an allocator like jemalloc
+/// serves it from: multiples of 16 up to 128 bytes, then four classes per doubling.
+pub fn alloc_size(size: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    if size <= 128 {
+        return (size + 15) & !15;
+    }
+    let power = usize::BITS - (size - 1).leading_zeros();
+    let step = 1 << (power - 3);
+    (size + step - 1) & !(step - 1)
+}
+
+/// The heap memory behind a byte string.
+pub fn bytes_size(bytes: &Vec<u8>) -> usize {
+    alloc_size(bytes.capacity())
+}
+
+/// The memory one slot of a hash table holding `T` takes: the value and a control byte.
+pub fn slot_size<T>() -> usize {
+    size_of::<T>() + 1
+}
+
+/// The memory of a hash table holding `T` with room for `capacity` elements. Tables keep
+/// an eighth of their slots free.
+pub fn table_size<T>(capacity: usize) -> usize {
+    alloc_size(capacity * 8 / 7 * slot_size::<T>())
+}
+
+/// The memory one element of an `IndexMap` holding `T` takes: the element and its hash in
+/// the entry vector, and its position in the hash table.
+pub fn index_map_slot_size<T>() -> usize {
+    size_of::<(u64, T)>() + slot_size::<usize>()
+}
+
+/// The memory of an `IndexMap` holding `T` with room for `capacity` elements.
+pub fn index_map_size<T>(capacity: usize) -> usize {
+    alloc_size(capacity * size_of::<(u64, T)>()) + table_size::<usize>(capacity)
+}
+
+/// Estimates the heap memory taken by `value`, including allocation slack and hash table
+/// slots. Aggregates are measured from up to `samples` of their elements, or from all of
+/// them when `samples` is 0.
+pub fn value_size(value: &Value, samples: usize) -> usize {
+    match value {
+        Value::Int(_) => 0,
+        Value::Str(s) => bytes_size(s),
+        Value::List(list) => {
+            alloc_size(list.capacity() * size_of::<Vec<u8>>())
+                + sampled_size(list.iter(), list.len(), samples, bytes_size)
+        }
+        Value::Hash(hash) => {
+            index_map_size::<(Vec<u8>, Vec<u8>)>(hash.capacity())
+                + hash.index_memory()
+                + sampled_size(hash.iter(), hash.len(), samples, |(f, v)| {
+                    bytes_size(f) + bytes_size(v)
+                })
+        }
+        Value::Set(set) => {
+            index_map_size::<Vec<u8>>(set.capacity())
+                + set.index_memory()
+                + sampled_size(set.iter(), set.len(), samples, bytes_size)
+        }
+        Value::ZSet(zset) => {
+            index_map_size::<(Vec<u8>, f64)>(zset.len())
+                + zset.index_memory()
+                + sampled_size(zset.iter(), zset.len(), samples, |(m, _)| {
+                    // The member is stored in both the score table and its skiplist node.
+                    2 * bytes_size(m) + zset::NODE_SIZE
+                })
+        }
+        Value::Stream(stream) => {
+            sampled_size(stream.entries(), stream.len(), samples, |(_, fields)| {
+                // B-tree nodes are two thirds full on average.
+                size_of::<(StreamId, Fields)>() * 3 / 2
+                    + alloc_size(fields.capacity() * size_of::<(Vec<u8>, Vec<u8>)>())
+                    + fields
+                        .iter()
+                        .map(|(f, v)| bytes_size(f) + bytes_size(v))
+                        .sum::<usize>()
+            })
+        }
+    }
+}
+
+/// Sums `size` over the first `samples` of `len` elements and scales it up to all of them.
+fn sampled_size<I, F>(elements: I, len: usize, samples: usize, size: F) -> usize
+where
+    I: Iterator,
+    F: Fn(I::Item) -> usize,
+{
+    if samples == 0 || len <= samples {
+        return elements.map(size).sum();
+    }
+    elements.take(samples).map(size).sum::<usize>() * len / samples
+}
+
+/// The memory overhead of one database.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbMemory {
+    pub index: usize,
+    pub keys: usize,
+    /// Unused slots of the keyspace's hash table.
+    pub keyspace_overhead: usize,
+    /// The index of keys by deadline.
+    pub expires_overhead: usize,
+}
+
+/// Where the memory of a store goes, as reported by MEMORY STATS.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub accounting: MemoryAccounting,
+    /// The memory use compared against maxmemory under `accounting`.
+    pub total: usize,
+    /// The bytes allocated by the whole process, if [`CountingAllocator`] is installed.
+    pub allocated: usize,
+    /// Keys and values, along with the hash table slots they occupy.
+    pub dataset: usize,
+    pub clients: usize,
+    /// The copy of the dataset a background save holds. Not part of `total`.
+    pub snapshot: usize,
+    pub keys: usize,
+    /// Databases that hold keys.
+    pub dbs: Vec<DbMemory>,
+}
+
+impl MemoryStats {
+    /// The estimated memory besides the dataset.
+    pub fn overhead(&self) -> usize {
+        self.keyspace_overhead() + self.expires_overhead() + self.clients
+    }
+
+    pub fn keyspace_overhead(&self) -> usize {
+        self.dbs.iter().map(|db| db.keyspace_overhead).sum()
+    }
+
+    pub fn expires_overhead(&self) -> usize {
+        self.dbs.iter().map(|db| db.expires_overhead).sum()
+    }
+
+    /// The estimated memory of the whole store.
+    pub fn estimate(&self) -> usize {
+        self.dataset + self.overhead()
+    }
+
+    /// The share of `total` taken by the dataset, as a percentage.
+    pub fn dataset_percentage(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.dataset as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+/// Looks for likely memory problems in `stats` and describes them along with advice, as
+/// MEMORY DOCTOR replies.
+pub fn doctor(stats: &MemoryStats, maxmemory: usize) -> String {
+    if stats.keys == 0 {
+        return "The dataset is empty, so there is nothing to diagnose.".to_owned();
+    }
+
+    let mut issues = vec![];
+    if maxmemory > 0 && stats.total * 10 > maxmemory * 9 {
+        issues.push(format!(
+            "Memory use is at {}% of maxmemory. Keys will soon be evicted, or writes refused \
+             under noeviction; consider raising maxmemory.",
+            stats.total * 100 / maxmemory
+        ));
+    }
+    let keyspace_overhead = stats.keyspace_overhead();
+    if keyspace_overhead > stats.dataset / 2 {
+        issues.push(format!(
+            "The keyspace hash tables hold {} bytes of empty slots, which usually follows \
+             deleting many keys. They shrink again once the database is flushed.",
+            keyspace_overhead
+        ));
+    }
+    if stats.dataset / stats.keys < 128 {
+        issues.push(format!(
+            "Keys average {} bytes each including their values, so per-key overhead \
+             dominates. Consider grouping small values into hashes.",
+            stats.dataset / stats.keys
+        ));
+    }
+    if stats.clients > stats.dataset && stats.clients > DOCTOR_MIN_MEMORY {
+        issues.push(format!(
+            "Client buffers take {} bytes, more than the dataset's {} bytes.",
+            stats.clients, stats.dataset
+        ));
+    }
+    if stats.allocated > DOCTOR_MIN_MEMORY && stats.allocated * 2 > stats.estimate() * 3 {
+        issues.push(format!(
+            "The process has {} bytes allocated but the store accounts for only {}. The rest \
+             is held by buffers, in-flight replies or memory not yet returned.",
+            stats.allocated,
+            stats.estimate()
+        ));
+    }
+
+    if issues.is_empty() {
+        "No memory issues detected.".to_owned()
+    } else {
+        issues
+            .iter()
+            .map(|issue| format!("* {}", issue))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_size() {
+        assert_eq!(0, alloc_size(0));
+        assert_eq!(16, alloc_size(1));
+        assert_eq!(128, alloc_size(128));
+        assert_eq!(160, alloc_size(129));
+        assert_eq!(256, alloc_size(256));
+        assert_eq!(320, alloc_size(257));
+        assert_eq!(1280, alloc_size(1025));
+    }
+
+    #[test]
+    fn test_value_size() {
+        let big = Value::Str(vec![b'x'; 100]);
+        assert!(value_size(&big, MEMORY_SAMPLES) >= 100);
+
+        let list = Value::List((0..100).map(|i| vec![b'x'; i]).collect());
+        let exact = value_size(&list, 0);
+        let sampled = value_size(&list, MEMORY_SAMPLES);
+        assert!(exact > sampled);
+        assert_eq!(exact, value_size(&list, 1000));
+    }
+
+    #[test]
+    fn test_doctor() {
+        let mut stats = MemoryStats::default();
+        assert!(doctor(&stats, 0).contains("empty"));
+
+        stats.keys = 10;
+        stats.dataset = 10_000;
+        stats.total = 10_000;
+        assert_eq!("No memory issues detected.", doctor(&stats, 0));
+        assert!(doctor(&stats, 10_500).contains("maxmemory"));
+
+        stats.allocated = 100_000;
+        assert_eq!("No memory issues detected.", doctor(&stats, 0));
+        stats.allocated = 10 * DOCTOR_MIN_MEMORY;
+        assert!(doctor(&stats, 0).contains("allocated"));
+
+        stats.allocated = 0;
+        stats.keys = 1000;
+        assert!(doctor(&stats, 0).contains("hashes"));
+    }
+}
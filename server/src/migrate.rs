@@ -0,0 +1,390 @@
+use crate::command::{Command, CommandOpt};
+use crate::expire;
+use crate::snapshot;
+use crate::store::{Db, TTL};
+use async_std::io::{self, prelude::*};
+use async_std::net::{TcpStream, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// How long MIGRATE waits on the target instance when not given a positive timeout.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Whether MIGRATE `cmd` names this instance, listening on `listen`, as its target: the
+/// target host resolves to the listen address, or both are loopback or unspecified.
+pub async fn targets_self(cmd: &Command, listen: SocketAddr) -> bool {
+    if cmd.args()[1] != listen.port().to_string().as_bytes() {
+        return false;
+    }
+    let host = String::from_utf8_lossy(&cmd.args()[0]);
+    let local = |ip: IpAddr| ip.is_loopback() || ip.is_unspecified();
+    match (host.as_ref(), listen.port()).to_socket_addrs().await {
+        Ok(mut addrs) => {
+            addrs.any(|addr| addr.ip() == listen.ip() || local(addr.ip()) && local(listen.ip()))
+        }
+        Err(_) => false,
+    }
+}
+
+/// The keys of a MIGRATE that exist, dumped as the RESTOREs that recreate them on the
+/// target instance.
+#[derive(Debug)]
+pub struct Migration {
+    host: String,
+    port: u16,
+    dest_db: Vec<u8>,
+    timeout: Duration,
+    copy: bool,
+    keys: Vec<Vec<u8>>,
+    restores: Vec<u8>,
+    /// The commands sent for each key: its RESTORE, led by ASKING when the target may be
+    /// importing the key's slot.
+    per_key: usize,
+}
+
+impl Migration {
+    /// Dumps the keys MIGRATE `cmd` names from `db`, or returns `None` if none exists.
+    /// With `asking`, each RESTORE is led by ASKING, which a cluster node importing the
+    /// keys' slot needs before it takes them.
+    pub fn new(db: &Db, cmd: &Command, asking: bool) -> Option<Migration> {
+        let args = cmd.args();
+        let keys: Vec<Vec<u8>> = args[4..]
+            .iter()
+            .filter(|key| db.get(key).is_some())
+            .cloned()
+            .collect();
+        if keys.is_empty() {
+            return None;
+        }
+
+        let replace = cmd.opts().contains(&CommandOpt::MigrateReplace);
+        let mut restores = vec![];
+        for key in keys.iter() {
+            let (ttl_ms, expires_at_ms) = match db.ttl(key) {
+                TTL::Expires(ttl_ms) => (ttl_ms.max(1), Some(expire::ttl_to_unix_ms(ttl_ms))),
+                _ => (0, None),
+            };
+            let payload = snapshot::dump_value(db.get(key).unwrap(), expires_at_ms);
+            let mut restore = vec![
+                b"RESTORE".to_vec(),
+                key.clone(),
+                ttl_ms.to_string().into_bytes(),
+                payload,
+            ];
+            if replace {
+                restore.push(b"REPLACE".to_vec());
+            }
+            if asking {
+                restores.extend(encode_argv(&[b"ASKING".to_vec()]));
+            }
+            restores.extend(encode_argv(&restore));
+        }
+
+        let timeout_ms = std::str::from_utf8(&args[3])
+            .ok()
+            .and_then(|timeout| timeout.parse::<i64>().ok())
+            .filter(|timeout| *timeout > 0)
+            .map_or(DEFAULT_TIMEOUT_MS, |timeout| timeout as u64);
+        Some(Migration {
+            host: String::from_utf8_lossy(&args[0]).into_owned(),
+            port: std::str::from_utf8(&args[1]).unwrap().parse().unwrap(),
+            dest_db: args[2].clone(),
+            timeout: Duration::from_millis(timeout_ms),
+            copy: cmd.opts().contains(&CommandOpt::MigrateCopy),
+            keys,
+            restores,
+            per_key: if asking { 2 } else { 1 },
+        })
+    }
+
+    /// Selects the destination database on the target instance and restores the keys
+    /// there. Returns the target's reply to each key's RESTORE, or just its reply to
+    /// SELECT if that failed. Each step gives up once the timeout passes.
+    pub async fn transfer(&self) -> io::Result<Vec<resp::Value>> {
+        let addr = (self.host.as_str(), self.port);
+        let target = io::timeout(self.timeout, TcpStream::connect(addr)).await?;
+        let select = encode_argv(&[b"SELECT".to_vec(), self.dest_db.clone()]);
+        let selected = io::timeout(self.timeout, request(&target, &select, 1)).await?;
+        if let Some(error) = selected
+            .into_iter()
+            .find(|reply| matches!(reply, resp::Value::Error(_)))
+        {
+            return Ok(vec![error]);
+        }
+        let count = self.keys.len() * self.per_key;
+        let replies = io::timeout(self.timeout, request(&target, &self.restores, count)).await?;
+        Ok(replies
+            .into_iter()
+            .skip(self.per_key - 1)
+            .step_by(self.per_key)
+            .collect())
+    }
+
+    /// Deletes the keys the target restored from `db`, unless COPY was given, and returns
+    /// MIGRATE's reply along with the keys deleted. A key the target turned down stays, as
+    /// does one written since `dumped_at`, when its values were dumped.
+    pub fn finish(
+        &self,
+        db: &mut Db,
+        replies: &[resp::Value],
+        dumped_at: Instant,
+    ) -> (resp::Value, Vec<Vec<u8>>) {
+        let mut error = None;
+        let mut changed = false;
+        let mut removed = vec![];
+        for (key, reply) in self.keys.iter().zip(replies.iter()) {
+            match reply {
+                resp::Value::Error(e) => {
+                    error.get_or_insert(e);
+                }
+                _ if self.copy => (),
+                _ => match db.last_touched(key) {
+                    Some(touched) if *touched >= dumped_at => changed = true,
+                    Some(_) => {
+                        db.remove(key);
+                        removed.push(key.clone());
+                    }
+                    None => (),
+                },
+            }
+        }
+        let reply = match error {
+            Some(e) => resp::error(&format!("ERR Target instance replied with error: {}", e)),
+            None if changed => resp::error("ERR Key changed during migration and was kept"),
+            None => resp::simple_string("OK"),
+        };
+        (reply, removed)
+    }
+}
+
+fn encode_argv(argv: &[Vec<u8>]) -> Vec<u8> {
+    resp::encode(&resp::array(argv.iter().map(resp::bulk_string).collect()))
+}
+
+/// Sends encoded commands to the target instance and reads `count` replies.
+async fn request(
+    mut stream: &TcpStream,
+    commands: &[u8],
+    count: usize,
+) -> io::Result<Vec<resp::Value>> {
+    stream.write_all(commands).await?;
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    let mut replies = vec![];
+    while replies.len() < count {
+        match resp::decode_prefix(&buf) {
+            Ok((reply, len)) => {
+                replies.push(reply);
+                buf.drain(..len);
+            }
+            Err(resp::Error::IncompleteRespError) => {
+                let read = stream.read(&mut chunk).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.extend_from_slice(&chunk[..read]);
+            }
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid reply")),
+        }
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Value;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    fn command(args: &[&str]) -> Command {
+        let array = args.iter().map(resp::bulk_string).collect();
+        Command::from_resp(resp::array(array)).unwrap()
+    }
+
+    /// Serves one connection as a target instance that answers the commands it reads
+    /// with `replies`, in order, and returns the commands.
+    fn fake_target(replies: Vec<resp::Value>) -> (u16, task::JoinHandle<Vec<resp::Value>>) {
+        let listener = task::block_on(TcpListener::bind(("127.0.0.1", 0))).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![];
+            let mut chunk = [0; 4096];
+            let mut received = vec![];
+            for reply in replies {
+                let cmd = loop {
+                    match resp::decode_prefix(&buf) {
+                        Ok((cmd, len)) => {
+                            buf.drain(..len);
+                            break cmd;
+                        }
+                        Err(_) => {
+                            let read = stream.read(&mut chunk).await.unwrap();
+                            buf.extend_from_slice(&chunk[..read]);
+                        }
+                    }
+                };
+                received.push(cmd);
+                stream.write_all(&resp::encode(&reply)).await.unwrap();
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    fn migrate(port: u16, opts: &[&str]) -> Command {
+        let port = port.to_string();
+        let mut args = vec!["MIGRATE", "127.0.0.1", &port, "", "3", "500"];
+        args.extend(opts);
+        args.extend(&["KEYS", "a", "b", "missing"]);
+        command(&args)
+    }
+
+    fn source() -> Db {
+        let mut db = Db::new(0);
+        db.set(b"a".to_vec(), b"one".to_vec(), false);
+        db.set(b"b".to_vec(), b"two".to_vec(), false);
+        db
+    }
+
+    #[test]
+    fn test_targets_self() {
+        let cmd = |host: &str, port: &str| command(&["MIGRATE", host, port, "k", "0", "0"]);
+        let targets = |cmd: Command, listen: &str| {
+            task::block_on(targets_self(&cmd, listen.parse().unwrap()))
+        };
+        assert!(targets(cmd("127.0.0.1", "8080"), "127.0.0.1:8080"));
+        assert!(targets(cmd("localhost", "8080"), "127.0.0.1:8080"));
+        assert!(targets(cmd("::1", "8080"), "0.0.0.0:8080"));
+        assert!(targets(cmd("10.0.0.1", "8080"), "10.0.0.1:8080"));
+        assert!(!targets(cmd("127.0.0.1", "8081"), "127.0.0.1:8080"));
+        assert!(!targets(cmd("10.0.0.1", "8080"), "127.0.0.1:8080"));
+        assert!(!targets(cmd("10.0.0.2", "8080"), "10.0.0.1:8080"));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (port, target) = fake_target(vec![
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+        ]);
+        let mut db = source();
+        let migration = Migration::new(&db, &migrate(port, &[]), false).unwrap();
+        let dumped_at = Instant::now();
+        let replies = task::block_on(migration.transfer()).unwrap();
+        assert_eq!(
+            (
+                resp::simple_string("OK"),
+                vec![b"a".to_vec(), b"b".to_vec()]
+            ),
+            migration.finish(&mut db, &replies, dumped_at)
+        );
+        assert_eq!(0, db.dbsize());
+
+        let received = task::block_on(target);
+        assert_eq!(
+            resp::array(vec![resp::bulk_string(b"SELECT"), resp::bulk_string(b"3")]),
+            received[0]
+        );
+        match &received[1] {
+            resp::Value::Array(restore) => {
+                assert_eq!(4, restore.len());
+                assert_eq!(resp::bulk_string(b"a"), restore[1]);
+            }
+            reply => panic!("Unexpected command {:?}", reply),
+        }
+        assert!(Migration::new(&db, &migrate(port, &[]), false).is_none());
+    }
+
+    #[test]
+    fn test_transfer_error_keeps_key() {
+        let (port, target) = fake_target(vec![
+            resp::simple_string("OK"),
+            resp::error("BUSYKEY Target key name already exists."),
+            resp::simple_string("OK"),
+        ]);
+        let mut db = source();
+        let migration = Migration::new(&db, &migrate(port, &[]), false).unwrap();
+        let dumped_at = Instant::now();
+        let replies = task::block_on(migration.transfer()).unwrap();
+        let (reply, removed) = migration.finish(&mut db, &replies, dumped_at);
+        assert_eq!(
+            resp::error(
+                "ERR Target instance replied with error: BUSYKEY Target key name already exists."
+            ),
+            reply
+        );
+        assert_eq!(vec![b"b".to_vec()], removed);
+        assert_eq!(Some(&Value::Str(b"one".to_vec())), db.get(b"a"));
+        assert_eq!(None, db.get(b"b"));
+        task::block_on(target);
+    }
+
+    #[test]
+    fn test_transfer_copy_replace() {
+        let (port, target) = fake_target(vec![
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+        ]);
+        let mut db = source();
+        let migration = Migration::new(&db, &migrate(port, &["COPY", "REPLACE"]), false).unwrap();
+        let dumped_at = Instant::now();
+        let replies = task::block_on(migration.transfer()).unwrap();
+        assert_eq!(
+            (resp::simple_string("OK"), vec![]),
+            migration.finish(&mut db, &replies, dumped_at)
+        );
+        assert_eq!(2, db.dbsize());
+
+        let received = task::block_on(target);
+        match &received[2] {
+            resp::Value::Array(restore) => {
+                assert_eq!(resp::bulk_string(b"b"), restore[1]);
+                assert_eq!(resp::bulk_string(b"REPLACE"), restore[4]);
+            }
+            reply => panic!("Unexpected command {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_transfer_select_error() {
+        let (port, target) = fake_target(vec![resp::error("ERR DB index is out of range")]);
+        let mut db = source();
+        let migration = Migration::new(&db, &migrate(port, &[]), false).unwrap();
+        let dumped_at = Instant::now();
+        let replies = task::block_on(migration.transfer()).unwrap();
+        let (reply, removed) = migration.finish(&mut db, &replies, dumped_at);
+        assert_eq!(
+            resp::error("ERR Target instance replied with error: ERR DB index is out of range"),
+            reply
+        );
+        assert!(removed.is_empty());
+        assert_eq!(2, db.dbsize());
+        task::block_on(target);
+    }
+
+    #[test]
+    fn test_transfer_keeps_changed_key() {
+        let (port, target) = fake_target(vec![
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+            resp::simple_string("OK"),
+        ]);
+        let mut db = source();
+        let migration = Migration::new(&db, &migrate(port, &[]), false).unwrap();
+        let dumped_at = Instant::now();
+        let replies = task::block_on(migration.transfer()).unwrap();
+        db.set(b"a".to_vec(), b"uno".to_vec(), false);
+        let (reply, removed) = migration.finish(&mut db, &replies, dumped_at);
+        assert_eq!(
+            resp::error("ERR Key changed during migration and was kept"),
+            reply
+        );
+        assert_eq!(vec![b"b".to_vec()], removed);
+        assert_eq!(Some(&Value::Str(b"uno".to_vec())), db.get(b"a"));
+        task::block_on(target);
+    }
+}
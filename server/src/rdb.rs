@@ -0,0 +1,980 @@
+use crate::clock;
+use crate::scan::ScanSet;
+use crate::snapshot::{crc64, Snapshot, SnapshotEntry};
+use crate::store::{self, Hash, Value};
+use crate::zset::{SortedSet, ZAddOptions};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// The first bytes of every RDB file, followed by the version as four digits.
+const MAGIC: &[u8] = b"REDIS";
+
+/// The version written: that of Redis 5 and 6, which later versions load as well.
+const VERSION: u32 = 9;
+
+/// The latest version read, that of Redis 7.2.
+const MAX_VERSION: u32 = 11;
+
+/// The first version to end with a checksum.
+const CHECKSUM_VERSION: u32 = 5;
+
+const OP_MODULE_AUX: u8 = 0xf7;
+const OP_IDLE: u8 = 0xf8;
+const OP_FREQ: u8 = 0xf9;
+const OP_AUX: u8 = 0xfa;
+const OP_RESIZEDB: u8 = 0xfb;
+const OP_EXPIRETIME_MS: u8 = 0xfc;
+const OP_EXPIRETIME: u8 = 0xfd;
+const OP_SELECTDB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Marks a length whose low six bits name one of the special string encodings below.
+const ENCODED: u8 = 0xc0;
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Strings longer than this are written LZF-compressed when that makes them shorter, as
+/// Redis does.
+const COMPRESS_MIN_LEN: usize = 20;
+
+/// How a node of a quicklist holds its elements: as one plain string, or as a listpack.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+/// Whether `bytes` start like a Redis RDB file.
+pub fn is_rdb(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Decodes a Redis RDB file, of any version up to that of Redis 7.2. Strings, lists, sets,
+/// sorted sets and hashes are read in every encoding Redis uses for them; streams and
+/// module types are refused.
+pub fn read_rdb(bytes: &[u8]) -> io::Result<Snapshot> {
+    if !is_rdb(bytes) || bytes.len() < MAGIC.len() + 4 {
+        return Err(invalid("not a Redis RDB file"));
+    }
+    let version = std::str::from_utf8(&bytes[MAGIC.len()..MAGIC.len() + 4])
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or_else(|| invalid("malformed version"))?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+
+    let mut rdb = Reader::new(bytes);
+    rdb.pos = MAGIC.len() + 4;
+    let mut snapshot = Snapshot::default();
+    let mut expires_at_ms = None;
+    loop {
+        match rdb.u8()? {
+            OP_EOF => break,
+            OP_SELECTDB => snapshot.dbs.push((rdb.length()? as usize, vec![])),
+            OP_RESIZEDB => {
+                rdb.length()?;
+                rdb.length()?;
+            }
+            OP_AUX => {
+                rdb.string()?;
+                rdb.string()?;
+            }
+            OP_EXPIRETIME_MS => expires_at_ms = Some(i64::from_le_bytes(rdb.array()?)),
+            OP_EXPIRETIME => {
+                expires_at_ms = Some(i32::from_le_bytes(rdb.array()?) as i64 * 1000);
+            }
+            OP_FREQ => {
+                rdb.u8()?;
+            }
+            OP_IDLE => {
+                rdb.length()?;
+            }
+            OP_MODULE_AUX => return Err(invalid("module data is not supported")),
+            value_type => {
+                let key = rdb.string()?;
+                let value = rdb.value(value_type)?;
+                if snapshot.dbs.is_empty() {
+                    snapshot.dbs.push((0, vec![]));
+                }
+                let (_, entries) = snapshot.dbs.last_mut().unwrap();
+                entries.push(SnapshotEntry {
+                    key,
+                    value,
+                    expires_at_ms: expires_at_ms.take(),
+                });
+            }
+        }
+    }
+
+    if version >= CHECKSUM_VERSION {
+        let body_len = rdb.pos;
+        let checksum = u64::from_le_bytes(rdb.array()?);
+        // Redis writes a zero checksum when checksums are turned off.
+        if checksum != 0 && checksum != crc64(0, &bytes[..body_len]) {
+            return Err(invalid("checksum mismatch"));
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Encodes `snapshot` as a Redis RDB file that Redis 5 and later can load. Streams have no
+/// encoding here, so they are left out; returns the keys of those left out.
+pub fn write_rdb<W: Write>(snapshot: &Snapshot, out: W) -> io::Result<Vec<Vec<u8>>> {
+    let mut skipped = vec![];
+    let mut rdb = Writer { out, crc: 0 };
+    rdb.write(MAGIC)?;
+    rdb.write(format!("{:04}", VERSION).as_bytes())?;
+    rdb.aux(b"redis-bits", b"64")?;
+    rdb.aux(b"ctime", (clock::now_ms() / 1000).to_string().as_bytes())?;
+    for (index, entries) in snapshot.dbs.iter() {
+        let (streams, entries): (Vec<_>, Vec<_>) = entries
+            .iter()
+            .partition(|entry| matches!(entry.value, Value::Stream(_)));
+        skipped.extend(streams.into_iter().map(|entry| entry.key.clone()));
+        rdb.u8(OP_SELECTDB)?;
+        rdb.length(*index as u64)?;
+        rdb.u8(OP_RESIZEDB)?;
+        rdb.length(entries.len() as u64)?;
+        let expires = entries.iter().filter(|e| e.expires_at_ms.is_some()).count();
+        rdb.length(expires as u64)?;
+        for entry in entries.iter() {
+            if let Some(expires_at_ms) = entry.expires_at_ms {
+                rdb.u8(OP_EXPIRETIME_MS)?;
+                rdb.write(&expires_at_ms.to_le_bytes())?;
+            }
+            rdb.entry(&entry.key, &entry.value)?;
+        }
+    }
+    rdb.u8(OP_EOF)?;
+    let crc = rdb.crc;
+    rdb.out.write_all(&crc.to_le_bytes())?;
+    Ok(skipped)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid RDB file: {}", msg),
+    )
+}
+
+fn int_bytes(int: i64) -> Vec<u8> {
+    int.to_string().into_bytes()
+}
+
+/// Writes RDB opcodes and values while keeping a running checksum.
+struct Writer<W: Write> {
+    out: W,
+    crc: u64,
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.out.write_all(bytes)
+    }
+
+    fn u8(&mut self, byte: u8) -> io::Result<()> {
+        self.write(&[byte])
+    }
+
+    /// Writes a length in as few bytes as fit it: 6 bits, 14 bits, or a 32 or 64-bit
+    /// big-endian number behind a marker byte.
+    fn length(&mut self, len: u64) -> io::Result<()> {
+        if len < 1 << 6 {
+            self.u8(len as u8)
+        } else if len < 1 << 14 {
+            self.write(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if let Ok(len) = u32::try_from(len) {
+            self.u8(0x80)?;
+            self.write(&len.to_be_bytes())
+        } else {
+            self.u8(0x81)?;
+            self.write(&len.to_be_bytes())
+        }
+    }
+
+    fn string(&mut self, s: &[u8]) -> io::Result<()> {
+        if s.len() > COMPRESS_MIN_LEN {
+            if let Some(compressed) = lzf_compress(s) {
+                self.u8(ENCODED | ENC_LZF)?;
+                self.length(compressed.len() as u64)?;
+                self.length(s.len() as u64)?;
+                return self.write(&compressed);
+            }
+        }
+        self.length(s.len() as u64)?;
+        self.write(s)
+    }
+
+    /// Writes an integer string in the smallest integer encoding that holds it.
+    fn int(&mut self, int: i64) -> io::Result<()> {
+        if let Ok(int) = i8::try_from(int) {
+            self.u8(ENCODED | ENC_INT8)?;
+            self.write(&int.to_le_bytes())
+        } else if let Ok(int) = i16::try_from(int) {
+            self.u8(ENCODED | ENC_INT16)?;
+            self.write(&int.to_le_bytes())
+        } else if let Ok(int) = i32::try_from(int) {
+            self.u8(ENCODED | ENC_INT32)?;
+            self.write(&int.to_le_bytes())
+        } else {
+            self.string(&int_bytes(int))
+        }
+    }
+
+    fn aux(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.u8(OP_AUX)?;
+        self.string(key)?;
+        self.string(value)
+    }
+
+    fn entry(&mut self, key: &[u8], value: &Value) -> io::Result<()> {
+        let value_type = match value {
+            Value::Str(_) | Value::Int(_) => TYPE_STRING,
+            Value::List(_) => TYPE_LIST,
+            Value::Set(_) => TYPE_SET,
+            Value::ZSet(_) => TYPE_ZSET_2,
+            Value::Hash(_) => TYPE_HASH,
+            Value::Stream(_) => unreachable!("streams are left out before writing"),
+        };
+        self.u8(value_type)?;
+        self.string(key)?;
+        match value {
+            Value::Str(s) => self.string(s),
+            Value::Int(int) => self.int(*int),
+            Value::List(list) => {
+                self.length(list.len() as u64)?;
+                list.iter().try_for_each(|s| self.string(s))
+            }
+            Value::Set(set) => {
+                self.length(set.len() as u64)?;
+                set.iter().try_for_each(|s| self.string(s))
+            }
+            Value::ZSet(zset) => {
+                self.length(zset.len() as u64)?;
+                zset.iter().try_for_each(|(member, score)| {
+                    self.string(member)?;
+                    self.write(&score.to_le_bytes())
+                })
+            }
+            Value::Hash(hash) => {
+                self.length(hash.len() as u64)?;
+                hash.iter().try_for_each(|(field, value)| {
+                    self.string(field)?;
+                    self.string(value)
+                })
+            }
+            Value::Stream(_) => unreachable!("streams are left out before writing"),
+        }
+    }
+}
+
+/// Reads RDB opcodes and values, and the ziplists, listpacks and intsets that compact
+/// encodings pack inside strings. Fails on truncated or malformed input.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("unexpected end of data"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a length, or the special encoding of a string that is not stored raw, in
+    /// which case the second value is true.
+    fn length_or_encoding(&mut self) -> io::Result<(u64, bool)> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => ((first & 0x3f) as u64, false),
+            1 => ((((first & 0x3f) as u64) << 8) | self.u8()? as u64, false),
+            2 => match first {
+                0x80 => (u32::from_be_bytes(self.array()?) as u64, false),
+                0x81 => (u64::from_be_bytes(self.array()?), false),
+                _ => return Err(invalid("malformed length")),
+            },
+            _ => ((first & 0x3f) as u64, true),
+        })
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        match self.length_or_encoding()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(invalid("expected a length")),
+        }
+    }
+
+    /// Reads a count of elements or bytes, refusing ones larger than the rest of the data
+    /// so that corrupt input cannot trigger huge allocations.
+    fn count(&mut self) -> io::Result<usize> {
+        let count = self.length()?;
+        if count > (self.buf.len() - self.pos) as u64 {
+            return Err(invalid("length past end of data"));
+        }
+        Ok(count as usize)
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let (len, encoded) = self.length_or_encoding()?;
+        if !encoded {
+            if len > (self.buf.len() - self.pos) as u64 {
+                return Err(invalid("length past end of data"));
+            }
+            return Ok(self.take(len as usize)?.to_vec());
+        }
+        Ok(match len as u8 {
+            ENC_INT8 => int_bytes(i8::from_le_bytes(self.array()?) as i64),
+            ENC_INT16 => int_bytes(i16::from_le_bytes(self.array()?) as i64),
+            ENC_INT32 => int_bytes(i32::from_le_bytes(self.array()?) as i64),
+            ENC_LZF => {
+                let compressed_len = self.count()?;
+                let len = self.length()?;
+                lzf_decompress(self.take(compressed_len)?, len as usize)?
+            }
+            encoding => return Err(invalid(&format!("unknown string encoding {}", encoding))),
+        })
+    }
+
+    /// Reads a score as the original ZSET type writes it: as text behind a length byte,
+    /// with three lengths reserved for NaN and the infinities.
+    fn text_score(&mut self) -> io::Result<f64> {
+        match self.u8()? {
+            253 => Err(invalid("NaN score")),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_score(self.take(len as usize)?),
+        }
+    }
+
+    fn value(&mut self, value_type: u8) -> io::Result<Value> {
+        Ok(match value_type {
+            TYPE_STRING => store::string_value(self.string()?),
+            TYPE_LIST => {
+                let len = self.count()?;
+                let mut list = VecDeque::with_capacity(len);
+                for _ in 0..len {
+                    list.push_back(self.string()?);
+                }
+                Value::List(list)
+            }
+            TYPE_SET => {
+                let len = self.count()?;
+                let mut set = ScanSet::new();
+                for _ in 0..len {
+                    set.insert(self.string()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.count()?;
+                let mut pairs = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET {
+                        self.text_score()?
+                    } else {
+                        f64::from_le_bytes(self.array()?)
+                    };
+                    pairs.push((member, score));
+                }
+                zset_value(pairs)?
+            }
+            TYPE_HASH => {
+                let len = self.count()?;
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    hash.insert(self.string()?, self.string()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_LIST_ZIPLIST => Value::List(ziplist(&self.string()?)?.into()),
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.count()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    if value_type == TYPE_LIST_QUICKLIST {
+                        list.extend(ziplist(&self.string()?)?);
+                        continue;
+                    }
+                    match self.length()? as usize {
+                        QUICKLIST_NODE_PLAIN => list.push_back(self.string()?),
+                        QUICKLIST_NODE_PACKED => list.extend(listpack(&self.string()?)?),
+                        _ => return Err(invalid("unknown quicklist node container")),
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_SET_INTSET => Value::Set(intset(&self.string()?)?.into_iter().collect()),
+            TYPE_SET_LISTPACK => Value::Set(listpack(&self.string()?)?.into_iter().collect()),
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let packed = self.string()?;
+                let items = if value_type == TYPE_ZSET_ZIPLIST {
+                    ziplist(&packed)?
+                } else {
+                    listpack(&packed)?
+                };
+                let pairs = pairs(items)?
+                    .into_iter()
+                    .map(|(member, score)| Ok((member, parse_score(&score)?)))
+                    .collect::<io::Result<Vec<_>>>()?;
+                zset_value(pairs)?
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let packed = self.string()?;
+                let items = if value_type == TYPE_HASH_ZIPLIST {
+                    ziplist(&packed)?
+                } else {
+                    listpack(&packed)?
+                };
+                Value::Hash(pairs(items)?.into_iter().collect())
+            }
+            _ => {
+                return Err(invalid(&format!(
+                    "value type {} is not supported",
+                    value_type
+                )))
+            }
+        })
+    }
+}
+
+fn parse_score(bytes: &[u8]) -> io::Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|score| score.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| invalid("malformed score"))
+}
+
+fn zset_value(pairs: Vec<(Vec<u8>, f64)>) -> io::Result<Value> {
+    let mut zset = SortedSet::new();
+    for (member, score) in pairs {
+        if score.is_nan() {
+            return Err(invalid("NaN score"));
+        }
+        zset.add(member, score, &ZAddOptions::default())
+            .map_err(|_| invalid("malformed score"))?;
+    }
+    Ok(Value::ZSet(zset))
+}
+
+/// Pairs up the flattened fields and values, or members and scores, of a packed encoding.
+fn pairs(items: Vec<Vec<u8>>) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !items.len().is_multiple_of(2) {
+        return Err(invalid("odd number of elements in a packed pair list"));
+    }
+    let mut items = items.into_iter();
+    let mut pairs = vec![];
+    while let (Some(first), Some(second)) = (items.next(), items.next()) {
+        pairs.push((first, second));
+    }
+    Ok(pairs)
+}
+
+/// Reads the elements of a ziplist, the compact list encoding of Redis before 7.0. Each
+/// entry is the previous entry's length, then a string or integer with its encoding.
+fn ziplist(bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut zl = Reader::new(bytes);
+    // The total size, the offset of the last entry and the entry count.
+    zl.take(10)?;
+    let mut items = vec![];
+    loop {
+        match zl.u8()? {
+            0xff => return Ok(items),
+            0xfe => {
+                zl.take(4)?;
+            }
+            _ => (),
+        }
+        let encoding = zl.u8()?;
+        let item = match encoding >> 6 {
+            0 => zl.take((encoding & 0x3f) as usize)?.to_vec(),
+            1 => {
+                let len = (((encoding & 0x3f) as usize) << 8) | zl.u8()? as usize;
+                zl.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(zl.array()?) as usize;
+                zl.take(len)?.to_vec()
+            }
+            _ => int_bytes(match encoding {
+                0xc0 => i16::from_le_bytes(zl.array()?) as i64,
+                0xd0 => i32::from_le_bytes(zl.array()?) as i64,
+                0xe0 => i64::from_le_bytes(zl.array()?),
+                0xf0 => {
+                    let [a, b, c] = zl.array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8) as i64
+                }
+                0xfe => i8::from_le_bytes(zl.array()?) as i64,
+                0xf1..=0xfd => (encoding & 0x0f) as i64 - 1,
+                _ => return Err(invalid("malformed ziplist entry")),
+            }),
+        };
+        items.push(item);
+    }
+}
+
+/// Reads the elements of a listpack, the compact list encoding of Redis 7.0 and later.
+/// Each entry is an encoded string or integer followed by its own length, for walking
+/// backwards.
+fn listpack(bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut lp = Reader::new(bytes);
+    // The total size and the entry count.
+    lp.take(6)?;
+    let mut items = vec![];
+    loop {
+        let start = lp.pos;
+        let encoding = lp.u8()?;
+        let item = if encoding == 0xff {
+            return Ok(items);
+        } else if encoding & 0x80 == 0 {
+            int_bytes((encoding & 0x7f) as i64)
+        } else if encoding & 0xc0 == 0x80 {
+            lp.take((encoding & 0x3f) as usize)?.to_vec()
+        } else if encoding & 0xe0 == 0xc0 {
+            let int = ((((encoding & 0x1f) as u16) << 8) | lp.u8()? as u16) << 3;
+            int_bytes((int as i16 >> 3) as i64)
+        } else if encoding & 0xf0 == 0xe0 {
+            let len = (((encoding & 0x0f) as usize) << 8) | lp.u8()? as usize;
+            lp.take(len)?.to_vec()
+        } else {
+            match encoding {
+                0xf0 => {
+                    let len = u32::from_le_bytes(lp.array()?) as usize;
+                    lp.take(len)?.to_vec()
+                }
+                0xf1 => int_bytes(i16::from_le_bytes(lp.array()?) as i64),
+                0xf2 => {
+                    let [a, b, c] = lp.array()?;
+                    int_bytes((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+                }
+                0xf3 => int_bytes(i32::from_le_bytes(lp.array()?) as i64),
+                0xf4 => int_bytes(i64::from_le_bytes(lp.array()?)),
+                _ => return Err(invalid("malformed listpack entry")),
+            }
+        };
+        let entry_len = lp.pos - start;
+        let backlen_len = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        lp.take(backlen_len)?;
+        items.push(item);
+    }
+}
+
+/// Reads the members of an intset: a sorted array of integers all stored in the width of
+/// the widest.
+fn intset(bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut set = Reader::new(bytes);
+    let width = u32::from_le_bytes(set.array()?);
+    let len = u32::from_le_bytes(set.array()?);
+    (0..len)
+        .map(|_| {
+            Ok(int_bytes(match width {
+                2 => i16::from_le_bytes(set.array()?) as i64,
+                4 => i32::from_le_bytes(set.array()?) as i64,
+                8 => i64::from_le_bytes(set.array()?),
+                _ => return Err(invalid("malformed intset")),
+            }))
+        })
+        .collect()
+}
+
+/// Decompresses LZF data that expands to `len` bytes. Each chunk is either a run of up to
+/// 32 literal bytes or a back-reference copying 3 to 264 bytes from up to 8KB back.
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(input.len() * 2));
+    let mut lzf = Reader::new(input);
+    while lzf.pos < input.len() {
+        let ctrl = lzf.u8()? as usize;
+        if ctrl < 32 {
+            out.extend_from_slice(lzf.take(ctrl + 1)?);
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += lzf.u8()? as usize;
+            }
+            let back = ((ctrl & 0x1f) << 8) + lzf.u8()? as usize + 1;
+            if back > out.len() {
+                return Err(invalid("LZF back-reference before start"));
+            }
+            let from = out.len() - back;
+            // Copied a byte at a time, as the run may overlap the bytes it produces.
+            for i in from..from + run + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return Err(invalid("LZF data longer than expected"));
+        }
+    }
+    if out.len() != len {
+        return Err(invalid("LZF data shorter than expected"));
+    }
+    Ok(out)
+}
+
+/// Compresses `input` with LZF, finding repeats through a hash of the next three bytes.
+/// Returns `None` if that would not make it shorter.
+fn lzf_compress(input: &[u8]) -> Option<Vec<u8>> {
+    const HASH_BITS: u32 = 14;
+    const MAX_LITERALS: usize = 32;
+    const MAX_BACK: usize = 1 << 13;
+    const MAX_RUN: usize = 7 + 255 + 2;
+
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut out = Vec::with_capacity(input.len());
+    let mut literals = 0;
+    let flush = |out: &mut Vec<u8>, end: usize, literals: &mut usize| {
+        if *literals > 0 {
+            out.push((*literals - 1) as u8);
+            out.extend_from_slice(&input[end - *literals..end]);
+            *literals = 0;
+        }
+    };
+
+    let mut pos = 0;
+    while pos + 2 < input.len() {
+        let next = u32::from_be_bytes([0, input[pos], input[pos + 1], input[pos + 2]]);
+        let slot = (next.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[slot], pos);
+        if candidate != usize::MAX
+            && pos - candidate <= MAX_BACK
+            && input[candidate..candidate + 3] == input[pos..pos + 3]
+        {
+            let max_run = MAX_RUN.min(input.len() - pos);
+            let mut run = 3;
+            while run < max_run && input[candidate + run] == input[pos + run] {
+                run += 1;
+            }
+            flush(&mut out, pos, &mut literals);
+            let back = pos - candidate - 1;
+            let encoded_run = run - 2;
+            if encoded_run < 7 {
+                out.push(((encoded_run << 5) | (back >> 8)) as u8);
+            } else {
+                out.push(((7 << 5) | (back >> 8)) as u8);
+                out.push((encoded_run - 7) as u8);
+            }
+            out.push(back as u8);
+            pos += run;
+        } else {
+            literals += 1;
+            pos += 1;
+            if literals == MAX_LITERALS {
+                flush(&mut out, pos, &mut literals);
+            }
+        }
+        if out.len() >= input.len() {
+            return None;
+        }
+    }
+    while pos < input.len() {
+        literals += 1;
+        pos += 1;
+        if literals == MAX_LITERALS {
+            flush(&mut out, pos, &mut literals);
+        }
+    }
+    flush(&mut out, pos, &mut literals);
+    if out.len() < input.len() {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream;
+
+    #[test]
+    fn test_lzf() {
+        let repetitive = b"abcabcabcabcabcabcabcabcabcabc-hello hello hello hello".repeat(50);
+        let compressed = lzf_compress(&repetitive).unwrap();
+        assert!(compressed.len() < repetitive.len() / 4);
+        assert_eq!(
+            repetitive,
+            lzf_decompress(&compressed, repetitive.len()).unwrap()
+        );
+
+        let mixed: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        if let Some(compressed) = lzf_compress(&mixed) {
+            assert_eq!(mixed, lzf_decompress(&compressed, mixed.len()).unwrap());
+        }
+        assert_eq!(None, lzf_compress(b"abcdefgh"));
+
+        // A literal "ab", then a run of 4 copied from 2 back.
+        assert_eq!(
+            b"ababab".to_vec(),
+            lzf_decompress(&[1, b'a', b'b', 2 << 5, 1], 6).unwrap()
+        );
+        assert!(lzf_decompress(&[1, b'a', b'b', 2 << 5, 5], 6).is_err());
+        assert!(lzf_decompress(&[1, b'a', b'b'], 6).is_err());
+    }
+
+    fn strings(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_packed_encodings() {
+        let ziplist_bytes = [
+            &[0; 10][..],
+            &[0, 0x01, b'a'],
+            &[3, 0xf6],
+            &[2, 0xc0, 0x2c, 0x01],
+            &[4, 0xfe, 0xfd],
+            &[3, 0xf0, 0x00, 0x00, 0x80],
+            &[0xff],
+        ]
+        .concat();
+        assert_eq!(
+            strings(&["a", "5", "300", "-3", "-8388608"]),
+            ziplist(&ziplist_bytes).unwrap()
+        );
+
+        let listpack_bytes = [
+            &[0; 6][..],
+            &[0x81, b'a', 2],
+            &[0x05, 1],
+            &[0xdf, 0xfd, 2],
+            &[0xf1, 0x2c, 0x01, 3],
+            &[0xf3, 0x00, 0x00, 0x00, 0x80, 5],
+            &[0xff],
+        ]
+        .concat();
+        assert_eq!(
+            strings(&["a", "5", "-3", "300", "-2147483648"]),
+            listpack(&listpack_bytes).unwrap()
+        );
+        assert!(listpack(&listpack_bytes[..listpack_bytes.len() - 1]).is_err());
+
+        let intset_bytes = [
+            &2u32.to_le_bytes()[..],
+            &3u32.to_le_bytes(),
+            &[0xfe, 0xff, 1, 0, 0x2c, 0x01],
+        ]
+        .concat();
+        assert_eq!(strings(&["-2", "1", "300"]), intset(&intset_bytes).unwrap());
+    }
+
+    fn sample() -> Snapshot {
+        let mut zset = SortedSet::new();
+        zset.add(b"m".to_vec(), -1.5, &ZAddOptions::default())
+            .unwrap();
+        zset.add(b"inf".to_vec(), f64::INFINITY, &ZAddOptions::default())
+            .unwrap();
+        let entry = |key: &str, value: Value, expires_at_ms: Option<i64>| SnapshotEntry {
+            key: key.as_bytes().to_vec(),
+            value,
+            expires_at_ms,
+        };
+        Snapshot {
+            dbs: vec![
+                (
+                    0,
+                    vec![
+                        entry("s", Value::Str(b"x".repeat(100)), None),
+                        entry("i", Value::Int(-70000), Some(4_102_444_800_000)),
+                        entry("big", Value::Int(i64::MAX), None),
+                        entry("l", Value::List(strings(&["a", "b", "a"]).into()), None),
+                    ],
+                ),
+                (
+                    3,
+                    vec![
+                        entry(
+                            "set",
+                            Value::Set(strings(&["1", "x"]).into_iter().collect()),
+                            None,
+                        ),
+                        entry("z", Value::ZSet(zset), None),
+                        entry(
+                            "h",
+                            Value::Hash(vec![(b"f".to_vec(), b"v".to_vec())].into_iter().collect()),
+                            None,
+                        ),
+                    ],
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = sample();
+        let mut bytes = vec![];
+        assert!(write_rdb(&snapshot, &mut bytes).unwrap().is_empty());
+        assert!(bytes.starts_with(b"REDIS0009"));
+        assert_eq!(snapshot, read_rdb(&bytes).unwrap());
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(read_rdb(&bytes).is_err());
+        assert!(read_rdb(&bytes[..bytes.len() / 2]).is_err());
+
+        let mut stream = Snapshot::default();
+        stream.dbs.push((
+            0,
+            vec![SnapshotEntry {
+                key: b"x".to_vec(),
+                value: Value::Stream(stream::Stream::new()),
+                expires_at_ms: None,
+            }],
+        ));
+        let mut bytes = vec![];
+        assert_eq!(vec![b"x".to_vec()], write_rdb(&stream, &mut bytes).unwrap());
+        let mut empty = Snapshot::default();
+        empty.dbs.push((0, vec![]));
+        assert_eq!(empty, read_rdb(&bytes).unwrap());
+    }
+
+    /// Assembles a file as Redis 7 writes it, with the compact encodings it picks for
+    /// small values.
+    #[test]
+    fn test_read_redis_7() {
+        let listpack_bytes = |items: &[&[u8]]| {
+            let mut lp = vec![0; 6];
+            for item in items.iter() {
+                lp.push(0x80 | item.len() as u8);
+                lp.extend_from_slice(item);
+                lp.push(item.len() as u8 + 1);
+            }
+            lp.push(0xff);
+            lp
+        };
+        let string = |bytes: &[u8]| [&[bytes.len() as u8][..], bytes].concat();
+
+        let mut file = b"REDIS0011".to_vec();
+        file.extend([&[OP_AUX][..], &string(b"redis-ver"), &string(b"7.2.4")].concat());
+        file.extend([OP_SELECTDB, 0, OP_RESIZEDB, 5, 1]);
+        file.extend(
+            [
+                &[TYPE_HASH_LISTPACK][..],
+                &string(b"h"),
+                &string(&listpack_bytes(&[b"f", b"v"])),
+            ]
+            .concat(),
+        );
+        file.extend(
+            [
+                &[TYPE_ZSET_LISTPACK][..],
+                &string(b"z"),
+                &string(&listpack_bytes(&[b"m", b"2.5"])),
+            ]
+            .concat(),
+        );
+        file.extend(
+            [
+                &[TYPE_LIST_QUICKLIST_2][..],
+                &string(b"l"),
+                &[2, QUICKLIST_NODE_PACKED as u8],
+                &string(&listpack_bytes(&[b"a", b"b"])),
+                &[QUICKLIST_NODE_PLAIN as u8],
+                &string(b"c"),
+            ]
+            .concat(),
+        );
+        let intset_bytes = [
+            &2u32.to_le_bytes()[..],
+            &1u32.to_le_bytes(),
+            &7i16.to_le_bytes(),
+        ]
+        .concat();
+        file.extend(
+            [
+                &[TYPE_SET_INTSET][..],
+                &string(b"set"),
+                &string(&intset_bytes),
+            ]
+            .concat(),
+        );
+        file.push(OP_EXPIRETIME_MS);
+        file.extend(4_102_444_800_000i64.to_le_bytes());
+        // "aaaaaaaaaa" as LZF: a literal "a" and a run of nine copied from one back.
+        file.extend([
+            TYPE_STRING,
+            1,
+            b's',
+            ENCODED | ENC_LZF,
+            5,
+            10,
+            0,
+            b'a',
+            7 << 5,
+            0,
+            0,
+        ]);
+        file.push(OP_EOF);
+        let crc = crc64(0, &file);
+        file.extend(crc.to_le_bytes());
+
+        let snapshot = read_rdb(&file).unwrap();
+        assert_eq!(1, snapshot.dbs.len());
+        let entries = &snapshot.dbs[0].1;
+        assert_eq!(
+            Value::Hash(vec![(b"f".to_vec(), b"v".to_vec())].into_iter().collect()),
+            entries[0].value
+        );
+        match &entries[1].value {
+            Value::ZSet(zset) => assert_eq!(Some(2.5), zset.score(b"m")),
+            value => panic!("expected a zset, got {:?}", value),
+        }
+        assert_eq!(
+            Value::List(strings(&["a", "b", "c"]).into()),
+            entries[2].value
+        );
+        assert_eq!(
+            Value::Set(strings(&["7"]).into_iter().collect()),
+            entries[3].value
+        );
+        assert_eq!(Value::Str(b"a".repeat(10)), entries[4].value);
+        assert_eq!(Some(4_102_444_800_000), entries[4].expires_at_ms);
+
+        assert!(read_rdb(b"REDIS0099\xff").is_err());
+        assert!(read_rdb(b"KYEV").is_err());
+    }
+}
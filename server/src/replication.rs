@@ -0,0 +1,368 @@
+use crate::aof::{self, Argv};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How often a replica's connection checks the backlog for new commands to send.
+pub const REPLICA_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the leader pings its replicas through the stream, so that they can tell a
+/// quiet leader from a lost one.
+pub const PING_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long a replica waits on a silent leader before reconnecting.
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a replica waits before reconnecting to its leader after losing it.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Generates a replication ID: 40 random hex digits.
+pub fn new_replid() -> String {
+    (0..40)
+        .map(|_| std::char::from_digit(rand::random::<u32>() % 16, 16).unwrap())
+        .collect()
+}
+
+/// The most recent part of the replication stream, kept so that a replica that lost its
+/// connection can pick up where it left off instead of syncing the whole store again.
+#[derive(Debug)]
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// The offset in the stream of the first byte held.
+    start: u64,
+}
+
+impl Backlog {
+    /// An empty backlog of `capacity` bytes that continues the stream at `offset`.
+    pub fn new(capacity: usize, offset: u64) -> Backlog {
+        Backlog {
+            buf: VecDeque::new(),
+            capacity,
+            start: offset,
+        }
+    }
+
+    /// Appends to the stream, dropping its oldest bytes beyond the capacity.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes);
+        let excess = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// The offset of the first byte held.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The offset just past the last byte held.
+    pub fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+
+    /// Whether the stream from `offset` on is still held.
+    pub fn contains(&self, offset: u64) -> bool {
+        self.start <= offset && offset <= self.end()
+    }
+
+    /// The stream from `offset` to the end, or `None` if part of it was already dropped.
+    pub fn read_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if !self.contains(offset) {
+            return None;
+        }
+        let skip = (offset - self.start) as usize;
+        Some(self.buf.iter().skip(skip).copied().collect())
+    }
+}
+
+/// Whether this server takes writes from clients or copies them from a leader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Leader,
+    Replica { host: String, port: u16 },
+}
+
+/// How a PSYNC request is answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Psync {
+    /// The replica continues the stream from the given offset.
+    Continue(u64),
+    /// The replica is sent a copy of the store, then the stream from then on.
+    Full,
+}
+
+/// A replica connected to this server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaInfo {
+    pub addr: SocketAddr,
+    /// How far into the stream the replica has been sent.
+    pub offset: u64,
+}
+
+/// The replication state of the server, whether it is a leader or a replica.
+///
+/// Both sides count the replication stream in bytes. A leader appends the commands it runs
+/// to its backlog; a replica appends the stream it receives, so that its own replicas see
+/// the exact same bytes and offsets as it does.
+#[derive(Debug)]
+pub struct Replication {
+    role: Role,
+    /// Names the stream the offset counts into. A replica takes on its leader's.
+    replid: String,
+    offset: u64,
+    /// The ID and end offset of the stream followed before the last promotion, so that
+    /// replicas of the former leader can continue with the promoted one.
+    previous: Option<(String, u64)>,
+    /// Only kept from the first time a replica syncs.
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    /// Bumped whenever the backlog starts over, so replica connections reading it can tell.
+    backlog_epoch: u64,
+    /// The database the stream last selected, so SELECT is only sent when it changes.
+    db: Option<usize>,
+    /// Bumped on every REPLICAOF, so the connection to a former leader winds down.
+    generation: u64,
+    link_up: bool,
+    replicas: HashMap<usize, ReplicaInfo>,
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Replication {
+        Replication {
+            role: Role::Leader,
+            replid: new_replid(),
+            offset: 0,
+            previous: None,
+            backlog: None,
+            backlog_size,
+            backlog_epoch: 0,
+            db: None,
+            generation: 0,
+            link_up: false,
+            replicas: HashMap::new(),
+        }
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.role != Role::Leader
+    }
+
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn backlog_epoch(&self) -> u64 {
+        self.backlog_epoch
+    }
+
+    pub fn backlog(&self) -> Option<&Backlog> {
+        self.backlog.as_ref()
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    pub fn set_link_up(&mut self, up: bool) {
+        self.link_up = up;
+    }
+
+    /// Follows the leader at `host` and `port`. Returns the new generation, which the
+    /// connection to the leader checks to know it is still wanted.
+    pub fn replicate_from(&mut self, host: String, port: u16) -> u64 {
+        self.role = Role::Replica { host, port };
+        self.link_up = false;
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Stops following the leader and starts a stream of its own, keeping the backlog so
+    /// that other replicas of the former leader can continue with this server.
+    pub fn promote(&mut self) {
+        if !self.is_replica() {
+            return;
+        }
+        self.role = Role::Leader;
+        self.link_up = false;
+        self.generation += 1;
+        let replid = std::mem::replace(&mut self.replid, new_replid());
+        self.previous = Some((replid, self.offset));
+        self.db = None;
+    }
+
+    /// Appends commands run on a leader to the stream. Replicas only relay their leader's
+    /// stream, and nothing is kept until a replica first syncs.
+    pub fn feed(&mut self, cmds: &[(usize, Argv)], transaction: bool) {
+        if self.is_replica() || self.backlog.is_none() {
+            return;
+        }
+        let bytes = aof::encode_commands(&mut self.db, cmds, transaction);
+        self.append(&bytes);
+    }
+
+    /// Appends a PING, so replicas can tell the leader is still there.
+    pub fn feed_ping(&mut self) {
+        if !self.replicas.is_empty() {
+            self.feed(&[(self.db.unwrap_or(0), vec![b"PING".to_vec()])], false);
+        }
+    }
+
+    /// Appends part of the leader's stream on a replica.
+    pub fn feed_from_leader(&mut self, bytes: &[u8]) {
+        self.append(bytes);
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.push(bytes);
+        }
+    }
+
+    /// The ID and offset a replica asks its leader to continue from.
+    pub fn psync_request(&self) -> (String, u64) {
+        (self.replid.clone(), self.offset)
+    }
+
+    /// Decides whether a replica that followed `replid` up to `offset` can continue from
+    /// the backlog.
+    pub fn psync(&self, replid: &str, offset: u64) -> Psync {
+        let known = replid == self.replid
+            || self
+                .previous
+                .as_ref()
+                .is_some_and(|(previous, end)| replid == previous && offset <= *end);
+        match &self.backlog {
+            Some(backlog) if known && backlog.contains(offset) => Psync::Continue(offset),
+            _ => Psync::Full,
+        }
+    }
+
+    /// Starts a full sync of a replica, keeping a backlog from now on if there was none.
+    /// Returns the ID and offset the copy of the store is taken at.
+    pub fn start_full_sync(&mut self) -> (String, u64) {
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog::new(self.backlog_size, self.offset));
+            self.backlog_epoch += 1;
+        }
+        // The replica does not know which database the stream has selected.
+        self.db = None;
+        (self.replid.clone(), self.offset)
+    }
+
+    /// Takes on the stream of the leader a replica fully synced from: its ID and offset,
+    /// with a backlog that starts over there.
+    pub fn finish_full_sync(&mut self, replid: String, offset: u64) {
+        self.replid = replid;
+        self.offset = offset;
+        self.previous = None;
+        self.backlog = Some(Backlog::new(self.backlog_size, offset));
+        self.backlog_epoch += 1;
+    }
+
+    /// Takes on the new ID of a leader that was promoted since this replica last synced.
+    pub fn continue_as(&mut self, replid: String) {
+        if replid != self.replid {
+            let previous = std::mem::replace(&mut self.replid, replid);
+            self.previous = Some((previous, self.offset));
+        }
+    }
+
+    pub fn add_replica(&mut self, id: usize, addr: SocketAddr, offset: u64) {
+        self.replicas.insert(id, ReplicaInfo { addr, offset });
+    }
+
+    pub fn set_replica_offset(&mut self, id: usize, offset: u64) {
+        if let Some(replica) = self.replicas.get_mut(&id) {
+            replica.offset = offset;
+        }
+    }
+
+    pub fn remove_replica(&mut self, id: usize) {
+        self.replicas.remove(&id);
+    }
+
+    pub fn replicas(&self) -> impl Iterator<Item = (&usize, &ReplicaInfo)> {
+        self.replicas.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        backlog.push(b"abcde");
+        assert_eq!(105, backlog.end());
+        assert_eq!(Some(b"cde".to_vec()), backlog.read_from(102));
+        assert_eq!(Some(vec![]), backlog.read_from(105));
+        assert_eq!(None, backlog.read_from(106));
+
+        backlog.push(b"fghij");
+        assert_eq!(110, backlog.end());
+        assert_eq!(None, backlog.read_from(101));
+        assert_eq!(Some(b"cdefghij".to_vec()), backlog.read_from(102));
+    }
+
+    #[test]
+    fn test_psync() {
+        let cmd = |args: &[&str]| -> Argv { args.iter().map(|a| a.as_bytes().to_vec()).collect() };
+        let mut leader = Replication::new(1024);
+        leader.feed(&[(0, cmd(&["SET", "a", "1"]))], false);
+        assert_eq!(0, leader.offset());
+        assert_eq!(Psync::Full, leader.psync(leader.replid(), 0));
+
+        let (replid, offset) = leader.start_full_sync();
+        assert_eq!(0, offset);
+        leader.feed(&[(0, cmd(&["SET", "a", "1"]))], false);
+        let stream = leader.backlog().unwrap().read_from(0).unwrap();
+        assert_eq!(
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n".to_vec(),
+            stream
+        );
+        assert_eq!(stream.len() as u64, leader.offset());
+        assert_eq!(Psync::Continue(10), leader.psync(&replid, 10));
+        assert_eq!(Psync::Full, leader.psync("other", 10));
+        assert_eq!(Psync::Full, leader.psync(&replid, leader.offset() + 1));
+
+        // A replica relays the stream as is, and once promoted still serves PSYNC for the
+        // former leader's ID up to where it stopped following it.
+        let mut replica = Replication::new(1024);
+        replica.replicate_from("127.0.0.1".to_owned(), 8080);
+        replica.finish_full_sync(replid.clone(), 0);
+        replica.feed(&[(0, cmd(&["SET", "b", "1"]))], false);
+        assert_eq!(0, replica.offset());
+        replica.feed_from_leader(&stream);
+        assert_eq!(leader.offset(), replica.offset());
+        assert_eq!((replid.clone(), leader.offset()), replica.psync_request());
+
+        replica.promote();
+        assert!(!replica.is_replica());
+        assert_ne!(replid, replica.replid());
+        assert_eq!(
+            Psync::Continue(leader.offset()),
+            replica.psync(&replid, leader.offset())
+        );
+        replica.feed(&[(0, cmd(&["SET", "b", "1"]))], false);
+        assert_eq!(Psync::Full, replica.psync(&replid, replica.offset()));
+        assert_eq!(
+            Psync::Continue(replica.offset()),
+            replica.psync(replica.replid(), replica.offset())
+        );
+    }
+}
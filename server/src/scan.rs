@@ -0,0 +1,483 @@
+use indexmap::{set as index_set, IndexMap, IndexSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::Deref;
+
+/// How many elements a SCAN call inspects when no COUNT is given.
+pub const DEFAULT_COUNT: usize = 10;
+
+/// The cursor to continue from and the elements of one SCAN call.
+pub type ScanPage<T> = (u64, Vec<T>);
+
+/// Returns whether `s` matches the glob-style `pattern`. Supports `*`, `?`, `[abc]`,
+/// `[^abc]`, `[a-z]` and `\` to escape the next character.
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let mut p = 0;
+    let mut i = 0;
+    // Where to resume if matching after the most recent `*` fails: the pattern just past
+    // the star, and the input with one more character consumed by the star.
+    let mut backtrack = None;
+    while i < s.len() || p < pattern.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, i + 1));
+            continue;
+        }
+        if i < s.len() {
+            if let Some(len) = match_token(&pattern[p..], s[i]) {
+                p += len;
+                i += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star_p, star_i)) if star_i <= s.len() => {
+                p = star_p;
+                i = star_i;
+                backtrack = Some((star_p, star_i + 1));
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Matches `c` against the token at the start of `pattern`, returning the token's length
+/// if it matched.
+fn match_token(pattern: &[u8], c: u8) -> Option<usize> {
+    let (matched, rest) = match pattern {
+        [] => return None,
+        [b'?', rest @ ..] => (true, rest),
+        [b'[', rest @ ..] => match_class(rest, c),
+        [b'\\', escaped, rest @ ..] => (*escaped == c, rest),
+        [literal, rest @ ..] => (*literal == c, rest),
+    };
+    if matched {
+        Some(pattern.len() - rest.len())
+    } else {
+        None
+    }
+}
+
+/// Matches `c` against the character class at the start of `pattern`, which follows an
+/// opening `[`. Returns whether it matched and the pattern after the closing `]`. A class
+/// left unclosed matches nothing.
+fn match_class(pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let (negate, mut pattern) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => return (false, pattern),
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == c;
+                pattern = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= low <= c && c <= high;
+                pattern = rest;
+            }
+            [class_char, rest @ ..] => {
+                matched |= *class_char == c;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
+
+/// Positions an element in the cursor space. Depends only on the element itself, so the
+/// order is unaffected by how the underlying map grows or rehashes.
+pub fn cursor_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Where elements sit in the cursor space of a [`ScanIndex`].
+pub trait CursorOrder {
+    fn position(key: &[u8]) -> u64;
+}
+
+/// Positions elements by [`cursor_hash`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashOrder;
+
+impl CursorOrder for HashOrder {
+    fn position(key: &[u8]) -> u64 {
+        cursor_hash(key)
+    }
+}
+
+/// The slots of a table's elements in cursor order, kept alongside the table so that a
+/// SCAN page is found in O(log N + COUNT) rather than by hashing every element. Only the
+/// slots are held, not copies of the elements, so the table must be addressable by slot,
+/// as an `IndexMap` is, and report the moves of a swap remove through [`ScanIndex::moved`].
+#[derive(Clone, Debug, Default)]
+pub struct ScanIndex<P = HashOrder> {
+    order: BTreeSet<(u64, usize)>,
+    positions: PhantomData<P>,
+}
+
+impl ScanIndex {
+    pub fn new() -> ScanIndex {
+        ScanIndex::default()
+    }
+}
+
+impl<P: CursorOrder> ScanIndex<P> {
+    /// Indexes `key`, held at `slot` of the table.
+    pub fn insert(&mut self, key: &[u8], slot: usize) {
+        self.order.insert((P::position(key), slot));
+    }
+
+    /// Drops `key`, which was held at `slot`, returning whether it was indexed.
+    pub fn remove(&mut self, key: &[u8], slot: usize) -> bool {
+        self.order.remove(&(P::position(key), slot))
+    }
+
+    /// Follows `key` from slot `from` to `to`, where a swap remove moved it.
+    pub fn moved(&mut self, key: &[u8], from: usize, to: usize) {
+        if self.remove(key, from) {
+            self.insert(key, to);
+        }
+    }
+
+    /// Indexes `keys` afresh, each at the slot of its place in the iteration.
+    pub fn rebuild<'a, I: IntoIterator<Item = &'a Vec<u8>>>(&mut self, keys: I) {
+        self.order.clear();
+        for (slot, key) in keys.into_iter().enumerate() {
+            self.insert(key, slot);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Returns the slots of up to `count` elements from `cursor` onwards, in cursor order,
+    /// along with the cursor to resume from. A returned cursor of 0 means the iteration is
+    /// complete.
+    ///
+    /// Elements sharing a position are never split across pages, so every element present
+    /// for the whole iteration is returned at least once.
+    pub fn page(&self, cursor: u64, count: usize) -> ScanPage<usize> {
+        let mut slots = vec![];
+        let mut last = None;
+        for (position, slot) in self.from(cursor) {
+            if slots.len() >= count && last != Some(position) {
+                // A later element is never at 0, as the page holds one at or below it.
+                return (position, slots);
+            }
+            slots.push(slot);
+            last = Some(position);
+        }
+        (0, slots)
+    }
+
+    /// The slots of the elements from `position` onwards, in cursor order, with their
+    /// positions.
+    pub fn from(&self, position: u64) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.order.range((position, 0)..).cloned()
+    }
+
+    /// Estimates the memory the index takes, counting its B-tree nodes as two thirds full.
+    pub fn memory(&self) -> usize {
+        self.order.len() * size_of::<(u64, usize)>() * 3 / 2
+    }
+}
+
+/// A set whose members can be scanned from a cursor. Reads go through `Deref`; writes go
+/// through the methods here, which keep the index in step.
+#[derive(Clone, Debug, Default)]
+pub struct ScanSet {
+    members: IndexSet<Vec<u8>>,
+    order: ScanIndex,
+}
+
+impl PartialEq for ScanSet {
+    fn eq(&self, other: &ScanSet) -> bool {
+        self.members == other.members
+    }
+}
+
+impl Eq for ScanSet {}
+
+impl ScanSet {
+    pub fn new() -> ScanSet {
+        ScanSet::default()
+    }
+
+    /// Adds `member`, returning whether it was new.
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        if self.members.contains(&member) {
+            return false;
+        }
+        self.order.insert(&member, self.members.len());
+        self.members.insert(member)
+    }
+
+    /// Removes `member`, returning whether it was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.members.swap_remove_full(member) {
+            Some((slot, member)) => {
+                self.order.remove(&member, slot);
+                if let Some(moved) = self.members.get_index(slot) {
+                    self.order.moved(moved, self.members.len(), slot);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn retain<F: FnMut(&Vec<u8>) -> bool>(&mut self, keep: F) {
+        let len = self.members.len();
+        self.members.retain(keep);
+        if self.members.len() < len {
+            self.order.rebuild(&self.members);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.members.clear();
+        self.order.clear();
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> ScanPage<&Vec<u8>> {
+        let (next, slots) = self.order.page(cursor, count);
+        let members = slots
+            .into_iter()
+            .filter_map(|slot| self.members.get_index(slot))
+            .collect();
+        (next, members)
+    }
+
+    /// The memory of the cursor index, which is not part of the table.
+    pub fn index_memory(&self) -> usize {
+        self.order.memory()
+    }
+}
+
+impl Deref for ScanSet {
+    type Target = IndexSet<Vec<u8>>;
+
+    fn deref(&self) -> &IndexSet<Vec<u8>> {
+        &self.members
+    }
+}
+
+impl IntoIterator for ScanSet {
+    type Item = Vec<u8>;
+    type IntoIter = index_set::IntoIter<Vec<u8>>;
+
+    fn into_iter(self) -> index_set::IntoIter<Vec<u8>> {
+        self.members.into_iter()
+    }
+}
+
+impl Extend<Vec<u8>> for ScanSet {
+    fn extend<I: IntoIterator<Item = Vec<u8>>>(&mut self, iter: I) {
+        for member in iter {
+            self.insert(member);
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for ScanSet {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> ScanSet {
+        let mut set = ScanSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+/// A map whose keys can be scanned from a cursor. Reads go through `Deref`; writes go
+/// through the methods here, which keep the index in step.
+#[derive(Clone, Debug, Default)]
+pub struct ScanMap<V> {
+    entries: IndexMap<Vec<u8>, V>,
+    order: ScanIndex,
+}
+
+impl<V: PartialEq> PartialEq for ScanMap<V> {
+    fn eq(&self, other: &ScanMap<V>) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<V: Eq> Eq for ScanMap<V> {}
+
+impl<V> ScanMap<V> {
+    pub fn new() -> ScanMap<V> {
+        ScanMap {
+            entries: IndexMap::new(),
+            order: ScanIndex::new(),
+        }
+    }
+
+    /// Sets `key` to `value`, returning the value it replaced.
+    pub fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        if let Some(current) = self.entries.get_mut(&key) {
+            return Some(std::mem::replace(current, value));
+        }
+        self.order.insert(&key, self.entries.len());
+        self.entries.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let (slot, key, value) = self.entries.swap_remove_full(key)?;
+        self.order.remove(&key, slot);
+        if let Some((moved, _)) = self.entries.get_index(slot) {
+            self.order.moved(moved, self.entries.len(), slot);
+        }
+        Some(value)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        self.entries.get_mut(key)
+    }
+
+    /// Returns a page of entries from `cursor`, as [`ScanIndex::page`] does.
+    pub fn scan(&self, cursor: u64, count: usize) -> ScanPage<(&Vec<u8>, &V)> {
+        let (next, slots) = self.order.page(cursor, count);
+        let entries = slots
+            .into_iter()
+            .filter_map(|slot| self.entries.get_index(slot))
+            .collect();
+        (next, entries)
+    }
+
+    /// The memory of the cursor index, which is not part of the table.
+    pub fn index_memory(&self) -> usize {
+        self.order.memory()
+    }
+}
+
+impl<V> Deref for ScanMap<V> {
+    type Target = IndexMap<Vec<u8>, V>;
+
+    fn deref(&self) -> &IndexMap<Vec<u8>, V> {
+        &self.entries
+    }
+}
+
+impl<V> FromIterator<(Vec<u8>, V)> for ScanMap<V> {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, V)>>(iter: I) -> ScanMap<V> {
+        let mut map = ScanMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"user:\\*", b"user:*"));
+        assert!(!glob_match(b"user:\\*", b"user:1"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a*b*c", b"a-b-b-c"));
+        assert!(glob_match(b"*a*", b"banana"));
+        assert!(!glob_match(b"*x*", b"banana"));
+        assert!(!glob_match(b"[^", b"a"));
+        assert!(!glob_match(b"*[a", b"banana"));
+        let mut many_stars = b"*a".repeat(20);
+        many_stars.push(b'b');
+        assert!(!glob_match(&many_stars, &[b'a'; 40]));
+    }
+
+    #[test]
+    fn test_page_covers_everything() {
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .collect();
+        let mut index = ScanIndex::new();
+        for (slot, key) in keys.iter().enumerate() {
+            index.insert(key, slot);
+        }
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, page) = index.page(cursor, 7);
+            assert!(page.len() <= 7);
+            seen.extend(page);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(keys.len(), seen.len());
+    }
+
+    #[test]
+    fn test_index_in_step() {
+        let mut set: ScanSet = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+            .into_iter()
+            .collect();
+        assert!(!set.insert(b"a".to_vec()));
+        assert!(set.remove(b"b"));
+        set.retain(|member| member != b"c");
+        assert_eq!((0, vec![&b"a".to_vec()]), set.scan(0, 10));
+
+        let mut map = ScanMap::new();
+        map.insert(b"f".to_vec(), 1);
+        assert_eq!(Some(1), map.insert(b"f".to_vec(), 2));
+        map.insert(b"g".to_vec(), 3);
+        let memory = map.index_memory();
+        assert_eq!(Some(3), map.remove(b"g"));
+        assert!(map.index_memory() < memory);
+        assert_eq!((0, vec![(&b"f".to_vec(), &2)]), map.scan(0, 10));
+    }
+
+    #[test]
+    fn test_index_follows_swap_removes() {
+        let mut set: ScanSet = (0..50).map(|i| format!("m{}", i).into_bytes()).collect();
+        for i in (0..50).step_by(3) {
+            assert!(set.remove(format!("m{}", i).as_bytes()));
+        }
+        let (next, mut members) = set.scan(0, 100);
+        assert_eq!(0, next);
+        members.sort();
+        let mut expected: Vec<&Vec<u8>> = set.iter().collect();
+        expected.sort();
+        assert_eq!(expected, members);
+        assert_eq!(set.len(), set.order.len());
+    }
+}
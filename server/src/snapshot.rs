@@ -0,0 +1,660 @@
+use crate::scan::ScanSet;
+use crate::store::{Hash, Value};
+use crate::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use crate::zset::{SortedSet, ZAddOptions};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// How often the save rules are checked.
+pub const SAVE_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// How long the save rules wait before retrying a background save that failed.
+pub const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The first bytes of every snapshot file.
+const MAGIC: &[u8] = b"KYEV";
+
+/// The format version written. Files with a later version are refused.
+const VERSION: u8 = 1;
+
+const OP_EXPIRE_MS: u8 = 0xfc;
+const OP_SELECT_DB: u8 = 0xfe;
+const OP_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_INT: u8 = 1;
+const TYPE_LIST: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_SET: u8 = 4;
+const TYPE_ZSET: u8 = 5;
+const TYPE_STREAM: u8 = 6;
+
+/// A rule to save once `seconds` have passed and at least `changes` keys were written since
+/// the last save.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    pub fn is_due(&self, changes: u64, secs_since_save: u64) -> bool {
+        changes >= self.changes && secs_since_save >= self.seconds
+    }
+}
+
+/// A key copied out of a database, with its deadline as a Unix time in milliseconds so it
+/// stays meaningful across restarts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub key: Vec<u8>,
+    pub value: Value,
+    pub expires_at_ms: Option<i64>,
+}
+
+/// A point-in-time copy of the databases that hold keys, by database index.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub dbs: Vec<(usize, Vec<SnapshotEntry>)>,
+}
+
+impl Snapshot {
+    pub fn keys(&self) -> usize {
+        self.dbs.iter().map(|(_, entries)| entries.len()).sum()
+    }
+}
+
+/// Writes `snapshot` to `path` through a temporary file that is renamed into place once
+/// synced, so a failed save leaves the previous snapshot intact.
+pub fn save(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let result = write_file(snapshot, Path::new(&tmp)).and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Writes `snapshot` to a new file at `path` and syncs it to disk.
+pub fn write_file(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_snapshot(snapshot, &mut out)?;
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()
+}
+
+/// Reads the snapshot at `path`, or `None` if there is no file.
+pub fn load(path: &Path) -> io::Result<Option<Snapshot>> {
+    match fs::read(path) {
+        Ok(bytes) => read_snapshot(&bytes).map(Some),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Encodes `snapshot`: the magic and version, then each database's entries, then an EOF
+/// marker followed by the CRC-64 of everything before it.
+pub fn write_snapshot<W: Write>(snapshot: &Snapshot, out: W) -> io::Result<()> {
+    let mut enc = Encoder { out, crc: 0 };
+    enc.write(MAGIC)?;
+    enc.u8(VERSION)?;
+    for (index, entries) in snapshot.dbs.iter() {
+        enc.u8(OP_SELECT_DB)?;
+        enc.varint(*index as u64)?;
+        for entry in entries.iter() {
+            if let Some(expires_at_ms) = entry.expires_at_ms {
+                enc.u8(OP_EXPIRE_MS)?;
+                enc.write(&expires_at_ms.to_le_bytes())?;
+            }
+            enc.u8(type_of(&entry.value))?;
+            enc.bytes(&entry.key)?;
+            enc.value(&entry.value)?;
+        }
+    }
+    enc.u8(OP_EOF)?;
+    let crc = enc.crc;
+    enc.out.write_all(&crc.to_le_bytes())
+}
+
+/// Decodes a snapshot written by [`write_snapshot`].
+pub fn read_snapshot(bytes: &[u8]) -> io::Result<Snapshot> {
+    let (snapshot, len) = read_snapshot_prefix(bytes)?;
+    if len != bytes.len() {
+        return Err(corrupt("trailing bytes after checksum"));
+    }
+    Ok(snapshot)
+}
+
+/// Whether `bytes` start like a snapshot.
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Decodes the snapshot at the start of `bytes`, such as the preamble of a rewritten
+/// append-only file, and returns it with the number of bytes it took.
+pub fn read_snapshot_prefix(bytes: &[u8]) -> io::Result<(Snapshot, usize)> {
+    if !is_snapshot(bytes) || bytes.len() < MAGIC.len() + 1 {
+        return Err(corrupt("not a kyev snapshot"));
+    }
+    let version = bytes[MAGIC.len()];
+    if version > VERSION {
+        return Err(corrupt(&format!("unsupported version {}", version)));
+    }
+
+    let mut dec = Decoder {
+        buf: bytes,
+        pos: MAGIC.len() + 1,
+    };
+    let mut snapshot = Snapshot::default();
+    let mut expires_at_ms = None;
+    loop {
+        match dec.u8()? {
+            OP_EOF => break,
+            OP_SELECT_DB => snapshot.dbs.push((dec.varint()? as usize, vec![])),
+            OP_EXPIRE_MS => expires_at_ms = Some(dec.i64()?),
+            value_type => {
+                let key = dec.bytes()?;
+                let value = dec.value(value_type)?;
+                let (_, entries) = snapshot
+                    .dbs
+                    .last_mut()
+                    .ok_or_else(|| corrupt("key outside of a database"))?;
+                entries.push(SnapshotEntry {
+                    key,
+                    value,
+                    expires_at_ms: expires_at_ms.take(),
+                });
+            }
+        }
+    }
+    let body_len = dec.pos;
+    if crc64(0, &bytes[..body_len]) != dec.u64_le()? {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok((snapshot, dec.pos))
+}
+
+/// Serializes one value and its deadline for DUMP: the value encoded as in a snapshot,
+/// followed by the format version and the CRC-64 of everything before it.
+pub fn dump_value(value: &Value, expires_at_ms: Option<i64>) -> Vec<u8> {
+    let mut enc = Encoder {
+        out: vec![],
+        crc: 0,
+    };
+    let encode = |enc: &mut Encoder<Vec<u8>>| -> io::Result<()> {
+        if let Some(expires_at_ms) = expires_at_ms {
+            enc.u8(OP_EXPIRE_MS)?;
+            enc.write(&expires_at_ms.to_le_bytes())?;
+        }
+        enc.u8(type_of(value))?;
+        enc.value(value)?;
+        enc.u8(VERSION)
+    };
+    encode(&mut enc).expect("writing to a Vec cannot fail");
+    let crc = enc.crc;
+    let mut payload = enc.out;
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Decodes a payload written by [`dump_value`] into the value and its deadline. Fails if
+/// the payload is corrupt or comes from a later version.
+pub fn restore_value(payload: &[u8]) -> io::Result<(Value, Option<i64>)> {
+    if payload.len() < 10 {
+        return Err(corrupt("payload too short"));
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    let mut crc_bytes = [0; 8];
+    crc_bytes.copy_from_slice(crc);
+    if crc64(0, body) != u64::from_le_bytes(crc_bytes) {
+        return Err(corrupt("checksum mismatch"));
+    }
+    let (version, body) = body.split_last().unwrap();
+    if *version > VERSION {
+        return Err(corrupt(&format!("unsupported version {}", version)));
+    }
+
+    let mut dec = Decoder { buf: body, pos: 0 };
+    let mut expires_at_ms = None;
+    let mut value_type = dec.u8()?;
+    if value_type == OP_EXPIRE_MS {
+        expires_at_ms = Some(dec.i64()?);
+        value_type = dec.u8()?;
+    }
+    let value = dec.value(value_type)?;
+    if dec.pos != body.len() {
+        return Err(corrupt("trailing bytes after value"));
+    }
+    Ok((value, expires_at_ms))
+}
+
+fn type_of(value: &Value) -> u8 {
+    match value {
+        Value::Str(_) => TYPE_STRING,
+        Value::Int(_) => TYPE_INT,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+        Value::Stream(_) => TYPE_STREAM,
+    }
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt snapshot: {}", msg),
+    )
+}
+
+/// Writes the encoded form of values while keeping a running checksum.
+struct Encoder<W: Write> {
+    out: W,
+    crc: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.out.write_all(bytes)
+    }
+
+    fn u8(&mut self, byte: u8) -> io::Result<()> {
+        self.write(&[byte])
+    }
+
+    /// Writes `n` as a LEB128 varint: seven bits per byte, low bits first.
+    fn varint(&mut self, mut n: u64) -> io::Result<()> {
+        let mut buf = [0; 10];
+        let mut i = 0;
+        while n >= 0x80 {
+            buf[i] = (n as u8) | 0x80;
+            n >>= 7;
+            i += 1;
+        }
+        buf[i] = n as u8;
+        self.write(&buf[..=i])
+    }
+
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.varint(len as u64)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.len(bytes.len())?;
+        self.write(bytes)
+    }
+
+    fn stream_id(&mut self, id: StreamId) -> io::Result<()> {
+        self.varint(id.ms)?;
+        self.varint(id.seq)
+    }
+
+    fn value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Str(s) => self.bytes(s),
+            Value::Int(i) => self.write(&i.to_le_bytes()),
+            Value::List(list) => {
+                self.len(list.len())?;
+                list.iter().try_for_each(|s| self.bytes(s))
+            }
+            Value::Hash(hash) => {
+                self.len(hash.len())?;
+                hash.iter().try_for_each(|(f, v)| {
+                    self.bytes(f)?;
+                    self.bytes(v)
+                })
+            }
+            Value::Set(set) => {
+                self.len(set.len())?;
+                set.iter().try_for_each(|s| self.bytes(s))
+            }
+            Value::ZSet(zset) => {
+                self.len(zset.len())?;
+                zset.iter().try_for_each(|(m, score)| {
+                    self.bytes(m)?;
+                    self.write(&score.to_bits().to_le_bytes())
+                })
+            }
+            Value::Stream(stream) => self.stream(stream),
+        }
+    }
+
+    fn stream(&mut self, stream: &Stream) -> io::Result<()> {
+        self.len(stream.len())?;
+        for (id, fields) in stream.entries() {
+            self.stream_id(*id)?;
+            self.len(fields.len())?;
+            for (f, v) in fields.iter() {
+                self.bytes(f)?;
+                self.bytes(v)?;
+            }
+        }
+        self.stream_id(stream.last_id())?;
+
+        self.len(stream.groups().len())?;
+        for (name, group) in stream.groups().iter() {
+            self.bytes(name)?;
+            self.stream_id(group.last_delivered())?;
+            self.len(group.pending().len())?;
+            for (id, pending) in group.pending().iter() {
+                self.stream_id(*id)?;
+                self.bytes(&pending.consumer)?;
+                self.varint(pending.delivery_time)?;
+                self.varint(pending.delivery_count)?;
+            }
+            self.len(group.consumers().len())?;
+            for (name, consumer) in group.consumers().iter() {
+                self.bytes(name)?;
+                self.varint(consumer.seen_time)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads values back from their encoded form, failing on truncated or malformed input.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(corrupt("unexpected end of file"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64_le(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(self.u64_le()? as i64)
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+
+    /// Reads a length, refusing ones longer than the rest of the file so that corrupt input
+    /// cannot trigger huge allocations.
+    fn len(&mut self) -> io::Result<usize> {
+        let len = self.varint()?;
+        if len > (self.buf.len() - self.pos) as u64 {
+            return Err(corrupt("length past end of file"));
+        }
+        Ok(len as usize)
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn stream_id(&mut self) -> io::Result<StreamId> {
+        Ok(StreamId::new(self.varint()?, self.varint()?))
+    }
+
+    fn value(&mut self, value_type: u8) -> io::Result<Value> {
+        Ok(match value_type {
+            TYPE_STRING => Value::Str(self.bytes()?),
+            TYPE_INT => Value::Int(self.i64()?),
+            TYPE_LIST => {
+                let len = self.len()?;
+                let mut list = VecDeque::with_capacity(len);
+                for _ in 0..len {
+                    list.push_back(self.bytes()?);
+                }
+                Value::List(list)
+            }
+            TYPE_HASH => {
+                let len = self.len()?;
+                let mut hash = Hash::new();
+                for _ in 0..len {
+                    hash.insert(self.bytes()?, self.bytes()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_SET => {
+                let len = self.len()?;
+                let mut set = ScanSet::new();
+                for _ in 0..len {
+                    set.insert(self.bytes()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET => {
+                let len = self.len()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..len {
+                    let member = self.bytes()?;
+                    let score = f64::from_bits(self.u64_le()?);
+                    if score.is_nan() {
+                        return Err(corrupt("NaN score"));
+                    }
+                    zset.add(member, score, &ZAddOptions::default())
+                        .map_err(|_| corrupt("invalid score"))?;
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_STREAM => Value::Stream(self.stream()?),
+            _ => return Err(corrupt(&format!("unknown value type {}", value_type))),
+        })
+    }
+
+    fn stream(&mut self) -> io::Result<Stream> {
+        let mut entries = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let id = self.stream_id()?;
+            let len = self.len()?;
+            let mut fields = Vec::with_capacity(len);
+            for _ in 0..len {
+                fields.push((self.bytes()?, self.bytes()?));
+            }
+            entries.insert(id, fields);
+        }
+        let last_id = self.stream_id()?;
+
+        let mut groups = HashMap::new();
+        for _ in 0..self.len()? {
+            let name = self.bytes()?;
+            let last_delivered = self.stream_id()?;
+            let mut pending = BTreeMap::new();
+            for _ in 0..self.len()? {
+                let id = self.stream_id()?;
+                let entry = PendingEntry {
+                    consumer: self.bytes()?,
+                    delivery_time: self.varint()?,
+                    delivery_count: self.varint()?,
+                };
+                pending.insert(id, entry);
+            }
+            let mut consumers = HashMap::new();
+            for _ in 0..self.len()? {
+                let name = self.bytes()?;
+                let seen_time = self.varint()?;
+                consumers.insert(name, Consumer { seen_time });
+            }
+            groups.insert(
+                name,
+                ConsumerGroup::from_parts(last_delivered, pending, consumers),
+            );
+        }
+        Ok(Stream::from_parts(entries, last_id, groups))
+    }
+}
+
+lazy_static! {
+    static ref CRC64_TABLE: [u64; 256] = {
+        let mut table = [0; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CRC64_POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            *slot = crc;
+        }
+        table
+    };
+}
+
+/// The reflected Jones polynomial, the CRC-64 variant Redis uses for its RDB files.
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+/// Continues the CRC-64 `crc` over `bytes`.
+pub fn crc64(crc: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC64_TABLE[((crc ^ *byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::XAddId;
+
+    fn sample() -> Snapshot {
+        let mut zset = SortedSet::new();
+        zset.add(b"m".to_vec(), -1.5, &ZAddOptions::default())
+            .unwrap();
+        let mut stream = Stream::new();
+        stream
+            .add(
+                XAddId::Explicit(StreamId::new(1, 1)),
+                vec![(b"f".to_vec(), b"v".to_vec())],
+                0,
+            )
+            .unwrap();
+        stream.create_group(b"g".to_vec(), None).unwrap();
+        let entries = vec![
+            (b"str".to_vec(), Value::Str(b"hello".to_vec()), None),
+            (b"int".to_vec(), Value::Int(-42), Some(1_700_000_000_000)),
+            (
+                b"list".to_vec(),
+                Value::List(vec![b"a".to_vec(), vec![0; 300]].into()),
+                None,
+            ),
+            (
+                b"hash".to_vec(),
+                Value::Hash(vec![(b"f".to_vec(), b"v".to_vec())].into_iter().collect()),
+                None,
+            ),
+            (
+                b"set".to_vec(),
+                Value::Set(vec![b"x".to_vec()].into_iter().collect()),
+                None,
+            ),
+            (b"zset".to_vec(), Value::ZSet(zset), None),
+            (b"stream".to_vec(), Value::Stream(stream), None),
+        ];
+        let entries = entries
+            .into_iter()
+            .map(|(key, value, expires_at_ms)| SnapshotEntry {
+                key,
+                value,
+                expires_at_ms,
+            })
+            .collect();
+        Snapshot {
+            dbs: vec![(0, entries), (3, vec![])],
+        }
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(0xe9c6_d914_c4b8_d9ca, crc64(0, b"123456789"));
+        assert_eq!(crc64(0, b"123456789"), crc64(crc64(0, b"1234"), b"56789"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = sample();
+        let mut bytes = vec![];
+        write_snapshot(&snapshot, &mut bytes).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(snapshot, read_snapshot(&bytes).unwrap());
+
+        let mut empty = vec![];
+        write_snapshot(&Snapshot::default(), &mut empty).unwrap();
+        assert_eq!(Snapshot::default(), read_snapshot(&empty).unwrap());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut bytes = vec![];
+        write_snapshot(&sample(), &mut bytes).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[20] ^= 1;
+        assert!(read_snapshot(&flipped).is_err());
+        assert!(read_snapshot(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_snapshot(b"REDIS0009").is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(read_snapshot(&trailing).is_err());
+        assert_eq!(bytes.len(), read_snapshot_prefix(&trailing).unwrap().1);
+
+        let mut future = bytes.clone();
+        future[MAGIC.len()] = VERSION + 1;
+        assert!(read_snapshot(&future).is_err());
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        for entry in sample().dbs.remove(0).1 {
+            let payload = dump_value(&entry.value, entry.expires_at_ms);
+            assert_eq!(
+                (entry.value, entry.expires_at_ms),
+                restore_value(&payload).unwrap()
+            );
+
+            let mut flipped = payload.clone();
+            flipped[1] ^= 1;
+            assert!(restore_value(&flipped).is_err());
+            assert!(restore_value(&payload[..payload.len() - 1]).is_err());
+            let mut future = payload.clone();
+            let version = future.len() - 9;
+            future[version] = VERSION + 1;
+            let crc = crc64(0, &future[..future.len() - 8]);
+            let crc_at = future.len() - 8;
+            future[crc_at..].copy_from_slice(&crc.to_le_bytes());
+            assert!(restore_value(&future).is_err());
+        }
+        assert!(restore_value(b"").is_err());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("kyev-test-{}.kyev", std::process::id()));
+        assert_eq!(None, load(&path).unwrap());
+        save(&sample(), &path).unwrap();
+        assert_eq!(Some(sample()), load(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,64 +1,373 @@
+use crate::clock;
+use crate::cluster::SlotOrder;
 use crate::command::Command;
+use crate::evict::{AccessStats, EvictionPolicy, EVICTION_SAMPLES};
+use crate::expire::{self, ExpiryIndex, ACTIVE_EXPIRE_KEYS_PER_LOOP};
+use crate::lease::Leases;
+use crate::memory::{
+    self, DbMemory, MemoryAccounting, MemoryStats, CLIENT_BUFFER_SIZE, MEMORY_SAMPLES,
+};
+use crate::scan::{self, ScanIndex, ScanMap, ScanPage, ScanSet};
+use crate::snapshot::{Snapshot, SnapshotEntry};
+use crate::stream::{
+    self, AutoClaim, ClaimOptions, EntryRef, Fields, GroupRead, GroupReadFrom, PendingInfo,
+    PendingSummary, Stream, StreamId, StreamRead, TrimStrategy, XAddId,
+};
+use crate::transaction::KeyLocks;
+use crate::zset::{self, AddOutcome, LexBound, ScoreBound, SortedSet, ZAddOptions};
 use async_std::net::SocketAddr;
-use async_std::task::JoinHandle;
-use std::collections::{hash_map, HashMap};
+use indexmap::{map as index_map, IndexMap};
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use std::collections::{hash_map, HashMap, VecDeque};
 use std::fmt;
 use std::mem::size_of;
-use time::{Duration, PrimitiveDateTime};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 type ClientId = usize;
 
+/// The largest string SETRANGE may grow a value to.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// The most members SRANDMEMBER draws with a negative count, so that a huge count cannot
+/// make it fill memory with repeats.
+pub const MAX_RANDOM_MEMBERS: usize = 1024 * 1024;
+
+pub type Hash = ScanMap<Vec<u8>>;
+
+/// A field and its value borrowed from a hash.
+pub type HashPair<'a> = (&'a Vec<u8>, &'a Vec<u8>);
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Value {
     Int(i64),
-    Str(String),
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(ScanSet),
+    ZSet(SortedSet),
+    Stream(Stream),
 }
 
-pub struct Expiration {
-    pub expires_at: PrimitiveDateTime,
-    pub handle: JoinHandle<()>,
-}
-
-impl Expiration {
-    pub fn new(duration: Duration, handle: JoinHandle<()>) -> Expiration {
-        Expiration {
-            expires_at: PrimitiveDateTime::now() + duration,
-            handle,
+impl Value {
+    /// The name TYPE reports for this value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) | Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// The keyspace table is shrunk once fewer than a tenth of its slots are in use, unless it
+/// has room for fewer keys than this.
+const KEYSPACE_MIN_CAPACITY: usize = 64;
+
 struct Entry {
     value: Value,
-    expiration: Option<Expiration>,
-    touched_at: PrimitiveDateTime,
+    expires_at: Option<Instant>,
+    touched_at: Instant,
+    access: AccessStats,
+    /// The size last accounted for this entry in [`Keyspace::used_memory`].
+    size: usize,
 }
 
 impl Entry {
     fn new(value: Value) -> Entry {
         Entry {
             value,
-            expiration: None,
-            touched_at: PrimitiveDateTime::now(),
+            expires_at: None,
+            touched_at: Instant::now(),
+            access: AccessStats::new(),
+            size: 0,
         }
     }
 
-    fn set_expiration(&mut self, expiration: Expiration) {
-        self.expiration = Some(expiration);
-        self.touched_at = PrimitiveDateTime::now();
+    fn touch(&mut self) {
+        self.touched_at = Instant::now();
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
 
+    /// The time left until the entry expires, in milliseconds.
     fn ttl(&self) -> Option<i64> {
-        if let Some(exp) = &self.expiration {
-            let ttl = exp.expires_at - PrimitiveDateTime::now();
-            Some(ttl.whole_seconds())
+        self.expires_at.map(|expires_at| {
+            expires_at
+                .saturating_duration_since(Instant::now())
+                .as_millis() as i64
+        })
+    }
+
+    fn touched_at(&self) -> &Instant {
+        &self.touched_at
+    }
+}
+
+/// The entries of one database along with the deadlines of those that have a TTL.
+///
+/// An entry whose deadline has passed is treated as missing: reads skip it, and writes
+/// remove it before touching the key. The active expire cycle reclaims the ones that are
+/// never accessed again.
+///
+/// Entries handed out for writing are remeasured by [`Keyspace::settle`], which keeps
+/// `used_memory` current without walking the whole keyspace.
+#[derive(Default)]
+struct Keyspace {
+    /// Addressable by position as well as by key, so eviction draws random keys in O(1).
+    entries: IndexMap<Vec<u8>, Entry>,
+    deadlines: ExpiryIndex,
+    /// The keys in SCAN cursor order, which groups them by slot.
+    order: ScanIndex<SlotOrder>,
+    /// A key `entry` indexed at the next slot of the table in case the caller inserts it,
+    /// along with that slot, until the index is next touched.
+    reserved: Option<(Vec<u8>, usize)>,
+    used_memory: usize,
+    /// Keys whose entries may have changed size since the last settle.
+    dirty: Vec<Vec<u8>>,
+    /// How many times keys were handed out for writing, inserted or removed. Never
+    /// decreases, so save rules can tell how much changed since a snapshot.
+    changes: u64,
+    /// Keys removed because they expired or were evicted rather than by a command, so
+    /// that their removal can be logged.
+    removed: Vec<Vec<u8>>,
+    /// While the store is loading, the time loading began. Expiry is judged against it
+    /// rather than the clock, so keys stay until loading ends, however late they were due.
+    loading_since: Option<Instant>,
+}
+
+impl Keyspace {
+    fn now(&self) -> Instant {
+        self.loading_since.unwrap_or_else(Instant::now)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&Entry> {
+        let entry = self
+            .entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now()))?;
+        entry.access.record();
+        Some(entry)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.remove_if_expired(key);
+        let entry = self.entries.get_mut(key)?;
+        entry.access.record();
+        self.dirty.push(key.to_vec());
+        self.changes += 1;
+        Some(entry)
+    }
+
+    /// Like `get`, but without recording an access.
+    fn peek(&self, key: &[u8]) -> Option<&Entry> {
+        self.entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now()))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn entry(&mut self, key: Vec<u8>) -> index_map::Entry<'_, Vec<u8>, Entry> {
+        self.remove_if_expired(&key);
+        self.release_reserved();
+        if !self.entries.contains_key(&key) {
+            // Indexed now in case the caller inserts, which puts the key in the next slot;
+            // `release_reserved` drops it again if not.
+            let slot = self.entries.len();
+            self.order.insert(&key, slot);
+            self.reserved = Some((key.clone(), slot));
+        }
+        self.dirty.push(key.clone());
+        self.changes += 1;
+        let entry = self.entries.entry(key);
+        if let index_map::Entry::Occupied(occupied) = &entry {
+            occupied.get().access.record();
+        }
+        entry
+    }
+
+    fn insert(&mut self, key: Vec<u8>, mut entry: Entry) {
+        self.release_reserved();
+        entry.size = entry_size(&key, &entry.value, MEMORY_SAMPLES);
+        self.used_memory += entry.size;
+        self.changes += 1;
+        let expires_at = entry.expires_at;
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.used_memory -= old.size;
+            if let Some(expires_at) = old.expires_at {
+                self.deadlines.cancel(&key, expires_at);
+            }
         } else {
-            None
+            self.order.insert(&key, self.entries.len() - 1);
+        }
+        if let Some(expires_at) = expires_at {
+            self.deadlines.schedule(key, expires_at);
         }
     }
 
-    fn touched_at(&self) -> &PrimitiveDateTime {
-        &self.touched_at
+    /// Remeasures the entries handed out for writing since the last call.
+    fn settle(&mut self) {
+        self.release_reserved();
+        for key in self.dirty.drain(..) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                let size = entry_size(&key, &entry.value, MEMORY_SAMPLES);
+                self.used_memory = self.used_memory - entry.size + size;
+                entry.size = size;
+            }
+        }
+    }
+
+    /// Drops the slot `entry` indexed for a key the caller did not insert after all, before
+    /// another key can take it.
+    fn release_reserved(&mut self) {
+        if let Some((key, slot)) = self.reserved.take() {
+            if !self.entries.contains_key(&key) {
+                self.order.remove(&key, slot);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.remove_entry(key).map(|(_, entry)| entry)
+    }
+
+    fn remove_entry(&mut self, key: &[u8]) -> Option<(Vec<u8>, Entry)> {
+        self.remove_if_expired(key);
+        self.evict(key)
+    }
+
+    /// Removes `key` whether or not it has expired.
+    fn evict(&mut self, key: &[u8]) -> Option<(Vec<u8>, Entry)> {
+        self.release_reserved();
+        let (slot, key, entry) = self.entries.swap_remove_full(key)?;
+        self.order.remove(&key, slot);
+        if let Some((moved, _)) = self.entries.get_index(slot) {
+            self.order.moved(moved, self.entries.len(), slot);
+        }
+        self.used_memory -= entry.size;
+        self.changes += 1;
+        if let Some(expires_at) = entry.expires_at {
+            self.deadlines.cancel(&key, expires_at);
+        }
+        self.shrink();
+        Some((key, entry))
+    }
+
+    /// Gives back the table's memory once few of its slots are in use, as after mass
+    /// deletes or evictions. The table still has room for twice the remaining keys.
+    fn shrink(&mut self) {
+        let capacity = self.entries.capacity();
+        if capacity > KEYSPACE_MIN_CAPACITY && self.entries.len() * 10 < capacity {
+            self.entries.shrink_to(self.entries.len() * 2);
+        }
+    }
+
+    /// The memory of the table's unused slots and of the cursor index. Used slots are part
+    /// of each entry's size.
+    fn table_overhead(&self) -> usize {
+        memory::index_map_size::<(Vec<u8>, Entry)>(self.entries.capacity())
+            .saturating_sub(self.entries.len() * memory::index_map_slot_size::<(Vec<u8>, Entry)>())
+            + self.order.memory()
+    }
+
+    fn remove_if_expired(&mut self, key: &[u8]) {
+        let now = self.now();
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            self.drop_key(key);
+        }
+    }
+
+    /// Evicts `key` on behalf of the store rather than a command, noting it in `removed`.
+    fn drop_key(&mut self, key: &[u8]) {
+        if let Some((key, _)) = self.evict(key) {
+            self.removed.push(key);
+        }
+    }
+
+    /// Sets or clears the deadline of `key`. Returns whether the key exists.
+    fn set_expiration(&mut self, key: &[u8], expires_at: Option<Instant>) -> bool {
+        let entry = match self.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let old = std::mem::replace(&mut entry.expires_at, expires_at);
+        entry.touch();
+        if let Some(old) = old {
+            self.deadlines.cancel(key, old);
+        }
+        if let Some(expires_at) = expires_at {
+            self.deadlines.schedule(key.to_vec(), expires_at);
+        }
+        true
+    }
+
+    /// Removes up to `limit` entries whose deadline has passed, returning how many it
+    /// removed.
+    fn remove_expired(&mut self, limit: usize) -> usize {
+        let due = self.deadlines.due(self.now(), limit);
+        for key in due.iter() {
+            self.drop_key(key);
+        }
+        due.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        let now = self.now();
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Draws `count` keys at random, possibly repeating some and including expired ones.
+    fn sample<R: Rng>(&self, rng: &mut R, count: usize) -> Vec<&Vec<u8>> {
+        if self.entries.is_empty() {
+            return vec![];
+        }
+        (0..count)
+            .map(|_| {
+                let position = rng.gen_range(0, self.entries.len());
+                self.entries.get_index(position).unwrap().0
+            })
+            .collect()
+    }
+
+    fn values_mut(&mut self) -> index_map::ValuesMut<'_, Vec<u8>, Entry> {
+        self.entries.values_mut()
+    }
+
+    /// Counts every entry, including expired ones not yet removed.
+    fn len(&self) -> usize {
+        self.entries.len()
     }
 }
 
@@ -67,6 +376,9 @@ pub struct Client {
     pub addr: SocketAddr,
 }
 
+/// The memory a connected client is assumed to take, including its read buffer.
+const CLIENT_SIZE: usize = size_of::<Client>() + CLIENT_BUFFER_SIZE;
+
 impl Client {
     fn new(id: ClientId, addr: SocketAddr) -> Client {
         Client { id, addr }
@@ -79,18 +391,58 @@ impl fmt::Display for Client {
     }
 }
 
+/// The number of databases a store is created with unless configured otherwise.
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// The numbered databases and the connected clients.
 pub struct Store {
-    data: HashMap<String, Entry>,
+    dbs: Vec<Db>,
     clients: HashMap<ClientId, Client>,
     next_client_id: ClientId,
+    key_locks: KeyLocks,
+    /// The memory budget in bytes, or 0 for none.
+    maxmemory: usize,
+    eviction_policy: EvictionPolicy,
+    evicted_keys: u64,
+    accounting: MemoryAccounting,
+    /// The changes counted up to the snapshot last saved.
+    saved_changes: u64,
+    /// When the last successful save finished, as a Unix time in seconds.
+    last_save: u64,
+    last_save_ok: bool,
+    saving: AtomicBool,
+    /// The estimated memory of the copy a background save is writing out. Reported, but
+    /// left out of the eviction budget, so live keys are not evicted to pay for a copy
+    /// that goes away once written.
+    snapshot_memory: AtomicUsize,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Store {
     pub fn new() -> Store {
+        Store::with_databases(DEFAULT_DATABASES)
+    }
+
+    pub fn with_databases(databases: usize) -> Store {
         Store {
-            data: HashMap::new(),
+            dbs: (0..databases).map(Db::new).collect(),
             clients: HashMap::new(),
             next_client_id: 1,
+            key_locks: KeyLocks::new(),
+            maxmemory: 0,
+            eviction_policy: EvictionPolicy::default(),
+            evicted_keys: 0,
+            accounting: MemoryAccounting::default(),
+            saved_changes: 0,
+            last_save: clock::now_ms() / 1000,
+            last_save_ok: true,
+            saving: AtomicBool::new(false),
+            snapshot_memory: AtomicUsize::new(0),
         }
     }
 
@@ -102,23 +454,450 @@ impl Store {
         client_id
     }
 
+    /// Forgets a client that disconnected, ending its interactive transaction, if any.
     pub fn remove_client(&mut self, id: ClientId) {
         self.clients.remove(&id);
+        self.key_locks.end(id);
+    }
+
+    /// The keys locked by interactive transactions.
+    pub fn key_locks_mut(&mut self) -> &mut KeyLocks {
+        &mut self.key_locks
+    }
+
+    pub fn clients(&self) -> hash_map::Values<'_, ClientId, Client> {
+        self.clients.values()
+    }
+
+    /// Fails if a lease held by a token other than `token` covers a key `cmd` would write,
+    /// whether named in `db_index`, the database it runs against, or reached in another.
+    pub fn check_write_leases(
+        &self,
+        db_index: usize,
+        cmd: &Command,
+        token: Option<u64>,
+    ) -> Result<(), StoreError> {
+        self.db(db_index).check_leases(&cmd.written_keys(), token)?;
+        for (index, key) in cmd.other_writes(db_index, self.databases()) {
+            match key {
+                Some(key) => self.db(index).check_leases(&[key], token)?,
+                None => self.db(index).check_all_leases(token)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with `Locked` if `cmd` writes a key another client locked with
+    /// BEGIN, counting a whole-database write as touching every lock in it.
+    pub fn check_write_locks(&mut self, db_index: usize, cmd: &Command) -> Result<(), StoreError> {
+        let now = Instant::now();
+        let mut locked = self
+            .key_locks
+            .any_locked(db_index, &cmd.written_keys(), now);
+        for (index, key) in cmd.other_writes(db_index, self.databases()) {
+            locked |= match key {
+                Some(key) => self.key_locks.any_locked(index, &[key], now),
+                None => self.key_locks.any_locked_in(index, now),
+            };
+        }
+        if locked {
+            return Err(StoreError::new(StoreErrorKind::Locked));
+        }
+        Ok(())
     }
 
-    pub fn set(&mut self, key: String, value: String, keep_ttl: bool) -> Option<()> {
-        let value = if let Ok(int) = value.parse::<i64>() {
-            Value::Int(int)
+    pub fn databases(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn db(&self, index: usize) -> &Db {
+        &self.dbs[index]
+    }
+
+    pub fn db_mut(&mut self, index: usize) -> &mut Db {
+        &mut self.dbs[index]
+    }
+
+    /// Checks that `index` names one of the databases.
+    pub fn db_index(&self, index: i64) -> Result<usize, StoreError> {
+        if index >= 0 && (index as usize) < self.dbs.len() {
+            Ok(index as usize)
         } else {
-            Value::Str(value)
-        };
+            Err(StoreError::new(StoreErrorKind::DbIndexOutOfRange))
+        }
+    }
+
+    /// Moves `key` from database `from` to `to` along with its expiration. Returns whether
+    /// it moved, which it does not if it is missing from `from` or already exists in `to`.
+    pub fn move_key(&mut self, key: &[u8], from: usize, to: usize) -> Result<bool, StoreError> {
+        if from == to {
+            return Err(StoreError::new(StoreErrorKind::SameObject));
+        }
+        if !self.dbs[from].data.contains_key(key) || self.dbs[to].data.contains_key(key) {
+            return Ok(false);
+        }
+        let (key, mut entry) = self.dbs[from].data.remove_entry(key).unwrap();
+        entry.touch();
+        self.dbs[to].data.insert(key, entry);
+        Ok(true)
+    }
+
+    /// Swaps the contents of two databases. Each keeps its index.
+    pub fn swap_dbs(&mut self, a: usize, b: usize) {
+        if a != b {
+            let data = std::mem::take(&mut self.dbs[a].data);
+            self.dbs[a].data = std::mem::replace(&mut self.dbs[b].data, data);
+            self.dbs[a].touch_all();
+            self.dbs[b].touch_all();
+            self.dbs[a].data.changes += 1;
+        }
+    }
+
+    /// Empties every database, returning the old contents so the caller decides where they
+    /// are dropped.
+    pub fn flush_all(&mut self) -> Vec<Db> {
+        self.dbs.iter_mut().map(|db| db.flush()).collect()
+    }
+
+    /// The memory use compared against maxmemory, measured as the accounting mode says.
+    pub fn mem_usage(&self) -> usize {
+        match self.accounting {
+            MemoryAccounting::Estimate => {
+                let dbs: usize = self
+                    .dbs
+                    .iter()
+                    .map(|db| {
+                        db.mem_usage() + db.data.table_overhead() + db.data.deadlines.memory()
+                    })
+                    .sum();
+                dbs + self.clients.len() * CLIENT_SIZE
+            }
+            MemoryAccounting::Allocator => memory::allocated(),
+        }
+    }
+
+    /// Breaks the memory use down into the dataset and the structures around it.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let dbs: Vec<DbMemory> = self
+            .dbs
+            .iter()
+            .map(|db| DbMemory {
+                index: db.index,
+                keys: db.data.len(),
+                keyspace_overhead: db.data.table_overhead(),
+                expires_overhead: db.data.deadlines.memory(),
+            })
+            .filter(|db| db.keys > 0 || db.keyspace_overhead > 0)
+            .collect();
+        MemoryStats {
+            accounting: self.accounting,
+            total: self.mem_usage(),
+            allocated: memory::allocated(),
+            dataset: self.dbs.iter().map(|db| db.mem_usage()).sum(),
+            clients: self.clients.len() * CLIENT_SIZE,
+            snapshot: self.snapshot_memory(),
+            keys: dbs.iter().map(|db| db.keys).sum(),
+            dbs,
+        }
+    }
+
+    /// Copies out every live key with its value and deadline.
+    pub fn snapshot(&self) -> Snapshot {
+        let dbs = self
+            .dbs
+            .iter()
+            .filter(|db| db.data.len() > 0)
+            .map(|db| {
+                let entries = db
+                    .data
+                    .iter()
+                    .map(|(key, entry)| SnapshotEntry {
+                        key: key.clone(),
+                        value: entry.value.clone(),
+                        expires_at_ms: entry.ttl().map(expire::ttl_to_unix_ms),
+                    })
+                    .collect();
+                (db.index, entries)
+            })
+            .collect();
+        Snapshot { dbs }
+    }
+
+    /// Copies out the data for a background save to write out. The copy is a deep one made
+    /// while the store is locked, so clients wait for as long as copying the dataset
+    /// takes. Its size is reported in MEMORY STATS until [`Store::finish_save`], but not
+    /// counted against maxmemory.
+    pub fn background_snapshot(&self) -> Snapshot {
+        let dataset = self.dbs.iter().map(|db| db.mem_usage()).sum();
+        self.snapshot_memory.store(dataset, Ordering::SeqCst);
+        self.snapshot()
+    }
+
+    /// The estimated memory of the copy a background save holds, if one is running.
+    pub fn snapshot_memory(&self) -> usize {
+        self.snapshot_memory.load(Ordering::SeqCst)
+    }
+
+    /// Empties the databases and fills them from `snapshot`, leaving out keys whose
+    /// deadline has passed unless the store is loading. Fails if the snapshot names a
+    /// database this store lacks.
+    pub fn load(&mut self, snapshot: Snapshot) -> Result<(), StoreError> {
+        if snapshot
+            .dbs
+            .iter()
+            .any(|(index, _)| *index >= self.dbs.len())
+        {
+            return Err(StoreError::new(StoreErrorKind::DbIndexOutOfRange));
+        }
+        self.flush_all();
+        for (index, entries) in snapshot.dbs {
+            let db = &mut self.dbs[index];
+            for SnapshotEntry {
+                key,
+                value,
+                expires_at_ms,
+            } in entries
+            {
+                let mut entry = Entry::new(value);
+                if let Some(expires_at_ms) = expires_at_ms {
+                    let ttl_ms = expire::unix_ms_to_ttl(expires_at_ms);
+                    if ttl_ms <= 0 && db.data.loading_since.is_none() {
+                        continue;
+                    }
+                    entry.expires_at =
+                        Instant::now().checked_add(Duration::from_millis(ttl_ms.max(0) as u64));
+                }
+                db.data.insert(key, entry);
+            }
+        }
+        self.saved_changes = self.changes();
+        Ok(())
+    }
+
+    /// How many times keys were written, counted over the life of the store.
+    pub fn changes(&self) -> u64 {
+        self.dbs.iter().map(|db| db.data.changes).sum()
+    }
+
+    /// How many times keys were written since the last successful save.
+    pub fn changes_since_save(&self) -> u64 {
+        self.changes() - self.saved_changes
+    }
+
+    /// Marks a save as running. Returns false if one already is.
+    pub fn begin_save(&self) -> bool {
+        self.saving
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Records the end of the save begun when `changes_since_save` was `changes`.
+    pub fn finish_save(&mut self, changes: u64, ok: bool) {
+        self.saving.store(false, Ordering::SeqCst);
+        self.snapshot_memory.store(0, Ordering::SeqCst);
+        self.last_save_ok = ok;
+        if ok {
+            self.saved_changes += changes;
+            self.last_save = clock::now_ms() / 1000;
+        }
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.saving.load(Ordering::SeqCst)
+    }
+
+    /// When the last successful save finished, as a Unix time in seconds.
+    pub fn last_save(&self) -> u64 {
+        self.last_save
+    }
+
+    pub fn last_save_ok(&self) -> bool {
+        self.last_save_ok
+    }
+
+    /// Takes the keys removed since the last call because they expired or were evicted,
+    /// each with its database index.
+    pub fn take_removed(&mut self) -> Vec<(usize, Vec<u8>)> {
+        let mut removed = vec![];
+        for db in self.dbs.iter_mut() {
+            let index = db.index;
+            removed.extend(db.data.removed.drain(..).map(|key| (index, key)));
+        }
+        removed
+    }
+
+    /// Starts or ends loading. While loading, no key expires, so that replaying logged
+    /// commands sees keys as they were when the commands ran.
+    pub fn set_loading(&mut self, loading: bool) {
+        let since = if loading { Some(Instant::now()) } else { None };
+        for db in self.dbs.iter_mut() {
+            db.data.loading_since = since;
+        }
+    }
+
+    pub fn set_memory_accounting(&mut self, accounting: MemoryAccounting) {
+        self.accounting = accounting;
+    }
+
+    /// Sets the memory budget, 0 for none, and which keys are evicted to stay within it.
+    pub fn set_maxmemory(&mut self, maxmemory: usize, policy: EvictionPolicy) {
+        self.maxmemory = maxmemory;
+        self.eviction_policy = policy;
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.maxmemory
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    /// How many keys were evicted to stay within the memory budget.
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys
+    }
+
+    /// Brings the memory accounting up to date after a write.
+    pub fn settle_memory(&mut self) {
+        for db in self.dbs.iter_mut() {
+            db.data.settle();
+        }
+    }
+
+    /// Evicts keys under the eviction policy until memory use is within the budget. Fails
+    /// with an OOM error if the policy allows no eviction or no key is left to evict.
+    pub fn evict(&mut self) -> Result<(), StoreError> {
+        if self.maxmemory == 0 {
+            return Ok(());
+        }
+        while self.mem_usage() > self.maxmemory {
+            let (index, key) = self
+                .eviction_candidate()
+                .ok_or_else(|| StoreError::new(StoreErrorKind::OutOfMemory))?;
+            self.dbs[index].data.drop_key(&key);
+            self.evicted_keys += 1;
+        }
+        Ok(())
+    }
+
+    /// Picks the key to evict next, as a database index and key: the best under the policy
+    /// among a sample of keys from each database.
+    fn eviction_candidate(&self) -> Option<(usize, Vec<u8>)> {
+        use EvictionPolicy::*;
+
+        let policy = self.eviction_policy;
+        let mut rng = rand::thread_rng();
+        let mut best: Option<(u64, usize, &Vec<u8>)> = None;
+        for db in self.dbs.iter() {
+            let keyspace = &db.data;
+            let sample: Vec<&Vec<u8>> = match policy {
+                NoEviction => return None,
+                VolatileTtl => keyspace
+                    .deadlines
+                    .soonest()
+                    .take(EVICTION_SAMPLES)
+                    .collect(),
+                _ if policy.is_volatile() => keyspace.deadlines.sample(&mut rng, EVICTION_SAMPLES),
+                _ => keyspace.sample(&mut rng, EVICTION_SAMPLES),
+            };
+            // Leased keys are held for their lease holder, so they are never evicted.
+            for key in sample
+                .into_iter()
+                .filter(|key| db.leases.get(key).is_none())
+            {
+                let entry = &keyspace.entries[key];
+                // Higher scores are evicted first.
+                let score = match policy {
+                    AllKeysLru | VolatileLru => entry.access.idle_ms(),
+                    AllKeysLfu | VolatileLfu => (u8::MAX - entry.access.frequency()) as u64,
+                    VolatileTtl => u64::MAX - entry.ttl().unwrap_or(0) as u64,
+                    _ => rng.gen(),
+                };
+                if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                    best = Some((score, db.index, key));
+                }
+            }
+        }
+        best.map(|(_, index, key)| (index, key.clone()))
+    }
+
+    /// Removes keys whose TTL has passed, a batch from each database at a time, until no
+    /// database fills a whole batch or `budget` is spent. Returns whether expired keys may
+    /// remain, in which case the next cycle should run soon.
+    pub fn active_expire_cycle(&mut self, budget: Duration) -> bool {
+        let started = Instant::now();
+        loop {
+            let mut backlog = false;
+            for db in self.dbs.iter_mut() {
+                let removed = db.data.remove_expired(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+                backlog |= removed == ACTIVE_EXPIRE_KEYS_PER_LOOP;
+                let released = db.leases.remove_expired(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+                backlog |= released == ACTIVE_EXPIRE_KEYS_PER_LOOP;
+            }
+            if !backlog {
+                return false;
+            }
+            if started.elapsed() >= budget {
+                return true;
+            }
+        }
+    }
+}
+
+/// One numbered keyspace.
+pub struct Db {
+    index: usize,
+    data: Keyspace,
+    /// Leases belong to key names in this database, so they stay when its data is
+    /// flushed or swapped.
+    leases: Leases,
+}
+
+impl Db {
+    pub fn new(index: usize) -> Db {
+        Db {
+            index,
+            data: Keyspace::default(),
+            leases: Leases::new(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Whether the store is loading, during which no key expires.
+    pub fn is_loading(&self) -> bool {
+        self.data.loading_since.is_some()
+    }
+
+    /// Empties the database, returning the old contents so the caller decides where they
+    /// are dropped.
+    pub fn flush(&mut self) -> Db {
+        let data = std::mem::take(&mut self.data);
+        self.data.changes = data.changes + data.len() as u64;
+        self.data.loading_since = data.loading_since;
+        Db {
+            index: self.index,
+            data,
+            leases: Leases::new(),
+        }
+    }
+
+    fn touch_all(&mut self) {
+        for entry in self.data.values_mut() {
+            entry.touch();
+        }
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>, keep_ttl: bool) -> Option<()> {
+        let value = string_value(value);
 
         let entry = if keep_ttl {
-            let maybe_expiration = self.data.remove(&key).and_then(|entry| entry.expiration);
+            let expires_at = self.data.get(&key).and_then(|entry| entry.expires_at);
             let mut new_entry = Entry::new(value);
-            if let Some(exp) = maybe_expiration {
-                new_entry.set_expiration(exp);
-            }
+            new_entry.expires_at = expires_at;
             new_entry
         } else {
             Entry::new(value)
@@ -128,90 +907,2819 @@ impl Store {
         Some(())
     }
 
-    pub fn get(&self, key: &String) -> Option<&Value> {
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
         self.data.get(key).map(|entry| &entry.value)
     }
 
-    pub fn remove(&mut self, key: &String) -> Option<()> {
-        self.data.remove(key).map(|_| ())
+    /// Adds `increment` to the integer at `key`, starting from 0 if the key is missing.
+    /// The key's TTL is left untouched.
+    pub fn incr_by(&mut self, key: Vec<u8>, increment: i64) -> Result<i64, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Int(0)));
+        let current = match &entry.value {
+            Value::Int(int) => *int,
+            Value::Str(_) => return Err(StoreError::new(StoreErrorKind::NotInteger)),
+            _ => return Err(StoreError::new(StoreErrorKind::WrongType)),
+        };
+        let result = current
+            .checked_add(increment)
+            .ok_or_else(|| StoreError::new(StoreErrorKind::Overflow))?;
+        entry.value = Value::Int(result);
+        entry.touch();
+        Ok(result)
     }
 
-    pub fn expire(&mut self, key: &String, expiration: Expiration) -> Option<()> {
-        if let Some(entry) = self.data.get_mut(key) {
-            entry.set_expiration(expiration);
-            Some(())
-        } else {
-            None
+    /// Adds `increment` to the number at `key`, starting from 0 if the key is missing, and
+    /// returns the new value in its stored string form. The key's TTL is left untouched.
+    pub fn incr_by_float(&mut self, key: Vec<u8>, increment: f64) -> Result<Vec<u8>, StoreError> {
+        let current = match self.data.get(&key).map(|entry| &entry.value) {
+            None => 0.0,
+            Some(Value::Int(int)) => *int as f64,
+            Some(Value::Str(s)) => zset::parse_score(s)
+                .filter(|float| float.is_finite())
+                .ok_or_else(|| StoreError::new(StoreErrorKind::NotFloat))?,
+            Some(_) => return Err(StoreError::new(StoreErrorKind::WrongType)),
+        };
+        let result = current + increment;
+        if !result.is_finite() {
+            return Err(StoreError::new(StoreErrorKind::NanOrInfinity));
         }
+        let bytes = result.to_string().into_bytes();
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Int(0)));
+        entry.value = string_value(bytes.clone());
+        entry.touch();
+        Ok(bytes)
     }
 
-    pub fn ttl(&self, key: &String) -> TTL {
-        if let Some(entry) = self.data.get(key) {
-            if let Some(ttl) = entry.ttl() {
-                TTL::Expires(ttl)
-            } else {
-                TTL::NoExpiration
-            }
-        } else {
-            TTL::KeyNotFound
+    /// Returns the string at `key`, with integers rendered in decimal form.
+    pub fn get_string(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            None => Ok(None),
+            Some(Value::Int(int)) => Ok(Some(int.to_string().into_bytes())),
+            Some(Value::Str(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
         }
     }
 
-    pub fn last_touched(&self, key: &String) -> Option<&PrimitiveDateTime> {
-        self.data.get(key).map(|entry| entry.touched_at()).or(None)
+    /// Applies `f` to the string at `key` in place, creating an empty string if the key is
+    /// missing. The key's TTL is left untouched.
+    fn update_string<F, T>(&mut self, key: Vec<u8>, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> T,
+    {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Str(vec![])));
+        let mut bytes = match &mut entry.value {
+            Value::Int(int) => int.to_string().into_bytes(),
+            Value::Str(s) => std::mem::take(s),
+            _ => return Err(StoreError::new(StoreErrorKind::WrongType)),
+        };
+        let result = f(&mut bytes);
+        entry.value = string_value(bytes);
+        entry.touch();
+        Ok(result)
     }
 
-    pub fn clients(&self) -> hash_map::Values<ClientId, Client> {
-        self.clients.values()
+    pub fn append(&mut self, key: Vec<u8>, value: &[u8]) -> Result<usize, StoreError> {
+        self.update_string(key, |s| {
+            s.extend_from_slice(value);
+            s.len()
+        })
     }
 
-    pub fn mem_usage(&self) -> usize {
-        let mut size = 0;
-        for (k, v) in self.data.iter() {
-            size += size_of::<Entry>();
-            size += size_of::<String>();
-            size += k.as_bytes().len();
-            size += match &v.value {
-                Value::Int(_) => 0,
-                Value::Str(s) => s.as_bytes().len(),
-            };
-        }
+    pub fn strlen(&self, key: &[u8]) -> Result<usize, StoreError> {
+        Ok(self.get_string(key)?.map_or(0, |s| s.len()))
+    }
 
-        size
+    /// Returns the bytes between `start` and `end` inclusive. Negative offsets count back
+    /// from the end of the string.
+    pub fn getrange(&self, key: &[u8], start: i64, end: i64) -> Result<Vec<u8>, StoreError> {
+        let s = self.get_string(key)?.unwrap_or_default();
+        let len = s.len() as i64;
+        let start = if start < 0 { len + start } else { start }.max(0);
+        let end = if end < 0 { len + end } else { end }.min(len - 1);
+        if start > end {
+            return Ok(vec![]);
+        }
+        Ok(s[start as usize..=end as usize].to_vec())
     }
-}
 
-pub enum TTL {
-    NoExpiration,
-    KeyNotFound,
-    Expires(i64),
-}
+    /// Overwrites the string at `key` from `offset`, zero-padding it if it is shorter than
+    /// `offset`. Returns the new length of the string.
+    pub fn setrange(
+        &mut self,
+        key: Vec<u8>,
+        offset: usize,
+        value: &[u8],
+    ) -> Result<usize, StoreError> {
+        if value.is_empty() {
+            return self.strlen(&key);
+        }
+        if offset + value.len() > MAX_STRING_LEN {
+            return Err(StoreError::new(StoreErrorKind::StringTooLong));
+        }
+        self.update_string(key, |s| {
+            let end = offset + value.len();
+            if s.len() < end {
+                s.resize(end, 0);
+            }
+            s[offset..end].copy_from_slice(value);
+            s.len()
+        })
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct StoreError {
-    kind: StoreErrorKind,
-    command: Option<Command>,
-}
+    /// Sets `key` to `value`, discarding any TTL, and returns the old string.
+    pub fn getset(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>, StoreError> {
+        let old = self.get_string(&key)?;
+        self.set(key, value, false);
+        Ok(old)
+    }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum StoreErrorKind {
-    Unknown,
-}
+    pub fn getdel(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        let value = self.get_string(key)?;
+        if value.is_some() {
+            self.data.remove(key);
+        }
+        Ok(value)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns the string at each key, or `None` for keys that are missing or hold another
+    /// type.
+    pub fn mget(&self, keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+        keys.iter()
+            .map(|key| self.get_string(key).unwrap_or(None))
+            .collect()
+    }
+
+    pub fn mset(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) {
+        for (key, value) in pairs {
+            self.set(key, value, false);
+        }
+    }
+
+    /// Sets every pair only if none of the keys exist. Returns whether the keys were set.
+    pub fn msetnx(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> bool {
+        if pairs.iter().any(|(key, _)| self.data.contains_key(key)) {
+            return false;
+        }
+        self.mset(pairs);
+        true
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<()> {
+        self.data.remove(key).map(|_| ())
+    }
+
+    /// Removes every key given and returns how many existed.
+    pub fn del(&mut self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .filter(|key| self.data.remove(key).is_some())
+            .count()
+    }
+
+    /// Counts the given keys that exist. A key given more than once is counted each time.
+    pub fn exists(&self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .filter(|key| self.data.contains_key(key))
+            .count()
+    }
+
+    pub fn key_type(&self, key: &[u8]) -> &'static str {
+        self.data
+            .get(key)
+            .map_or("none", |entry| entry.value.type_name())
+    }
+
+    /// Moves the value at `key` to `new_key` along with its expiration. When `nx` is set an
+    /// existing `new_key` is left alone. Returns whether the key was renamed.
+    pub fn rename(&mut self, key: &[u8], new_key: Vec<u8>, nx: bool) -> Result<bool, StoreError> {
+        if !self.data.contains_key(key) {
+            return Err(StoreError::new(StoreErrorKind::NoSuchKey));
+        }
+        if nx && self.data.contains_key(&new_key) {
+            return Ok(false);
+        }
+        if key == new_key.as_slice() {
+            return Ok(true);
+        }
+        let mut entry = self.data.remove(key).unwrap();
+        entry.touch();
+        self.data.insert(new_key, entry);
+        Ok(true)
+    }
+
+    /// Copies the value at `key` to `dest` along with its expiration, overwriting `dest` only
+    /// if `replace` is set. Returns whether the value was copied.
+    pub fn copy(&mut self, key: &[u8], dest: Vec<u8>, replace: bool) -> bool {
+        if key == dest.as_slice() || (!replace && self.data.contains_key(&dest)) {
+            return false;
+        }
+        match self.data.get(key) {
+            Some(entry) => {
+                let mut copy = Entry::new(entry.value.clone());
+                copy.expires_at = entry.expires_at;
+                self.data.insert(dest, copy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stores `value` at `key` as RESTORE does, without a TTL and, with `access`, with the
+    /// access stats IDLETIME or FREQ gave. An existing key is only overwritten if `replace`
+    /// is set.
+    pub fn restore(
+        &mut self,
+        key: Vec<u8>,
+        value: Value,
+        access: Option<AccessStats>,
+        replace: bool,
+    ) -> Result<(), StoreError> {
+        if !replace && self.data.contains_key(&key) {
+            return Err(StoreError::new(StoreErrorKind::BusyKey));
+        }
+        let mut entry = Entry::new(value);
+        if let Some(access) = access {
+            entry.access = access;
+        }
+        self.data.insert(key, entry);
+        Ok(())
+    }
+
+    /// Leases `key` for `ttl_ms`, or renews its lease when given the lease's token.
+    /// Returns the lease's token.
+    pub fn lease(
+        &mut self,
+        key: Vec<u8>,
+        ttl_ms: u64,
+        token: Option<u64>,
+    ) -> Result<u64, StoreError> {
+        self.leases
+            .acquire(key, Duration::from_millis(ttl_ms), token)
+    }
+
+    /// Releases the lease on `key` if `token` holds it. Returns whether it did.
+    pub fn release_lease(&mut self, key: &[u8], token: u64) -> bool {
+        self.leases.release(key, token)
+    }
+
+    /// Fails if any of `keys` is leased to a token other than `token`.
+    pub fn check_leases(&self, keys: &[&[u8]], token: Option<u64>) -> Result<(), StoreError> {
+        if keys.iter().all(|key| self.leases.allows(key, token)) {
+            Ok(())
+        } else {
+            Err(StoreError::new(StoreErrorKind::Leased))
+        }
+    }
+
+    /// Fails if any key is leased to a token other than `token`. Commands that empty or swap
+    /// the whole database write every key in it.
+    pub fn check_all_leases(&self, token: Option<u64>) -> Result<(), StoreError> {
+        if self
+            .leases
+            .iter()
+            .all(|(key, _)| self.leases.allows(key, token))
+        {
+            Ok(())
+        } else {
+            Err(StoreError::new(StoreErrorKind::Leased))
+        }
+    }
+
+    pub fn leases(&self) -> &Leases {
+        &self.leases
+    }
+
+    pub fn keys(&self, pattern: &[u8]) -> Vec<&Vec<u8>> {
+        self.data
+            .keys()
+            .filter(|key| scan::glob_match(pattern, key))
+            .collect()
+    }
+
+    /// The keys that hash to `slot` in cluster mode. Found in O(log N) through the cursor
+    /// index, which keeps the keys of a slot together.
+    pub fn keys_in_slot(&self, slot: u16) -> impl Iterator<Item = &Vec<u8>> {
+        self.data
+            .order
+            .from(SlotOrder::slot_start(slot))
+            .take_while(move |(position, _)| SlotOrder::slot_of(*position) == slot)
+            .filter_map(move |(_, index)| self.data.entries.get_index(index))
+            .filter(move |(_, entry)| !entry.is_expired(self.data.now()))
+            .map(|(key, _)| key)
+    }
+
+    /// Returns a page of keys from `cursor`. Like Redis, `pattern` and `type_name` are applied
+    /// after the page is taken, so a page may come back empty before the iteration ends.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_name: Option<&str>,
+    ) -> ScanPage<&Vec<u8>> {
+        let (next, slots) = self.data.order.page(cursor, count);
+        let keys = slots
+            .into_iter()
+            .filter_map(|slot| self.data.entries.get_index(slot).map(|(key, _)| key))
+            .filter(|key| {
+                self.data.peek(key).is_some_and(|entry| {
+                    pattern.is_none_or(|pattern| scan::glob_match(pattern, key))
+                        && type_name.is_none_or(|name| entry.value.type_name() == name)
+                })
+            })
+            .collect();
+        (next, keys)
+    }
+
+    pub fn sscan(
+        &self,
+        key: &[u8],
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<ScanPage<&Vec<u8>>, StoreError> {
+        let set = match self.get_set(key)? {
+            Some(set) => set,
+            None => return Ok((0, vec![])),
+        };
+        let (next, mut members) = set.scan(cursor, count);
+        members.retain(|member| pattern.is_none_or(|pattern| scan::glob_match(pattern, member)));
+        Ok((next, members))
+    }
+
+    pub fn hscan(
+        &self,
+        key: &[u8],
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<ScanPage<HashPair<'_>>, StoreError> {
+        let hash = match self.get_hash(key)? {
+            Some(hash) => hash,
+            None => return Ok((0, vec![])),
+        };
+        let (next, mut pairs) = hash.scan(cursor, count);
+        pairs.retain(|(field, _)| pattern.is_none_or(|pattern| scan::glob_match(pattern, field)));
+        Ok((next, pairs))
+    }
+
+    pub fn zscan(
+        &self,
+        key: &[u8],
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+    ) -> Result<ScanPage<(&Vec<u8>, f64)>, StoreError> {
+        let zset = match self.get_zset(key)? {
+            Some(zset) => zset,
+            None => return Ok((0, vec![])),
+        };
+        let (next, mut members) = zset.scan(cursor, count);
+        members
+            .retain(|(member, _)| pattern.is_none_or(|pattern| scan::glob_match(pattern, member)));
+        Ok((next, members))
+    }
+
+    pub fn random_key(&self) -> Option<&Vec<u8>> {
+        self.data.keys().choose(&mut rand::thread_rng())
+    }
+
+    pub fn dbsize(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Expires `key` at `expires_at`. Returns `None` if the key is missing.
+    pub fn expire(&mut self, key: &[u8], expires_at: Instant) -> Option<()> {
+        if self.data.set_expiration(key, Some(expires_at)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Removes the TTL from `key`, returning whether it had one.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        self.expires_at(key).is_some() && self.data.set_expiration(key, None)
+    }
+
+    pub fn expires_at(&self, key: &[u8]) -> Option<Instant> {
+        self.data.get(key).and_then(|entry| entry.expires_at)
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> TTL {
+        if let Some(entry) = self.data.get(key) {
+            if let Some(ttl) = entry.ttl() {
+                TTL::Expires(ttl)
+            } else {
+                TTL::NoExpiration
+            }
+        } else {
+            TTL::KeyNotFound
+        }
+    }
+
+    pub fn last_touched(&self, key: &[u8]) -> Option<&Instant> {
+        self.data.get(key).map(|entry| entry.touched_at()).or(None)
+    }
+
+    /// The estimated memory of the keys and values, including their slots in the keyspace.
+    pub fn mem_usage(&self) -> usize {
+        self.data.used_memory
+    }
+
+    /// Estimates the memory taken by `key` and its value, measuring up to `samples`
+    /// elements of an aggregate value, or all of them when `samples` is 0.
+    pub fn memory_usage(&self, key: &[u8], samples: usize) -> Option<usize> {
+        self.data
+            .peek(key)
+            .map(|entry| entry_size(key, &entry.value, samples))
+    }
+
+    fn get_list(&self, key: &[u8]) -> Result<Option<&VecDeque<Vec<u8>>>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_list_mut(&mut self, key: &[u8]) -> Result<Option<&mut VecDeque<Vec<u8>>>, StoreError> {
+        match self.data.get_mut(key) {
+            Some(entry) => match &mut entry.value {
+                Value::List(list) => {
+                    entry.touched_at = Instant::now();
+                    Ok(Some(list))
+                }
+                _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn list_entry(&mut self, key: Vec<u8>) -> Result<&mut VecDeque<Vec<u8>>, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::List(VecDeque::new())));
+        match &mut entry.value {
+            Value::List(list) => {
+                entry.touched_at = Instant::now();
+                Ok(list)
+            }
+            _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+        }
+    }
+
+    fn get_hash(&self, key: &[u8]) -> Result<Option<&Hash>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_hash_mut(&mut self, key: &[u8]) -> Result<Option<&mut Hash>, StoreError> {
+        match self.data.get_mut(key) {
+            Some(entry) => match &mut entry.value {
+                Value::Hash(hash) => {
+                    entry.touched_at = Instant::now();
+                    Ok(Some(hash))
+                }
+                _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn hash_entry(&mut self, key: Vec<u8>) -> Result<&mut Hash, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Hash(Hash::new())));
+        match &mut entry.value {
+            Value::Hash(hash) => {
+                entry.touched_at = Instant::now();
+                Ok(hash)
+            }
+            _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+        }
+    }
+
+    fn get_set(&self, key: &[u8]) -> Result<Option<&ScanSet>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_set_mut(&mut self, key: &[u8]) -> Result<Option<&mut ScanSet>, StoreError> {
+        match self.data.get_mut(key) {
+            Some(entry) => match &mut entry.value {
+                Value::Set(set) => {
+                    entry.touched_at = Instant::now();
+                    Ok(Some(set))
+                }
+                _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn set_entry(&mut self, key: Vec<u8>) -> Result<&mut ScanSet, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Set(ScanSet::new())));
+        match &mut entry.value {
+            Value::Set(set) => {
+                entry.touched_at = Instant::now();
+                Ok(set)
+            }
+            _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+        }
+    }
+
+    fn get_zset(&self, key: &[u8]) -> Result<Option<&SortedSet>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::ZSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_zset_mut(&mut self, key: &[u8]) -> Result<Option<&mut SortedSet>, StoreError> {
+        match self.data.get_mut(key) {
+            Some(entry) => match &mut entry.value {
+                Value::ZSet(zset) => {
+                    entry.touched_at = Instant::now();
+                    Ok(Some(zset))
+                }
+                _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn zset_entry(&mut self, key: Vec<u8>) -> Result<&mut SortedSet, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::ZSet(SortedSet::new())));
+        match &mut entry.value {
+            Value::ZSet(zset) => {
+                entry.touched_at = Instant::now();
+                Ok(zset)
+            }
+            _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+        }
+    }
+
+    fn get_stream(&self, key: &[u8]) -> Result<Option<&Stream>, StoreError> {
+        match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(StoreError::new(StoreErrorKind::WrongType)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_stream_mut(&mut self, key: &[u8]) -> Result<Option<&mut Stream>, StoreError> {
+        match self.data.get_mut(key) {
+            Some(entry) => match &mut entry.value {
+                Value::Stream(stream) => {
+                    entry.touched_at = Instant::now();
+                    Ok(Some(stream))
+                }
+                _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn stream_entry(&mut self, key: Vec<u8>) -> Result<&mut Stream, StoreError> {
+        let entry = self
+            .data
+            .entry(key)
+            .or_insert_with(|| Entry::new(Value::Stream(Stream::new())));
+        match &mut entry.value {
+            Value::Stream(stream) => {
+                entry.touched_at = Instant::now();
+                Ok(stream)
+            }
+            _ => Err(StoreError::new(StoreErrorKind::WrongType)),
+        }
+    }
+
+    /// Like [`Store::get_stream_mut`], but a missing key is reported as a missing group.
+    fn group_stream_mut(&mut self, key: &[u8], group: &[u8]) -> Result<&mut Stream, StoreError> {
+        self.get_stream_mut(key)?
+            .ok_or_else(|| stream::no_group(group))
+    }
+
+    /// Aggregate values are never left empty; removes `key` if its value was drained.
+    fn remove_if_empty(&mut self, key: &[u8]) {
+        let is_empty = match self.data.get(key).map(|entry| &entry.value) {
+            Some(Value::List(list)) => list.is_empty(),
+            Some(Value::Hash(hash)) => hash.is_empty(),
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::ZSet(zset)) => zset.is_empty(),
+            _ => false,
+        };
+        if is_empty {
+            self.data.remove(key);
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+        end: ListEnd,
+    ) -> Result<usize, StoreError> {
+        let list = self.list_entry(key)?;
+        for value in values {
+            match end {
+                ListEnd::Left => list.push_front(value),
+                ListEnd::Right => list.push_back(value),
+            }
+        }
+        Ok(list.len())
+    }
+
+    pub fn pop(
+        &mut self,
+        key: &[u8],
+        count: usize,
+        end: ListEnd,
+    ) -> Result<Option<Vec<Vec<u8>>>, StoreError> {
+        let popped = if let Some(list) = self.get_list_mut(key)? {
+            let count = count.min(list.len());
+            let popped = match end {
+                ListEnd::Left => list.drain(..count).collect(),
+                ListEnd::Right => list.drain(list.len() - count..).rev().collect(),
+            };
+            Some(popped)
+        } else {
+            None
+        };
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>, StoreError> {
+        if let Some(list) = self.get_list(key)? {
+            if let Some((start, stop)) = list_range(list.len(), start, stop) {
+                return Ok(list.range(start..=stop).cloned().collect());
+            }
+        }
+        Ok(vec![])
+    }
+
+    pub fn lindex(&self, key: &[u8], index: i64) -> Result<Option<&Vec<u8>>, StoreError> {
+        Ok(self
+            .get_list(key)?
+            .and_then(|list| list_index(list.len(), index).and_then(|i| list.get(i))))
+    }
+
+    pub fn lset(&mut self, key: &[u8], index: i64, value: Vec<u8>) -> Result<(), StoreError> {
+        let list = self
+            .get_list_mut(key)?
+            .ok_or_else(|| StoreError::new(StoreErrorKind::NoSuchKey))?;
+        let index = list_index(list.len(), index)
+            .ok_or_else(|| StoreError::new(StoreErrorKind::IndexOutOfRange))?;
+        list[index] = value;
+        Ok(())
+    }
+
+    /// Removes up to `count` occurrences of `value`, scanning from the head when `count` is
+    /// positive, from the tail when negative, and removing every occurrence when zero.
+    pub fn lrem(&mut self, key: &[u8], count: i64, value: &[u8]) -> Result<usize, StoreError> {
+        let removed = if let Some(list) = self.get_list_mut(key)? {
+            let limit = if count == 0 {
+                list.len()
+            } else {
+                count.unsigned_abs() as usize
+            };
+            let mut removed = 0;
+            if count < 0 {
+                let mut i = list.len();
+                while i > 0 && removed < limit {
+                    i -= 1;
+                    if list[i] == value {
+                        list.remove(i);
+                        removed += 1;
+                    }
+                }
+            } else {
+                let mut i = 0;
+                while i < list.len() && removed < limit {
+                    if list[i] == value {
+                        list.remove(i);
+                        removed += 1;
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            removed
+        } else {
+            0
+        };
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn ltrim(&mut self, key: &[u8], start: i64, stop: i64) -> Result<(), StoreError> {
+        if let Some(list) = self.get_list_mut(key)? {
+            if let Some((start, stop)) = list_range(list.len(), start, stop) {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            } else {
+                list.clear();
+            }
+        }
+        self.remove_if_empty(key);
+        Ok(())
+    }
+
+    pub fn llen(&self, key: &[u8]) -> Result<usize, StoreError> {
+        Ok(self.get_list(key)?.map(|list| list.len()).unwrap_or(0))
+    }
+
+    /// Inserts `value` next to the first occurrence of `pivot`. Returns the new length of the
+    /// list, `0` if the key does not exist, or `-1` if `pivot` was not found.
+    pub fn linsert(
+        &mut self,
+        key: &[u8],
+        before: bool,
+        pivot: &[u8],
+        value: Vec<u8>,
+    ) -> Result<i64, StoreError> {
+        if let Some(list) = self.get_list_mut(key)? {
+            if let Some(i) = list.iter().position(|item| item == pivot) {
+                let i = if before { i } else { i + 1 };
+                list.insert(i, value);
+                Ok(list.len() as i64)
+            } else {
+                Ok(-1)
+            }
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Sets each field/value pair, returning the number of fields that were newly added.
+    pub fn hset(
+        &mut self,
+        key: Vec<u8>,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<usize, StoreError> {
+        let hash = self.hash_entry(key)?;
+        let mut added = 0;
+        for (field, value) in pairs {
+            if hash.insert(field, value).is_none() {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn hsetnx(
+        &mut self,
+        key: Vec<u8>,
+        field: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<bool, StoreError> {
+        if let Some(hash) = self.get_hash(&key)? {
+            if hash.contains_key(&field) {
+                return Ok(false);
+            }
+        }
+        self.hash_entry(key)?.insert(field, value);
+        Ok(true)
+    }
+
+    pub fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<&Vec<u8>>, StoreError> {
+        Ok(self.get_hash(key)?.and_then(|hash| hash.get(field)))
+    }
+
+    pub fn hmget(
+        &self,
+        key: &[u8],
+        fields: &[Vec<u8>],
+    ) -> Result<Vec<Option<&Vec<u8>>>, StoreError> {
+        let hash = self.get_hash(key)?;
+        Ok(fields
+            .iter()
+            .map(|field| hash.and_then(|hash| hash.get(field)))
+            .collect())
+    }
+
+    pub fn hdel(&mut self, key: &[u8], fields: &[Vec<u8>]) -> Result<usize, StoreError> {
+        let removed = if let Some(hash) = self.get_hash_mut(key)? {
+            fields
+                .iter()
+                .filter(|field| hash.remove(field).is_some())
+                .count()
+        } else {
+            0
+        };
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn hgetall(&self, key: &[u8]) -> Result<Vec<HashPair<'_>>, StoreError> {
+        Ok(self
+            .get_hash(key)?
+            .map(|hash| hash.iter().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn hkeys(&self, key: &[u8]) -> Result<Vec<&Vec<u8>>, StoreError> {
+        Ok(self
+            .get_hash(key)?
+            .map(|hash| hash.keys().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn hvals(&self, key: &[u8]) -> Result<Vec<&Vec<u8>>, StoreError> {
+        Ok(self
+            .get_hash(key)?
+            .map(|hash| hash.values().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn hlen(&self, key: &[u8]) -> Result<usize, StoreError> {
+        Ok(self.get_hash(key)?.map(|hash| hash.len()).unwrap_or(0))
+    }
+
+    pub fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool, StoreError> {
+        Ok(self
+            .get_hash(key)?
+            .map(|hash| hash.contains_key(field))
+            .unwrap_or(false))
+    }
+
+    /// Adds `increment` to the integer stored at `field`, treating a missing field as `0`.
+    pub fn hincrby(
+        &mut self,
+        key: Vec<u8>,
+        field: Vec<u8>,
+        increment: i64,
+    ) -> Result<i64, StoreError> {
+        if let Some(hash) = self.get_hash(&key)? {
+            if let Some(value) = hash.get(&field) {
+                parse_int(value)
+                    .ok_or_else(|| StoreError::new(StoreErrorKind::HashValueNotInteger))?;
+            }
+        }
+        let hash = self.hash_entry(key)?;
+        let current = hash.get(&field).and_then(|value| parse_int(value));
+        let result = current
+            .unwrap_or(0)
+            .checked_add(increment)
+            .ok_or_else(|| StoreError::new(StoreErrorKind::Overflow))?;
+        hash.insert(field, result.to_string().into_bytes());
+        Ok(result)
+    }
+
+    pub fn sadd(&mut self, key: Vec<u8>, members: Vec<Vec<u8>>) -> Result<usize, StoreError> {
+        let set = self.set_entry(key)?;
+        Ok(members
+            .into_iter()
+            .filter(|member| set.insert(member.clone()))
+            .count())
+    }
+
+    pub fn srem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<usize, StoreError> {
+        let removed = if let Some(set) = self.get_set_mut(key)? {
+            members.iter().filter(|member| set.remove(member)).count()
+        } else {
+            0
+        };
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn smembers(&self, key: &[u8]) -> Result<Vec<&Vec<u8>>, StoreError> {
+        Ok(self
+            .get_set(key)?
+            .map(|set| set.iter().collect())
+            .unwrap_or_default())
+    }
+
+    pub fn sismember(&self, key: &[u8], member: &[u8]) -> Result<bool, StoreError> {
+        Ok(self
+            .get_set(key)?
+            .map(|set| set.contains(member))
+            .unwrap_or(false))
+    }
+
+    pub fn scard(&self, key: &[u8]) -> Result<usize, StoreError> {
+        Ok(self.get_set(key)?.map(|set| set.len()).unwrap_or(0))
+    }
+
+    /// Removes and returns up to `count` random members, or `None` if the key does not exist.
+    pub fn spop(&mut self, key: &[u8], count: usize) -> Result<Option<Vec<Vec<u8>>>, StoreError> {
+        let popped = if let Some(set) = self.get_set_mut(key)? {
+            let count = count.min(set.len());
+            let members: Vec<Vec<u8>> = set
+                .iter()
+                .cloned()
+                .choose_multiple(&mut rand::thread_rng(), count);
+            for member in members.iter() {
+                set.remove(member);
+            }
+            Some(members)
+        } else {
+            None
+        };
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Returns up to `count` distinct random members when `count` is positive, or `-count`
+    /// members that may repeat when it is negative, up to [`MAX_RANDOM_MEMBERS`].
+    pub fn srandmember(&self, key: &[u8], count: i64) -> Result<Vec<&Vec<u8>>, StoreError> {
+        let set = match self.get_set(key)? {
+            Some(set) => set,
+            None => return Ok(vec![]),
+        };
+        let mut rng = rand::thread_rng();
+        if count >= 0 {
+            let count = (count as u64).min(set.len() as u64) as usize;
+            Ok(set.iter().choose_multiple(&mut rng, count))
+        } else {
+            let members: Vec<&Vec<u8>> = set.iter().collect();
+            let count = count.unsigned_abs().min(MAX_RANDOM_MEMBERS as u64) as usize;
+            Ok((0..count)
+                .filter_map(|_| members.choose(&mut rng).cloned())
+                .collect())
+        }
+    }
+
+    /// Computes the intersection, union or difference of the sets at `keys`. Missing keys are
+    /// treated as empty sets.
+    pub fn set_op(&self, op: SetOp, keys: &[Vec<u8>]) -> Result<ScanSet, StoreError> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            sets.push(self.get_set(key)?);
+        }
+
+        let mut iter = sets.into_iter();
+        let mut result = iter.next().flatten().cloned().unwrap_or_default();
+        for set in iter {
+            match (op, set) {
+                (SetOp::Inter, Some(set)) => result.retain(|member| set.contains(member)),
+                (SetOp::Inter, None) => result.clear(),
+                (SetOp::Union, Some(set)) => result.extend(set.iter().cloned()),
+                (SetOp::Diff, Some(set)) => result.retain(|member| !set.contains(member)),
+                (_, None) => continue,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Stores the result of [`Store::set_op`] at `dest`, replacing any existing value, and
+    /// returns its cardinality.
+    pub fn set_op_store(
+        &mut self,
+        op: SetOp,
+        dest: Vec<u8>,
+        keys: &[Vec<u8>],
+    ) -> Result<usize, StoreError> {
+        let result = self.set_op(op, keys)?;
+        let len = result.len();
+        if result.is_empty() {
+            self.data.remove(&dest);
+        } else {
+            self.data.insert(dest, Entry::new(Value::Set(result)));
+        }
+        Ok(len)
+    }
+
+    pub fn zadd(
+        &mut self,
+        key: Vec<u8>,
+        members: Vec<(f64, Vec<u8>)>,
+        opts: &ZAddOptions,
+    ) -> Result<Vec<AddOutcome>, StoreError> {
+        if opts.xx && self.get_zset(&key)?.is_none() {
+            return Ok(members.iter().map(|_| AddOutcome::Skipped).collect());
+        }
+        let zset = self.zset_entry(key.clone())?;
+        let mut outcomes = Vec::with_capacity(members.len());
+        let mut result = Ok(());
+        for (score, member) in members {
+            match zset.add(member, score, opts) {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.remove_if_empty(&key);
+        result.map(|_| outcomes)
+    }
+
+    pub fn zincrby(
+        &mut self,
+        key: Vec<u8>,
+        increment: f64,
+        member: Vec<u8>,
+    ) -> Result<f64, StoreError> {
+        let opts = ZAddOptions {
+            incr: true,
+            ..ZAddOptions::default()
+        };
+        match self.zadd(key, vec![(increment, member)], &opts)?.first() {
+            Some(AddOutcome::Added(score))
+            | Some(AddOutcome::Updated(score))
+            | Some(AddOutcome::Unchanged(score)) => Ok(*score),
+            _ => Err(StoreError::new(StoreErrorKind::Unknown)),
+        }
+    }
+
+    pub fn zrem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<usize, StoreError> {
+        let removed = if let Some(zset) = self.get_zset_mut(key)? {
+            members.iter().filter(|member| zset.remove(member)).count()
+        } else {
+            0
+        };
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn zscore(&self, key: &[u8], member: &[u8]) -> Result<Option<f64>, StoreError> {
+        Ok(self.get_zset(key)?.and_then(|zset| zset.score(member)))
+    }
+
+    pub fn zrank(&self, key: &[u8], member: &[u8], rev: bool) -> Result<Option<usize>, StoreError> {
+        Ok(self.get_zset(key)?.and_then(|zset| zset.rank(member, rev)))
+    }
+
+    pub fn zrange(
+        &self,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+        rev: bool,
+    ) -> Result<Vec<(&Vec<u8>, f64)>, StoreError> {
+        Ok(self
+            .get_zset(key)?
+            .map(|zset| zset.range_by_rank(start, stop, rev))
+            .unwrap_or_default())
+    }
+
+    pub fn zrange_by_score(
+        &self,
+        key: &[u8],
+        min: &ScoreBound,
+        max: &ScoreBound,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+    ) -> Result<Vec<(&Vec<u8>, f64)>, StoreError> {
+        Ok(self
+            .get_zset(key)?
+            .map(|zset| zset.range_by_score(min, max, rev, limit))
+            .unwrap_or_default())
+    }
+
+    pub fn zrange_by_lex(
+        &self,
+        key: &[u8],
+        min: &LexBound,
+        max: &LexBound,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+    ) -> Result<Vec<(&Vec<u8>, f64)>, StoreError> {
+        Ok(self
+            .get_zset(key)?
+            .map(|zset| zset.range_by_lex(min, max, rev, limit))
+            .unwrap_or_default())
+    }
+
+    /// Removes and returns up to `count` members with the lowest (or highest if `max`) scores.
+    pub fn zpop(
+        &mut self,
+        key: &[u8],
+        count: usize,
+        max: bool,
+    ) -> Result<Vec<(Vec<u8>, f64)>, StoreError> {
+        let popped = self
+            .get_zset_mut(key)?
+            .map(|zset| zset.pop(count, max))
+            .unwrap_or_default();
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    /// Appends an entry, creating the stream unless `no_mk_stream`, then applies `trim`.
+    /// Returns the new entry's ID, or `None` if the stream did not exist and was not created.
+    pub fn xadd(
+        &mut self,
+        key: Vec<u8>,
+        id: XAddId,
+        fields: Fields,
+        no_mk_stream: bool,
+        trim: Option<(TrimStrategy, Option<usize>)>,
+    ) -> Result<Option<StreamId>, StoreError> {
+        let exists = self.get_stream(&key)?.is_some();
+        if no_mk_stream && !exists {
+            return Ok(None);
+        }
+        let stream = self.stream_entry(key.clone())?;
+        let id = match stream.add(id, fields, clock::now_ms()) {
+            Ok(id) => id,
+            Err(e) => {
+                if !exists {
+                    self.data.remove(&key);
+                }
+                return Err(e);
+            }
+        };
+        if let Some((strategy, limit)) = trim {
+            stream.trim(&strategy, limit);
+        }
+        Ok(Some(id))
+    }
+
+    pub fn xrange(
+        &self,
+        key: &[u8],
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Result<Vec<EntryRef<'_>>, StoreError> {
+        Ok(self
+            .get_stream(key)?
+            .map(|stream| stream.range(start, end, count, rev))
+            .unwrap_or_default())
+    }
+
+    pub fn xlen(&self, key: &[u8]) -> Result<usize, StoreError> {
+        Ok(self
+            .get_stream(key)?
+            .map(|stream| stream.len())
+            .unwrap_or(0))
+    }
+
+    pub fn xtrim(
+        &mut self,
+        key: &[u8],
+        strategy: &TrimStrategy,
+        limit: Option<usize>,
+    ) -> Result<usize, StoreError> {
+        Ok(self
+            .get_stream_mut(key)?
+            .map(|stream| stream.trim(strategy, limit))
+            .unwrap_or(0))
+    }
+
+    /// The ID of the last entry ever added to the stream at `key`, or `0-0` if it is missing.
+    pub fn stream_last_id(&self, key: &[u8]) -> Result<StreamId, StoreError> {
+        Ok(self
+            .get_stream(key)?
+            .map(|stream| stream.last_id())
+            .unwrap_or_default())
+    }
+
+    /// Returns the entries after each given ID, omitting streams with nothing new.
+    pub fn xread<'a>(
+        &'a self,
+        streams: &'a [(Vec<u8>, StreamId)],
+        count: Option<usize>,
+    ) -> Result<Vec<StreamRead<'a>>, StoreError> {
+        let mut result = vec![];
+        for (key, after) in streams {
+            let stream = match self.get_stream(key)? {
+                Some(stream) => stream,
+                None => continue,
+            };
+            let entries = match after.next() {
+                Some(start) => stream.range(start, StreamId::MAX, count, false),
+                None => continue,
+            };
+            if !entries.is_empty() {
+                result.push((key, entries));
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn xgroup_create(
+        &mut self,
+        key: Vec<u8>,
+        group: Vec<u8>,
+        id: Option<StreamId>,
+        mk_stream: bool,
+    ) -> Result<(), StoreError> {
+        if !mk_stream && self.get_stream(&key)?.is_none() {
+            return Err(StoreError::new(StoreErrorKind::NoStreamForGroup));
+        }
+        self.stream_entry(key)?.create_group(group, id)
+    }
+
+    pub fn xgroup_setid(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        id: Option<StreamId>,
+    ) -> Result<(), StoreError> {
+        self.get_stream_mut(key)?
+            .ok_or_else(|| StoreError::new(StoreErrorKind::NoStreamForGroup))?
+            .set_group_id(group, id)
+    }
+
+    pub fn xgroup_destroy(&mut self, key: &[u8], group: &[u8]) -> Result<bool, StoreError> {
+        Ok(self
+            .get_stream_mut(key)?
+            .ok_or_else(|| StoreError::new(StoreErrorKind::NoStreamForGroup))?
+            .destroy_group(group))
+    }
+
+    pub fn xgroup_createconsumer(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+    ) -> Result<bool, StoreError> {
+        self.group_stream_mut(key, group)?
+            .create_consumer(group, consumer, clock::now_ms())
+    }
+
+    pub fn xgroup_delconsumer(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+    ) -> Result<usize, StoreError> {
+        self.group_stream_mut(key, group)?
+            .delete_consumer(group, consumer)
+    }
+
+    /// Reads from each stream on behalf of `consumer`. Streams read with
+    /// [`GroupReadFrom::New`] are omitted when they have nothing new.
+    pub fn xreadgroup(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        streams: &[(Vec<u8>, GroupReadFrom)],
+        count: Option<usize>,
+        no_ack: bool,
+    ) -> Result<Vec<GroupRead>, StoreError> {
+        let now = clock::now_ms();
+        let mut result = vec![];
+        for (key, from) in streams {
+            let entries = self
+                .group_stream_mut(key, group)?
+                .read_group(group, consumer, *from, count, no_ack, now)?;
+            if *from != GroupReadFrom::New || !entries.is_empty() {
+                result.push((key.clone(), entries));
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn xack(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        ids: &[StreamId],
+    ) -> Result<usize, StoreError> {
+        match self.get_stream_mut(key)? {
+            Some(stream) => stream.ack(group, ids),
+            None => Ok(0),
+        }
+    }
+
+    pub fn xpending_summary(
+        &self,
+        key: &[u8],
+        group: &[u8],
+    ) -> Result<PendingSummary<'_>, StoreError> {
+        self.get_stream(key)?
+            .ok_or_else(|| stream::no_group(group))?
+            .pending_summary(group)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn xpending_range(
+        &self,
+        key: &[u8],
+        group: &[u8],
+        start: StreamId,
+        end: StreamId,
+        count: usize,
+        consumer: Option<&[u8]>,
+        min_idle: Option<u64>,
+    ) -> Result<Vec<PendingInfo<'_>>, StoreError> {
+        self.get_stream(key)?
+            .ok_or_else(|| stream::no_group(group))?
+            .pending_range(
+                group,
+                start,
+                end,
+                count,
+                consumer,
+                min_idle,
+                clock::now_ms(),
+            )
+    }
+
+    pub fn xclaim(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        ids: &[StreamId],
+        opts: &ClaimOptions,
+    ) -> Result<Vec<(StreamId, Fields)>, StoreError> {
+        self.group_stream_mut(key, group)?.claim(
+            group,
+            consumer,
+            min_idle,
+            ids,
+            opts,
+            clock::now_ms(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn xautoclaim(
+        &mut self,
+        key: &[u8],
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    ) -> Result<AutoClaim, StoreError> {
+        self.group_stream_mut(key, group)?.auto_claim(
+            group,
+            consumer,
+            min_idle,
+            start,
+            count,
+            just_id,
+            clock::now_ms(),
+        )
+    }
+}
+
+/// Stores `bytes` as [`Value::Int`] when it is a canonical integer, otherwise as
+/// [`Value::Str`].
+pub fn string_value(bytes: Vec<u8>) -> Value {
+    match parse_int(&bytes) {
+        Some(int) => Value::Int(int),
+        None => Value::Str(bytes),
+    }
+}
+
+/// Parses `bytes` as an integer only if it is in canonical form, so that storing it as
+/// [`Value::Int`] round-trips to the exact same bytes.
+fn parse_int(bytes: &[u8]) -> Option<i64> {
+    let int = std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()?;
+    if int.to_string().as_bytes() == bytes {
+        Some(int)
+    } else {
+        None
+    }
+}
+
+/// Resolves a possibly negative `index` against a list of length `len`.
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let len = len as i64;
+    let index = if index < 0 { len + index } else { index };
+    if index < 0 || index >= len {
+        None
+    } else {
+        Some(index as usize)
+    }
+}
+
+/// Resolves an inclusive, possibly negative `start..=stop` range against a list of length
+/// `len`, clamping to its bounds. Returns `None` if the range is empty.
+fn list_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Estimates the memory taken by an entry stored at `key`, including its slot in the
+/// keyspace table.
+fn entry_size(key: &[u8], value: &Value, samples: usize) -> usize {
+    memory::index_map_slot_size::<(Vec<u8>, Entry)>()
+        + memory::alloc_size(key.len())
+        + memory::value_size(value, samples)
+}
+
+pub enum TTL {
+    NoExpiration,
+    KeyNotFound,
+    /// The milliseconds left until the key expires.
+    Expires(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreError {
+    kind: StoreErrorKind,
+    command: Option<Box<Command>>,
+    other_context: Option<String>,
+}
+
+impl StoreError {
+    pub fn new(kind: StoreErrorKind) -> StoreError {
+        StoreError {
+            kind,
+            command: None,
+            other_context: None,
+        }
+    }
+
+    pub fn new_with_context(kind: StoreErrorKind, other_context: String) -> StoreError {
+        StoreError {
+            kind,
+            command: None,
+            other_context: Some(other_context),
+        }
+    }
+
+    pub fn kind(&self) -> &StoreErrorKind {
+        &self.kind
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_deref()
+    }
+
+    fn context(&self) -> &str {
+        self.other_context.as_deref().unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreErrorKind {
+    Unknown,
+    WrongType,
+    NoSuchKey,
+    IndexOutOfRange,
+    HashValueNotInteger,
+    Overflow,
+    NotANumber,
+    NotInteger,
+    NotFloat,
+    NanOrInfinity,
+    StringTooLong,
+    StreamIdTooSmall,
+    StreamIdZero,
+    BusyGroup,
+    NoGroup,
+    NoStreamForGroup,
+    DbIndexOutOfRange,
+    SameObject,
+    OutOfMemory,
+    BusyKey,
+    Leased,
+    NoSuchLease,
+    Locked,
+    ClusterDisabled,
+    SelectInCluster,
+    SlotBusy,
+    SlotRepeated,
+    NotSlotOwner,
+    AlreadySlotOwner,
+    MigrateToSelf,
+    ImportFromSelf,
+    SlotNotEmpty,
+    UnknownNode,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::StoreErrorKind::*;
+
+        match &self.kind {
+            Unknown => "ERR unknown error".fmt(f),
+            WrongType => "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f),
+            NoSuchKey => "ERR no such key".fmt(f),
+            IndexOutOfRange => "ERR index out of range".fmt(f),
+            HashValueNotInteger => "ERR hash value is not an integer".fmt(f),
+            Overflow => "ERR increment or decrement would overflow".fmt(f),
+            NotANumber => "ERR resulting score is not a number (NaN)".fmt(f),
+            NotInteger => "ERR value is not an integer or out of range".fmt(f),
+            NotFloat => "ERR value is not a valid float".fmt(f),
+            NanOrInfinity => "ERR increment would produce NaN or Infinity".fmt(f),
+            StringTooLong => "ERR string exceeds maximum allowed size (proto-max-bulk-len)".fmt(f),
+            StreamIdTooSmall => {
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .fmt(f)
+            }
+            StreamIdZero => "ERR The ID specified in XADD must be greater than 0-0".fmt(f),
+            BusyGroup => "BUSYGROUP Consumer Group name already exists".fmt(f),
+            NoGroup => write!(
+                f,
+                "NOGROUP No such key or consumer group '{}'",
+                self.other_context.as_deref().unwrap_or("")
+            ),
+            DbIndexOutOfRange => "ERR DB index is out of range".fmt(f),
+            SameObject => "ERR source and destination objects are the same".fmt(f),
+            OutOfMemory => "OOM command not allowed when used memory > 'maxmemory'.".fmt(f),
+            BusyKey => "BUSYKEY Target key name already exists.".fmt(f),
+            Leased => "LEASED The key is leased and the command does not carry the lease's token"
+                .fmt(f),
+            NoSuchLease => "ERR The lease expired or was released".fmt(f),
+            Locked => "LOCKED The key is locked by a transaction started with BEGIN".fmt(f),
+            ClusterDisabled => "ERR This instance has cluster support disabled".fmt(f),
+            SelectInCluster => "ERR SELECT is not allowed in cluster mode".fmt(f),
+            SlotBusy => write!(f, "ERR Slot {} is already busy", self.context()),
+            SlotRepeated => write!(f, "ERR Slot {} specified multiple times", self.context()),
+            NotSlotOwner => write!(f, "ERR I'm not the owner of hash slot {}", self.context()),
+            AlreadySlotOwner => {
+                write!(f, "ERR I'm already the owner of hash slot {}", self.context())
+            }
+            MigrateToSelf => write!(f, "ERR Can't migrate hash slot {} to myself", self.context()),
+            ImportFromSelf => {
+                write!(f, "ERR Can't import hash slot {} from myself", self.context())
+            }
+            SlotNotEmpty => write!(
+                f,
+                "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                self.context()
+            ),
+            UnknownNode => write!(f, "ERR I don't know about node {}", self.context()),
+            NoStreamForGroup => "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_set_get() {
+        let mut store = Db::new(0);
+        store.set(b"foo".to_vec(), b"bar".to_vec(), false);
+        store.set(b"a_num".to_vec(), b"42".to_vec(), false);
+        assert_eq!(Some(&Value::Str(b"bar".to_vec())), store.get(b"foo"));
+        assert_eq!(Some(&Value::Int(42)), store.get(b"a_num"));
+        assert_eq!(None, store.get(b"not_here"));
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let mut store = Db::new(0);
+        let key = vec![0, 159, 146, 150];
+        let blob = vec![b'\r', b'\n', 0, 255, 254];
+        store.set(key.clone(), blob.clone(), false);
+        assert_eq!(Some(&Value::Str(blob)), store.get(&key));
+
+        store.set(b"padded".to_vec(), b"007".to_vec(), false);
+        store.set(b"plus".to_vec(), b"+7".to_vec(), false);
+        assert_eq!(Some(&Value::Str(b"007".to_vec())), store.get(b"padded"));
+        assert_eq!(Some(&Value::Str(b"+7".to_vec())), store.get(b"plus"));
+    }
+
+    #[test]
+    fn test_incr_by() {
+        let mut store = Db::new(0);
+        assert_eq!(Ok(1), store.incr_by(b"n".to_vec(), 1));
+        assert_eq!(Ok(-4), store.incr_by(b"n".to_vec(), -5));
+        store.set(b"max".to_vec(), b"9223372036854775807".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::Overflow)),
+            store.incr_by(b"max".to_vec(), 1)
+        );
+        store.set(b"s".to_vec(), b"abc".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NotInteger)),
+            store.incr_by(b"s".to_vec(), 1)
+        );
+        store
+            .push(b"list".to_vec(), strings(&["a"]), ListEnd::Left)
+            .unwrap();
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.incr_by(b"list".to_vec(), 1)
+        );
+    }
+
+    #[test]
+    fn test_incr_by_float() {
+        let mut store = Db::new(0);
+        assert_eq!(
+            Ok(b"10.5".to_vec()),
+            store.incr_by_float(b"f".to_vec(), 10.5)
+        );
+        assert_eq!(Ok(b"3".to_vec()), store.incr_by_float(b"f".to_vec(), -7.5));
+        assert_eq!(Some(&Value::Int(3)), store.get(b"f"));
+        assert_eq!(Ok(4), store.incr_by(b"f".to_vec(), 1));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NanOrInfinity)),
+            store.incr_by_float(b"f".to_vec(), f64::INFINITY)
+        );
+        store.set(b"s".to_vec(), b"abc".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NotFloat)),
+            store.incr_by_float(b"s".to_vec(), 1.0)
+        );
+    }
+
+    #[test]
+    fn test_append_strlen() {
+        let mut store = Db::new(0);
+        assert_eq!(Ok(0), store.strlen(b"s"));
+        assert_eq!(Ok(5), store.append(b"s".to_vec(), b"hello"));
+        assert_eq!(Ok(11), store.append(b"s".to_vec(), b" world"));
+        assert_eq!(Ok(Some(b"hello world".to_vec())), store.get_string(b"s"));
+        store.set(b"n".to_vec(), b"12".to_vec(), false);
+        assert_eq!(Ok(3), store.append(b"n".to_vec(), b"3"));
+        assert_eq!(Some(&Value::Int(123)), store.get(b"n"));
+        assert_eq!(Ok(3), store.strlen(b"n"));
+    }
+
+    #[test]
+    fn test_getrange() {
+        let mut store = Db::new(0);
+        store.set(b"s".to_vec(), b"This is a string".to_vec(), false);
+        assert_eq!(Ok(b"This".to_vec()), store.getrange(b"s", 0, 3));
+        assert_eq!(Ok(b"ing".to_vec()), store.getrange(b"s", -3, -1));
+        assert_eq!(
+            Ok(b"This is a string".to_vec()),
+            store.getrange(b"s", 0, -1)
+        );
+        assert_eq!(Ok(b"string".to_vec()), store.getrange(b"s", 10, 100));
+        assert_eq!(Ok(vec![]), store.getrange(b"s", 5, 3));
+        assert_eq!(Ok(vec![]), store.getrange(b"missing", 0, -1));
+    }
+
+    #[test]
+    fn test_setrange() {
+        let mut store = Db::new(0);
+        store.set(b"s".to_vec(), b"Hello World".to_vec(), false);
+        assert_eq!(Ok(11), store.setrange(b"s".to_vec(), 6, b"Redis"));
+        assert_eq!(Ok(Some(b"Hello Redis".to_vec())), store.get_string(b"s"));
+        assert_eq!(Ok(8), store.setrange(b"pad".to_vec(), 3, b"abcde"));
+        assert_eq!(Ok(Some(b"\0\0\0abcde".to_vec())), store.get_string(b"pad"));
+        assert_eq!(Ok(0), store.setrange(b"empty".to_vec(), 3, b""));
+        assert_eq!(None, store.get(b"empty"));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::StringTooLong)),
+            store.setrange(b"s".to_vec(), MAX_STRING_LEN, b"x")
+        );
+    }
+
+    #[test]
+    fn test_getset_getdel() {
+        let mut store = Db::new(0);
+        assert_eq!(Ok(None), store.getset(b"s".to_vec(), b"a".to_vec()));
+        assert_eq!(
+            Ok(Some(b"a".to_vec())),
+            store.getset(b"s".to_vec(), b"b".to_vec())
+        );
+        assert_eq!(Ok(Some(b"b".to_vec())), store.getdel(b"s"));
+        assert_eq!(None, store.get(b"s"));
+        assert_eq!(Ok(None), store.getdel(b"s"));
+        store
+            .push(b"list".to_vec(), strings(&["a"]), ListEnd::Left)
+            .unwrap();
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.getdel(b"list")
+        );
+    }
+
+    #[test]
+    fn test_mset_mget() {
+        let mut store = Db::new(0);
+        store.mset(pairs(&[("a", "1"), ("b", "x")]));
+        store
+            .push(b"list".to_vec(), strings(&["a"]), ListEnd::Left)
+            .unwrap();
+        assert_eq!(
+            vec![Some(b"1".to_vec()), Some(b"x".to_vec()), None, None],
+            store.mget(&strings(&["a", "b", "list", "missing"]))
+        );
+        assert!(!store.msetnx(pairs(&[("c", "1"), ("a", "2")])));
+        assert_eq!(None, store.get(b"c"));
+        assert!(store.msetnx(pairs(&[("c", "1"), ("d", "2")])));
+        assert_eq!(vec![Some(b"2".to_vec())], store.mget(&strings(&["d"])));
+    }
+
+    #[test]
+    fn test_keyspace() {
+        let mut store = Db::new(0);
+        store.mset(pairs(&[("a", "1"), ("b", "2")]));
+        store
+            .push(b"list".to_vec(), strings(&["x"]), ListEnd::Left)
+            .unwrap();
+        assert_eq!(3, store.dbsize());
+        assert_eq!(3, store.exists(&strings(&["a", "a", "list", "missing"])));
+        assert_eq!("string", store.key_type(b"a"));
+        assert_eq!("list", store.key_type(b"list"));
+        assert_eq!("none", store.key_type(b"missing"));
+        assert_eq!(1, store.del(&strings(&["a", "missing"])));
+        assert!(store.random_key().is_some());
+        assert_eq!(2, store.dbsize());
+    }
+
+    #[test]
+    fn test_rename_copy() {
+        let mut store = Db::new(0);
+        store.mset(pairs(&[("a", "1"), ("b", "2")]));
+        assert_eq!(Ok(false), store.rename(b"a", b"b".to_vec(), true));
+        assert_eq!(Ok(true), store.rename(b"a", b"c".to_vec(), false));
+        assert_eq!(Ok(true), store.rename(b"c", b"c".to_vec(), false));
+        assert_eq!(None, store.get(b"a"));
+        assert_eq!(Some(&Value::Int(1)), store.get(b"c"));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NoSuchKey)),
+            store.rename(b"a", b"d".to_vec(), false)
+        );
+
+        assert!(!store.copy(b"c", b"b".to_vec(), false));
+        assert!(store.copy(b"c", b"b".to_vec(), true));
+        assert!(store.copy(b"c", b"d".to_vec(), false));
+        assert!(!store.copy(b"missing", b"e".to_vec(), false));
+        assert_eq!(
+            vec![Some(b"1".to_vec()), Some(b"1".to_vec())],
+            store.mget(&strings(&["b", "d"]))
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut store = Db::new(0);
+        let list = Value::List(strings(&["a", "b"]).into());
+        assert_eq!(
+            Ok(()),
+            store.restore(
+                b"l".to_vec(),
+                list.clone(),
+                Some(AccessStats::with_idle(60_000)),
+                false
+            )
+        );
+        assert!(store.data.peek(b"l").unwrap().access.idle_ms() >= 60_000);
+        assert_eq!(Some(&list), store.get(b"l"));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::BusyKey)),
+            store.restore(b"l".to_vec(), Value::Int(1), None, false)
+        );
+        store.expire(b"l", Instant::now() + Duration::from_secs(10));
+        assert_eq!(
+            Ok(()),
+            store.restore(b"l".to_vec(), Value::Int(1), None, true)
+        );
+        assert_eq!(Some(&Value::Int(1)), store.get(b"l"));
+        assert_eq!(None, store.expires_at(b"l"));
+    }
+
+    #[test]
+    fn test_databases() {
+        let mut store = Store::with_databases(2);
+        assert_eq!(Ok(1), store.db_index(1));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::DbIndexOutOfRange)),
+            store.db_index(2)
+        );
+        assert!(store.db_index(-1).is_err());
+
+        store.db_mut(0).mset(pairs(&[("a", "1"), ("b", "2")]));
+        store.db_mut(1).mset(pairs(&[("b", "3")]));
+        assert_eq!(Ok(true), store.move_key(b"a", 0, 1));
+        assert_eq!(Ok(false), store.move_key(b"b", 0, 1));
+        assert_eq!(Ok(false), store.move_key(b"missing", 0, 1));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::SameObject)),
+            store.move_key(b"b", 0, 0)
+        );
+        assert_eq!(1, store.db(0).dbsize());
+        assert_eq!(2, store.db(1).dbsize());
+
+        store.swap_dbs(0, 1);
+        assert_eq!(2, store.db(0).dbsize());
+        assert_eq!(1, store.db(1).index());
+        assert_eq!(Some(&Value::Int(1)), store.db(0).get(b"a"));
+
+        assert_eq!(2, store.db_mut(0).flush().dbsize());
+        assert_eq!(0, store.db(0).dbsize());
+        assert_eq!(2, store.flush_all().len());
+        assert_eq!(0, store.db(1).dbsize());
+    }
+
+    #[test]
+    fn test_ttl_persist() {
+        let mut store = Db::new(0);
+        store.set(b"a".to_vec(), b"1".to_vec(), false);
+        assert!(matches!(store.ttl(b"a"), TTL::NoExpiration));
+        assert!(!store.persist(b"a"));
+
+        let deadline = Instant::now() + Duration::from_millis(1500);
+        store.expire(b"a", deadline);
+        match store.ttl(b"a") {
+            TTL::Expires(ttl) => assert!(ttl > 1000 && ttl <= 1500),
+            _ => panic!("expected a TTL"),
+        }
+        assert!(store.persist(b"a"));
+        assert!(matches!(store.ttl(b"a"), TTL::NoExpiration));
+        assert_eq!(0, store.data.deadlines.len());
+    }
+
+    #[test]
+    fn test_lazy_expiration() {
+        let mut store = Db::new(0);
+        let past = Instant::now() - Duration::from_secs(1);
+        store.mset(pairs(&[("a", "1"), ("b", "2")]));
+        assert_eq!(Some(()), store.expire(b"a", past));
+        assert_eq!(None, store.get(b"a"));
+        assert_eq!(1, store.exists(&strings(&["a", "b"])));
+        assert_eq!(vec![&b"b".to_vec()], store.keys(b"*"));
+        assert!(matches!(store.ttl(b"a"), TTL::KeyNotFound));
+        assert_eq!(None, store.expire(b"a", past));
+
+        store.set(b"a".to_vec(), b"3".to_vec(), true);
+        assert_eq!(None, store.expires_at(b"a"));
+        assert_eq!(0, store.data.deadlines.len());
+    }
+
+    #[test]
+    fn test_active_expire_cycle() {
+        let mut store = Store::with_databases(2);
+        let past = Instant::now() - Duration::from_secs(1);
+        let future = Instant::now() + Duration::from_secs(60);
+        for i in 0..50 {
+            let key = format!("key:{}", i).into_bytes();
+            store.db_mut(1).set(key.clone(), b"v".to_vec(), false);
+            store.db_mut(1).expire(&key, past);
+        }
+        store.db_mut(1).set(b"kept".to_vec(), b"v".to_vec(), false);
+        store
+            .db_mut(1)
+            .expire(b"kept", future + Duration::from_secs(60));
+        store.db_mut(1).expire(b"kept", future);
+        assert_eq!(51, store.db(1).dbsize());
+
+        while store.active_expire_cycle(Duration::from_millis(25)) {}
+        assert_eq!(1, store.db(1).dbsize());
+        assert_eq!(1, store.db(1).data.deadlines.len());
+        assert_eq!(Some(future), store.db(1).expires_at(b"kept"));
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let mut store = Db::new(0);
+        assert_eq!(0, store.mem_usage());
+        store.set(b"a".to_vec(), b"1234".to_vec(), false);
+        let string_size = store.mem_usage();
+        assert!(string_size > 0);
+
+        store
+            .push(b"list".to_vec(), strings(&["x", "y"]), ListEnd::Left)
+            .unwrap();
+        store.data.settle();
+        let list_size = store.mem_usage() - string_size;
+        store
+            .push(b"list".to_vec(), strings(&["z"; 10]), ListEnd::Left)
+            .unwrap();
+        store.data.settle();
+        assert!(store.mem_usage() - string_size > list_size);
+
+        store.del(&strings(&["list"]));
+        assert_eq!(string_size, store.mem_usage());
+        store.flush();
+        assert_eq!(0, store.mem_usage());
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut store = Store::with_databases(2);
+        let list: Vec<Vec<u8>> = (0..100).map(|i| vec![b'x'; i]).collect();
+        store
+            .db_mut(1)
+            .push(b"list".to_vec(), list, ListEnd::Right)
+            .unwrap();
+        store.settle_memory();
+        assert_eq!(None, store.db(1).memory_usage(b"missing", MEMORY_SAMPLES));
+        let exact = store.db(1).memory_usage(b"list", 0).unwrap();
+        assert!(exact > (0..100).sum());
+        assert_ne!(exact, store.db(1).memory_usage(b"list", 1).unwrap());
+
+        store.db_mut(1).set(b"a".to_vec(), b"1".to_vec(), false);
+        store
+            .db_mut(1)
+            .expire(b"a", Instant::now() + Duration::from_secs(60));
+        let stats = store.memory_stats();
+        assert_eq!(2, stats.keys);
+        assert_eq!(1, stats.dbs.len());
+        assert_eq!(1, stats.dbs[0].index);
+        assert!(stats.expires_overhead() > 0);
+        assert_eq!(store.db(1).mem_usage(), stats.dataset);
+        assert_eq!(store.mem_usage(), stats.total);
+        assert_eq!(stats.estimate(), stats.total);
+
+        // The keyspace table gives its memory back after mass deletes.
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("k{}", i).into_bytes()).collect();
+        for key in keys.iter() {
+            store.db_mut(0).set(key.clone(), b"v".to_vec(), false);
+        }
+        let full = store.db(0).data.table_overhead() + store.db(0).mem_usage();
+        store.db_mut(0).del(&keys[1..]);
+        assert!(store.db(0).data.table_overhead() < full / 10);
+    }
+
+    #[test]
+    fn test_background_snapshot_memory() {
+        let mut store = Store::with_databases(1);
+        for i in 0..10 {
+            let key = format!("k{}", i).into_bytes();
+            store.db_mut(0).set(key, vec![b'v'; 100], false);
+        }
+        store.settle_memory();
+        let before = store.mem_usage();
+        let dataset = store.db(0).mem_usage();
+        store.set_maxmemory(before + 1000, EvictionPolicy::AllKeysLru);
+
+        assert!(store.begin_save());
+        let snapshot = store.background_snapshot();
+        assert_eq!(10, snapshot.dbs[0].1.len());
+        assert_eq!(before, store.mem_usage());
+        assert_eq!(dataset, store.memory_stats().snapshot);
+
+        // A write during the save does not evict keys to pay for the copy.
+        assert_eq!(Ok(()), store.evict());
+        store.db_mut(0).set(b"new".to_vec(), vec![b'v'; 100], false);
+        store.settle_memory();
+        assert_eq!(Ok(()), store.evict());
+        assert_eq!(11, store.db(0).dbsize());
+
+        store.finish_save(0, true);
+        assert_eq!(0, store.memory_stats().snapshot);
+    }
 
     #[test]
-    fn test_set_get() {
+    fn test_snapshot() {
+        let mut store = Store::with_databases(4);
+        store.db_mut(0).set(b"a".to_vec(), b"1".to_vec(), false);
+        store.db_mut(2).set(b"b".to_vec(), b"2".to_vec(), false);
+        store
+            .db_mut(2)
+            .expire(b"b", Instant::now() + Duration::from_secs(60));
+        let changes = store.changes_since_save();
+        assert!(changes >= 3);
+
+        let snapshot = store.snapshot();
+        assert_eq!(2, snapshot.dbs.len());
+        assert!(store.begin_save());
+        assert!(!store.begin_save());
+        store.db_mut(0).del(&strings(&["a"]));
+        store.finish_save(changes, true);
+        assert!(store.changes_since_save() > 0);
+        assert!(store.changes_since_save() < changes);
+
+        let mut loaded = Store::with_databases(4);
+        loaded.db_mut(1).set(b"gone".to_vec(), b"x".to_vec(), false);
+        loaded.load(snapshot.clone()).unwrap();
+        assert_eq!(0, loaded.changes_since_save());
+        assert_eq!(None, loaded.db(1).get(b"gone"));
+        assert_eq!(Some(Value::Int(1)), loaded.db(0).get(b"a").cloned());
+        match loaded.db(2).ttl(b"b") {
+            TTL::Expires(ms) => assert!(ms > 58_000 && ms <= 60_000),
+            _ => panic!("expected a TTL"),
+        }
+
+        let mut expired = snapshot.clone();
+        expired.dbs[1].1[0].expires_at_ms = Some(0);
+        loaded.load(expired.clone()).unwrap();
+        assert_eq!(0, loaded.db(2).dbsize());
+
+        // While loading, a key past its deadline stays until loading ends.
+        loaded.set_loading(true);
+        loaded.load(expired).unwrap();
+        assert_eq!(1, loaded.db(2).exists(&[b"b".to_vec()]));
+        loaded.set_loading(false);
+        assert_eq!(0, loaded.db(2).exists(&[b"b".to_vec()]));
+        loaded.active_expire_cycle(Duration::from_millis(10));
+        assert_eq!(vec![(2, b"b".to_vec())], loaded.take_removed());
+        assert!(loaded.take_removed().is_empty());
+
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::DbIndexOutOfRange)),
+            Store::with_databases(2).load(snapshot)
+        );
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut store = Store::with_databases(2);
+        for i in 0..100 {
+            let key = format!("key:{}", i).into_bytes();
+            store.db_mut(i % 2).set(key, vec![b'v'; 1000], false);
+        }
+        let used = store.mem_usage();
+        store.set_maxmemory(used / 2, EvictionPolicy::NoEviction);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::OutOfMemory)),
+            store.evict()
+        );
+
+        store.set_maxmemory(used / 2, EvictionPolicy::AllKeysLru);
+        assert_eq!(Ok(()), store.evict());
+        assert!(store.mem_usage() <= used / 2);
+        let evicted = store.evicted_keys();
+        assert!(evicted >= 50);
+        assert_eq!(
+            100,
+            store.db(0).dbsize() + store.db(1).dbsize() + evicted as usize
+        );
+
+        // Volatile policies only evict keys with a TTL, soonest deadline first for
+        // volatile-ttl.
+        let later = Instant::now() + Duration::from_secs(60);
+        store.db_mut(0).set(b"soon".to_vec(), b"v".to_vec(), false);
+        store.db_mut(0).expire(b"soon", later);
+        store.db_mut(1).set(b"later".to_vec(), b"v".to_vec(), false);
+        store
+            .db_mut(1)
+            .expire(b"later", later + Duration::from_secs(60));
+        let used = store.mem_usage();
+        store.set_maxmemory(used - 1, EvictionPolicy::VolatileTtl);
+        assert_eq!(Ok(()), store.evict());
+        assert_eq!(None, store.db(0).get(b"soon"));
+        assert!(store.db(1).get(b"later").is_some());
+
+        store.set_maxmemory(1, EvictionPolicy::VolatileLru);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::OutOfMemory)),
+            store.evict()
+        );
+        assert_eq!(None, store.db(1).get(b"later"));
+    }
+
+    #[test]
+    fn test_eviction_sample() {
+        let mut db = Db::new(0);
+        for i in 0..10 {
+            db.set(format!("key:{}", i).into_bytes(), b"v".to_vec(), false);
+        }
+        let removed: Vec<Vec<u8>> = (0..10)
+            .step_by(2)
+            .map(|i| format!("key:{}", i).into_bytes())
+            .collect();
+        db.del(&removed);
+        let mut rng = rand::thread_rng();
+        let sample = db.data.sample(&mut rng, 50);
+        assert_eq!(50, sample.len());
+        assert!(sample
+            .iter()
+            .all(|key| db.data.contains_key(key) && !removed.contains(key)));
+        assert!(Db::new(1).data.sample(&mut rng, 5).is_empty());
+    }
+
+    #[test]
+    fn test_leases_reach_other_dbs() {
+        let command = |args: &[&str]| {
+            let array = args.iter().map(resp::bulk_string).collect();
+            Command::from_resp(resp::array(array)).unwrap()
+        };
+        let mut store = Store::with_databases(3);
+        let token = store.db_mut(1).lease(b"k".to_vec(), 60_000, None).unwrap();
+        let leased = Err(StoreError::new(StoreErrorKind::Leased));
+
+        assert_eq!(
+            leased,
+            store.check_write_leases(0, &command(&["MOVE", "k", "1"]), None)
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_leases(0, &command(&["MOVE", "k", "1"]), Some(token))
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_leases(0, &command(&["MOVE", "k", "2"]), None)
+        );
+        assert_eq!(
+            leased,
+            store.check_write_leases(1, &command(&["FLUSHDB"]), None)
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_leases(0, &command(&["FLUSHDB"]), None)
+        );
+        assert_eq!(
+            leased,
+            store.check_write_leases(0, &command(&["FLUSHALL"]), None)
+        );
+        assert_eq!(
+            leased,
+            store.check_write_leases(0, &command(&["SWAPDB", "2", "1"]), None)
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_leases(1, &command(&["SWAPDB", "0", "2"]), None)
+        );
+
+        store.db_mut(1).release_lease(b"k", token);
+        assert_eq!(
+            Ok(()),
+            store.check_write_leases(0, &command(&["FLUSHALL"]), None)
+        );
+    }
+
+    #[test]
+    fn test_locks_reach_whole_db() {
+        let command = |args: &[&str]| {
+            let array = args.iter().map(resp::bulk_string).collect();
+            Command::from_resp(resp::array(array)).unwrap()
+        };
+        let now = Instant::now();
+        let mut store = Store::with_databases(3);
+        store
+            .key_locks_mut()
+            .begin(1, now + Duration::from_secs(10));
+        store.key_locks_mut().lock(1, 1, &[b"k"], now);
+        let locked = Err(StoreError::new(StoreErrorKind::Locked));
+
+        assert_eq!(
+            locked,
+            store.check_write_locks(1, &command(&["SET", "k", "v"]))
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_locks(1, &command(&["SET", "x", "v"]))
+        );
+        assert_eq!(locked, store.check_write_locks(1, &command(&["FLUSHDB"])));
+        assert_eq!(Ok(()), store.check_write_locks(0, &command(&["FLUSHDB"])));
+        assert_eq!(locked, store.check_write_locks(0, &command(&["FLUSHALL"])));
+        assert_eq!(
+            locked,
+            store.check_write_locks(0, &command(&["SWAPDB", "2", "1"]))
+        );
+        assert_eq!(
+            Ok(()),
+            store.check_write_locks(0, &command(&["SWAPDB", "0", "2"]))
+        );
+        assert_eq!(
+            locked,
+            store.check_write_locks(0, &command(&["MOVE", "k", "1"]))
+        );
+
+        store.key_locks_mut().end(1);
+        assert_eq!(Ok(()), store.check_write_locks(0, &command(&["FLUSHALL"])));
+    }
+
+    #[test]
+    fn test_evict_skips_leased() {
         let mut store = Store::new();
-        store.set("foo".to_owned(), "bar".to_owned(), false);
-        store.set("a_num".to_owned(), "42".to_owned(), false);
+        store
+            .db_mut(0)
+            .set(b"leased".to_vec(), vec![b'v'; 1000], false);
+        store
+            .db_mut(0)
+            .set(b"free".to_vec(), vec![b'v'; 1000], false);
+        store
+            .db_mut(0)
+            .lease(b"leased".to_vec(), 60_000, None)
+            .unwrap();
+        store.set_maxmemory(store.mem_usage() - 1, EvictionPolicy::AllKeysRandom);
+        while store.db(0).get(b"free").is_some() {
+            let _ = store.evict();
+        }
+        assert!(store.db(0).get(b"leased").is_some());
+
+        store.set_maxmemory(1, EvictionPolicy::AllKeysLru);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::OutOfMemory)),
+            store.evict()
+        );
+        assert!(store.db(0).get(b"leased").is_some());
+    }
+
+    #[test]
+    fn test_keys_scan() {
+        let mut store = Db::new(0);
+        for i in 0..20 {
+            store.set(format!("user:{}", i).into_bytes(), b"v".to_vec(), false);
+        }
+        store
+            .push(b"user:list".to_vec(), strings(&["a"]), ListEnd::Left)
+            .unwrap();
+        assert_eq!(10, store.keys(b"user:1?").len());
+        assert_eq!(21, store.keys(b"*").len());
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, 3, Some(b"user:*"), Some("string"));
+            seen.extend(keys.into_iter().cloned());
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(20, seen.len());
+        assert!(!seen.contains(b"user:list".as_slice()));
+    }
+
+    #[test]
+    fn test_type_scans() {
+        let mut store = Db::new(0);
+        store
+            .sadd(b"s".to_vec(), strings(&["a", "b", "c"]))
+            .unwrap();
+        store
+            .hset(b"h".to_vec(), pairs(&[("a", "1"), ("b", "2")]))
+            .unwrap();
+        let (next, members) = store.sscan(b"s", 0, 10, Some(b"[ab]")).unwrap();
+        assert_eq!(0, next);
+        assert_eq!(
+            strings(&["a", "b"]),
+            sorted(members.into_iter().cloned().collect())
+        );
+        let (_, pairs) = store.hscan(b"h", 0, 10, Some(b"b")).unwrap();
+        assert_eq!(vec![(&b"b".to_vec(), &b"2".to_vec())], pairs);
+        assert_eq!(Ok((0, vec![])), store.zscan(b"missing", 0, 10, None));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.zscan(b"s", 0, 10, None)
+        );
+    }
+
+    #[test]
+    fn test_scan_follows_writes() {
+        let mut store = Db::new(0);
+        store.set(b"a".to_vec(), b"1".to_vec(), false);
+        store.sadd(b"s".to_vec(), strings(&["x", "y"])).unwrap();
+        store.rename(b"a", b"b".to_vec(), false).unwrap();
+        store.srem(b"s", &strings(&["x"])).unwrap();
+        store.hset(b"h".to_vec(), pairs(&[("f", "v")])).unwrap();
+        store.hdel(b"h", &strings(&["f"])).unwrap();
+        store.data.settle();
+
+        let (next, keys) = store.scan(0, 10, None, None);
+        assert_eq!(0, next);
+        assert_eq!(
+            strings(&["b", "s"]),
+            sorted(keys.into_iter().cloned().collect())
+        );
+        assert_eq!(2, store.data.order.len());
+        let (_, members) = store.sscan(b"s", 0, 10, None).unwrap();
+        assert_eq!(vec![&b"y".to_vec()], members);
+
+        store.del(&strings(&["b", "s"]));
+        assert_eq!((0, vec![]), store.scan(0, 10, None, None));
+        assert_eq!(0, store.data.order.memory());
+    }
+
+    #[test]
+    fn test_scan_after_swap_removes() {
+        let mut store = Db::new(0);
+        let keys: Vec<Vec<u8>> = (0..30).map(|i| format!("k{}", i).into_bytes()).collect();
+        for key in keys.iter() {
+            store.set(key.clone(), b"v".to_vec(), false);
+        }
+        let removed: Vec<Vec<u8>> = keys.iter().step_by(4).cloned().collect();
+        store.del(&removed);
+        store.data.settle();
+
+        let (next, found) = store.scan(0, 100, None, None);
+        assert_eq!(0, next);
+        let expected: Vec<Vec<u8>> = keys
+            .into_iter()
+            .filter(|key| !removed.contains(key))
+            .collect();
+        assert_eq!(
+            sorted(expected),
+            sorted(found.into_iter().cloned().collect())
+        );
+    }
+
+    #[test]
+    fn test_keys_in_slot() {
+        let mut db = Db::new(0);
+        let keys = strings(&["{a}1", "{a}2", "{b}1", "c"]);
+        for key in keys.iter() {
+            db.set(key.clone(), b"v".to_vec(), false);
+        }
+        // The last slot ends at the top of the cursor space.
+        let last = (0..)
+            .map(|i| format!("k{}", i).into_bytes())
+            .find(|key| cluster::key_slot(key) as usize == cluster::SLOTS - 1)
+            .unwrap();
+        db.set(last.clone(), b"v".to_vec(), false);
+
+        let slot = cluster::key_slot(b"a");
+        assert_eq!(
+            strings(&["{a}1", "{a}2"]),
+            sorted(db.keys_in_slot(slot).cloned().collect())
+        );
+        assert_eq!(
+            vec![&last],
+            db.keys_in_slot(cluster::SLOTS as u16 - 1)
+                .collect::<Vec<_>>()
+        );
+        db.expire(b"{a}2", Instant::now() - Duration::from_millis(1));
+        db.del(&strings(&["{a}1"]));
+        assert_eq!(0, db.keys_in_slot(slot).count());
+        assert_eq!(1, db.keys_in_slot(cluster::key_slot(b"b")).count());
+
+        let (next, page) = db.scan(0, 10, None, None);
+        assert_eq!(0, next);
+        assert_eq!(3, page.len());
+    }
+
+    fn strings(values: &[&str]) -> Vec<Vec<u8>> {
+        values.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut store = Db::new(0);
+        assert_eq!(
+            Ok(2),
+            store.push(b"list".to_vec(), strings(&["b", "a"]), ListEnd::Left)
+        );
+        assert_eq!(
+            Ok(4),
+            store.push(b"list".to_vec(), strings(&["c", "d"]), ListEnd::Right)
+        );
+        assert_eq!(
+            Ok(strings(&["a", "b", "c", "d"])),
+            store.lrange(b"list", 0, -1)
+        );
+        assert_eq!(
+            Ok(Some(strings(&["a"]))),
+            store.pop(b"list", 1, ListEnd::Left)
+        );
+        assert_eq!(
+            Ok(Some(strings(&["d", "c"]))),
+            store.pop(b"list", 2, ListEnd::Right)
+        );
+        assert_eq!(
+            Ok(Some(strings(&["b"]))),
+            store.pop(b"list", 5, ListEnd::Right)
+        );
+        assert_eq!(None, store.get(b"list"));
+        assert_eq!(Ok(None), store.pop(b"list", 1, ListEnd::Left));
+    }
+
+    #[test]
+    fn test_list_wrong_type() {
+        let mut store = Db::new(0);
+        store.set(b"foo".to_vec(), b"bar".to_vec(), false);
+        let wrong_type = Err(StoreError::new(StoreErrorKind::WrongType));
+        assert_eq!(
+            wrong_type,
+            store.push(b"foo".to_vec(), strings(&["a"]), ListEnd::Left)
+        );
+        assert_eq!(wrong_type, store.llen(b"foo"));
+    }
+
+    #[test]
+    fn test_lrange() {
+        let mut store = Db::new(0);
+        store
+            .push(
+                b"list".to_vec(),
+                strings(&["a", "b", "c", "d"]),
+                ListEnd::Right,
+            )
+            .unwrap();
+        assert_eq!(Ok(strings(&["b", "c"])), store.lrange(b"list", 1, 2));
+        assert_eq!(Ok(strings(&["c", "d"])), store.lrange(b"list", -2, 100));
+        assert_eq!(
+            Ok(strings(&["a", "b", "c", "d"])),
+            store.lrange(b"list", -100, 100)
+        );
+        assert_eq!(Ok(vec![]), store.lrange(b"list", 3, 1));
+        assert_eq!(Ok(vec![]), store.lrange(b"list", 5, 10));
+        assert_eq!(Ok(vec![]), store.lrange(b"missing", 0, -1));
+    }
+
+    #[test]
+    fn test_lindex_lset() {
+        let mut store = Db::new(0);
+        store
+            .push(b"list".to_vec(), strings(&["a", "b", "c"]), ListEnd::Right)
+            .unwrap();
+        assert_eq!(Ok(Some(&b"c".to_vec())), store.lindex(b"list", -1));
+        assert_eq!(Ok(None), store.lindex(b"list", 3));
+        assert_eq!(Ok(()), store.lset(b"list", -2, b"z".to_vec()));
+        assert_eq!(Ok(strings(&["a", "z", "c"])), store.lrange(b"list", 0, -1));
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::IndexOutOfRange)),
+            store.lset(b"list", 3, b"z".to_vec())
+        );
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NoSuchKey)),
+            store.lset(b"missing", 0, b"z".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_lrem() {
+        let mut store = Db::new(0);
+        store
+            .push(
+                b"list".to_vec(),
+                strings(&["a", "b", "a", "c", "a"]),
+                ListEnd::Right,
+            )
+            .unwrap();
+        assert_eq!(Ok(1), store.lrem(b"list", -1, b"a"));
+        assert_eq!(
+            Ok(strings(&["a", "b", "a", "c"])),
+            store.lrange(b"list", 0, -1)
+        );
+        assert_eq!(Ok(1), store.lrem(b"list", 1, b"a"));
+        assert_eq!(Ok(strings(&["b", "a", "c"])), store.lrange(b"list", 0, -1));
+        assert_eq!(Ok(1), store.lrem(b"list", 0, b"a"));
+        assert_eq!(Ok(0), store.lrem(b"list", 0, b"a"));
+        assert_eq!(Ok(strings(&["b", "c"])), store.lrange(b"list", 0, -1));
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let mut store = Db::new(0);
+        store
+            .push(
+                b"list".to_vec(),
+                strings(&["a", "b", "c", "d"]),
+                ListEnd::Right,
+            )
+            .unwrap();
+        assert_eq!(Ok(()), store.ltrim(b"list", 1, -2));
+        assert_eq!(Ok(strings(&["b", "c"])), store.lrange(b"list", 0, -1));
+        assert_eq!(Ok(()), store.ltrim(b"list", 5, 10));
+        assert_eq!(None, store.get(b"list"));
+    }
+
+    #[test]
+    fn test_linsert() {
+        let mut store = Db::new(0);
+        store
+            .push(b"list".to_vec(), strings(&["a", "c"]), ListEnd::Right)
+            .unwrap();
+        assert_eq!(Ok(3), store.linsert(b"list", true, b"c", b"b".to_vec()));
+        assert_eq!(Ok(4), store.linsert(b"list", false, b"c", b"d".to_vec()));
+        assert_eq!(Ok(-1), store.linsert(b"list", false, b"x", b"y".to_vec()));
+        assert_eq!(Ok(0), store.linsert(b"missing", false, b"x", b"y".to_vec()));
+        assert_eq!(
+            Ok(strings(&["a", "b", "c", "d"])),
+            store.lrange(b"list", 0, -1)
+        );
+        assert_eq!(Ok(4), store.llen(b"list"));
+    }
+
+    fn pairs(values: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        values
+            .iter()
+            .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_hset_hget() {
+        let mut store = Db::new(0);
+        assert_eq!(
+            Ok(2),
+            store.hset(b"h".to_vec(), pairs(&[("a", "1"), ("b", "2")]))
+        );
+        assert_eq!(
+            Ok(1),
+            store.hset(b"h".to_vec(), pairs(&[("a", "3"), ("c", "4")]))
+        );
+        assert_eq!(Ok(Some(&b"3".to_vec())), store.hget(b"h", b"a"));
+        assert_eq!(Ok(None), store.hget(b"h", b"z"));
+        assert_eq!(
+            Ok(vec![Some(&b"2".to_vec()), None]),
+            store.hmget(b"h", &strings(&["b", "z"]))
+        );
+        assert_eq!(Ok(3), store.hlen(b"h"));
+        assert_eq!(Ok(true), store.hexists(b"h", b"c"));
+        assert_eq!(Ok(vec![None]), store.hmget(b"missing", &strings(&["a"])));
+    }
+
+    #[test]
+    fn test_hsetnx_hdel() {
+        let mut store = Db::new(0);
+        assert_eq!(
+            Ok(true),
+            store.hsetnx(b"h".to_vec(), b"a".to_vec(), b"1".to_vec())
+        );
+        assert_eq!(
+            Ok(false),
+            store.hsetnx(b"h".to_vec(), b"a".to_vec(), b"2".to_vec())
+        );
+        assert_eq!(Ok(Some(&b"1".to_vec())), store.hget(b"h", b"a"));
+        assert_eq!(Ok(1), store.hdel(b"h", &strings(&["a", "b"])));
+        assert_eq!(None, store.get(b"h"));
+    }
+
+    #[test]
+    fn test_hincrby() {
+        let mut store = Db::new(0);
+        assert_eq!(Ok(5), store.hincrby(b"h".to_vec(), b"n".to_vec(), 5));
+        assert_eq!(Ok(2), store.hincrby(b"h".to_vec(), b"n".to_vec(), -3));
+        store
+            .hset(
+                b"h".to_vec(),
+                pairs(&[("s", "abc"), ("max", "9223372036854775807")]),
+            )
+            .unwrap();
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::HashValueNotInteger)),
+            store.hincrby(b"h".to_vec(), b"s".to_vec(), 1)
+        );
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::Overflow)),
+            store.hincrby(b"h".to_vec(), b"max".to_vec(), 1)
+        );
+        store.set(b"str".to_vec(), b"v".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.hincrby(b"str".to_vec(), b"n".to_vec(), 1)
+        );
+    }
+
+    fn sorted(mut values: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_sadd_srem() {
+        let mut store = Db::new(0);
+        assert_eq!(Ok(2), store.sadd(b"s".to_vec(), strings(&["a", "b", "a"])));
+        assert_eq!(Ok(1), store.sadd(b"s".to_vec(), strings(&["b", "c"])));
+        assert_eq!(Ok(3), store.scard(b"s"));
+        assert_eq!(Ok(true), store.sismember(b"s", b"c"));
+        assert_eq!(Ok(2), store.srem(b"s", &strings(&["a", "c", "z"])));
+        assert_eq!(Ok(vec![&b"b".to_vec()]), store.smembers(b"s"));
+        assert_eq!(Ok(1), store.srem(b"s", &strings(&["b"])));
+        assert_eq!(None, store.get(b"s"));
+    }
+
+    #[test]
+    fn test_spop_srandmember() {
+        let mut store = Db::new(0);
+        store
+            .sadd(b"s".to_vec(), strings(&["a", "b", "c"]))
+            .unwrap();
+        assert_eq!(2, store.srandmember(b"s", 2).unwrap().len());
+        assert_eq!(3, store.srandmember(b"s", 10).unwrap().len());
+        assert_eq!(5, store.srandmember(b"s", -5).unwrap().len());
+        let popped = store.spop(b"s", 2).unwrap().unwrap();
+        assert_eq!(2, popped.len());
+        assert_eq!(Ok(1), store.scard(b"s"));
+        assert_eq!(1, store.spop(b"s", 2).unwrap().unwrap().len());
+        assert_eq!(Ok(None), store.spop(b"s", 1));
+    }
+
+    #[test]
+    fn test_spop_srandmember_extreme_counts() {
+        let mut store = Db::new(0);
+        store
+            .sadd(b"s".to_vec(), strings(&["a", "b", "c"]))
+            .unwrap();
+        assert_eq!(3, store.srandmember(b"s", i64::MAX).unwrap().len());
+        assert_eq!(
+            MAX_RANDOM_MEMBERS,
+            store.srandmember(b"s", i64::MIN).unwrap().len()
+        );
+        assert_eq!(
+            3,
+            store.spop(b"s", i64::MAX as usize).unwrap().unwrap().len()
+        );
+        assert!(store.srandmember(b"s", i64::MIN).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_op() {
+        let mut store = Db::new(0);
+        store
+            .sadd(b"a".to_vec(), strings(&["1", "2", "3"]))
+            .unwrap();
+        store
+            .sadd(b"b".to_vec(), strings(&["2", "3", "4"]))
+            .unwrap();
+        let keys = strings(&["a", "b"]);
+        let inter = store.set_op(SetOp::Inter, &keys).unwrap();
+        assert_eq!(strings(&["2", "3"]), sorted(inter.into_iter().collect()));
+        let union = store.set_op(SetOp::Union, &keys).unwrap();
+        assert_eq!(
+            strings(&["1", "2", "3", "4"]),
+            sorted(union.into_iter().collect())
+        );
+        let diff = store.set_op(SetOp::Diff, &keys).unwrap();
+        assert_eq!(strings(&["1"]), sorted(diff.into_iter().collect()));
+        let with_missing = strings(&["a", "missing"]);
+        assert!(store
+            .set_op(SetOp::Inter, &with_missing)
+            .unwrap()
+            .is_empty());
+        assert_eq!(3, store.set_op(SetOp::Diff, &with_missing).unwrap().len());
+    }
+
+    #[test]
+    fn test_set_op_store() {
+        let mut store = Db::new(0);
+        store.sadd(b"a".to_vec(), strings(&["1", "2"])).unwrap();
+        store.sadd(b"b".to_vec(), strings(&["2"])).unwrap();
+        store.set(b"dest".to_vec(), b"v".to_vec(), false);
+        assert_eq!(
+            Ok(1),
+            store.set_op_store(SetOp::Diff, b"dest".to_vec(), &strings(&["a", "b"]))
+        );
+        assert_eq!(Ok(vec![&b"1".to_vec()]), store.smembers(b"dest"));
+        assert_eq!(
+            Ok(0),
+            store.set_op_store(SetOp::Inter, b"dest".to_vec(), &strings(&["a", "x"]))
+        );
+        assert_eq!(None, store.get(b"dest"));
+        store.set(b"str".to_vec(), b"v".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.set_op(SetOp::Union, &strings(&["a", "str"]))
+        );
+    }
+
+    #[test]
+    fn test_zadd_zrange() {
+        let mut store = Db::new(0);
+        let members = vec![(2.0, b"b".to_vec()), (1.0, b"a".to_vec())];
+        let outcomes = store
+            .zadd(b"z".to_vec(), members, &ZAddOptions::default())
+            .unwrap();
+        assert_eq!(
+            vec![AddOutcome::Added(2.0), AddOutcome::Added(1.0)],
+            outcomes
+        );
+        assert_eq!(
+            Ok(vec![(&b"a".to_vec(), 1.0), (&b"b".to_vec(), 2.0)]),
+            store.zrange(b"z", 0, -1, false)
+        );
+        assert_eq!(Ok(Some(1)), store.zrank(b"z", b"b", false));
+        assert_eq!(Ok(Some(2.0)), store.zscore(b"z", b"b"));
+        assert_eq!(Ok(4.5), store.zincrby(b"z".to_vec(), 3.5, b"a".to_vec()));
+        assert_eq!(Ok(Some(0)), store.zrank(b"z", b"a", true));
+    }
+
+    #[test]
+    fn test_zadd_xx_missing_key() {
+        let mut store = Db::new(0);
+        let opts = ZAddOptions {
+            xx: true,
+            ..ZAddOptions::default()
+        };
+        let outcomes = store
+            .zadd(b"z".to_vec(), vec![(1.0, b"a".to_vec())], &opts)
+            .unwrap();
+        assert_eq!(vec![AddOutcome::Skipped], outcomes);
+        assert_eq!(None, store.get(b"z"));
+    }
+
+    #[test]
+    fn test_zrem_zpop() {
+        let mut store = Db::new(0);
+        let members = vec![
+            (1.0, b"a".to_vec()),
+            (2.0, b"b".to_vec()),
+            (3.0, b"c".to_vec()),
+        ];
+        store
+            .zadd(b"z".to_vec(), members, &ZAddOptions::default())
+            .unwrap();
+        assert_eq!(Ok(1), store.zrem(b"z", &strings(&["b", "x"])));
+        assert_eq!(Ok(vec![(b"c".to_vec(), 3.0)]), store.zpop(b"z", 1, true));
+        assert_eq!(Ok(vec![(b"a".to_vec(), 1.0)]), store.zpop(b"z", 5, false));
+        assert_eq!(None, store.get(b"z"));
+        store.set(b"str".to_vec(), b"v".to_vec(), false);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::WrongType)),
+            store.zscore(b"str", b"a")
+        );
+    }
+
+    #[test]
+    fn test_xadd_xrange() {
+        let mut store = Db::new(0);
+        let id = |ms| XAddId::Explicit(StreamId::new(ms, 0));
+        for ms in 1..=3 {
+            store
+                .xadd(b"s".to_vec(), id(ms), pairs(&[("f", "v")]), false, None)
+                .unwrap();
+        }
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::StreamIdTooSmall)),
+            store.xadd(b"s".to_vec(), id(2), pairs(&[("f", "v")]), false, None)
+        );
+        assert_eq!(
+            Ok(None),
+            store.xadd(b"missing".to_vec(), id(1), pairs(&[("f", "v")]), true, None)
+        );
+        assert_eq!(None, store.get(b"missing"));
+        assert_eq!(
+            Ok(Some(StreamId::new(4, 0))),
+            store.xadd(
+                b"s".to_vec(),
+                id(4),
+                pairs(&[("f", "v")]),
+                false,
+                Some((TrimStrategy::MaxLen(2), None))
+            )
+        );
+        assert_eq!(Ok(2), store.xlen(b"s"));
+        let range = store
+            .xrange(b"s", StreamId::MIN, StreamId::MAX, None, true)
+            .unwrap();
+        assert_eq!(
+            vec![StreamId::new(4, 0), StreamId::new(3, 0)],
+            range.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+
+        let streams = vec![(b"s".to_vec(), StreamId::new(3, 0))];
+        let read = store.xread(&streams, None).unwrap();
+        assert_eq!(1, read.len());
+        assert_eq!(1, read[0].1.len());
+        let streams = vec![(b"s".to_vec(), StreamId::new(4, 0))];
+        assert!(store.xread(&streams, None).unwrap().is_empty());
+
+        assert_eq!(Ok(2), store.xtrim(b"s", &TrimStrategy::MaxLen(0), None));
+        assert_eq!(Ok(0), store.xlen(b"s"));
+        assert!(store.get(b"s").is_some());
+    }
+
+    #[test]
+    fn test_xgroup() {
+        let mut store = Db::new(0);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NoStreamForGroup)),
+            store.xgroup_create(b"s".to_vec(), b"g".to_vec(), None, false)
+        );
+        assert_eq!(
+            Ok(()),
+            store.xgroup_create(b"s".to_vec(), b"g".to_vec(), None, true)
+        );
+        store
+            .xadd(
+                b"s".to_vec(),
+                XAddId::Auto,
+                pairs(&[("f", "v")]),
+                false,
+                None,
+            )
+            .unwrap();
+
+        let streams = vec![(b"s".to_vec(), GroupReadFrom::New)];
+        let read = store
+            .xreadgroup(b"g", b"alice", &streams, None, false)
+            .unwrap();
+        assert_eq!(1, read[0].1.len());
+        assert!(store
+            .xreadgroup(b"g", b"alice", &streams, None, false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(1, store.xpending_summary(b"s", b"g").unwrap().count);
+
+        let id = read[0].1[0].0;
+        assert_eq!(Ok(1), store.xack(b"s", b"g", &[id]));
+        assert_eq!(Ok(0), store.xack(b"missing", b"g", &[id]));
         assert_eq!(
-            Some(&Value::Str("bar".to_owned())),
-            store.get(&"foo".to_owned())
+            Err(StoreError::new_with_context(
+                StoreErrorKind::NoGroup,
+                "nope".to_owned()
+            )),
+            store.xreadgroup(b"nope", b"alice", &streams, None, false)
         );
-        assert_eq!(Some(&Value::Int(42)), store.get(&"a_num".to_owned()));
-        assert_eq!(None, store.get(&"not_here".to_owned()));
+        assert_eq!(Ok(true), store.xgroup_destroy(b"s", b"g"));
     }
 }
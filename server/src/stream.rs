@@ -0,0 +1,831 @@
+use crate::store::{StoreError, StoreErrorKind};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub type EntryRef<'a> = (StreamId, &'a Fields);
+
+/// An entry read through a consumer group. Fields are `None` if the entry was trimmed from
+/// the stream after being delivered.
+pub type GroupEntry = (StreamId, Option<Fields>);
+
+/// The entries read from one stream by XREAD.
+pub type StreamRead<'a> = (&'a Vec<u8>, Vec<EntryRef<'a>>);
+
+/// The entries read from one stream by XREADGROUP.
+pub type GroupRead = (Vec<u8>, Vec<GroupEntry>);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `<ms>-<seq>`, or `<ms>` with the sequence defaulting to `default_seq`.
+    fn parse_with_default(s: &str, default_seq: u64) -> Option<StreamId> {
+        let mut parts = s.splitn(2, '-');
+        let ms = parts.next()?.parse::<u64>().ok()?;
+        let seq = match parts.next() {
+            Some(seq) => seq.parse::<u64>().ok()?,
+            None => default_seq,
+        };
+        Some(StreamId { ms, seq })
+    }
+
+    pub fn parse<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<StreamId> {
+        StreamId::parse_with_default(std::str::from_utf8(s.as_ref()).ok()?, 0)
+    }
+
+    /// Parses the start of a range: `-`, `(<id>` for an exclusive start, or an ID whose
+    /// sequence defaults to `0`.
+    pub fn parse_start<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<StreamId> {
+        let s = std::str::from_utf8(s.as_ref()).ok()?;
+        if s == "-" {
+            Some(StreamId::MIN)
+        } else if let Some(id) = s.strip_prefix('(') {
+            StreamId::parse_with_default(id, 0)?.next()
+        } else {
+            StreamId::parse_with_default(s, 0)
+        }
+    }
+
+    /// Parses the end of a range: `+`, `(<id>` for an exclusive end, or an ID whose sequence
+    /// defaults to the maximum.
+    pub fn parse_end<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<StreamId> {
+        let s = std::str::from_utf8(s.as_ref()).ok()?;
+        if s == "+" {
+            Some(StreamId::MAX)
+        } else if let Some(id) = s.strip_prefix('(') {
+            StreamId::parse_with_default(id, u64::MAX)?.prev()
+        } else {
+            StreamId::parse_with_default(s, u64::MAX)
+        }
+    }
+
+    pub fn next(self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            Some(StreamId::new(self.ms, self.seq + 1))
+        } else if self.ms < u64::MAX {
+            Some(StreamId::new(self.ms + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    pub fn prev(self) -> Option<StreamId> {
+        if self.seq > 0 {
+            Some(StreamId::new(self.ms, self.seq - 1))
+        } else if self.ms > 0 {
+            Some(StreamId::new(self.ms - 1, u64::MAX))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of XADD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XAddId {
+    /// `*`: the current time, or the last ID's sequence plus one if the clock went backwards.
+    Auto,
+    /// `<ms>-*`: an explicit time with an auto-generated sequence.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    pub fn parse<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<XAddId> {
+        let s = std::str::from_utf8(s.as_ref()).ok()?;
+        if s == "*" {
+            Some(XAddId::Auto)
+        } else if let Some(ms) = s.strip_suffix("-*") {
+            ms.parse::<u64>().ok().map(XAddId::AutoSeq)
+        } else {
+            StreamId::parse(s).map(XAddId::Explicit)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingEntry {
+    pub consumer: Vec<u8>,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Consumer {
+    pub seen_time: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerGroup {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: HashMap<Vec<u8>, Consumer>,
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered: StreamId) -> ConsumerGroup {
+        ConsumerGroup {
+            last_delivered,
+            pending: BTreeMap::new(),
+            consumers: HashMap::new(),
+        }
+    }
+
+    /// Rebuilds a group from the state saved in a snapshot.
+    pub fn from_parts(
+        last_delivered: StreamId,
+        pending: BTreeMap<StreamId, PendingEntry>,
+        consumers: HashMap<Vec<u8>, Consumer>,
+    ) -> ConsumerGroup {
+        ConsumerGroup {
+            last_delivered,
+            pending,
+            consumers,
+        }
+    }
+
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    pub fn pending(&self) -> &BTreeMap<StreamId, PendingEntry> {
+        &self.pending
+    }
+
+    pub fn consumers(&self) -> &HashMap<Vec<u8>, Consumer> {
+        &self.consumers
+    }
+
+    fn touch_consumer(&mut self, consumer: &[u8], now: u64) {
+        self.consumers
+            .entry(consumer.to_vec())
+            .or_insert(Consumer { seen_time: now })
+            .seen_time = now;
+    }
+}
+
+/// Where XREADGROUP starts reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupReadFrom {
+    /// `>`: entries never delivered to any consumer of the group.
+    New,
+    /// An explicit ID: the consumer's own pending entries after that ID.
+    History(StreamId),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClaimOptions {
+    pub idle: Option<u64>,
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+pub struct PendingSummary<'a> {
+    pub count: usize,
+    pub first: Option<StreamId>,
+    pub last: Option<StreamId>,
+    pub consumers: Vec<(&'a Vec<u8>, usize)>,
+}
+
+pub struct PendingInfo<'a> {
+    pub id: StreamId,
+    pub consumer: &'a Vec<u8>,
+    pub idle: u64,
+    pub delivery_count: u64,
+}
+
+pub struct AutoClaim {
+    pub next: StreamId,
+    pub claimed: Vec<(StreamId, Fields)>,
+    pub deleted: Vec<StreamId>,
+}
+
+/// An append-only log of field/value entries keyed by monotonically increasing IDs, plus the
+/// consumer groups reading from it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: HashMap<Vec<u8>, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.iter()
+    }
+
+    pub fn groups(&self) -> &HashMap<Vec<u8>, ConsumerGroup> {
+        &self.groups
+    }
+
+    /// Rebuilds a stream from the state saved in a snapshot.
+    pub fn from_parts(
+        entries: BTreeMap<StreamId, Fields>,
+        last_id: StreamId,
+        groups: HashMap<Vec<u8>, ConsumerGroup>,
+    ) -> Stream {
+        Stream {
+            entries,
+            last_id,
+            groups,
+        }
+    }
+
+    pub fn add(&mut self, id: XAddId, fields: Fields, now: u64) -> Result<StreamId, StoreError> {
+        let last = self.last_id;
+        let id = match id {
+            XAddId::Auto if now > last.ms => StreamId::new(now, 0),
+            XAddId::Auto => last
+                .next()
+                .ok_or_else(|| StoreError::new(StoreErrorKind::StreamIdTooSmall))?,
+            XAddId::AutoSeq(ms) if ms == last.ms && (ms, last.seq) != (0, 0) => last
+                .next()
+                .filter(|id| id.ms == ms)
+                .ok_or_else(|| StoreError::new(StoreErrorKind::StreamIdTooSmall))?,
+            XAddId::AutoSeq(0) => StreamId::new(0, 1),
+            XAddId::AutoSeq(ms) => StreamId::new(ms, 0),
+            XAddId::Explicit(id) => id,
+        };
+        if id == StreamId::MIN {
+            return Err(StoreError::new(StoreErrorKind::StreamIdZero));
+        }
+        if id <= last {
+            return Err(StoreError::new(StoreErrorKind::StreamIdTooSmall));
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Evicts the oldest entries according to `strategy`, removing at most `limit` entries.
+    /// Returns the number of entries removed.
+    pub fn trim(&mut self, strategy: &TrimStrategy, limit: Option<usize>) -> usize {
+        let mut removed = 0;
+        while limit.is_none_or(|limit| removed < limit) {
+            let oldest = match self.entries.keys().next() {
+                Some(id) => *id,
+                None => break,
+            };
+            let evict = match strategy {
+                TrimStrategy::MaxLen(max_len) => self.entries.len() > *max_len,
+                TrimStrategy::MinId(min_id) => oldest < *min_id,
+            };
+            if !evict {
+                break;
+            }
+            self.entries.remove(&oldest);
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Returns entries between the inclusive bounds `start` and `end`, newest first if `rev`.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<EntryRef<'_>> {
+        if start > end {
+            return vec![];
+        }
+        let range = self.entries.range(start..=end).map(|(id, f)| (*id, f));
+        let count = count.unwrap_or(usize::MAX);
+        if rev {
+            range.rev().take(count).collect()
+        } else {
+            range.take(count).collect()
+        }
+    }
+
+    fn group_mut(&mut self, group: &[u8]) -> Result<&mut ConsumerGroup, StoreError> {
+        self.groups.get_mut(group).ok_or_else(|| no_group(group))
+    }
+
+    fn group(&self, group: &[u8]) -> Result<&ConsumerGroup, StoreError> {
+        self.groups.get(group).ok_or_else(|| no_group(group))
+    }
+
+    /// Creates a group that will deliver entries after `id`, or after the last entry if `None`.
+    pub fn create_group(&mut self, group: Vec<u8>, id: Option<StreamId>) -> Result<(), StoreError> {
+        if self.groups.contains_key(&group) {
+            return Err(StoreError::new(StoreErrorKind::BusyGroup));
+        }
+        let id = id.unwrap_or(self.last_id);
+        self.groups.insert(group, ConsumerGroup::new(id));
+        Ok(())
+    }
+
+    pub fn destroy_group(&mut self, group: &[u8]) -> bool {
+        self.groups.remove(group).is_some()
+    }
+
+    pub fn set_group_id(&mut self, group: &[u8], id: Option<StreamId>) -> Result<(), StoreError> {
+        let last_id = self.last_id;
+        self.group_mut(group)?.last_delivered = id.unwrap_or(last_id);
+        Ok(())
+    }
+
+    pub fn create_consumer(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        now: u64,
+    ) -> Result<bool, StoreError> {
+        let group = self.group_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Ok(false);
+        }
+        group.touch_consumer(consumer, now);
+        Ok(true)
+    }
+
+    /// Deletes a consumer along with its pending entries, returning how many were pending.
+    pub fn delete_consumer(&mut self, group: &[u8], consumer: &[u8]) -> Result<usize, StoreError> {
+        let group = self.group_mut(group)?;
+        if group.consumers.remove(consumer).is_none() {
+            return Ok(0);
+        }
+        let before = group.pending.len();
+        group
+            .pending
+            .retain(|_, pending| pending.consumer != consumer);
+        Ok(before - group.pending.len())
+    }
+
+    /// Reads entries on behalf of `consumer`. New entries are added to the group's pending
+    /// entries list unless `no_ack`; history reads return entries already pending for the
+    /// consumer, with `None` fields for entries trimmed from the stream since delivery.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        from: GroupReadFrom,
+        count: Option<usize>,
+        no_ack: bool,
+        now: u64,
+    ) -> Result<Vec<GroupEntry>, StoreError> {
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or_else(|| no_group(group))?;
+        group.touch_consumer(consumer, now);
+        let count = count.unwrap_or(usize::MAX);
+
+        let mut result = vec![];
+        match from {
+            GroupReadFrom::New => {
+                let range = entries.range((Excluded(group.last_delivered), Unbounded));
+                for (id, fields) in range.take(count) {
+                    group.last_delivered = *id;
+                    if !no_ack {
+                        group.pending.insert(
+                            *id,
+                            PendingEntry {
+                                consumer: consumer.to_vec(),
+                                delivery_time: now,
+                                delivery_count: 1,
+                            },
+                        );
+                    }
+                    result.push((*id, Some(fields.clone())));
+                }
+            }
+            GroupReadFrom::History(start) => {
+                let range = group
+                    .pending
+                    .range_mut((Excluded(start), Unbounded))
+                    .filter(|(_, pending)| pending.consumer == consumer);
+                for (id, pending) in range.take(count) {
+                    pending.delivery_time = now;
+                    pending.delivery_count += 1;
+                    result.push((*id, entries.get(id).cloned()));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> Result<usize, StoreError> {
+        let group = self.group_mut(group)?;
+        Ok(ids
+            .iter()
+            .filter(|id| group.pending.remove(id).is_some())
+            .count())
+    }
+
+    pub fn pending_summary(&self, group: &[u8]) -> Result<PendingSummary<'_>, StoreError> {
+        let group = self.group(group)?;
+        let mut counts: BTreeMap<&Vec<u8>, usize> = BTreeMap::new();
+        for pending in group.pending.values() {
+            *counts.entry(&pending.consumer).or_insert(0) += 1;
+        }
+        Ok(PendingSummary {
+            count: group.pending.len(),
+            first: group.pending.keys().next().cloned(),
+            last: group.pending.keys().next_back().cloned(),
+            consumers: counts.into_iter().collect(),
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn pending_range(
+        &self,
+        group: &[u8],
+        start: StreamId,
+        end: StreamId,
+        count: usize,
+        consumer: Option<&[u8]>,
+        min_idle: Option<u64>,
+        now: u64,
+    ) -> Result<Vec<PendingInfo<'_>>, StoreError> {
+        let group = self.group(group)?;
+        if start > end {
+            return Ok(vec![]);
+        }
+        Ok(group
+            .pending
+            .range((Included(start), Included(end)))
+            .filter(|(_, pending)| consumer.is_none_or(|c| pending.consumer == c))
+            .map(|(id, pending)| PendingInfo {
+                id: *id,
+                consumer: &pending.consumer,
+                idle: now.saturating_sub(pending.delivery_time),
+                delivery_count: pending.delivery_count,
+            })
+            .filter(|info| min_idle.is_none_or(|min_idle| info.idle >= min_idle))
+            .take(count)
+            .collect())
+    }
+
+    /// Transfers ownership of pending entries idle for at least `min_idle` ms to `consumer`.
+    /// Entries trimmed from the stream are dropped from the pending entries list.
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        ids: &[StreamId],
+        opts: &ClaimOptions,
+        now: u64,
+    ) -> Result<Vec<(StreamId, Fields)>, StoreError> {
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or_else(|| no_group(group))?;
+        group.touch_consumer(consumer, now);
+        if let Some(last_id) = opts.last_id {
+            if last_id > group.last_delivered {
+                group.last_delivered = last_id;
+            }
+        }
+
+        let delivery_time = match (opts.idle, opts.time) {
+            (Some(idle), _) => now.saturating_sub(idle),
+            (None, Some(time)) => time,
+            (None, None) => now,
+        };
+        let mut claimed = vec![];
+        for id in ids {
+            let fields = match entries.get(id) {
+                Some(fields) => fields,
+                None => {
+                    group.pending.remove(id);
+                    continue;
+                }
+            };
+            if !group.pending.contains_key(id) {
+                if !opts.force {
+                    continue;
+                }
+                group.pending.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.to_vec(),
+                        delivery_time: now,
+                        delivery_count: 0,
+                    },
+                );
+            } else if now.saturating_sub(group.pending[id].delivery_time) < min_idle {
+                continue;
+            }
+
+            let pending = group.pending.get_mut(id).unwrap();
+            pending.consumer = consumer.to_vec();
+            pending.delivery_time = delivery_time;
+            if let Some(retry_count) = opts.retry_count {
+                pending.delivery_count = retry_count;
+            } else if !opts.just_id {
+                pending.delivery_count += 1;
+            }
+            claimed.push((*id, fields.clone()));
+        }
+        Ok(claimed)
+    }
+
+    /// Claims up to `count` idle pending entries starting at `start`, returning the ID to
+    /// resume scanning from (`0-0` once the whole list has been scanned).
+    #[allow(clippy::too_many_arguments)]
+    pub fn auto_claim(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+        now: u64,
+    ) -> Result<AutoClaim, StoreError> {
+        let entries = &self.entries;
+        let group = self.groups.get_mut(group).ok_or_else(|| no_group(group))?;
+        group.touch_consumer(consumer, now);
+
+        let mut result = AutoClaim {
+            next: StreamId::MIN,
+            claimed: vec![],
+            deleted: vec![],
+        };
+        let ids: Vec<StreamId> = group.pending.range(start..).map(|(id, _)| *id).collect();
+        for id in ids {
+            if result.claimed.len() == count {
+                result.next = id;
+                break;
+            }
+            let fields = match entries.get(&id) {
+                Some(fields) => fields,
+                None => {
+                    group.pending.remove(&id);
+                    result.deleted.push(id);
+                    continue;
+                }
+            };
+            let pending = group.pending.get_mut(&id).unwrap();
+            if now.saturating_sub(pending.delivery_time) < min_idle {
+                continue;
+            }
+            pending.consumer = consumer.to_vec();
+            pending.delivery_time = now;
+            if !just_id {
+                pending.delivery_count += 1;
+            }
+            result.claimed.push((id, fields.clone()));
+        }
+        Ok(result)
+    }
+}
+
+pub fn no_group(group: &[u8]) -> StoreError {
+    StoreError::new_with_context(
+        StoreErrorKind::NoGroup,
+        String::from_utf8_lossy(group).into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Fields {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn ids(entries: Vec<(StreamId, &Fields)>) -> Vec<String> {
+        entries.iter().map(|(id, _)| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(Some(StreamId::new(5, 3)), StreamId::parse("5-3"));
+        assert_eq!(Some(StreamId::new(5, 0)), StreamId::parse_start("5"));
+        assert_eq!(Some(StreamId::new(5, u64::MAX)), StreamId::parse_end("5"));
+        assert_eq!(Some(StreamId::new(5, 4)), StreamId::parse_start("(5-3"));
+        assert_eq!(Some(StreamId::new(5, 2)), StreamId::parse_end("(5-3"));
+        assert_eq!(Some(StreamId::MIN), StreamId::parse_start("-"));
+        assert_eq!(Some(StreamId::MAX), StreamId::parse_end("+"));
+        assert_eq!(None, StreamId::parse("abc"));
+        assert_eq!(Some(XAddId::AutoSeq(7)), XAddId::parse("7-*"));
+    }
+
+    #[test]
+    fn test_add_ids() {
+        let mut stream = Stream::new();
+        assert_eq!(
+            Ok(StreamId::new(100, 0)),
+            stream.add(XAddId::Auto, fields(&[]), 100)
+        );
+        assert_eq!(
+            Ok(StreamId::new(100, 1)),
+            stream.add(XAddId::Auto, fields(&[]), 90)
+        );
+        assert_eq!(
+            Ok(StreamId::new(100, 2)),
+            stream.add(XAddId::AutoSeq(100), fields(&[]), 0)
+        );
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::StreamIdTooSmall)),
+            stream.add(XAddId::Explicit(StreamId::new(100, 2)), fields(&[]), 0)
+        );
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::StreamIdZero)),
+            Stream::new().add(XAddId::Explicit(StreamId::MIN), fields(&[]), 0)
+        );
+        assert_eq!(
+            Ok(StreamId::new(0, 1)),
+            Stream::new().add(XAddId::AutoSeq(0), fields(&[]), 0)
+        );
+    }
+
+    #[test]
+    fn test_range_and_trim() {
+        let mut stream = Stream::new();
+        for i in 1..=5 {
+            stream
+                .add(
+                    XAddId::Explicit(StreamId::new(i, 0)),
+                    fields(&[("n", "v")]),
+                    0,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            vec!["2-0", "3-0"],
+            ids(stream.range(StreamId::new(2, 0), StreamId::new(3, 0), None, false))
+        );
+        assert_eq!(
+            vec!["5-0", "4-0"],
+            ids(stream.range(StreamId::MIN, StreamId::MAX, Some(2), true))
+        );
+        assert_eq!(2, stream.trim(&TrimStrategy::MaxLen(3), None));
+        assert_eq!(
+            1,
+            stream.trim(&TrimStrategy::MinId(StreamId::new(4, 0)), None)
+        );
+        assert_eq!(1, stream.trim(&TrimStrategy::MaxLen(0), Some(1)));
+        assert_eq!(1, stream.len());
+        assert_eq!(StreamId::new(5, 0), stream.last_id());
+    }
+
+    #[test]
+    fn test_consumer_group() {
+        let mut stream = Stream::new();
+        for i in 1..=3 {
+            stream
+                .add(
+                    XAddId::Explicit(StreamId::new(i, 0)),
+                    fields(&[("n", "v")]),
+                    0,
+                )
+                .unwrap();
+        }
+        stream
+            .create_group(b"g".to_vec(), Some(StreamId::MIN))
+            .unwrap();
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::BusyGroup)),
+            stream.create_group(b"g".to_vec(), None)
+        );
+
+        let read = stream
+            .read_group(b"g", b"alice", GroupReadFrom::New, Some(2), false, 1000)
+            .unwrap();
+        assert_eq!(2, read.len());
+        let read = stream
+            .read_group(b"g", b"bob", GroupReadFrom::New, None, false, 1000)
+            .unwrap();
+        assert_eq!(
+            vec![StreamId::new(3, 0)],
+            read.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+
+        let summary = stream.pending_summary(b"g").unwrap();
+        assert_eq!(3, summary.count);
+        assert_eq!(Some(StreamId::new(1, 0)), summary.first);
+        assert_eq!(2, summary.consumers.len());
+
+        assert_eq!(
+            Ok(1),
+            stream.ack(b"g", &[StreamId::new(1, 0), StreamId::new(9, 0)])
+        );
+        let history = stream
+            .read_group(
+                b"g",
+                b"alice",
+                GroupReadFrom::History(StreamId::MIN),
+                None,
+                false,
+                1500,
+            )
+            .unwrap();
+        assert_eq!(
+            vec![StreamId::new(2, 0)],
+            history.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+
+        let pending = stream
+            .pending_range(
+                b"g",
+                StreamId::MIN,
+                StreamId::MAX,
+                10,
+                Some(&b"alice"[..]),
+                None,
+                2000,
+            )
+            .unwrap();
+        assert_eq!(1, pending.len());
+        assert_eq!(500, pending[0].idle);
+        assert_eq!(2, pending[0].delivery_count);
+
+        assert!(stream
+            .read_group(b"missing", b"alice", GroupReadFrom::New, None, false, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut stream = Stream::new();
+        for i in 1..=3 {
+            stream
+                .add(
+                    XAddId::Explicit(StreamId::new(i, 0)),
+                    fields(&[("n", "v")]),
+                    0,
+                )
+                .unwrap();
+        }
+        stream
+            .create_group(b"g".to_vec(), Some(StreamId::MIN))
+            .unwrap();
+        stream
+            .read_group(b"g", b"alice", GroupReadFrom::New, None, false, 1000)
+            .unwrap();
+
+        let ids = [StreamId::new(1, 0), StreamId::new(2, 0)];
+        let claimed = stream
+            .claim(b"g", b"bob", 5000, &ids, &ClaimOptions::default(), 2000)
+            .unwrap();
+        assert!(claimed.is_empty());
+        let claimed = stream
+            .claim(b"g", b"bob", 500, &ids, &ClaimOptions::default(), 2000)
+            .unwrap();
+        assert_eq!(2, claimed.len());
+
+        stream.trim(&TrimStrategy::MinId(StreamId::new(2, 0)), None);
+        let auto = stream
+            .auto_claim(b"g", b"carol", 0, StreamId::MIN, 1, false, 3000)
+            .unwrap();
+        assert_eq!(vec![StreamId::new(1, 0)], auto.deleted);
+        assert_eq!(1, auto.claimed.len());
+        assert_eq!(StreamId::new(3, 0), auto.next);
+
+        let summary = stream.pending_summary(b"g").unwrap();
+        assert_eq!(2, summary.count);
+    }
+}
@@ -1,4 +1,9 @@
+use crate::aof::Argv;
 use crate::command::Command;
+use crate::lease;
+use crate::store::{Store, StoreError};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct Transaction {
@@ -6,6 +11,12 @@ pub struct Transaction {
     queue: Vec<Command>,
 }
 
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Transaction {
     pub fn new() -> Transaction {
         Transaction {
@@ -14,11 +25,419 @@ impl Transaction {
         }
     }
 
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.error
+    }
+
     pub fn push(&mut self, cmd: Command) {
         self.queue.push(cmd);
     }
 
-    pub fn drain_queue(&mut self) -> std::vec::Drain<Command> {
+    pub fn drain_queue(&mut self) -> std::vec::Drain<'_, Command> {
         self.queue.drain(..)
     }
 }
+
+/// Identifies an interactive transaction by the ID of the client running it.
+pub type TxnId = usize;
+
+/// A key in one of the databases.
+type LockedKey = (usize, Vec<u8>);
+
+/// What came of an interactive transaction asking for locks on keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockOutcome {
+    /// The transaction now holds every key it asked for.
+    Acquired,
+    /// Another transaction holds one of the keys, so the transaction must wait and ask again.
+    Wait,
+    /// Waiting would never end, as the holder waits on the transaction in turn. The
+    /// transaction was ended, releasing its locks.
+    Deadlock,
+    /// The transaction timed out, or was never begun.
+    Ended,
+}
+
+/// The keys locked by interactive transactions, which other clients may read but not write
+/// until the transaction that locked them ends. A transaction that asks for a key another
+/// holds waits for it; the waits are tracked so that a wait that closes a cycle is turned
+/// down rather than left to hang.
+#[derive(Debug, Default)]
+pub struct KeyLocks {
+    owners: HashMap<LockedKey, TxnId>,
+    /// When each transaction times out, and the keys it holds.
+    txns: HashMap<TxnId, (Instant, Vec<LockedKey>)>,
+    waits_for: HashMap<TxnId, TxnId>,
+}
+
+impl KeyLocks {
+    pub fn new() -> KeyLocks {
+        KeyLocks::default()
+    }
+
+    /// Starts a transaction that times out at `deadline`, releasing its locks.
+    pub fn begin(&mut self, txn: TxnId, deadline: Instant) {
+        self.end(txn);
+        self.txns.insert(txn, (deadline, vec![]));
+    }
+
+    /// Whether the transaction has begun and not yet ended or timed out.
+    pub fn is_active(&mut self, txn: TxnId, now: Instant) -> bool {
+        self.end_timed_out(now);
+        self.txns.contains_key(&txn)
+    }
+
+    /// Locks `keys` of database `db` for the transaction, all of them or none.
+    pub fn lock(&mut self, txn: TxnId, db: usize, keys: &[&[u8]], now: Instant) -> LockOutcome {
+        if !self.is_active(txn, now) {
+            return LockOutcome::Ended;
+        }
+        let holder = keys.iter().find_map(|key| {
+            self.owners
+                .get(&(db, key.to_vec()))
+                .filter(|holder| **holder != txn)
+        });
+        if let Some(holder) = holder.copied() {
+            if self.waits_on(holder, txn) {
+                self.end(txn);
+                return LockOutcome::Deadlock;
+            }
+            self.waits_for.insert(txn, holder);
+            return LockOutcome::Wait;
+        }
+        self.waits_for.remove(&txn);
+        let held = &mut self.txns.get_mut(&txn).unwrap().1;
+        for key in keys {
+            let key = (db, key.to_vec());
+            if !self.owners.contains_key(&key) {
+                self.owners.insert(key.clone(), txn);
+                held.push(key);
+            }
+        }
+        LockOutcome::Acquired
+    }
+
+    /// Whether any of `keys` of database `db` is locked by a transaction.
+    pub fn any_locked(&mut self, db: usize, keys: &[&[u8]], now: Instant) -> bool {
+        self.end_timed_out(now);
+        keys.iter()
+            .any(|key| self.owners.contains_key(&(db, key.to_vec())))
+    }
+
+    /// Whether any key of database `db` is locked by a transaction.
+    pub fn any_locked_in(&mut self, db: usize, now: Instant) -> bool {
+        self.end_timed_out(now);
+        self.owners.keys().any(|(index, _)| *index == db)
+    }
+
+    /// Ends the transaction, releasing its locks.
+    pub fn end(&mut self, txn: TxnId) {
+        if let Some((_, held)) = self.txns.remove(&txn) {
+            for key in held {
+                self.owners.remove(&key);
+            }
+        }
+        self.waits_for.remove(&txn);
+    }
+
+    /// Whether `txn` waits, directly or through other transactions, on `target`.
+    fn waits_on(&self, mut txn: TxnId, target: TxnId) -> bool {
+        for _ in 0..=self.waits_for.len() {
+            match self.waits_for.get(&txn) {
+                Some(next) if *next == target => return true,
+                Some(next) => txn = *next,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn end_timed_out(&mut self, now: Instant) {
+        let timed_out: Vec<TxnId> = self
+            .txns
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(txn, _)| *txn)
+            .collect();
+        for txn in timed_out {
+            self.end(txn);
+        }
+    }
+}
+
+/// A write made in an interactive transaction: the command that redoes it, the database it
+/// runs against and the lease token it carried.
+pub type BufferedWrite = (usize, Argv, Option<u64>);
+
+/// A transaction started with BEGIN. The keys it touches are copied into a workspace of its
+/// own as they are locked; reads run against the workspace straight away, and so do writes,
+/// which are also kept to be applied to the store at COMMIT.
+pub struct InteractiveTransaction {
+    workspace: Store,
+    copied: HashSet<LockedKey>,
+    writes: Vec<BufferedWrite>,
+}
+
+impl InteractiveTransaction {
+    pub fn new(databases: usize) -> InteractiveTransaction {
+        InteractiveTransaction {
+            workspace: Store::with_databases(databases),
+            copied: HashSet::new(),
+            writes: vec![],
+        }
+    }
+
+    pub fn workspace(&self) -> &Store {
+        &self.workspace
+    }
+
+    pub fn workspace_mut(&mut self) -> &mut Store {
+        &mut self.workspace
+    }
+
+    /// Copies `keys` of database `db` from `store` into the workspace, with their TTLs,
+    /// unless they were copied before. Missing keys stay missing.
+    pub fn copy_in(&mut self, store: &Store, db: usize, keys: &[&[u8]]) {
+        for key in keys {
+            if !self.copied.insert((db, key.to_vec())) {
+                continue;
+            }
+            let source = store.db(db);
+            if let Some(value) = source.get(key) {
+                let target = self.workspace.db_mut(db);
+                target
+                    .restore(key.to_vec(), value.clone(), None, true)
+                    .unwrap();
+                if let Some(expires_at) = source.expires_at(key) {
+                    target.expire(key, expires_at);
+                }
+            }
+        }
+    }
+
+    pub fn buffer(&mut self, writes: impl IntoIterator<Item = BufferedWrite>) {
+        self.writes.extend(writes);
+    }
+
+    /// The buffered writes as the commands that apply them to `store` at COMMIT, each with
+    /// its database, leaving none buffered. Fails if a lease on a key written is now held
+    /// by a token other than the one the write carried, or if the writes would grow the
+    /// dataset and evicting keys cannot bring memory use within maxmemory.
+    pub fn take_commands(
+        &mut self,
+        store: &mut Store,
+    ) -> Result<Vec<(usize, Command)>, StoreError> {
+        let cmds: Vec<(usize, Command, Option<u64>)> = std::mem::take(&mut self.writes)
+            .into_iter()
+            .map(|(db, argv, token)| {
+                let mut array = vec![];
+                if let Some(token) = token {
+                    array.push(resp::bulk_string(b"WITHLEASE"));
+                    array.push(resp::bulk_string(lease::format_token(token).as_bytes()));
+                }
+                array.extend(argv.iter().map(resp::bulk_string));
+                (db, Command::from_resp(resp::array(array)).unwrap(), token)
+            })
+            .collect();
+        for (db, cmd, token) in cmds.iter() {
+            store.check_write_leases(*db, cmd, *token)?;
+        }
+        if cmds.iter().any(|(_, cmd, _)| cmd.action().grows_dataset()) {
+            store.evict()?;
+        }
+        Ok(cmds.into_iter().map(|(db, cmd, _)| (db, cmd)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Action, CommandOpt};
+    use crate::evict::EvictionPolicy;
+    use crate::store::{StoreErrorKind, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn test_queue_error() {
+        let mut trx = Transaction::new();
+        trx.push(Command::new(Action::Ping, vec![], None));
+        assert!(!trx.has_error());
+
+        trx.set_error();
+        assert!(trx.has_error());
+        assert_eq!(1, trx.drain_queue().count());
+        assert!(trx.has_error());
+    }
+
+    #[test]
+    fn test_key_locks() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let mut locks = KeyLocks::new();
+        assert_eq!(LockOutcome::Ended, locks.lock(1, 0, &[b"a"], now));
+
+        locks.begin(1, later);
+        locks.begin(2, later);
+        assert_eq!(LockOutcome::Acquired, locks.lock(1, 0, &[b"a"], now));
+        assert_eq!(LockOutcome::Acquired, locks.lock(1, 0, &[b"a", b"b"], now));
+        assert_eq!(LockOutcome::Acquired, locks.lock(2, 1, &[b"a"], now));
+        assert!(locks.any_locked(0, &[b"x", b"b"], now));
+        assert!(!locks.any_locked(0, &[b"x"], now));
+
+        assert_eq!(LockOutcome::Wait, locks.lock(2, 0, &[b"c", b"b"], now));
+        assert!(!locks.any_locked(0, &[b"c"], now));
+        assert_eq!(LockOutcome::Deadlock, locks.lock(1, 1, &[b"a"], now));
+        assert!(!locks.is_active(1, now));
+        assert!(!locks.any_locked(0, &[b"a", b"b"], now));
+        assert_eq!(LockOutcome::Acquired, locks.lock(2, 0, &[b"c", b"b"], now));
+
+        assert!(locks.any_locked_in(0, now));
+        assert!(!locks.any_locked_in(2, now));
+
+        locks.end(2);
+        assert!(!locks.any_locked(1, &[b"a"], now));
+        assert!(!locks.any_locked_in(0, now));
+    }
+
+    #[test]
+    fn test_key_lock_timeout() {
+        let now = Instant::now();
+        let mut locks = KeyLocks::new();
+        locks.begin(1, now + Duration::from_millis(100));
+        locks.begin(2, now + Duration::from_secs(10));
+        assert_eq!(LockOutcome::Acquired, locks.lock(1, 0, &[b"a"], now));
+        assert_eq!(LockOutcome::Wait, locks.lock(2, 0, &[b"a"], now));
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(LockOutcome::Acquired, locks.lock(2, 0, &[b"a"], later));
+        assert!(!locks.is_active(1, later));
+        assert_eq!(LockOutcome::Ended, locks.lock(1, 0, &[b"b"], later));
+    }
+
+    fn argv(args: &[&str]) -> Argv {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_copy_in() {
+        let expires_at = Instant::now() + Duration::from_secs(60);
+        let mut store = Store::with_databases(2);
+        store.db_mut(0).set(b"a".to_vec(), b"one".to_vec(), false);
+        store.db_mut(0).expire(b"a", expires_at);
+        store.db_mut(0).set(b"b".to_vec(), b"two".to_vec(), false);
+
+        let mut txn = InteractiveTransaction::new(2);
+        txn.copy_in(&store, 0, &[b"a", b"b", b"c"]);
+        let workspace = txn.workspace().db(0);
+        assert_eq!(Some(&Value::Str(b"one".to_vec())), workspace.get(b"a"));
+        assert_eq!(Some(expires_at), workspace.expires_at(b"a"));
+        assert_eq!(Some(&Value::Str(b"two".to_vec())), workspace.get(b"b"));
+        assert_eq!(None, workspace.expires_at(b"b"));
+        assert_eq!(None, workspace.get(b"c"));
+
+        store.db_mut(0).set(b"a".to_vec(), b"three".to_vec(), false);
+        store.db_mut(0).set(b"c".to_vec(), b"four".to_vec(), false);
+        txn.copy_in(&store, 0, &[b"a", b"c"]);
+        txn.copy_in(&store, 1, &[b"a"]);
+        let workspace = txn.workspace().db(0);
+        assert_eq!(Some(&Value::Str(b"one".to_vec())), workspace.get(b"a"));
+        assert_eq!(None, workspace.get(b"c"));
+        assert_eq!(None, txn.workspace().db(1).get(b"a"));
+    }
+
+    #[test]
+    fn test_workspace_writes() {
+        let now = Instant::now();
+        let mut store = Store::with_databases(1);
+        store.db_mut(0).set(b"a".to_vec(), b"one".to_vec(), false);
+        store
+            .key_locks_mut()
+            .begin(1, now + Duration::from_secs(10));
+        assert_eq!(
+            LockOutcome::Acquired,
+            store.key_locks_mut().lock(1, 0, &[b"a"], now)
+        );
+
+        let mut txn = InteractiveTransaction::new(1);
+        txn.copy_in(&store, 0, &[b"a"]);
+        txn.workspace_mut()
+            .db_mut(0)
+            .set(b"a".to_vec(), b"two".to_vec(), false);
+        txn.buffer(vec![(0, argv(&["SET", "a", "two"]), None)]);
+        assert_eq!(
+            Some(&Value::Str(b"two".to_vec())),
+            txn.workspace().db(0).get(b"a")
+        );
+        assert_eq!(Some(&Value::Str(b"one".to_vec())), store.db(0).get(b"a"));
+
+        // ROLLBACK ends the transaction and drops its workspace and writes.
+        store.key_locks_mut().end(1);
+        drop(txn);
+        assert_eq!(Some(&Value::Str(b"one".to_vec())), store.db(0).get(b"a"));
+        assert!(!store.key_locks_mut().any_locked(0, &[b"a"], now));
+    }
+
+    #[test]
+    fn test_take_commands() {
+        let mut store = Store::with_databases(2);
+        let mut txn = InteractiveTransaction::new(2);
+        txn.buffer(vec![
+            (0, argv(&["SET", "a", "1"]), None),
+            (1, argv(&["DEL", "b"]), Some(7)),
+        ]);
+
+        let cmds = txn.take_commands(&mut store).unwrap();
+        assert_eq!(2, cmds.len());
+        assert_eq!(0, cmds[0].0);
+        assert_eq!(&Action::Set, cmds[0].1.action());
+        assert_eq!(vec![&b"a"[..]], cmds[0].1.written_keys());
+        assert_eq!(1, cmds[1].0);
+        assert_eq!(&Action::Del, cmds[1].1.action());
+        assert!(cmds[1].1.opts().contains(&CommandOpt::LeaseToken(7)));
+        assert_eq!(vec![&b"b"[..]], cmds[1].1.written_keys());
+        assert!(txn.take_commands(&mut store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_take_commands_leased() {
+        let mut store = Store::with_databases(1);
+        let token = store.db_mut(0).lease(b"a".to_vec(), 60_000, None).unwrap();
+
+        let mut txn = InteractiveTransaction::new(1);
+        txn.buffer(vec![(0, argv(&["SET", "a", "1"]), None)]);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::Leased)),
+            txn.take_commands(&mut store).map(|cmds| cmds.len())
+        );
+
+        txn.buffer(vec![(0, argv(&["SET", "a", "1"]), Some(token))]);
+        assert_eq!(Ok(1), txn.take_commands(&mut store).map(|cmds| cmds.len()));
+    }
+
+    #[test]
+    fn test_take_commands_out_of_memory() {
+        let mut store = Store::with_databases(1);
+        store.db_mut(0).set(b"a".to_vec(), b"one".to_vec(), false);
+        store.settle_memory();
+        store.set_maxmemory(1, EvictionPolicy::NoEviction);
+
+        let mut txn = InteractiveTransaction::new(1);
+        txn.buffer(vec![
+            (0, argv(&["SET", "b", "two"]), None),
+            (0, argv(&["SET", "c", "three"]), None),
+        ]);
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::OutOfMemory)),
+            txn.take_commands(&mut store).map(|cmds| cmds.len())
+        );
+        assert_eq!(None, store.db(0).get(b"b"));
+        assert_eq!(None, store.db(0).get(b"c"));
+
+        txn.buffer(vec![(0, argv(&["DEL", "a"]), None)]);
+        assert_eq!(Ok(1), txn.take_commands(&mut store).map(|cmds| cmds.len()));
+    }
+}
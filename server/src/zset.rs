@@ -0,0 +1,841 @@
+use crate::scan::{ScanIndex, ScanPage};
+use crate::store::{StoreError, StoreErrorKind};
+use indexmap::{map as index_map, IndexMap};
+use rand::Rng;
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+const MAX_LEVEL: usize = 32;
+const LEVEL_P: f64 = 0.25;
+const HEAD: usize = 0;
+
+/// Parses a sorted set score, rejecting NaN. Accepts `inf`, `+inf` and `-inf`.
+pub fn parse_score<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<f64> {
+    std::str::from_utf8(s.as_ref())
+        .ok()?
+        .parse::<f64>()
+        .ok()
+        .filter(|score| !score.is_nan())
+}
+
+/// One end of a `BYSCORE` range, e.g. `1.5`, `(1.5` or `-inf`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn parse<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<ScoreBound> {
+        let s = s.as_ref();
+        let (exclusive, value) = match s.strip_prefix(b"(") {
+            Some(value) => (true, value),
+            None => (false, s),
+        };
+        parse_score(value).map(|value| ScoreBound { value, exclusive })
+    }
+
+    fn is_above_min(&self, score: f64) -> bool {
+        if self.exclusive {
+            score > self.value
+        } else {
+            score >= self.value
+        }
+    }
+
+    fn is_below_max(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+/// One end of a `BYLEX` range, e.g. `[a`, `(a`, `-` or `+`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    pub fn parse<B: AsRef<[u8]> + ?Sized>(s: &B) -> Option<LexBound> {
+        let s = s.as_ref();
+        match s {
+            b"-" => Some(LexBound::NegInf),
+            b"+" => Some(LexBound::PosInf),
+            _ => {
+                if let Some(member) = s.strip_prefix(b"[") {
+                    Some(LexBound::Inclusive(member.to_vec()))
+                } else {
+                    s.strip_prefix(b"(")
+                        .map(|member| LexBound::Exclusive(member.to_vec()))
+                }
+            }
+        }
+    }
+
+    fn is_above_min(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_slice(),
+            LexBound::Exclusive(min) => member > min.as_slice(),
+        }
+    }
+
+    fn is_below_max(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_slice(),
+            LexBound::Exclusive(max) => member < max.as_slice(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub incr: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddOutcome {
+    Added(f64),
+    Updated(f64),
+    Unchanged(f64),
+    /// The member was left untouched because of NX, XX, GT or LT.
+    Skipped,
+}
+
+#[derive(Clone, Debug)]
+struct Level {
+    forward: Option<usize>,
+    span: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// The memory a member takes in the skiplist besides its name, for a node with the
+/// average 4/3 levels.
+pub const NODE_SIZE: usize = size_of::<Node>() + size_of::<Level>() * 4 / 3;
+
+/// A skiplist ordered by `(score, member)` whose links carry spans, so rank lookups and
+/// rank-based seeks are O(log n). Nodes live in an arena and link to each other by index;
+/// index 0 is the header.
+#[derive(Clone, Debug)]
+struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    level: usize,
+    len: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            member: Vec::new(),
+            score: 0.0,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+        };
+        SkipList {
+            nodes: vec![head],
+            free: vec![],
+            tail: None,
+            level: 1,
+            len: 0,
+        }
+    }
+}
+
+impl SkipList {
+    fn random_level() -> usize {
+        let mut rng = rand::thread_rng();
+        let mut level = 1;
+        while level < MAX_LEVEL && rng.gen::<f64>() < LEVEL_P {
+            level += 1;
+        }
+        level
+    }
+
+    fn cmp(&self, node: usize, score: f64, member: &[u8]) -> Ordering {
+        let node = &self.nodes[node];
+        node.score
+            .partial_cmp(&score)
+            .unwrap()
+            .then_with(|| node.member.as_slice().cmp(member))
+    }
+
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn first(&self) -> Option<usize> {
+        self.forward(HEAD, 0)
+    }
+
+    fn next(&self, node: usize) -> Option<usize> {
+        self.forward(node, 0)
+    }
+
+    fn prev(&self, node: usize) -> Option<usize> {
+        self.nodes[node].backward
+    }
+
+    /// Finds, for each level, the last node ordered before `(score, member)`.
+    fn find_update(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(x, i) {
+                if self.cmp(next, score, member) == Ordering::Less {
+                    rank[i] += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        if let Some(index) = self.free.pop() {
+            self.nodes[index] = node;
+            index
+        } else {
+            self.nodes.push(node);
+            self.nodes.len() - 1
+        }
+    }
+
+    /// Inserts `(score, member)`. The caller must ensure `member` is not already present.
+    fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let (mut update, mut rank) = self.find_update(score, &member);
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let x = self.alloc(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+        });
+        for i in 0..level {
+            let prev = update[i];
+            let prev_span = self.nodes[prev].levels[i].span;
+            self.nodes[x].levels[i].forward = self.nodes[prev].levels[i].forward;
+            self.nodes[x].levels[i].span = prev_span - (rank[0] - rank[i]);
+            self.nodes[prev].levels[i].forward = Some(x);
+            self.nodes[prev].levels[i].span = (rank[0] - rank[i]) + 1;
+        }
+        for (i, node) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*node].levels[i].span += 1;
+        }
+
+        self.nodes[x].backward = if update[0] == HEAD {
+            None
+        } else {
+            Some(update[0])
+        };
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    /// Removes `(score, member)`, returning whether it was present.
+    fn delete(&mut self, score: f64, member: &[u8]) -> bool {
+        let (update, _) = self.find_update(score, member);
+        let x = match self.forward(update[0], 0) {
+            Some(x) if self.cmp(x, score, member) == Ordering::Equal => x,
+            _ => return false,
+        };
+
+        for (i, node) in update.iter().enumerate().take(self.level) {
+            if self.nodes[*node].levels[i].forward == Some(x) {
+                self.nodes[*node].levels[i].span += self.nodes[x].levels[i].span;
+                self.nodes[*node].levels[i].span -= 1;
+                self.nodes[*node].levels[i].forward = self.nodes[x].levels[i].forward;
+            } else {
+                self.nodes[*node].levels[i].span -= 1;
+            }
+        }
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward,
+        }
+        while self.level > 1 && self.forward(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x].member = Vec::new();
+        self.nodes[x].levels = vec![];
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Returns the 0-based rank of `(score, member)`.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if self.cmp(next, score, member) != Ordering::Greater {
+                    rank += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if x != HEAD && self.cmp(x, score, member) == Ordering::Equal {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Returns the node at 0-based `rank`.
+    fn by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if traversed + self.nodes[x].levels[i].span <= target {
+                    traversed += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    fn first_in_score_range(&self, min: &ScoreBound, max: &ScoreBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if min.is_above_min(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.forward(x, 0)
+            .filter(|x| max.is_below_max(self.nodes[*x].score))
+    }
+
+    fn last_in_score_range(&self, min: &ScoreBound, max: &ScoreBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !max.is_below_max(self.nodes[next].score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        Some(x).filter(|x| *x != HEAD && min.is_above_min(self.nodes[*x].score))
+    }
+
+    fn first_in_lex_range(&self, min: &LexBound, max: &LexBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if min.is_above_min(&self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.forward(x, 0)
+            .filter(|x| max.is_below_max(&self.nodes[*x].member))
+    }
+
+    fn last_in_lex_range(&self, min: &LexBound, max: &LexBound) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !max.is_below_max(&self.nodes[next].member) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        Some(x).filter(|x| *x != HEAD && min.is_above_min(&self.nodes[*x].member))
+    }
+}
+
+/// A Redis-style sorted set: a member→score map for O(1) score lookups alongside a skiplist
+/// ordered by `(score, member)` for rank and range queries.
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: IndexMap<Vec<u8>, f64>,
+    list: SkipList,
+    /// The members in SCAN cursor order.
+    order: ScanIndex,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &SortedSet) -> bool {
+        self.scores == other.scores
+    }
+}
+
+// Scores are never NaN, so equality is reflexive.
+impl Eq for SortedSet {}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn iter(&self) -> index_map::Iter<'_, Vec<u8>, f64> {
+        self.scores.iter()
+    }
+
+    /// Returns a page of members and their scores from `cursor`, as [`ScanIndex::page`] does.
+    pub fn scan(&self, cursor: u64, count: usize) -> ScanPage<(&Vec<u8>, f64)> {
+        let (next, slots) = self.order.page(cursor, count);
+        let members = slots
+            .into_iter()
+            .filter_map(|slot| self.scores.get_index(slot))
+            .map(|(member, score)| (member, *score))
+            .collect();
+        (next, members)
+    }
+
+    /// The memory of the cursor index, which is not part of the table or the skiplist.
+    pub fn index_memory(&self) -> usize {
+        self.order.memory()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).cloned()
+    }
+
+    /// Adds or updates `member` following ZADD semantics. With `opts.incr`, `score` is added
+    /// to the current score instead of replacing it.
+    pub fn add(
+        &mut self,
+        member: Vec<u8>,
+        score: f64,
+        opts: &ZAddOptions,
+    ) -> Result<AddOutcome, StoreError> {
+        if let Some(current) = self.scores.get(&member).cloned() {
+            if opts.nx {
+                return Ok(AddOutcome::Skipped);
+            }
+            let new_score = if opts.incr { current + score } else { score };
+            if new_score.is_nan() {
+                return Err(StoreError::new(StoreErrorKind::NotANumber));
+            }
+            if (opts.gt && new_score <= current) || (opts.lt && new_score >= current) {
+                return Ok(AddOutcome::Skipped);
+            }
+            if new_score == current {
+                return Ok(AddOutcome::Unchanged(current));
+            }
+            self.list.delete(current, &member);
+            self.list.insert(new_score, member.clone());
+            self.scores.insert(member, new_score);
+            Ok(AddOutcome::Updated(new_score))
+        } else {
+            if opts.xx {
+                return Ok(AddOutcome::Skipped);
+            }
+            self.list.insert(score, member.clone());
+            self.order.insert(&member, self.scores.len());
+            self.scores.insert(member, score);
+            Ok(AddOutcome::Added(score))
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        if let Some((slot, _, score)) = self.scores.swap_remove_full(member) {
+            self.list.delete(score, member);
+            self.order.remove(member, slot);
+            if let Some((moved, _)) = self.scores.get_index(slot) {
+                self.order.moved(moved, self.scores.len(), slot);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the 0-based rank of `member`, counting from the highest score if `rev`.
+    pub fn rank(&self, member: &[u8], rev: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let rank = self.list.rank(score, member)?;
+        Some(if rev { self.len() - 1 - rank } else { rank })
+    }
+
+    fn entry(&self, node: usize) -> (&Vec<u8>, f64) {
+        let node = &self.list.nodes[node];
+        (&node.member, node.score)
+    }
+
+    /// Collects entries starting at `first` and walking forwards (or backwards if `rev`),
+    /// skipping `offset` entries and stopping after `count` or once `in_range` fails.
+    fn collect<F>(
+        &self,
+        first: Option<usize>,
+        rev: bool,
+        offset: usize,
+        count: Option<usize>,
+        in_range: F,
+    ) -> Vec<(&Vec<u8>, f64)>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let mut result = vec![];
+        let mut x = first;
+        let mut skipped = 0;
+        while let Some(node) = x {
+            if !in_range(&self.list.nodes[node]) || count.is_some_and(|c| result.len() >= c) {
+                break;
+            }
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                result.push(self.entry(node));
+            }
+            x = if rev {
+                self.list.prev(node)
+            } else {
+                self.list.next(node)
+            };
+        }
+        result
+    }
+
+    /// Returns entries between the inclusive, possibly negative ranks `start` and `stop`.
+    pub fn range_by_rank(&self, start: i64, stop: i64, rev: bool) -> Vec<(&Vec<u8>, f64)> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return vec![];
+        }
+        let first = if rev {
+            self.list.by_rank((len - 1 - start) as usize)
+        } else {
+            self.list.by_rank(start as usize)
+        };
+        let count = (stop - start + 1) as usize;
+        self.collect(first, rev, 0, Some(count), |_| true)
+    }
+
+    /// Returns entries with scores between `min` and `max`, ordered from `max` down if `rev`.
+    /// A negative `limit` count means no limit.
+    pub fn range_by_score(
+        &self,
+        min: &ScoreBound,
+        max: &ScoreBound,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+    ) -> Vec<(&Vec<u8>, f64)> {
+        let (offset, count) = match resolve_limit(limit) {
+            Some(limit) => limit,
+            None => return vec![],
+        };
+        if rev {
+            let first = self.list.last_in_score_range(min, max);
+            self.collect(first, rev, offset, count, |node| {
+                min.is_above_min(node.score)
+            })
+        } else {
+            let first = self.list.first_in_score_range(min, max);
+            self.collect(first, rev, offset, count, |node| {
+                max.is_below_max(node.score)
+            })
+        }
+    }
+
+    /// Returns entries with members between `min` and `max`. Only meaningful when every
+    /// member has the same score.
+    pub fn range_by_lex(
+        &self,
+        min: &LexBound,
+        max: &LexBound,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+    ) -> Vec<(&Vec<u8>, f64)> {
+        let (offset, count) = match resolve_limit(limit) {
+            Some(limit) => limit,
+            None => return vec![],
+        };
+        if rev {
+            let first = self.list.last_in_lex_range(min, max);
+            self.collect(first, rev, offset, count, |node| {
+                min.is_above_min(&node.member)
+            })
+        } else {
+            let first = self.list.first_in_lex_range(min, max);
+            self.collect(first, rev, offset, count, |node| {
+                max.is_below_max(&node.member)
+            })
+        }
+    }
+
+    /// Removes and returns up to `count` entries with the lowest (or highest if `max`) scores.
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(Vec<u8>, f64)> {
+        let mut popped = vec![];
+        while popped.len() < count {
+            let node = if max {
+                self.list.tail
+            } else {
+                self.list.first()
+            };
+            let (member, score) = match node {
+                Some(node) => {
+                    let (member, score) = self.entry(node);
+                    (member.clone(), score)
+                }
+                None => break,
+            };
+            self.remove(&member);
+            popped.push((member, score));
+        }
+        popped
+    }
+}
+
+/// Converts a `LIMIT offset count` pair into a skip count and an optional maximum. Returns
+/// `None` when the range is empty because `offset` is negative.
+fn resolve_limit(limit: Option<(i64, i64)>) -> Option<(usize, Option<usize>)> {
+    match limit {
+        Some((offset, _)) if offset < 0 => None,
+        Some((offset, count)) if count < 0 => Some((offset as usize, None)),
+        Some((offset, count)) => Some((offset as usize, Some(count as usize))),
+        None => Some((0, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zset(entries: &[(&str, f64)]) -> SortedSet {
+        let mut zset = SortedSet::new();
+        for (member, score) in entries {
+            zset.add(member.as_bytes().to_vec(), *score, &ZAddOptions::default())
+                .unwrap();
+        }
+        zset
+    }
+
+    fn members(entries: Vec<(&Vec<u8>, f64)>) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|(member, _)| std::str::from_utf8(member).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rank_and_range_by_rank() {
+        let mut zset = SortedSet::new();
+        for i in 0..200 {
+            zset.add(
+                format!("m{:03}", i).into_bytes(),
+                (i % 50) as f64,
+                &ZAddOptions::default(),
+            )
+            .unwrap();
+        }
+        let all = zset.range_by_rank(0, -1, false);
+        assert_eq!(200, all.len());
+        for (rank, (member, _)) in all.iter().enumerate() {
+            assert_eq!(Some(rank), zset.rank(member, false));
+            assert_eq!(Some(199 - rank), zset.rank(member, true));
+        }
+        assert!(all.windows(2).all(|w| (w[0].1, w[0].0) < (w[1].1, w[1].0)));
+
+        for i in (0..200).step_by(2) {
+            assert!(zset.remove(format!("m{:03}", i).as_bytes()));
+        }
+        assert_eq!(100, zset.len());
+        let all = zset.range_by_rank(0, -1, false);
+        for (rank, (member, _)) in all.iter().enumerate() {
+            assert_eq!(Some(rank), zset.rank(member, false));
+        }
+        assert_eq!(None, zset.rank(b"m000", false));
+    }
+
+    #[test]
+    fn test_range_by_rank_bounds() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        assert_eq!(vec!["b", "c"], members(zset.range_by_rank(1, 2, false)));
+        assert_eq!(vec!["d", "c"], members(zset.range_by_rank(0, 1, true)));
+        assert_eq!(vec!["c", "d"], members(zset.range_by_rank(-2, 100, false)));
+        assert!(zset.range_by_rank(3, 1, false).is_empty());
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        let min = ScoreBound::parse("(1").unwrap();
+        let max = ScoreBound::parse("3").unwrap();
+        assert_eq!(
+            vec!["b", "c"],
+            members(zset.range_by_score(&min, &max, false, None))
+        );
+        assert_eq!(
+            vec!["c", "b"],
+            members(zset.range_by_score(&min, &max, true, None))
+        );
+        let min = ScoreBound::parse("-inf").unwrap();
+        let max = ScoreBound::parse("+inf").unwrap();
+        assert_eq!(
+            vec!["b", "c"],
+            members(zset.range_by_score(&min, &max, false, Some((1, 2))))
+        );
+        assert_eq!(
+            vec!["c", "b", "a"],
+            members(zset.range_by_score(&min, &max, true, Some((1, -1))))
+        );
+        assert!(zset
+            .range_by_score(&min, &max, false, Some((-1, 2)))
+            .is_empty());
+        assert_eq!(None, ScoreBound::parse("nan"));
+    }
+
+    #[test]
+    fn test_range_by_lex() {
+        let zset = zset(&[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0)]);
+        let min = LexBound::parse("[b").unwrap();
+        let max = LexBound::parse("(d").unwrap();
+        assert_eq!(
+            vec!["b", "c"],
+            members(zset.range_by_lex(&min, &max, false, None))
+        );
+        assert_eq!(
+            vec!["c", "b"],
+            members(zset.range_by_lex(&min, &max, true, None))
+        );
+        assert_eq!(
+            vec!["a", "b", "c", "d"],
+            members(zset.range_by_lex(&LexBound::NegInf, &LexBound::PosInf, false, None))
+        );
+        assert_eq!(None, LexBound::parse("b"));
+    }
+
+    #[test]
+    fn test_add_options() {
+        let mut zset = zset(&[("a", 5.0)]);
+        let opts = |nx, xx, gt, lt, incr| ZAddOptions {
+            nx,
+            xx,
+            gt,
+            lt,
+            incr,
+        };
+        assert_eq!(
+            Ok(AddOutcome::Skipped),
+            zset.add(b"a".to_vec(), 1.0, &opts(true, false, false, false, false))
+        );
+        assert_eq!(
+            Ok(AddOutcome::Skipped),
+            zset.add(b"b".to_vec(), 1.0, &opts(false, true, false, false, false))
+        );
+        assert_eq!(
+            Ok(AddOutcome::Skipped),
+            zset.add(b"a".to_vec(), 1.0, &opts(false, false, true, false, false))
+        );
+        assert_eq!(
+            Ok(AddOutcome::Updated(7.0)),
+            zset.add(b"a".to_vec(), 7.0, &opts(false, false, true, false, false))
+        );
+        assert_eq!(
+            Ok(AddOutcome::Updated(4.0)),
+            zset.add(b"a".to_vec(), -3.0, &opts(false, false, false, false, true))
+        );
+        assert_eq!(
+            Ok(AddOutcome::Unchanged(4.0)),
+            zset.add(b"a".to_vec(), 4.0, &ZAddOptions::default())
+        );
+        zset.add(b"inf".to_vec(), f64::INFINITY, &ZAddOptions::default())
+            .unwrap();
+        assert_eq!(
+            Err(StoreError::new(StoreErrorKind::NotANumber)),
+            zset.add(
+                b"inf".to_vec(),
+                f64::NEG_INFINITY,
+                &opts(false, false, false, false, true)
+            )
+        );
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        assert_eq!(vec![(b"c".to_vec(), 3.0)], zset.pop(1, true));
+        assert_eq!(
+            vec![(b"a".to_vec(), 1.0), (b"b".to_vec(), 2.0)],
+            zset.pop(5, false)
+        );
+        assert!(zset.is_empty());
+        assert!(zset.pop(1, false).is_empty());
+    }
+}